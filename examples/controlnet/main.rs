@@ -2,9 +2,11 @@
 // https://huggingface.co/lllyasviel/sd-controlnet-canny/blob/main/diffusion_pytorch_model.safetensors
 // This has to be copied in data/controlnet.safetensors
 use clap::Parser;
-use diffusers::pipelines::stable_diffusion;
+use diffusers::pipelines::denoise::DenoiseLoop;
+use diffusers::pipelines::{controlnet, guidance, stable_diffusion};
 use diffusers::transformers::clip;
-use tch::{nn, nn::Module, Device, Kind, Tensor};
+use diffusers::utils::Interpolation;
+use tch::{nn, nn::Module, Kind, Tensor};
 
 const GUIDANCE_SCALE: f64 = 7.5;
 
@@ -86,6 +88,41 @@ struct Args {
     /// The type of ControlNet model to be used.
     #[arg(long, value_enum, default_value = "canny")]
     control_type: ControlType,
+
+    /// The low threshold of the canny edge detector.
+    #[arg(long, default_value_t = 50.)]
+    canny_low: f64,
+
+    /// The high threshold of the canny edge detector.
+    #[arg(long, default_value_t = 100.)]
+    canny_high: f64,
+
+    /// The interpolation used to resize the conditioning image to the generated image
+    /// size, nearest works best for edge maps and bilinear for depth maps.
+    #[arg(long, value_enum, default_value = "nearest")]
+    conditioning_interpolation: ConditioningInterpolation,
+
+    /// The scale applied to the ControlNet residuals, lower values give the prompt more
+    /// freedom with respect to the conditioning image.
+    #[arg(long, default_value_t = 1.0)]
+    conditioning_scale: f64,
+
+    /// An optional second input image, the ControlNet conditioning switches to this image
+    /// at the step specified by `--switch-step`.
+    #[arg(long, value_name = "FILE")]
+    switch_image: Option<String>,
+
+    /// The index of the step at which to switch to the second input image.
+    #[arg(long, default_value_t = 0)]
+    switch_step: usize,
+
+    /// The fraction of the steps after which the ControlNet starts being applied.
+    #[arg(long, default_value_t = 0.)]
+    control_guidance_start: f64,
+
+    /// The fraction of the steps after which the ControlNet stops being applied.
+    #[arg(long, default_value_t = 1.)]
+    control_guidance_end: f64,
 }
 
 fn output_filename(
@@ -115,31 +152,27 @@ fn output_filename(
     }
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ConditioningInterpolation {
+    /// Do not resize, the conditioning image must have the generated image size.
+    None,
+    Nearest,
+    Bilinear,
+}
+
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
 enum ControlType {
+    /// Detect the edges of the input image.
     Canny,
+    /// Use the input image as is, e.g. for a precomputed depth or pose map.
+    Passthrough,
 }
 
 impl ControlType {
-    fn image_preprocess<T: AsRef<std::path::Path>>(&self, path: T) -> anyhow::Result<Tensor> {
+    fn conditioning_type(&self, canny_low: f64, canny_high: f64) -> controlnet::ConditioningType {
         match self {
-            Self::Canny => {
-                // TODO: Use an implementation of the Canny edge detector in PyTorch
-                // and remove this dependency.
-                use image::EncodableLayout;
-                let image = image::open(path)?.to_luma8();
-                let edges = imageproc::edges::canny(&image, 50., 100.);
-                let tensor = Tensor::f_from_data_size(
-                    edges.as_bytes(),
-                    &[1, 1, edges.height() as i64, edges.width() as i64],
-                    Kind::Uint8,
-                )?;
-                let tensor = Tensor::f_concat(&[&tensor, &tensor, &tensor], 1)?;
-                // In order to look at the detected edges, uncomment the following line:
-                // tch::vision::image::save(&tensor.squeeze(), "/tmp/edges.png").unwrap();
-                let tensor = Tensor::f_concat(&[&tensor, &tensor], 0)?;
-                Ok(tensor.to_kind(Kind::Float) / 255.)
-            }
+            Self::Canny => controlnet::ConditioningType::Canny { low: canny_low, high: canny_high },
+            Self::Passthrough => controlnet::ConditioningType::Passthrough,
         }
     }
 }
@@ -162,8 +195,14 @@ fn run(args: Args) -> anyhow::Result<()> {
         clip_weights,
         controlnet_weights,
         control_type,
+        switch_image,
+        switch_step,
+        control_guidance_start,
+        control_guidance_end,
         ..
     } = args;
+    let control_window =
+        controlnet::ControlGuidanceWindow::new(control_guidance_start, control_guidance_end)?;
     tch::maybe_init_cuda();
     println!("Cuda available: {}", tch::Cuda::is_available());
     println!("Cudnn available: {}", tch::Cuda::cudnn_is_available());
@@ -172,12 +211,30 @@ fn run(args: Args) -> anyhow::Result<()> {
     let sd_config =
         stable_diffusion::StableDiffusionConfig::v1_5(sliced_attention_size, height, width);
 
-    let image = control_type.image_preprocess(input_image)?;
+    let interpolation = match args.conditioning_interpolation {
+        ConditioningInterpolation::None => None,
+        ConditioningInterpolation::Nearest => Some(Interpolation::Nearest),
+        ConditioningInterpolation::Bilinear => Some(Interpolation::Bilinear),
+    };
+    let conditioning_type = control_type.conditioning_type(args.canny_low, args.canny_high);
+    let image_preprocess = |path: String| -> anyhow::Result<Tensor> {
+        let image = tch::vision::image::load(path)?.unsqueeze(0);
+        let image = image.to_kind(Kind::Float) / 255.;
+        conditioning_type.prepare(&image, sd_config.height, sd_config.width, interpolation)
+    };
+    let image = image_preprocess(input_image)?;
+    let conditioning = match switch_image {
+        None => controlnet::ConditioningSchedule::single(image),
+        Some(switch_image) => {
+            let switch_image = image_preprocess(switch_image)?;
+            controlnet::ConditioningSchedule::switch(image, switch_image, switch_step, n_steps)
+        }
+    };
     let device_setup = diffusers::utils::DeviceSetup::new(cpu);
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
-    let scheduler = sd_config.build_scheduler(n_steps);
+    let mut scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
     println!("Running with prompt \"{prompt}\".");
@@ -199,60 +256,73 @@ fn run(args: Args) -> anyhow::Result<()> {
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
     println!("Building the unet.");
-    let unet = sd_config.build_unet(&unet_weights, unet_device, 4)?;
+    let unet = sd_config.build_unet(&unet_weights, unet_device, sd_config.latent_channels())?;
     println!("Building the controlnet.");
     let mut vs_controlnet = nn::VarStore::new(unet_device);
-    let controlnet =
-        diffusers::models::controlnet::ControlNet::new(vs_controlnet.root(), 4, Default::default());
+    let controlnet = diffusers::models::controlnet::ControlNet::new(
+        vs_controlnet.root(),
+        sd_config.latent_channels(),
+        Default::default(),
+    );
     vs_controlnet.load(controlnet_weights)?;
 
     let bsize = 1;
     for idx in 0..num_samples {
-        tch::manual_seed(seed + idx);
-        let mut latents = Tensor::randn(
-            [bsize, 4, sd_config.height / 8, sd_config.width / 8],
-            (Kind::Float, unet_device),
-        );
+        diffusers::utils::set_seed(seed + idx);
+        let latents = Tensor::randn(sd_config.latent_shape(bsize)?, (Kind::Float, unet_device));
 
         // scale the initial noise by the standard deviation required by the scheduler
-        latents *= scheduler.init_noise_sigma();
-
-        for (timestep_index, &timestep) in scheduler.timesteps().iter().enumerate() {
-            println!("Timestep {timestep_index}/{n_steps}");
-            let latent_model_input = Tensor::cat(&[&latents, &latents], 0);
-
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
-            let (down_block_additional_residuals, mid_block_additional_residuals) = controlnet
-                .forward(&latent_model_input, timestep as f64, &text_embeddings, &image, 1.);
-            let noise_pred = unet.forward_with_additional_residuals(
-                &latent_model_input,
-                timestep as f64,
-                &text_embeddings,
-                Some(&down_block_additional_residuals),
-                Some(&mid_block_additional_residuals),
-            );
-            let noise_pred = noise_pred.chunk(2, 0);
-            let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-            let noise_pred =
-                noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * GUIDANCE_SCALE;
-            latents = scheduler.step(&noise_pred, timestep, &latents);
-
-            if args.intermediary_images {
-                let latents = latents.to(vae_device);
-                let image = vae.decode(&(&latents / 0.18215));
-                let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
-                let image = (image * 255.).to_kind(Kind::Uint8);
-                let final_image =
-                    output_filename(&final_image, idx + 1, num_samples, Some(timestep_index + 1));
-                tch::vision::image::save(&image, final_image)?;
-            }
-        }
+        let latents = latents * scheduler.init_noise_sigma();
+
+        let latents = DenoiseLoop::new()
+            .on_step(|step_index, latents| {
+                println!("Timestep {step_index}/{n_steps}");
+                if args.intermediary_images {
+                    let latents = latents.to(vae_device);
+                    let image = vae.decode(&vae.unscale_latents(&latents));
+                    let image = vae.postprocess(&image);
+                    let final_image =
+                        output_filename(&final_image, idx + 1, num_samples, Some(step_index + 1));
+                    tch::vision::image::save(&image, final_image)?;
+                }
+                Ok(())
+            })
+            .run(&mut scheduler, latents, |step_index, timestep, latent_model_input| {
+                let control_active = control_window.is_active(step_index, n_steps);
+                Ok(guidance::guided_prediction(
+                    latent_model_input,
+                    &text_embeddings,
+                    GUIDANCE_SCALE,
+                    true,
+                    |xs, embeddings| {
+                        if !control_active {
+                            return unet.forward(xs, timestep, embeddings);
+                        }
+                        let image = conditioning.conditioning(step_index);
+                        let (down_block_additional_residuals, mid_block_additional_residuals) =
+                            controlnet.forward(
+                                xs,
+                                timestep,
+                                embeddings,
+                                image,
+                                args.conditioning_scale,
+                            );
+                        unet.forward_with_additional_residuals(
+                            xs,
+                            timestep,
+                            embeddings,
+                            Some(&down_block_additional_residuals),
+                            Some(&mid_block_additional_residuals),
+                        )
+                    },
+                ))
+            })?
+            .latents;
 
         println!("Generating the final image for sample {}/{}.", idx + 1, num_samples);
         let latents = latents.to(vae_device);
-        let image = vae.decode(&(&latents / 0.18215));
-        let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
-        let image = (image * 255.).to_kind(Kind::Uint8);
+        let image = vae.decode(&vae.unscale_latents(&latents));
+        let image = vae.postprocess(&image);
         let final_image = output_filename(&final_image, idx + 1, num_samples, None);
         tch::vision::image::save(&image, final_image)?;
     }
@@ -9,9 +9,11 @@
 // image: https://raw.githubusercontent.com/CompVis/stable-diffusion/main/assets/stable-samples/img2img/sketch-mountains-input.jpg
 // prompt = "A fantasy landscape, trending on artstation"
 use clap::Parser;
-use diffusers::pipelines::stable_diffusion;
+use diffusers::pipelines::denoise::DenoiseLoop;
+use diffusers::pipelines::{guidance, hires, inpaint, stable_diffusion};
 use diffusers::transformers::clip;
-use tch::{nn::Module, Device, Kind, Tensor};
+use diffusers::utils::Interpolation;
+use tch::{nn::Module, Kind, Tensor};
 
 const GUIDANCE_SCALE: f64 = 7.5;
 
@@ -19,8 +21,28 @@ const GUIDANCE_SCALE: f64 = 7.5;
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// The input image.
+    #[arg(long, value_name = "FILE", required_unless_present = "init_latents")]
+    input_image: Option<String>,
+
+    /// Start from latents saved with `--save-latents` rather than encoding the input
+    /// image, this skips the autoencoder encoding step.
+    #[arg(long, value_name = "FILE")]
+    init_latents: Option<String>,
+
+    /// Save the encoded input image latents to this file so that they can be reused
+    /// with `--init-latents`.
     #[arg(long, value_name = "FILE")]
-    input_image: String,
+    save_latents: Option<String>,
+
+    /// Only modify the image where this mask is white, the rest of the image being
+    /// composited from the input image at full resolution. Gray levels blend smoothly.
+    #[arg(long, value_name = "FILE", requires = "input_image")]
+    mask_image: Option<String>,
+
+    /// Match the color histogram of the generated image to the one of the input image,
+    /// this preserves the palette of the input image.
+    #[arg(long, action, requires = "input_image")]
+    color_match: bool,
 
     /// The prompt to be used for image generation.
     #[arg(long, default_value = "A fantasy landscape, trending on artstation.")]
@@ -61,6 +83,11 @@ struct Args {
     #[arg(long, default_value_t = 0.8)]
     strength: f64,
 
+    /// Add the noise at this absolute timestep, e.g. 600, rather than using the strength.
+    /// The denoising then starts from the first scheduler timestep not above this value.
+    #[arg(long)]
+    start_timestep: Option<usize>,
+
     /// The random seed to be used for the generation.
     #[arg(long, default_value_t = 32)]
     seed: i64,
@@ -79,6 +106,46 @@ struct Args {
 
     #[arg(long, value_enum, default_value = "v2-1")]
     sd_version: StableDiffusionVersion,
+
+    /// Upscale the initial latents by this factor before running img2img, this is the
+    /// second pass of the hires fix, e.g. with the latents saved by the main example.
+    #[arg(long, conflicts_with = "mask_image")]
+    upscale: Option<f64>,
+
+    /// How the initial latents get upscaled with `--upscale`.
+    #[arg(long, value_enum, default_value = "vae")]
+    upscale_mode: UpscaleMode,
+
+    /// The interpolation used by `--upscale`.
+    #[arg(long, value_enum, default_value = "lanczos")]
+    upscale_interpolation: UpscaleInterpolation,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum UpscaleMode {
+    /// Resize the latents directly.
+    Latent,
+    /// Decode the latents, resize the image, and encode it back.
+    Vae,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum UpscaleInterpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl UpscaleInterpolation {
+    fn interpolation(&self) -> Interpolation {
+        match self {
+            Self::Nearest => Interpolation::Nearest,
+            Self::Bilinear => Interpolation::Bilinear,
+            Self::Bicubic => Interpolation::Bicubic,
+            Self::Lanczos => Interpolation::Lanczos,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
@@ -122,12 +189,31 @@ impl Args {
 fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Tensor> {
     let image = tch::vision::image::load(path)?;
     let (_num_channels, height, width) = image.size3()?;
-    let height = height - height % 32;
-    let width = width - width % 32;
+    // The image is resized to the nearest multiple of 64 so that the latents can go
+    // through all the UNet downsampling layers.
+    let height = ((height + 32) / 64).max(1) * 64;
+    let width = ((width + 32) / 64).max(1) * 64;
     let image = tch::vision::image::resize(&image, width, height)?;
     Ok((image / 255. * 2. - 1.).unsqueeze(0))
 }
 
+// Loads a grayscale mask with values in [0, 1] and resizes it to the image resolution.
+fn mask_preprocess<T: AsRef<std::path::Path>>(
+    path: T,
+    height: i64,
+    width: i64,
+) -> anyhow::Result<Tensor> {
+    let mask = tch::vision::image::load(path)?;
+    let mask = mask.mean_dim(Some([0].as_slice()), true, Kind::Float) / 255.;
+    let mask = diffusers::utils::resize(
+        &mask.unsqueeze(0),
+        height,
+        width,
+        diffusers::utils::Interpolation::Bilinear,
+    );
+    Ok(mask)
+}
+
 fn run(args: Args) -> anyhow::Result<()> {
     let clip_weights = args.clip_weights();
     let vae_weights = args.vae_weights();
@@ -142,6 +228,9 @@ fn run(args: Args) -> anyhow::Result<()> {
         num_samples,
         strength,
         input_image,
+        init_latents,
+        save_latents,
+        mask_image,
         sd_version,
         vocab_file,
         ..
@@ -161,12 +250,11 @@ fn run(args: Args) -> anyhow::Result<()> {
         }
     };
 
-    let init_image = image_preprocess(input_image)?;
     let device_setup = diffusers::utils::DeviceSetup::new(cpu);
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
-    let scheduler = sd_config.build_scheduler(n_steps);
+    let mut scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
     println!("Running with prompt \"{prompt}\".");
@@ -188,42 +276,143 @@ fn run(args: Args) -> anyhow::Result<()> {
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
     println!("Building the unet.");
-    let unet = sd_config.build_unet(&unet_weights, unet_device, 4)?;
+    let unet = sd_config.build_unet(&unet_weights, unet_device, sd_config.latent_channels())?;
 
-    println!("Generating the latent from the input image {:?}.", init_image.size());
-    let init_image = init_image.to(vae_device);
-    let init_latent_dist = vae.encode(&init_image);
+    let (init_latents, init_image) = match (init_latents, input_image) {
+        (Some(init_latents), _) => {
+            println!("Loading the initial latents from {init_latents}.");
+            (Tensor::load(init_latents)?, None)
+        }
+        (None, Some(input_image)) => {
+            let init_image = image_preprocess(input_image)?;
+            println!("Generating the latent from the input image {:?}.", init_image.size());
+            (vae.encode_image(&init_image.to(vae_device)), Some(init_image))
+        }
+        (None, None) => anyhow::bail!("one of --input-image or --init-latents is required"),
+    };
+    if let Some(save_latents) = save_latents {
+        init_latents.save(save_latents)?;
+    }
+    let init_latents = match args.upscale {
+        None => init_latents,
+        Some(upscale) => {
+            let (_, _, latent_height, latent_width) = init_latents.size4()?;
+            let factor = vae.config.downsampling_factor();
+            // Rounds the upscaled size to a multiple of 64 pixels for the UNet.
+            let round = |latent_size: i64| {
+                let size = (latent_size * factor) as f64 * upscale;
+                ((size / 64.).round() as i64).max(1) * 64
+            };
+            let (height, width) = (round(latent_height), round(latent_width));
+            println!("Upscaling the initial latents to {width}x{height}.");
+            let interpolation = args.upscale_interpolation.interpolation();
+            let latents = init_latents.to(vae_device);
+            match args.upscale_mode {
+                UpscaleMode::Latent => {
+                    hires::upscale_latents(&vae, &latents, width, height, interpolation)?
+                }
+                UpscaleMode::Vae => {
+                    hires::upscale_latents_with_vae(&vae, &latents, width, height, interpolation)?
+                }
+            }
+        }
+    };
+    let init_latents = init_latents.to(unet_device);
+    // The input image with values in [0, 1], and the mask at full resolution and at the
+    // latent resolution.
+    let original = init_image.as_ref().map(|init_image| (init_image + 1.) / 2.);
+    let mask = match (mask_image, &init_image) {
+        (Some(mask_image), Some(init_image)) => {
+            let (_, _, height, width) = init_image.size4()?;
+            let mask = mask_preprocess(mask_image, height, width)?;
+            let (_, _, latent_height, latent_width) = init_latents.size4()?;
+            let latent_mask = diffusers::utils::resize(
+                &mask,
+                latent_height,
+                latent_width,
+                diffusers::utils::Interpolation::Bilinear,
+            );
+            Some((mask, latent_mask.to(unet_device)))
+        }
+        _ => None,
+    };
 
-    let t_start = n_steps - (n_steps as f64 * strength) as usize;
+    let (t_start, noise_timestep) = match args.start_timestep {
+        None => {
+            let t_start = n_steps - (n_steps as f64 * strength) as usize;
+            (t_start, scheduler.timesteps()[t_start])
+        }
+        Some(start_timestep) => {
+            let train_timesteps = sd_config.scheduler_config().train_timesteps;
+            let t_start = diffusers::schedulers::start_index_for_timestep(
+                scheduler.timesteps(),
+                start_timestep,
+                train_timesteps,
+            )?;
+            (t_start, start_timestep)
+        }
+    };
 
     for idx in 0..num_samples {
-        tch::manual_seed(seed + idx);
-        let latents = (init_latent_dist.sample() * 0.18215).to(unet_device);
+        diffusers::utils::set_seed(seed + idx);
+        let noise = init_latents.randn_like();
+        let latents = scheduler.add_noise(&init_latents, noise.shallow_clone(), noise_timestep);
+        // The initial latents noised to the level reached after each step, these replace
+        // the latents outside of the mask.
         let timesteps = scheduler.timesteps();
-        let noise = latents.randn_like();
-        let mut latents = scheduler.add_noise(&latents, noise, timesteps[t_start]);
+        let original_latents: Vec<Tensor> = match &mask {
+            None => vec![],
+            Some(_) => (0..timesteps.len())
+                .map(|step_index| match timesteps.get(step_index + 1) {
+                    Some(&timestep) => {
+                        scheduler.add_noise(&init_latents, noise.shallow_clone(), timestep)
+                    }
+                    None => init_latents.shallow_clone(),
+                })
+                .collect(),
+        };
 
-        for (timestep_index, &timestep) in timesteps.iter().enumerate() {
-            if timestep_index < t_start {
-                continue;
-            }
-            println!("Timestep {timestep_index}/{n_steps}");
-            let latent_model_input = Tensor::cat(&[&latents, &latents], 0);
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
-
-            let noise_pred = unet.forward(&latent_model_input, timestep as f64, &text_embeddings);
-            let noise_pred = noise_pred.chunk(2, 0);
-            let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-            let noise_pred =
-                noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * GUIDANCE_SCALE;
-            latents = scheduler.step(&noise_pred, timestep, &latents);
-        }
+        let latents = DenoiseLoop::new()
+            .start_step(t_start)
+            .post_step(|step_index, latents| match &mask {
+                None => latents,
+                Some((_, latent_mask)) => inpaint::keep_original_latents(
+                    &latents,
+                    &original_latents[step_index],
+                    latent_mask,
+                ),
+            })
+            .on_step(|step_index, _| {
+                println!("Timestep {step_index}/{n_steps}");
+                Ok(())
+            })
+            .run(&mut scheduler, latents, |_, timestep, latent_model_input| {
+                Ok(guidance::guided_prediction(
+                    latent_model_input,
+                    &text_embeddings,
+                    GUIDANCE_SCALE,
+                    true,
+                    |xs, embeddings| unet.forward(xs, timestep, embeddings),
+                ))
+            })?
+            .latents;
 
         println!("Generating the final image for sample {}/{}.", idx + 1, num_samples);
         let latents = latents.to(vae_device);
-        let image = vae.decode(&(&latents / 0.18215));
-        let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
-        let image = (image * 255.).to_kind(Kind::Uint8);
+        let image = vae.decode(&vae.unscale_latents(&latents));
+        let image = match (&mask, &original) {
+            (None, _) if !args.color_match => vae.postprocess(&image),
+            (mask, original) => {
+                let mut image = vae.config.output_range.to_unit_range(&image);
+                if let (true, Some(original)) = (args.color_match, original) {
+                    image = diffusers::utils::match_histogram(&image, original)
+                }
+                if let (Some((mask, _)), Some(original)) = (mask, original) {
+                    image = inpaint::composite_with_original(&image, original, mask)
+                }
+                (image.clamp(0., 1.) * 255.).to_kind(Kind::Uint8).to_device(tch::Device::Cpu)
+            }
+        };
         let final_image = if num_samples > 1 {
             match final_image.rsplit_once('.') {
                 None => format!("{}.{}.png", final_image, idx + 1),
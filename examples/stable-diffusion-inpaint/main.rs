@@ -15,7 +15,8 @@
 // Sample mask:
 // https://raw.githubusercontent.com/CompVis/latent-diffusion/main/data/inpainting_examples/overture-creations-5sI6fQgYIuo_mask.png
 use clap::Parser;
-use diffusers::pipelines::stable_diffusion;
+use diffusers::pipelines::denoise::DenoiseLoop;
+use diffusers::pipelines::{guidance, inpaint, stable_diffusion};
 use diffusers::transformers::clip;
 use tch::{nn::Module, Device, Kind, Tensor};
 
@@ -88,6 +89,43 @@ struct Args {
 
     #[arg(long, value_enum, default_value = "v1-5")]
     sd_version: StableDiffusionVersion,
+
+    /// When set, the initial latents are built from the input image rather than from pure
+    /// noise, this specifies how the masked region is initialized.
+    #[arg(long, value_enum)]
+    fill_mode: Option<FillMode>,
+
+    /// The number of final steps for which the latents of the unmasked region are replaced
+    /// by the ones of the original image.
+    #[arg(long, default_value_t = 0)]
+    keep_original_steps: usize,
+
+    /// Save the final image as RGBA, the alpha channel being the inpainting mask.
+    #[arg(long, action)]
+    rgba: bool,
+
+    /// The radius in pixels used to feather the mask edges in the RGBA output.
+    #[arg(long, default_value_t = 0)]
+    mask_feather: i64,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum FillMode {
+    Original,
+    Fill,
+    LatentNoise,
+    LatentNothing,
+}
+
+impl FillMode {
+    fn to_fill_mode(self) -> inpaint::InpaintFillMode {
+        match self {
+            Self::Original => inpaint::InpaintFillMode::Original,
+            Self::Fill => inpaint::InpaintFillMode::Fill,
+            Self::LatentNoise => inpaint::InpaintFillMode::LatentNoise,
+            Self::LatentNothing => inpaint::InpaintFillMode::LatentNothing,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
@@ -131,15 +169,15 @@ impl Args {
 fn prepare_mask_and_masked_image<T: AsRef<std::path::Path>>(
     path_input: T,
     path_mask: T,
-) -> anyhow::Result<(Tensor, Tensor)> {
+) -> anyhow::Result<(Tensor, Tensor, Tensor)> {
     let image = tch::vision::image::load(path_input)?;
     let image = image / 255. * 2. - 1.;
 
     let mask = tch::vision::image::load(path_mask)?;
     let mask = mask.mean_dim(Some([0].as_slice()), true, Kind::Float);
     let mask = mask.ge(122.5).totype(Kind::Float);
-    let masked_image: Tensor = image * (1 - &mask);
-    Ok((mask.unsqueeze(0), masked_image.unsqueeze(0)))
+    let masked_image: Tensor = &image * (1 - &mask);
+    Ok((image.unsqueeze(0), mask.unsqueeze(0), masked_image.unsqueeze(0)))
 }
 
 fn run(args: Args) -> anyhow::Result<()> {
@@ -160,6 +198,10 @@ fn run(args: Args) -> anyhow::Result<()> {
         mask_image,
         vocab_file,
         sd_version,
+        fill_mode,
+        keep_original_steps,
+        rgba,
+        mask_feather,
         ..
     } = args;
     tch::maybe_init_cuda();
@@ -175,13 +217,13 @@ fn run(args: Args) -> anyhow::Result<()> {
             width,
         ),
     };
-    let (mask, masked_image) = prepare_mask_and_masked_image(input_image, mask_image)?;
+    let (image, mask, masked_image) = prepare_mask_and_masked_image(input_image, mask_image)?;
     println!("Loaded input image and mask, {:?} {:?}.", masked_image.size(), mask.size());
     let device_setup = diffusers::utils::DeviceSetup::new(cpu);
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
-    let scheduler = sd_config.build_scheduler(n_steps);
+    let mut scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
     println!("Running with prompt \"{prompt}\".");
@@ -203,45 +245,81 @@ fn run(args: Args) -> anyhow::Result<()> {
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
     println!("Building the unet.");
-    let unet = sd_config.build_unet(&unet_weights, unet_device, 9)?;
+    let unet =
+        sd_config.build_unet(&unet_weights, unet_device, 2 * sd_config.latent_channels() + 1)?;
 
-    let mask = mask.upsample_nearest2d([sd_config.height / 8, sd_config.width / 8], None, None);
-    let mask = Tensor::cat(&[&mask, &mask], 0).to_device(unet_device);
+    let [_, _, latent_height, latent_width] = sd_config.latent_shape(1)?;
+    let latent_mask =
+        inpaint::latent_mask(&mask, latent_height, latent_width).to_device(unet_device);
     let masked_image_dist = vae.encode(&masked_image.to_device(vae_device));
+    let image_dist = vae.encode(&image.to_device(vae_device));
+    let init_image_dist = match fill_mode {
+        Some(fill_mode) => {
+            let init_image = fill_mode.to_fill_mode().prepare_image(&image, &mask);
+            Some(vae.encode(&init_image.to_device(vae_device)))
+        }
+        None => None,
+    };
+    let full_res_mask = mask.to_device(Device::Cpu);
+    let mask = Tensor::cat(&[&latent_mask, &latent_mask], 0);
 
     let bsize = 1;
     for idx in 0..num_samples {
-        tch::manual_seed(seed + idx);
-        let masked_image_latents = (masked_image_dist.sample() * 0.18215).to(unet_device);
+        diffusers::utils::set_seed(seed + idx);
+        let masked_image_latents = vae.scale_latents(&masked_image_dist.sample()).to(unet_device);
         let masked_image_latents = Tensor::cat(&[&masked_image_latents, &masked_image_latents], 0);
-        let mut latents = Tensor::randn(
-            [bsize, 4, sd_config.height / 8, sd_config.width / 8],
-            (Kind::Float, unet_device),
-        );
-
-        // scale the initial noise by the standard deviation required by the scheduler
-        latents *= scheduler.init_noise_sigma();
-
-        for (timestep_index, &timestep) in scheduler.timesteps().iter().enumerate() {
-            println!("Timestep {timestep_index}/{n_steps}");
-            let latent_model_input = Tensor::cat(&[&latents, &latents], 0);
-
-            // concat latents, mask, masked_image_latents in the channel dimension
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
-            let latent_model_input =
-                Tensor::cat(&[&latent_model_input, &mask, &masked_image_latents], 1);
-            let noise_pred = unet.forward(&latent_model_input, timestep as f64, &text_embeddings);
-            let noise_pred = noise_pred.chunk(2, 0);
-            let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-            let noise_pred =
-                noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * GUIDANCE_SCALE;
-            latents = scheduler.step(&noise_pred, timestep, &latents);
-        }
+        let noise = Tensor::randn(sd_config.latent_shape(bsize)?, (Kind::Float, unet_device));
+        let original_latents = vae.scale_latents(&image_dist.sample()).to(unet_device);
+        let latents = match (fill_mode, &init_image_dist) {
+            (Some(fill_mode), Some(init_image_dist)) => {
+                let init_latents = vae.scale_latents(&init_image_dist.sample()).to(unet_device);
+                let init_latents = fill_mode.to_fill_mode().prepare_latents(
+                    &init_latents,
+                    &latent_mask,
+                    &init_latents.randn_like(),
+                );
+                scheduler.add_noise(&init_latents, noise, scheduler.timesteps()[0])
+            }
+            // scale the initial noise by the standard deviation required by the scheduler
+            _ => noise * scheduler.init_noise_sigma(),
+        };
+
+        let latents = DenoiseLoop::new()
+            .post_step(|step_index, latents| {
+                if step_index + keep_original_steps >= n_steps {
+                    inpaint::keep_original_latents(&latents, &original_latents, &latent_mask)
+                } else {
+                    latents
+                }
+            })
+            .on_step(|step_index, _| {
+                println!("Timestep {step_index}/{n_steps}");
+                Ok(())
+            })
+            .run(&mut scheduler, latents, |_, timestep, latent_model_input| {
+                Ok(guidance::guided_prediction(
+                    latent_model_input,
+                    &text_embeddings,
+                    GUIDANCE_SCALE,
+                    true,
+                    |xs, embeddings| {
+                        // concat latents, mask, masked_image_latents in the channel dimension
+                        let xs = Tensor::cat(&[xs, &mask, &masked_image_latents], 1);
+                        unet.forward(&xs, timestep, embeddings)
+                    },
+                ))
+            })?
+            .latents;
 
         println!("Generating the final image for sample {}/{}.", idx + 1, num_samples);
         let latents = latents.to(vae_device);
-        let image = vae.decode(&(&latents / 0.18215));
-        let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
+        let image = vae.decode(&vae.unscale_latents(&latents));
+        let image = vae.config.output_range.to_unit_range(&image).to_device(Device::Cpu);
+        let image = if rgba {
+            inpaint::rgba_with_mask(&image, &full_res_mask, mask_feather, false)
+        } else {
+            image
+        };
         let image = (image * 255.).to_kind(Kind::Uint8);
         let final_image = if num_samples > 1 {
             match final_image.rsplit_once('.') {
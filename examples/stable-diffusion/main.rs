@@ -57,11 +57,19 @@
 //   model = torch.load("./unet.bin")
 //   save_file(dict(model), './unet.safetensors')
 use clap::Parser;
-use diffusers::pipelines::stable_diffusion;
+use diffusers::models::attention::Region;
+use diffusers::models::lora;
+use diffusers::models::vae::LatentDecoder;
+use diffusers::pipelines::denoise::{self, DenoiseLoop};
+use diffusers::pipelines::prompt_schedule::PromptSchedule;
+use diffusers::pipelines::reference::ReferenceAttention;
+use diffusers::pipelines::{guidance, regional, stable_diffusion};
+use diffusers::schedulers::config::{
+    check_prediction_type, PretrainedSchedulerConfig, SchedulerConfig,
+};
+use diffusers::schedulers::{ddim, lcm, tcd, PredictionType, Scheduler};
 use diffusers::transformers::clip;
-use tch::{nn::Module, Device, Kind, Tensor};
-
-const GUIDANCE_SCALE: f64 = 7.5;
+use tch::{Kind, Tensor};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -73,6 +81,11 @@ struct Args {
     )]
     prompt: String,
 
+    /// The negative prompt, prompts longer than the text model context are split in
+    /// chunks, the BREAK keyword can be used to force a new chunk.
+    #[arg(long, default_value = "")]
+    negative_prompt: String,
+
     /// When set, use the CPU for the listed devices, can be 'all', 'unet', 'clip', etc.
     /// Multiple values can be set.
     #[arg(long)]
@@ -98,6 +111,12 @@ struct Args {
     #[arg(long, value_name = "FILE")]
     vae_weights: Option<String>,
 
+    /// Decode the final images with the consistency decoder using this weight file, e.g.
+    /// the diffusers openai/consistency-decoder checkpoint in .safetensors format. This
+    /// only works with stable diffusion 1.5.
+    #[arg(long, value_name = "FILE")]
+    consistency_decoder_weights: Option<String>,
+
     #[arg(long, value_name = "FILE", default_value = "data/bpe_simple_vocab_16e6.txt")]
     /// The file specifying the vocabulary to used for tokenization.
     vocab_file: String,
@@ -107,9 +126,91 @@ struct Args {
     sliced_attention_size: Option<i64>,
 
     /// The number of steps to run the diffusion for.
-    #[arg(long, default_value_t = 30)]
+    #[arg(long, alias = "steps", default_value_t = 30)]
     n_steps: usize,
 
+    /// The classifier free guidance scale, higher values follow the prompt more closely.
+    #[arg(long, alias = "guidance", default_value_t = 7.5)]
+    guidance_scale: f64,
+
+    /// Use the LCM scheduler for the few-step sampling of LCM and turbo models, or of a
+    /// model with an LCM-LoRA passed with `--lora`. These are typically run with 4 steps
+    /// and a guidance scale of 1, which skips the unconditional branch.
+    #[arg(long, action)]
+    lcm: bool,
+
+    /// Use the TCD scheduler for the few-step sampling of a model with a TCD-LoRA, see
+    /// `--tcd-eta`. As with `--lcm`, a guidance scale of 1 is typical.
+    #[arg(long, action, conflicts_with = "lcm")]
+    tcd: bool,
+
+    /// The stochasticity of the TCD sampling, 0 is deterministic and gives more details,
+    /// higher values give smoother images.
+    #[arg(long, default_value_t = 0.3)]
+    tcd_eta: f64,
+
+    /// Rescale the guided predictions to reduce overexposure, values around 0.7 are
+    /// typical. Mostly useful with v-prediction models such as stable diffusion 2.1.
+    #[arg(long, default_value_t = 0.)]
+    guidance_rescale: f64,
+
+    /// Taper the guidance rescaling off linearly so that it stops at this step index.
+    #[arg(long)]
+    guidance_rescale_end_step: Option<usize>,
+
+    /// The scale for perturbed attention guidance, this runs the UNet a third time per
+    /// step. Values around 3 are typical, 0 disables it.
+    #[arg(long, default_value_t = 0.)]
+    pag_scale: f64,
+
+    /// Use a separate guidance scale for steering away from the negative prompt, the
+    /// guidance then also runs the UNet on the empty prompt.
+    #[arg(long)]
+    negative_guidance_scale: Option<f64>,
+
+    /// A mask image restricting the guidance scale to its white region, the guidance
+    /// scale is `--guidance-scale-outside` on the black region.
+    #[arg(long, value_name = "FILE")]
+    guidance_mask: Option<String>,
+
+    /// The guidance scale used outside of `--guidance-mask`.
+    #[arg(long, default_value_t = 3.)]
+    guidance_scale_outside: f64,
+
+    /// An image used as a reference for reference-only control, the generated images
+    /// follow its style and content.
+    #[arg(long, value_name = "FILE")]
+    reference_image: Option<String>,
+
+    /// How much the reference image gets followed, between 0 and 1.
+    #[arg(long, default_value_t = 1.)]
+    reference_scale: f64,
+
+    /// A diffusers `scheduler_config.json` file, its noise schedule and prediction type
+    /// are used rather than the built-in ones for the selected version.
+    #[arg(long, value_name = "FILE")]
+    scheduler_config: Option<String>,
+
+    /// A diffusers model folder, e.g. a clone of the stabilityai/stable-diffusion-2-1
+    /// repo, the noise schedule and prediction type of its scheduler config are used.
+    /// The weight files are still set with the weight flags.
+    #[arg(long, value_name = "DIR", conflicts_with = "scheduler_config")]
+    model_dir: Option<String>,
+
+    /// Override the prediction type, one of epsilon, v_prediction, or sample. A warning is
+    /// printed when it does not match the one of the model scheduler config.
+    #[arg(long, value_parser = PredictionType::from_name)]
+    prediction_type: Option<PredictionType>,
+
+    /// Save the full pipeline configuration as json to this file.
+    #[arg(long, value_name = "FILE")]
+    save_config: Option<String>,
+
+    /// Use the pipeline configuration saved with `--save-config` rather than the one of
+    /// `--sd-version`, the weight files still have to match it.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["scheduler_config", "model_dir"])]
+    config: Option<String>,
+
     /// The random seed to be used for the generation.
     #[arg(long, default_value_t = 32)]
     seed: i64,
@@ -118,8 +219,13 @@ struct Args {
     #[arg(long, default_value_t = 1)]
     num_samples: i64,
 
+    /// A comma separated list of seeds, one per sample. This overrides both the seed
+    /// and the number of samples.
+    #[arg(long, value_delimiter = ',')]
+    seeds: Vec<i64>,
+
     /// The name of the final image to generate.
-    #[arg(long, value_name = "FILE", default_value = "sd_final.png")]
+    #[arg(long, alias = "output", value_name = "FILE", default_value = "sd_final.png")]
     final_image: String,
 
     /// Use autocast (disabled by default as it may use more memory in some cases).
@@ -132,14 +238,210 @@ struct Args {
     /// Generate intermediary images at each step.
     #[arg(long, action)]
     intermediary_images: bool,
+
+    /// Generate images of the denoised latents predicted at each step, this shows the
+    /// final image emerging from the noise.
+    #[arg(long, action)]
+    intermediary_predictions: bool,
+
+    /// Skip the VAE decoding and save a low resolution preview computed directly from the
+    /// latents.
+    #[arg(long, action)]
+    latent_preview: bool,
+
+    /// Print the activation memory used at each block boundary for the first
+    /// unet step and for the vae decoding.
+    #[arg(long, action)]
+    memory_report: bool,
+
+    /// Enable FreeU with the suggested values for the stable diffusion version.
+    #[arg(long, action)]
+    freeu: bool,
+
+    /// Run the linear layers of the UNet with int8 weights, this requires running the UNet
+    /// on the cpu.
+    #[arg(long, action)]
+    int8: bool,
+
+    /// A LoRA safetensors file in the kohya-ss format, merged in the model weights.
+    #[arg(long, value_name = "FILE", conflicts_with = "int8")]
+    lora: Option<String>,
+
+    /// The strength of the LoRA, 1 applies it as trained.
+    #[arg(long, default_value_t = 1.0)]
+    lora_strength: f64,
+
+    /// The components the LoRA gets applied to.
+    #[arg(long, value_enum, default_value = "all")]
+    lora_target: LoraTarget,
+
+    /// Decode the final latents in tiles of this many latent pixels to reduce the VAE
+    /// memory usage, e.g. 64 for large images.
+    #[arg(long)]
+    vae_tile_size: Option<i64>,
+
+    /// The overlap between the VAE tiles, in latent pixels.
+    #[arg(long, default_value_t = 8)]
+    vae_tile_overlap: i64,
+
+    /// Run the unconditional and conditional UNet passes sequentially rather than as a
+    /// single batch, this lowers the memory usage at the cost of speed.
+    #[arg(long, action)]
+    sequential_cfg: bool,
+
+    /// Add a per-channel offset to the initial noise, this helps models trained with a
+    /// noise offset to generate very dark or bright images.
+    #[arg(long, default_value_t = 0.0)]
+    noise_offset: f64,
+
+    /// Apply a prompt to a region of the image, the format is `x0,y0,x1,y1:prompt` with
+    /// coordinates given as fractions of the image size, e.g. `0,0,0.5,1:a red car`.
+    /// Can be repeated, the main prompt then applies to the whole image.
+    #[arg(long)]
+    region: Vec<String>,
+
+    /// Stop the denoising after the step with this index and save the latents without
+    /// decoding them to `--latents-file`, e.g. to hand them over to img2img with
+    /// `--init-latents`. Values past the last step run the full denoising.
+    #[arg(long)]
+    stop_at_step: Option<usize>,
+
+    /// Blend the latents with the ones from this file after the step `--blend-step`, the
+    /// file can be generated by another run with `--stop-at-step`.
+    #[arg(long, value_name = "FILE", requires = "blend_step")]
+    blend_latents: Option<String>,
+
+    /// The index of the step after which the latents get blended.
+    #[arg(long)]
+    blend_step: Option<usize>,
+
+    /// The weight of the latents from `--blend-latents` in the blend.
+    #[arg(long, default_value_t = 0.5)]
+    blend_weight: f64,
+
+    /// The file where the latents are saved when using `--stop-at-step`.
+    #[arg(long, value_name = "FILE", default_value = "sd_latents.pt")]
+    latents_file: String,
+
+    /// Allow any height and width by generating at the next multiple of 64 and cropping
+    /// the center of the result back to the requested size.
+    #[arg(long, action)]
+    pad_and_crop: bool,
+
+    /// Stop the denoising once the latents change by less than this tolerance between two
+    /// steps, this is only effective with deterministic schedulers.
+    #[arg(long)]
+    early_stop_tolerance: Option<f64>,
+}
+
+// The schedulers that can be selected from the command line.
+enum ExampleScheduler {
+    Ddim(ddim::DDIMScheduler),
+    Lcm(lcm::LCMScheduler),
+    Tcd(tcd::TCDScheduler),
+}
+
+impl ExampleScheduler {
+    fn min_recommended_steps(&self) -> usize {
+        match self {
+            Self::Ddim(s) => s.min_recommended_steps(),
+            Self::Lcm(s) => s.min_recommended_steps(),
+            Self::Tcd(s) => s.min_recommended_steps(),
+        }
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Tensor {
+        match self {
+            Self::Ddim(s) => s.add_noise(original, noise, timestep),
+            Self::Lcm(s) => s.add_noise(original, noise, timestep),
+            Self::Tcd(s) => s.add_noise(original, noise, timestep),
+        }
+    }
+}
+
+impl Scheduler for ExampleScheduler {
+    type Timestep = usize;
+
+    fn timesteps(&self) -> &[usize] {
+        match self {
+            Self::Ddim(s) => s.timesteps(),
+            Self::Lcm(s) => s.timesteps(),
+            Self::Tcd(s) => s.timesteps(),
+        }
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        match self {
+            Self::Ddim(s) => s.init_noise_sigma(),
+            Self::Lcm(s) => s.init_noise_sigma(),
+            Self::Tcd(s) => s.init_noise_sigma(),
+        }
+    }
+
+    fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Tensor {
+        match self {
+            Self::Ddim(s) => s.scale_model_input(sample, timestep),
+            Self::Lcm(s) => s.scale_model_input(sample, timestep),
+            Self::Tcd(s) => s.scale_model_input(sample, timestep),
+        }
+    }
+
+    fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
+        match self {
+            Self::Ddim(s) => s.step(model_output, timestep, sample),
+            Self::Lcm(s) => s.step(model_output, timestep, sample),
+            Self::Tcd(s) => s.step(model_output, timestep, sample),
+        }
+    }
+
+    fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> Option<Tensor> {
+        let pred = match self {
+            Self::Ddim(s) => s.pred_original_sample(model_output, timestep, sample),
+            Self::Lcm(s) => s.pred_original_sample(model_output, timestep, sample),
+            Self::Tcd(s) => s.pred_original_sample(model_output, timestep, sample),
+        };
+        Some(pred)
+    }
+
+    fn scheduler_config(&self) -> Option<SchedulerConfig> {
+        match self {
+            Self::Ddim(s) => s.scheduler_config(),
+            Self::Lcm(s) => s.scheduler_config(),
+            Self::Tcd(s) => s.scheduler_config(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum LoraTarget {
+    All,
+    Unet,
+    TextEncoder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 enum StableDiffusionVersion {
     V1_5,
     V2_1,
 }
 
+fn parse_region(region: &str) -> anyhow::Result<(Region, String)> {
+    let (coords, prompt) = match region.split_once(':') {
+        Some(v) => v,
+        None => anyhow::bail!("expected x0,y0,x1,y1:prompt, got {region}"),
+    };
+    let coords = coords.split(',').map(|v| v.trim().parse()).collect::<Result<Vec<f64>, _>>()?;
+    match coords.as_slice() {
+        &[x0, y0, x1, y1] => Ok((Region { x0, y0, x1, y1 }, prompt.to_string())),
+        _ => anyhow::bail!("expected four coordinates, got {region}"),
+    }
+}
+
 impl Args {
     fn clip_weights(&self) -> String {
         match &self.clip_weights {
@@ -205,6 +507,7 @@ fn run(args: Args) -> anyhow::Result<()> {
     let unet_weights = args.unet_weights();
     let Args {
         prompt,
+        negative_prompt,
         cpu,
         height,
         width,
@@ -222,6 +525,18 @@ fn run(args: Args) -> anyhow::Result<()> {
     println!("Cudnn available: {}", tch::Cuda::cudnn_is_available());
     println!("MPS available: {}", tch::utils::has_mps());
 
+    // The requested size, used to crop the generated images when padding.
+    let crop_size = match (args.pad_and_crop, height, width) {
+        (true, Some(h), Some(w)) => Some((h, w)),
+        _ => None,
+    };
+    let (height, width) = match crop_size {
+        Some((h, w)) => {
+            let (h, w) = diffusers::utils::padded_size(h, w, 64);
+            (Some(h), Some(w))
+        }
+        None => (height, width),
+    };
     let sd_config = match sd_version {
         StableDiffusionVersion::V1_5 => {
             stable_diffusion::StableDiffusionConfig::v1_5(sliced_attention_size, height, width)
@@ -230,74 +545,392 @@ fn run(args: Args) -> anyhow::Result<()> {
             stable_diffusion::StableDiffusionConfig::v2_1(sliced_attention_size, height, width)
         }
     };
-
+    let sd_config = match &args.config {
+        None => sd_config,
+        Some(file) => {
+            stable_diffusion::StableDiffusionConfig::from_json(&std::fs::read_to_string(file)?)?
+        }
+    };
+    let pretrained_scheduler = match (&args.scheduler_config, &args.model_dir) {
+        (Some(file), _) => Some((file.clone(), PretrainedSchedulerConfig::from_json(file)?)),
+        (None, Some(dir)) => {
+            PretrainedSchedulerConfig::from_model_dir(dir)?.map(|config| (dir.clone(), config))
+        }
+        (None, None) => None,
+    };
+    let sd_config = match &pretrained_scheduler {
+        None => sd_config,
+        Some((file, config)) => {
+            println!(
+                "Using the noise schedule from {file} ({}, {:?}).",
+                config.class_name, config.prediction_type
+            );
+            sd_config.with_scheduler_config(config)
+        }
+    };
+    let sd_config = match args.prediction_type {
+        None => sd_config,
+        Some(prediction_type) => {
+            let detected = pretrained_scheduler.as_ref().map(|(_, c)| c.prediction_type);
+            if let Some(warning) = check_prediction_type(prediction_type, detected) {
+                println!("Warning: {warning}.");
+            }
+            sd_config.with_prediction_type(prediction_type)
+        }
+    };
     let device_setup = diffusers::utils::DeviceSetup::new(cpu);
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
-    let scheduler = sd_config.build_scheduler(n_steps);
+    let mut scheduler = if args.lcm {
+        ExampleScheduler::Lcm(sd_config.build_lcm_scheduler(n_steps))
+    } else if args.tcd {
+        ExampleScheduler::Tcd(sd_config.build_tcd_scheduler(n_steps, args.tcd_eta))
+    } else {
+        ExampleScheduler::Ddim(sd_config.build_scheduler(n_steps))
+    };
+    if let Some(save_config) = &args.save_config {
+        let mut config = sd_config.clone();
+        if let Some(scheduler) = scheduler.scheduler_config() {
+            config = config.with_scheduler(scheduler)
+        }
+        if let Some(lora) = &args.lora {
+            config = config.with_lora(lora, args.lora_strength)
+        }
+        std::fs::write(save_config, config.config_json())?;
+    }
+    let min_steps = scheduler.min_recommended_steps();
+    if let Some(warning) =
+        diffusers::schedulers::validate_inference_steps(n_steps, min_steps, false)?
+    {
+        println!("Warning: {warning}.");
+    }
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
+    let prompt_schedule = PromptSchedule::parse(&prompt, n_steps)?;
+    if prompt_schedule.entries().len() > 1 && !args.region.is_empty() {
+        anyhow::bail!("prompt editing cannot be combined with regional prompts")
+    }
+    if args.negative_guidance_scale.is_some() {
+        if prompt_schedule.entries().len() > 1 || !args.region.is_empty() {
+            anyhow::bail!("negative guidance cannot be combined with prompt editing or regions")
+        }
+        if args.pag_scale != 0. {
+            anyhow::bail!("negative guidance cannot be combined with perturbed attention guidance")
+        }
+    }
+    if args.guidance_mask.is_some()
+        && (args.negative_guidance_scale.is_some() || args.pag_scale != 0.)
+    {
+        anyhow::bail!("a guidance mask cannot be combined with negative or perturbed guidance")
+    }
+    let prompt = &prompt_schedule.entries()[0].1;
     println!("Running with prompt \"{prompt}\".");
-    let tokens = tokenizer.encode(&prompt)?;
-    let tokens: Vec<i64> = tokens.into_iter().map(|x| x as i64).collect();
-    let tokens = Tensor::from_slice(&tokens).view((1, -1)).to(clip_device);
-    let uncond_tokens = tokenizer.encode("")?;
-    let uncond_tokens: Vec<i64> = uncond_tokens.into_iter().map(|x| x as i64).collect();
-    let uncond_tokens = Tensor::from_slice(&uncond_tokens).view((1, -1)).to(clip_device);
+    let (tokens, uncond_tokens) = tokenizer.encode_chunks_pair(prompt, &negative_prompt)?;
+    let chunks_to_tensor = |chunks: Vec<Vec<usize>>| {
+        let n_chunks = chunks.len() as i64;
+        let tokens: Vec<i64> = chunks.into_iter().flatten().map(|x| x as i64).collect();
+        Tensor::from_slice(&tokens).view((n_chunks, -1)).to(clip_device)
+    };
+    let tokens = chunks_to_tensor(tokens);
+    let uncond_tokens = chunks_to_tensor(uncond_tokens);
 
     let no_grad_guard = tch::no_grad_guard();
 
     println!("Building the Clip transformer.");
-    let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-    let text_embeddings = text_model.forward(&tokens);
-    let uncond_embeddings = text_model.forward(&uncond_tokens);
-    let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+    let lora = match &args.lora {
+        None => None,
+        Some(lora) => {
+            let options = lora::LoraOptions {
+                strength: args.lora_strength,
+                apply_to_unet: !matches!(args.lora_target, LoraTarget::TextEncoder),
+                apply_to_text_encoder: !matches!(args.lora_target, LoraTarget::Unet),
+            };
+            Some((lora::Lora::read(lora)?, options))
+        }
+    };
+    let (text_model, clip_vs) =
+        sd_config.build_clip_transformer_with_var_store(&clip_weights, clip_device)?;
+    if let Some((lora, options)) = lora.as_ref().filter(|(_, o)| o.apply_to_text_encoder) {
+        let n = lora::merge_lora(&clip_vs, &lora.text_encoder, options.strength)?;
+        println!("Merged the LoRA in {n} text encoder weights.");
+    }
+    let text_embeddings = text_model.forward_chunks(&tokens);
+    let uncond_embeddings = text_model.forward_chunks(&uncond_tokens);
+    let regional_attention = if args.region.is_empty() {
+        None
+    } else {
+        let mut prompt_embeddings = vec![(Region::full(), text_embeddings.shallow_clone())];
+        for region in args.region.iter() {
+            let (region, prompt) = parse_region(region)?;
+            println!("Running with prompt \"{prompt}\" for region {region:?}.");
+            let tokens = chunks_to_tensor(tokenizer.encode_chunks(&prompt)?);
+            prompt_embeddings.push((region, text_model.forward_chunks(&tokens)))
+        }
+        let [_, _, latent_height, latent_width] = sd_config.latent_shape(1)?;
+        let (embeddings, regional_attention) = regional::regional_embeddings(
+            &uncond_embeddings,
+            &prompt_embeddings,
+            latent_height,
+            latent_width,
+        )?;
+        Some((embeddings, regional_attention))
+    };
+    let (text_embeddings, regional_attention) = match regional_attention {
+        Some((embeddings, regional_attention)) => (embeddings, Some(regional_attention)),
+        None if args.negative_guidance_scale.is_some() => {
+            // The empty prompt embeddings, repeated to match the prompt chunks.
+            let n_chunks = tokens.size()[0];
+            let empty_tokens = chunks_to_tensor(vec![tokenizer.encode("")?]);
+            let empty_embeddings =
+                text_model.forward_chunks(&empty_tokens).repeat([1, n_chunks, 1]);
+            (Tensor::cat(&[empty_embeddings, text_embeddings, uncond_embeddings], 0), None)
+        }
+        None => (Tensor::cat(&[uncond_embeddings, text_embeddings], 0), None),
+    };
+    // The embeddings for each of the prompts of the schedule.
+    let mut text_embeddings = vec![text_embeddings.to(unet_device)];
+    for (end_step, prompt) in prompt_schedule.entries().windows(2).map(|w| (w[0].0, &w[1].1)) {
+        println!("Switching to prompt \"{prompt}\" at step {end_step}.");
+        let (tokens, uncond_tokens) = tokenizer.encode_chunks_pair(prompt, &negative_prompt)?;
+        let embeddings = text_model.forward_chunks(&chunks_to_tensor(tokens));
+        let uncond_embeddings = text_model.forward_chunks(&chunks_to_tensor(uncond_tokens));
+        text_embeddings.push(Tensor::cat(&[uncond_embeddings, embeddings], 0).to(unet_device))
+    }
 
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
+    let consistency_decoder = match &args.consistency_decoder_weights {
+        None => None,
+        Some(_) if sd_version != StableDiffusionVersion::V1_5 => {
+            anyhow::bail!("the consistency decoder only supports stable diffusion 1.5")
+        }
+        Some(weights) => Some(sd_config.build_consistency_decoder(weights, vae_device)?),
+    };
     println!("Building the unet.");
-    let unet = sd_config.build_unet(&unet_weights, unet_device, 4)?;
+    let mut unet = if args.int8 {
+        sd_config.build_unet_int8(&unet_weights, unet_device, sd_config.latent_channels())?
+    } else {
+        let (unet, unet_vs) = sd_config.build_unet_with_var_store(
+            &unet_weights,
+            unet_device,
+            sd_config.latent_channels(),
+        )?;
+        if let Some((lora, options)) = lora.as_ref().filter(|(_, o)| o.apply_to_unet) {
+            let n = lora::merge_lora(&unet_vs, &lora.unet, options.strength)?;
+            println!("Merged the LoRA in {n} unet weights.");
+        }
+        unet
+    };
+    unet.set_regional_attention(regional_attention);
+    if args.freeu {
+        match sd_version {
+            StableDiffusionVersion::V1_5 => unet.enable_freeu(0.9, 0.2, 1.5, 1.6),
+            StableDiffusionVersion::V2_1 => unet.enable_freeu(0.9, 0.2, 1.4, 1.6),
+        }
+    }
+
+    let guidance_rescale = match args.guidance_rescale_end_step {
+        None => guidance::GuidanceRescale::constant(args.guidance_rescale),
+        Some(end_step) => guidance::GuidanceRescale::until(args.guidance_rescale, end_step),
+    };
+    let reference = match &args.reference_image {
+        None => None,
+        Some(reference_image) => {
+            println!("Encoding the reference image.");
+            let image = tch::vision::image::load_and_resize(
+                reference_image,
+                sd_config.width,
+                sd_config.height,
+            )?;
+            let image = (image / 255. * 2. - 1.).unsqueeze(0).to(vae_device);
+            let reference_latents = vae.encode_image(&image).to(unet_device);
+            let reference = ReferenceAttention::new(args.reference_scale);
+            reference.install(&mut unet)?;
+            Some((reference, reference_latents))
+        }
+    };
+
+    let guidance_scale_map = match &args.guidance_mask {
+        None => None,
+        Some(guidance_mask) => {
+            let mask = tch::vision::image::load_and_resize(
+                guidance_mask,
+                sd_config.width,
+                sd_config.height,
+            )?;
+            let mask = mask.mean_dim(Some([0].as_slice()), true, Kind::Float) / 255.;
+            let [_, _, latent_height, latent_width] = sd_config.latent_shape(1)?;
+            let map = guidance::guidance_scale_map(
+                &mask.unsqueeze(0),
+                latent_height,
+                latent_width,
+                args.guidance_scale,
+                args.guidance_scale_outside,
+            );
+            Some(map.to(unet_device))
+        }
+    };
+
+    let blend_latents = match &args.blend_latents {
+        None => None,
+        Some(file) => Some(Tensor::load(file)?.to_device(unet_device)),
+    };
 
     let bsize = 1;
-    for idx in 0..num_samples {
-        tch::manual_seed(seed + idx);
-        let mut latents = Tensor::randn(
-            [bsize, 4, sd_config.height / 8, sd_config.width / 8],
-            (Kind::Float, unet_device),
-        );
+    let (seeds, num_samples) = if args.seeds.is_empty() {
+        ((seed..seed + num_samples).collect::<Vec<_>>(), num_samples)
+    } else {
+        (args.seeds.clone(), args.seeds.len() as i64)
+    };
+    for (idx, &sample_seed) in (0..num_samples).zip(seeds.iter()) {
+        diffusers::utils::set_seed(sample_seed);
+        // The reference latents noised to each of the timesteps.
+        let reference_inputs: Vec<Tensor> = match &reference {
+            None => vec![],
+            Some((_, reference_latents)) => {
+                let noise = reference_latents.randn_like();
+                let timesteps = scheduler.timesteps();
+                let noised = timesteps.iter().map(|&timestep| {
+                    let latents =
+                        scheduler.add_noise(reference_latents, noise.shallow_clone(), timestep);
+                    scheduler.scale_model_input(latents, timestep)
+                });
+                noised.collect()
+            }
+        };
+        let latents = Tensor::randn(sd_config.latent_shape(bsize)?, (Kind::Float, unet_device));
+        let latents = diffusers::utils::apply_noise_offset(&latents, args.noise_offset);
 
         // scale the initial noise by the standard deviation required by the scheduler
-        latents *= scheduler.init_noise_sigma();
-
-        for (timestep_index, &timestep) in scheduler.timesteps().iter().enumerate() {
-            println!("Timestep {timestep_index}/{n_steps}");
-            let latent_model_input = Tensor::cat(&[&latents, &latents], 0);
-
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
-            let noise_pred = unet.forward(&latent_model_input, timestep as f64, &text_embeddings);
-            let noise_pred = noise_pred.chunk(2, 0);
-            let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-            let noise_pred =
-                noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * GUIDANCE_SCALE;
-            latents = scheduler.step(&noise_pred, timestep, &latents);
-
-            if args.intermediary_images {
-                let latents = latents.to(vae_device);
-                let image = vae.decode(&(&latents / 0.18215));
-                let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
-                let image = (image * 255.).to_kind(Kind::Uint8);
-                let final_image =
-                    output_filename(&final_image, idx + 1, num_samples, Some(timestep_index + 1));
-                tch::vision::image::save(&image, final_image)?;
-            }
+        let latents = latents * scheduler.init_noise_sigma();
+
+        let mut timer = diffusers::utils::StepTimer::new(scheduler.timesteps().len(), 10);
+        let mut memory_report = args.memory_report && idx == 0;
+        let output = DenoiseLoop::new()
+            .stop_after_step(args.stop_at_step)
+            .early_stopping(args.early_stop_tolerance)
+            .post_step(|step_index, latents| match &blend_latents {
+                Some(other) if args.blend_step == Some(step_index) => {
+                    denoise::blend_latents(&latents, other, args.blend_weight)
+                }
+                _ => latents,
+            })
+            .on_step(|step_index, latents| {
+                timer.step();
+                println!("Timestep {timer}");
+                if args.intermediary_images {
+                    let latents = latents.to(vae_device);
+                    let image = vae.decode(&vae.unscale_latents(&latents));
+                    let image = vae.postprocess(&image);
+                    let final_image =
+                        output_filename(&final_image, idx + 1, num_samples, Some(step_index + 1));
+                    tch::vision::image::save(&image, final_image)?;
+                }
+                Ok(())
+            })
+            .on_pred_original_sample(|step_index, pred_original_sample| {
+                if args.intermediary_predictions {
+                    let latents = pred_original_sample.to(vae_device);
+                    let image = vae.decode(&vae.unscale_latents(&latents));
+                    let image = vae.postprocess(&image);
+                    let final_image = output_filename(
+                        &final_image.replace(".png", ".x0.png"),
+                        idx + 1,
+                        num_samples,
+                        Some(step_index + 1),
+                    );
+                    tch::vision::image::save(&image, final_image)?;
+                }
+                Ok(())
+            })
+            .run(&mut scheduler, latents, |step_index, timestep, latent_model_input| {
+                let text_embeddings = &text_embeddings[prompt_schedule.index_at(step_index)];
+                if let Some((reference, _)) = &reference {
+                    let cond_embeddings = text_embeddings.narrow(0, 1, 1);
+                    let reference_input = &reference_inputs[step_index];
+                    reference.store_reference(&unet, reference_input, timestep, &cond_embeddings);
+                }
+                let mut unet_forward = |xs: &Tensor, embeddings: &Tensor, perturbed: bool| {
+                    if args.pag_scale != 0. {
+                        unet.set_perturbed_self_attention(perturbed);
+                    }
+                    if memory_report {
+                        memory_report = false;
+                        let mut report = diffusers::utils::MemoryReport::new();
+                        let noise_pred =
+                            unet.forward_with_memory_report(xs, timestep, embeddings, &mut report);
+                        println!("UNet memory report:\n{report}");
+                        noise_pred
+                    } else {
+                        unet.forward(xs, timestep, embeddings)
+                    }
+                };
+                if let Some(guidance_scale_map) = &guidance_scale_map {
+                    return guidance::guided_prediction_masked(
+                        latent_model_input,
+                        text_embeddings,
+                        guidance_scale_map,
+                        guidance_rescale.value_at(step_index),
+                        !args.sequential_cfg,
+                        |xs, embeddings| unet_forward(xs, embeddings, false),
+                    );
+                }
+                if let Some(negative_guidance_scale) = args.negative_guidance_scale {
+                    return Ok(guidance::guided_prediction_with_negative(
+                        latent_model_input,
+                        text_embeddings,
+                        args.guidance_scale,
+                        negative_guidance_scale,
+                        !args.sequential_cfg,
+                        |xs, embeddings| unet_forward(xs, embeddings, false),
+                    ));
+                }
+                Ok(guidance::guided_prediction_pag(
+                    latent_model_input,
+                    text_embeddings,
+                    args.guidance_scale,
+                    guidance_rescale.value_at(step_index),
+                    args.pag_scale,
+                    !args.sequential_cfg,
+                    unet_forward,
+                ))
+            })?;
+        if output.converged {
+            println!("Converged after {} steps.", output.steps);
+        }
+        let latents = output.latents;
+
+        if args.stop_at_step.is_some() {
+            let latents_file = output_filename(&args.latents_file, idx + 1, num_samples, None);
+            println!("Saving the undecoded latents to {latents_file}.");
+            latents.save(latents_file)?;
+            continue;
         }
 
         println!("Generating the final image for sample {}/{}.", idx + 1, num_samples);
         let latents = latents.to(vae_device);
-        let image = vae.decode(&(&latents / 0.18215));
-        let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
-        let image = (image * 255.).to_kind(Kind::Uint8);
+        let image = if args.latent_preview {
+            diffusers::utils::latents_to_rgb_preview(&latents)
+        } else if args.memory_report && idx == 0 {
+            let mut report = diffusers::utils::MemoryReport::new();
+            let image = vae.decode_with_memory_report(&vae.unscale_latents(&latents), &mut report);
+            println!("VAE memory report:\n{report}");
+            image
+        } else if let Some(decoder) = &consistency_decoder {
+            LatentDecoder::decode(decoder, &(latents / decoder.scaling_factor()))
+        } else if let Some(tile_size) = args.vae_tile_size {
+            vae.decode_tiled(&vae.unscale_latents(&latents), tile_size, args.vae_tile_overlap)?
+        } else {
+            vae.decode(&vae.unscale_latents(&latents))
+        };
+        let image = match crop_size {
+            Some((h, w)) if !args.latent_preview => {
+                diffusers::utils::crop(&image, h, w, diffusers::utils::CropAnchor::Center)
+            }
+            _ => image,
+        };
+        let image = vae.postprocess(&image);
         let final_image = output_filename(&final_image, idx + 1, num_samples, None);
         tch::vision::image::save(&image, final_image)?;
     }
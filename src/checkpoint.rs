@@ -0,0 +1,683 @@
+//! # Checkpoint Loading
+//!
+//! Helpers to load weights from checkpoint files that do not use the same layout
+//! as the models from this crate, e.g. the single file checkpoints bundling the
+//! UNet, VAE, and text encoder weights that are commonly used for community models.
+//!
+//! The loaders convert the floating point tensors of a checkpoint to the kind of the
+//! variables they are copied to, so that e.g. a float16 or bfloat16 checkpoint can be
+//! loaded in a float32 model and the other way around. The kind of a model is the one of
+//! its var store, `VarStore::set_kind` can be used before loading to select it.
+use crate::models::unet_2d::UNet2DConditionModelConfig;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use tch::{nn, Kind, Tensor};
+
+/// The key naming convention used by a single file checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointFormat {
+    /// The original CompVis/Stability naming, e.g. `model.diffusion_model.input_blocks.0.0.weight`
+    /// for the UNet, `first_stage_model.*` for the VAE and `cond_stage_model.*` for the
+    /// text encoder.
+    CompVis,
+    /// The diffusers naming with a prefix per component, i.e. `unet.*`, `vae.*`, and
+    /// `text_encoder.*`.
+    Diffusers,
+}
+
+impl CheckpointFormat {
+    /// Detects the format of a checkpoint based on its tensor names.
+    pub fn detect<S: AsRef<str>>(names: &[S]) -> anyhow::Result<Self> {
+        let has_prefix = |p: &str| names.iter().any(|n| n.as_ref().starts_with(p));
+        if has_prefix("model.diffusion_model.") {
+            Ok(Self::CompVis)
+        } else if has_prefix("unet.") {
+            Ok(Self::Diffusers)
+        } else {
+            anyhow::bail!("unable to detect the checkpoint format, no unet weights found")
+        }
+    }
+}
+
+/// Which set of UNet weights to use for checkpoints that include both the regular
+/// weights and their exponential moving average (EMA).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WeightVariant {
+    /// Use the EMA weights when available, the regular ones otherwise.
+    #[default]
+    PreferEma,
+    /// Use the EMA weights, failing if they are missing.
+    Ema,
+    /// Use the regular weights.
+    Regular,
+}
+
+// CompVis training checkpoints store the EMA of `model.diffusion_model.x.y` as
+// `model_ema.diffusion_modelxy`, see `LitEma` in the original repo.
+fn compvis_ema_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix("model.")?;
+    Some(format!("model_ema.{}", name.replace('.', "")))
+}
+
+/// Selects the EMA or regular UNet weights of a CompVis style training checkpoint. The
+/// EMA tensors replace the regular ones and are then removed from the returned list.
+///
+/// The returned variant is the one actually used, i.e. `Regular` when EMA weights were
+/// requested through `PreferEma` but are not present, so that callers can warn about it.
+pub fn select_weight_variant(
+    tensors: Vec<(String, Tensor)>,
+    variant: WeightVariant,
+) -> anyhow::Result<(Vec<(String, Tensor)>, WeightVariant)> {
+    let names: Vec<String> = tensors.iter().map(|(name, _)| name.clone()).collect();
+    let (sources, variant) = weight_variant_sources(&names, variant)?;
+    let tensors: HashMap<String, Tensor> = tensors.into_iter().collect();
+    let tensors = sources
+        .into_iter()
+        .map(|(name, source)| {
+            let tensor = tensors[&source].shallow_clone();
+            (name, tensor)
+        })
+        .collect();
+    Ok((tensors, variant))
+}
+
+// Returns the tensors to be used for a weight variant as pairs of the name of a tensor
+// and the name of the tensor it gets read from, see `select_weight_variant`.
+fn weight_variant_sources(
+    names: &[String],
+    variant: WeightVariant,
+) -> anyhow::Result<(Vec<(String, String)>, WeightVariant)> {
+    let is_ema = |name: &str| name.starts_with("model_ema.");
+    let ema: HashSet<&str> = names.iter().map(|n| n.as_str()).filter(|n| is_ema(n)).collect();
+    let use_ema = match variant {
+        WeightVariant::Regular => false,
+        WeightVariant::PreferEma => !ema.is_empty(),
+        WeightVariant::Ema if ema.is_empty() => {
+            anyhow::bail!("the checkpoint does not contain EMA weights")
+        }
+        WeightVariant::Ema => true,
+    };
+    let mut sources = vec![];
+    let mut missing = vec![];
+    for name in names.iter().filter(|n| !is_ema(n)) {
+        if !use_ema || !name.starts_with("model.diffusion_model.") {
+            sources.push((name.clone(), name.clone()));
+            continue;
+        }
+        match compvis_ema_name(name).filter(|n| ema.contains(n.as_str())) {
+            Some(source) => sources.push((name.clone(), source)),
+            None => missing.push(name.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!("missing EMA weights for {}", missing.join(", "))
+    }
+    let variant = if use_ema { WeightVariant::Ema } else { WeightVariant::Regular };
+    Ok((sources, variant))
+}
+
+/// The weights from a merged checkpoint split per component, the tensor names
+/// use the naming expected by the models of this crate.
+pub struct MergedCheckpoint {
+    pub unet: HashMap<String, Tensor>,
+    pub vae: HashMap<String, Tensor>,
+    pub text_encoder: HashMap<String, Tensor>,
+}
+
+impl MergedCheckpoint {
+    /// Splits the tensors from a merged checkpoint per component, converting the
+    /// tensor names if needed. The UNet configuration is required to map the
+    /// CompVis block indexes to the diffusers ones.
+    pub fn split(
+        tensors: Vec<(String, Tensor)>,
+        unet_config: &UNet2DConditionModelConfig,
+        vae_n_blocks: usize,
+    ) -> anyhow::Result<Self> {
+        let format = CheckpointFormat::detect(&tensors.iter().map(|(n, _)| n).collect::<Vec<_>>())?;
+        let mut unet = HashMap::new();
+        let mut vae = HashMap::new();
+        let mut text_encoder = HashMap::new();
+        for (name, tensor) in tensors.into_iter() {
+            for (component, name, tensor) in
+                merged_tensors(format, &name, tensor, unet_config, vae_n_blocks)
+            {
+                let tensors = match component {
+                    Component::Unet => &mut unet,
+                    Component::Vae => &mut vae,
+                    Component::TextEncoder => &mut text_encoder,
+                };
+                tensors.insert(name, tensor);
+            }
+        }
+        Ok(Self { unet, vae, text_encoder })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Component {
+    Unet,
+    Vae,
+    TextEncoder,
+}
+
+// Converts a tensor from a merged checkpoint to the tensors of the components using it,
+// with the naming expected by the models of this crate. Tensors that are not used by
+// any component result in an empty list.
+fn merged_tensors(
+    format: CheckpointFormat,
+    name: &str,
+    tensor: Tensor,
+    unet_config: &UNet2DConditionModelConfig,
+    vae_n_blocks: usize,
+) -> Vec<(Component, String, Tensor)> {
+    match format {
+        CheckpointFormat::Diffusers => {
+            if let Some(name) = name.strip_prefix("unet.") {
+                vec![(Component::Unet, name.to_string(), tensor)]
+            } else if let Some(name) = name.strip_prefix("vae.") {
+                vec![(Component::Vae, name.to_string(), tensor)]
+            } else if let Some(name) = name.strip_prefix("text_encoder.") {
+                vec![(Component::TextEncoder, name.to_string(), tensor)]
+            } else {
+                vec![]
+            }
+        }
+        CheckpointFormat::CompVis => {
+            if let Some(name) = name.strip_prefix("model.diffusion_model.") {
+                compvis_unet_name(name, unet_config)
+                    .map(|name| (Component::Unet, name, tensor))
+                    .into_iter()
+                    .collect()
+            } else if let Some(name) = name.strip_prefix("first_stage_model.") {
+                let name = match compvis_vae_name(name, vae_n_blocks) {
+                    None => return vec![],
+                    Some(name) => name,
+                };
+                // The attention layers use 1x1 convolutions rather than linear layers.
+                let tensor = if name.contains(".attentions.") && tensor.dim() == 4 {
+                    tensor.squeeze_dim(-1).squeeze_dim(-1)
+                } else {
+                    tensor
+                };
+                vec![(Component::Vae, name, tensor)]
+            } else if let Some(name) = name.strip_prefix("cond_stage_model.transformer.") {
+                let name = if name.starts_with("text_model.") {
+                    name.to_string()
+                } else {
+                    format!("text_model.{name}")
+                };
+                vec![(Component::TextEncoder, name, tensor)]
+            } else if let Some(name) = name.strip_prefix("cond_stage_model.model.") {
+                open_clip_tensors(name, tensor)
+                    .into_iter()
+                    .map(|(name, tensor)| (Component::TextEncoder, name, tensor))
+                    .collect()
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+/// Loads the weights of a merged safetensors checkpoint in the var stores of its
+/// components, the tensor names being converted as with `MergedCheckpoint::split`.
+///
+/// The tensors are read one at a time and copied to their variables right away, so on
+/// top of the var stores only a single tensor of the checkpoint is held in host memory
+/// rather than the whole file. The weight variant actually used is returned, see
+/// `select_weight_variant`.
+pub fn load_merged_safetensors<P: AsRef<std::path::Path>>(
+    path: P,
+    variant: WeightVariant,
+    unet_config: &UNet2DConditionModelConfig,
+    vae_n_blocks: usize,
+    vs_text_encoder: &nn::VarStore,
+    vs_vae: &nn::VarStore,
+    vs_unet: &nn::VarStore,
+) -> anyhow::Result<WeightVariant> {
+    let mut reader = SafeTensorsReader::open(path)?;
+    let names: Vec<String> = reader.names().into_iter().map(String::from).collect();
+    let format = CheckpointFormat::detect(&names)?;
+    let (sources, variant) = weight_variant_sources(&names, variant)?;
+    // The variables are removed once loaded, the remaining ones are the missing ones.
+    let mut text_encoder = vs_text_encoder.variables();
+    let mut vae = vs_vae.variables();
+    let mut unet = vs_unet.variables();
+    tch::no_grad(|| {
+        for (name, source) in sources.iter() {
+            let tensor = reader.read(source)?;
+            for (component, name, tensor) in
+                merged_tensors(format, name, tensor, unet_config, vae_n_blocks)
+            {
+                let variables = match component {
+                    Component::Unet => &mut unet,
+                    Component::Vae => &mut vae,
+                    Component::TextEncoder => &mut text_encoder,
+                };
+                if let Some(mut var) = variables.remove(&name) {
+                    let src = convert_kind(&name, &tensor, var.kind())?;
+                    var.f_copy_(&src).map_err(|e| anyhow::Error::new(e).context(name.clone()))?
+                }
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+    for (component, variables) in [("text encoder", text_encoder), ("vae", vae), ("unet", unet)] {
+        if !variables.is_empty() {
+            let mut missing: Vec<String> = variables.into_keys().collect();
+            missing.sort();
+            anyhow::bail!("missing {component} tensors in checkpoint: {}", missing.join(", "))
+        }
+    }
+    Ok(variant)
+}
+
+fn is_float(kind: Kind) -> bool {
+    matches!(kind, Kind::Half | Kind::BFloat16 | Kind::Float | Kind::Double)
+}
+
+// Converts a checkpoint tensor to the kind of the variable it gets copied to. Only the
+// conversions between floating point kinds are allowed, copying e.g. int8 quantized
+// weights into a float variable would silently produce garbage.
+fn convert_kind(name: &str, tensor: &Tensor, kind: Kind) -> anyhow::Result<Tensor> {
+    let src_kind = tensor.kind();
+    if src_kind == kind {
+        Ok(tensor.shallow_clone())
+    } else if is_float(src_kind) && is_float(kind) {
+        Ok(tensor.to_kind(kind))
+    } else {
+        anyhow::bail!("{name}: cannot load a {src_kind:?} tensor in a {kind:?} variable")
+    }
+}
+
+/// Copies the tensors from `tensors` in the variables of a var-store, this fails if
+/// some variables of the var-store have no associated tensor. Tensors are converted
+/// to the kind and device of the variables, the kinds having to be both floating point
+/// ones if they differ.
+pub fn load_var_store(vs: &nn::VarStore, tensors: &HashMap<String, Tensor>) -> anyhow::Result<()> {
+    let mut missing = vec![];
+    tch::no_grad(|| {
+        for (name, mut var) in vs.variables() {
+            match tensors.get(&name) {
+                None => missing.push(name),
+                Some(src) => {
+                    let src = convert_kind(&name, src, var.kind())?;
+                    var.f_copy_(&src).map_err(|e| anyhow::Error::new(e).context(name.clone()))?
+                }
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+    if !missing.is_empty() {
+        missing.sort();
+        anyhow::bail!("missing tensors in checkpoint: {}", missing.join(", "))
+    }
+    Ok(())
+}
+
+// Adds a variable to a var-store using its full dotted name, the variable keeps the kind
+// of `tensor` and is not trainable.
+fn insert_var(vs: &nn::VarStore, name: &str, tensor: &Tensor) -> anyhow::Result<()> {
+    let mut path = vs.root();
+    let mut parts: Vec<&str> = name.split('.').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        path = &path / part;
+    }
+    let mut var = path.f_zeros_no_train(last, &tensor.size())?;
+    var.set_data(tensor);
+    Ok(())
+}
+
+// Applies `f` to the matching variables of some var-stores and stores the results in a
+// new var-store on the same device as the first one. The floating point variables are
+// combined in single precision and converted back to the kind of the first var-store
+// variable, the other variables, e.g. integer position ids, have to be equal in all the
+// var-stores and are copied unchanged.
+fn merge_with<F>(vss: &[&nn::VarStore], f: F) -> anyhow::Result<nn::VarStore>
+where
+    F: Fn(&[Tensor]) -> Tensor,
+{
+    let variables: Vec<HashMap<String, Tensor>> = vss.iter().map(|vs| vs.variables()).collect();
+    for (i, vars) in variables.iter().enumerate().skip(1) {
+        let missing: Vec<&String> =
+            variables[0].keys().filter(|k| !vars.contains_key(*k)).collect();
+        let extra: Vec<&String> = vars.keys().filter(|k| !variables[0].contains_key(*k)).collect();
+        if !missing.is_empty() || !extra.is_empty() {
+            anyhow::bail!(
+                "mismatching variables between checkpoints 0 and {i}, missing {missing:?}, extra {extra:?}"
+            )
+        }
+    }
+    let merged = nn::VarStore::new(vss[0].device());
+    let mut names: Vec<&String> = variables[0].keys().collect();
+    names.sort();
+    tch::no_grad(|| {
+        for name in names {
+            let tensors: Vec<Tensor> =
+                variables.iter().map(|v| v[name].to_device(merged.device()).detach()).collect();
+            let (size, kind) = (tensors[0].size(), tensors[0].kind());
+            if let Some(t) = tensors.iter().find(|t| t.size() != size) {
+                anyhow::bail!("shape mismatch for {name}: {size:?} vs {:?}", t.size())
+            }
+            let merged_tensor = if tensors.iter().all(|t| is_float(t.kind())) {
+                let tensors: Vec<Tensor> = tensors.iter().map(|t| t.to_kind(Kind::Float)).collect();
+                f(&tensors).to_kind(kind)
+            } else {
+                if tensors.iter().any(|t| t.kind() != kind || !t.equal(&tensors[0])) {
+                    anyhow::bail!("{name}: non floating point variables differ between checkpoints")
+                }
+                tensors[0].copy()
+            };
+            insert_var(&merged, name, &merged_tensor)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+    Ok(merged)
+}
+
+/// Merges two checkpoints with matching variables using a weighted sum, each merged
+/// variable being `a * ratio + b * (1 - ratio)`.
+///
+/// The merged variables keep the kind of the variables of `a` and are not trainable. The
+/// non floating point variables, e.g. position ids, are copied unchanged and have to be
+/// equal in both checkpoints.
+pub fn merge_weighted_sum(
+    a: &nn::VarStore,
+    b: &nn::VarStore,
+    ratio: f64,
+) -> anyhow::Result<nn::VarStore> {
+    merge_with(&[a, b], |ts| &ts[0] * ratio + &ts[1] * (1. - ratio))
+}
+
+/// Merges checkpoints with matching variables by adding the difference between `a`
+/// and `b` to `base`, each merged variable being `base + (a - b) * ratio`. This is
+/// typically used to transfer a fine-tune, `a` being fine-tuned from `b`, to another
+/// base model. The kinds are handled as in `merge_weighted_sum`, using `base` ones.
+pub fn merge_add_difference(
+    base: &nn::VarStore,
+    a: &nn::VarStore,
+    b: &nn::VarStore,
+    ratio: f64,
+) -> anyhow::Result<nn::VarStore> {
+    merge_with(&[base, a, b], |ts| &ts[0] + (&ts[1] - &ts[2]) * ratio)
+}
+
+fn compvis_resnet_name(rest: &[&str]) -> Option<String> {
+    let (layer, rest) = match rest {
+        ["in_layers", "0", rest @ ..] => ("norm1", rest),
+        ["in_layers", "2", rest @ ..] => ("conv1", rest),
+        ["emb_layers", "1", rest @ ..] => ("time_emb_proj", rest),
+        ["out_layers", "0", rest @ ..] => ("norm2", rest),
+        ["out_layers", "3", rest @ ..] => ("conv2", rest),
+        ["skip_connection", rest @ ..] => ("conv_shortcut", rest),
+        _ => return None,
+    };
+    Some(format!("{layer}.{}", rest.join(".")))
+}
+
+// https://github.com/huggingface/diffusers/blob/main/scripts/convert_original_stable_diffusion_to_diffusers.py
+fn compvis_unet_name(name: &str, config: &UNet2DConditionModelConfig) -> Option<String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let n_blocks = config.blocks.len();
+    let layers_per_block = config.layers_per_block as usize;
+    let index = |s: &str| s.parse::<usize>().ok();
+    let name = match parts.as_slice() {
+        ["time_embed", "0", rest @ ..] => format!("time_embedding.linear_1.{}", rest.join(".")),
+        ["time_embed", "2", rest @ ..] => format!("time_embedding.linear_2.{}", rest.join(".")),
+        ["input_blocks", "0", "0", rest @ ..] => format!("conv_in.{}", rest.join(".")),
+        ["input_blocks", idx, sub, rest @ ..] => {
+            let idx = index(idx)? - 1;
+            let (i, j) = (idx / (layers_per_block + 1), idx % (layers_per_block + 1));
+            match (*sub, rest) {
+                ("0", ["op", rest @ ..]) if j == layers_per_block => {
+                    format!("down_blocks.{i}.downsamplers.0.conv.{}", rest.join("."))
+                }
+                ("0", rest) => {
+                    format!("down_blocks.{i}.resnets.{j}.{}", compvis_resnet_name(rest)?)
+                }
+                ("1", rest) => format!("down_blocks.{i}.attentions.{j}.{}", rest.join(".")),
+                _ => return None,
+            }
+        }
+        ["middle_block", "0", rest @ ..] => {
+            format!("mid_block.resnets.0.{}", compvis_resnet_name(rest)?)
+        }
+        ["middle_block", "1", rest @ ..] => format!("mid_block.attentions.0.{}", rest.join(".")),
+        ["middle_block", "2", rest @ ..] => {
+            format!("mid_block.resnets.1.{}", compvis_resnet_name(rest)?)
+        }
+        ["output_blocks", idx, sub, rest @ ..] => {
+            let idx = index(idx)?;
+            let (i, j) = (idx / (layers_per_block + 1), idx % (layers_per_block + 1));
+            let has_attn = config.blocks.get(n_blocks.checked_sub(i + 1)?)?.use_cross_attn;
+            match *sub {
+                "0" => format!("up_blocks.{i}.resnets.{j}.{}", compvis_resnet_name(rest)?),
+                "1" if has_attn => format!("up_blocks.{i}.attentions.{j}.{}", rest.join(".")),
+                "1" | "2" => format!("up_blocks.{i}.upsamplers.0.{}", rest.join(".")),
+                _ => return None,
+            }
+        }
+        ["out", "0", rest @ ..] => format!("conv_norm_out.{}", rest.join(".")),
+        ["out", "2", rest @ ..] => format!("conv_out.{}", rest.join(".")),
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn compvis_vae_name(name: &str, n_blocks: usize) -> Option<String> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let resnet = |rest: &[&str]| match rest {
+        ["nin_shortcut", rest @ ..] => format!("conv_shortcut.{}", rest.join(".")),
+        rest => rest.join("."),
+    };
+    let name = match parts.as_slice() {
+        ["quant_conv" | "post_quant_conv", ..] => name.to_string(),
+        [coder @ ("encoder" | "decoder"), rest @ ..] => {
+            let rest = match rest {
+                ["conv_in" | "conv_out", ..] => rest.join("."),
+                ["norm_out", rest @ ..] => format!("conv_norm_out.{}", rest.join(".")),
+                ["down", i, "block", j, rest @ ..] => {
+                    format!("down_blocks.{i}.resnets.{j}.{}", resnet(rest))
+                }
+                ["down", i, "downsample", rest @ ..] => {
+                    format!("down_blocks.{i}.downsamplers.0.{}", rest.join("."))
+                }
+                ["up", i, "block", j, rest @ ..] => {
+                    let i = n_blocks.checked_sub(i.parse::<usize>().ok()? + 1)?;
+                    format!("up_blocks.{i}.resnets.{j}.{}", resnet(rest))
+                }
+                ["up", i, "upsample", rest @ ..] => {
+                    let i = n_blocks.checked_sub(i.parse::<usize>().ok()? + 1)?;
+                    format!("up_blocks.{i}.upsamplers.0.{}", rest.join("."))
+                }
+                ["mid", "block_1", rest @ ..] => format!("mid_block.resnets.0.{}", resnet(rest)),
+                ["mid", "block_2", rest @ ..] => format!("mid_block.resnets.1.{}", resnet(rest)),
+                ["mid", "attn_1", layer, rest @ ..] => {
+                    let layer = match *layer {
+                        "norm" => "group_norm",
+                        "q" => "query",
+                        "k" => "key",
+                        "v" => "value",
+                        "proj_out" => "proj_attn",
+                        _ => return None,
+                    };
+                    format!("mid_block.attentions.0.{layer}.{}", rest.join("."))
+                }
+                _ => return None,
+            };
+            format!("{coder}.{rest}")
+        }
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Converts the OpenCLIP text encoder weights used by stable diffusion 2.x.
+fn open_clip_tensors(name: &str, tensor: Tensor) -> Vec<(String, Tensor)> {
+    let parts: Vec<&str> = name.split('.').collect();
+    let single = |name: String| vec![(name, tensor.shallow_clone())];
+    match parts.as_slice() {
+        ["token_embedding", "weight"] => {
+            single("text_model.embeddings.token_embedding.weight".to_string())
+        }
+        ["positional_embedding"] => {
+            single("text_model.embeddings.position_embedding.weight".to_string())
+        }
+        ["ln_final", rest] => single(format!("text_model.final_layer_norm.{rest}")),
+        ["transformer", "resblocks", i, rest @ ..] => {
+            let prefix = format!("text_model.encoder.layers.{i}");
+            match rest {
+                ["attn", p @ ("in_proj_weight" | "in_proj_bias")] => {
+                    let suffix = if *p == "in_proj_weight" { "weight" } else { "bias" };
+                    let qkv = tensor.chunk(3, 0);
+                    ["q_proj", "k_proj", "v_proj"]
+                        .iter()
+                        .zip(qkv)
+                        .map(|(n, t)| (format!("{prefix}.self_attn.{n}.{suffix}"), t))
+                        .collect()
+                }
+                ["attn", "out_proj", rest] => single(format!("{prefix}.self_attn.out_proj.{rest}")),
+                ["ln_1", rest] => single(format!("{prefix}.layer_norm1.{rest}")),
+                ["ln_2", rest] => single(format!("{prefix}.layer_norm2.{rest}")),
+                ["mlp", "c_fc", rest] => single(format!("{prefix}.mlp.fc1.{rest}")),
+                ["mlp", "c_proj", rest] => single(format!("{prefix}.mlp.fc2.{rest}")),
+                _ => vec![],
+            }
+        }
+        _ => vec![],
+    }
+}
+
+/// The location of a tensor within a safetensors file.
+#[derive(Debug, Clone)]
+struct TensorInfo {
+    kind: Kind,
+    shape: Vec<i64>,
+    start: u64,
+    end: u64,
+}
+
+/// A safetensors file from which tensors are read one at a time.
+///
+/// Only the header is parsed when opening the file, the tensor data is read on demand
+/// so that loading a model only requires enough host memory for its largest tensor
+/// rather than for the whole file.
+#[derive(Debug)]
+pub struct SafeTensorsReader {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    data_offset: u64,
+    tensors: HashMap<String, TensorInfo>,
+}
+
+impl SafeTensorsReader {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = std::fs::File::open(&path)
+            .map_err(|e| anyhow::Error::new(e).context(format!("opening {path:?}")))?;
+        let mut header_len = [0u8; 8];
+        file.read_exact(&mut header_len)?;
+        let header_len = u64::from_le_bytes(header_len);
+        let file_len = file.metadata()?.len();
+        if header_len > file_len.saturating_sub(8) {
+            anyhow::bail!("{path:?}: invalid safetensors header length {header_len}")
+        }
+        let mut header = vec![0u8; header_len as usize];
+        file.read_exact(&mut header)?;
+        let header: serde_json::Value = serde_json::from_slice(&header)
+            .map_err(|e| anyhow::Error::new(e).context(format!("{path:?}: invalid header")))?;
+        let header = match header {
+            serde_json::Value::Object(header) => header,
+            _ => anyhow::bail!("{path:?}: the safetensors header is not an object"),
+        };
+        let data_offset = 8 + header_len;
+        let mut tensors = HashMap::new();
+        for (name, info) in header.into_iter() {
+            if name == "__metadata__" {
+                continue;
+            }
+            let info = parse_tensor_info(&info)
+                .map_err(|e| e.context(format!("{path:?}: invalid entry for {name}")))?;
+            if data_offset + info.end > file_len {
+                anyhow::bail!("{path:?}: the data for {name} is out of bounds")
+            }
+            tensors.insert(name, info);
+        }
+        Ok(Self { path, file, data_offset, tensors })
+    }
+
+    /// The names of the tensors stored in the file, in no particular order.
+    pub fn names(&self) -> Vec<&str> {
+        self.tensors.keys().map(|s| s.as_str()).collect()
+    }
+
+    /// Reads a single tensor from the file, the returned tensor is on the CPU.
+    pub fn read(&mut self, name: &str) -> anyhow::Result<Tensor> {
+        let info = match self.tensors.get(name) {
+            Some(info) => info,
+            None => anyhow::bail!("{:?}: cannot find tensor {name}", self.path),
+        };
+        let mut data = vec![0u8; (info.end - info.start) as usize];
+        self.file.seek(SeekFrom::Start(self.data_offset + info.start))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Tensor::f_from_data_size(&data, &info.shape, info.kind)?)
+    }
+
+    /// Copies the tensors from the file to the variables of a var store, one variable at a
+    /// time. The variables keep their device and kind, the tensors from the file being
+    /// converted if needed, e.g. from float16 to float32. Unlike `VarStore::load` the file
+    /// is never fully loaded in memory.
+    pub fn load_var_store(&mut self, vs: &nn::VarStore) -> anyhow::Result<()> {
+        let mut variables = vs.variables().into_iter().collect::<Vec<_>>();
+        variables.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+        let missing: Vec<_> = variables
+            .iter()
+            .filter(|(n, _)| !self.tensors.contains_key(n))
+            .map(|(n, _)| n)
+            .collect();
+        if !missing.is_empty() {
+            let missing: Vec<_> = missing.iter().map(|s| s.as_str()).collect();
+            anyhow::bail!("{:?}: missing tensors {}", self.path, missing.join(", "))
+        }
+        tch::no_grad(|| {
+            for (name, mut var) in variables.into_iter() {
+                let src = convert_kind(&name, &self.read(&name)?, var.kind())?;
+                var.f_copy_(&src).map_err(|e| anyhow::Error::new(e).context(name.clone()))?
+            }
+            Ok(())
+        })
+    }
+}
+
+fn parse_tensor_info(info: &serde_json::Value) -> anyhow::Result<TensorInfo> {
+    let kind = match info["dtype"].as_str() {
+        Some("BOOL") => Kind::Bool,
+        Some("U8") => Kind::Uint8,
+        Some("I8") => Kind::Int8,
+        Some("I16") => Kind::Int16,
+        Some("I32") => Kind::Int,
+        Some("I64") => Kind::Int64,
+        Some("BF16") => Kind::BFloat16,
+        Some("F16") => Kind::Half,
+        Some("F32") => Kind::Float,
+        Some("F64") => Kind::Double,
+        dtype => anyhow::bail!("unsupported dtype {dtype:?}"),
+    };
+    let as_u64s = |v: &serde_json::Value| -> Option<Vec<u64>> {
+        v.as_array()?.iter().map(|v| v.as_u64()).collect()
+    };
+    let shape = match as_u64s(&info["shape"]) {
+        Some(shape) => shape.into_iter().map(|d| d as i64).collect::<Vec<_>>(),
+        None => anyhow::bail!("invalid shape"),
+    };
+    let (start, end) = match as_u64s(&info["data_offsets"]).as_deref() {
+        Some(&[start, end]) if start <= end => (start, end),
+        _ => anyhow::bail!("invalid data offsets"),
+    };
+    let numel: i64 = shape.iter().product();
+    if (end - start) as i64 != numel * kind.elt_size_in_bytes() as i64 {
+        anyhow::bail!("the data size does not match the shape {shape:?}")
+    }
+    Ok(TensorInfo { kind, shape, start, end })
+}
@@ -9,9 +9,47 @@
 //!
 //! The models can used pre-trained weights adapted from the Python
 //! implementation.
+//!
+//! ## Thread safety
+//!
+//! The models, e.g. `UNet2DConditionModel`, `AutoEncoderKL`, `ControlNet`, or
+//! `ClipTextTransformer`, are `Send + Sync` and can be shared between threads behind an
+//! `Arc` to serve concurrent generations with a single copy of the weights. Their
+//! forward passes take `&self` and only read the weight tensors, which libtorch supports
+//! from multiple threads. The methods changing a model, e.g. setting attention
+//! processors or the perturbed attention flag, take `&mut self` so they cannot run while
+//! the model is shared. As `tch::Tensor` is not `Sync`, each model implements `Sync`
+//! manually, the safety comment next to each implementation lists the state it holds.
+//!
+//! The weights can still be changed through the `VarStore` the model was built from,
+//! e.g. when merging LoRA weights or with `models::quantize::simulate_int8`. This must
+//! not happen while other threads run forward passes on the model.
+//!
+//! The per-generation state lives outside of the models: each request should build its
+//! own scheduler, which is cheap as it only computes the noise schedule, and its own
+//! latents. `StableDiffusion` caches the unconditional embedding behind a mutex so it can
+//! be shared as well.
 
+pub mod checkpoint;
 pub mod models;
 pub mod pipelines;
 pub mod schedulers;
 pub mod transformers;
 pub mod utils;
+
+// Checks the thread safety documented above, this fails to compile if a model gains a
+// field that cannot be sent to another thread.
+#[allow(dead_code)]
+fn assert_models_are_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<models::unet_2d::UNet2DConditionModel>();
+    check::<models::vae::AutoEncoderKL>();
+    check::<models::controlnet::ControlNet>();
+    check::<models::t2i_adapter::T2IAdapter>();
+    check::<models::consistency_decoder::ConsistencyDecoder>();
+    check::<transformers::clip::ClipTextTransformer>();
+    check::<transformers::clip::ClipTextModelWithProjection>();
+    check::<transformers::clip::Tokenizer>();
+    check::<pipelines::stable_diffusion::StableDiffusion>();
+    check::<pipelines::sdxl::SdxlTextEncoders>();
+}
@@ -1,13 +1,15 @@
 //! Attention Based Building Blocks
-use tch::{nn, nn::Module, IndexOp, Kind, Tensor};
+use super::quantize::Linear;
+use std::sync::Arc;
+use tch::{nn, nn::Module, Device, IndexOp, Kind, Tensor};
 #[derive(Debug)]
 struct GeGlu {
-    proj: nn::Linear,
+    proj: Linear,
 }
 
 impl GeGlu {
     fn new(vs: nn::Path, dim_in: i64, dim_out: i64) -> Self {
-        let proj = nn::linear(&vs / "proj", dim_in, dim_out * 2, Default::default());
+        let proj = Linear::new(&vs / "proj", dim_in, dim_out * 2, Default::default());
         Self { proj }
     }
 }
@@ -23,7 +25,7 @@ impl Module for GeGlu {
 #[derive(Debug)]
 struct FeedForward {
     project_in: GeGlu,
-    linear: nn::Linear,
+    linear: Linear,
 }
 
 impl FeedForward {
@@ -36,9 +38,15 @@ impl FeedForward {
         let dim_out = dim_out.unwrap_or(dim);
         let vs = &vs / "net";
         let project_in = GeGlu::new(&vs / 0, dim, inner_dim);
-        let linear = nn::linear(&vs / 2, inner_dim, dim_out, Default::default());
+        let linear = Linear::new(&vs / 2, inner_dim, dim_out, Default::default());
         Self { project_in, linear }
     }
+
+    fn quantize_int8(&mut self) -> anyhow::Result<usize> {
+        self.project_in.proj.quantize_int8()?;
+        self.linear.quantize_int8()?;
+        Ok(2)
+    }
 }
 
 impl Module for FeedForward {
@@ -47,15 +55,126 @@ impl Module for FeedForward {
     }
 }
 
+/// A rectangular region of the image, the coordinates are fractions of the image
+/// width and height in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+impl Region {
+    /// The region covering the whole image.
+    pub fn full() -> Self {
+        Self { x0: 0., y0: 0., x1: 1., y1: 1. }
+    }
+
+    // Uses the center of the cell to decide whether it belongs to the region.
+    fn contains(&self, y: i64, x: i64, height: i64, width: i64) -> bool {
+        let y = (y as f64 + 0.5) / height as f64;
+        let x = (x as f64 + 0.5) / width as f64;
+        self.y0 <= y && y < self.y1 && self.x0 <= x && x < self.x1
+    }
+}
+
+/// Restricts the cross-attention of some prompts to spatial regions of the latents.
+///
+/// The prompt embeddings are concatenated along the sequence dimension, the first
+/// `n_tokens[0]` tokens belong to the first region, the next `n_tokens[1]` ones to the
+/// second region and so on. Each latent position only attends to the tokens of the
+/// regions it belongs to, positions outside of all the regions attend to every token.
+#[derive(Debug, Clone)]
+pub struct RegionalAttention {
+    regions: Vec<(Region, i64)>,
+    latent_height: i64,
+    latent_width: i64,
+}
+
+impl RegionalAttention {
+    /// Creates the regional masks for latents of size `latent_height` by `latent_width`,
+    /// `regions` contains each region together with the number of tokens of its prompt.
+    pub fn new(
+        regions: Vec<(Region, i64)>,
+        latent_height: i64,
+        latent_width: i64,
+    ) -> anyhow::Result<Self> {
+        for (region, _) in regions.iter() {
+            let valid_range = |v0: f64, v1: f64| 0. <= v0 && v0 < v1 && v1 <= 1.;
+            if !valid_range(region.x0, region.x1) || !valid_range(region.y0, region.y1) {
+                anyhow::bail!("invalid region {region:?}")
+            }
+        }
+        Ok(Self { regions, latent_height, latent_width })
+    }
+
+    pub fn n_tokens(&self) -> i64 {
+        self.regions.iter().map(|(_, n)| n).sum()
+    }
+
+    // The resolution of the attention layers is obtained by successive downsamplings
+    // of the latents by a factor 2, rounding up.
+    fn resolution(&self, sequence_length: i64) -> Option<(i64, i64)> {
+        let (mut height, mut width) = (self.latent_height, self.latent_width);
+        while height * width > sequence_length {
+            (height, width) = ((height + 1) / 2, (width + 1) / 2);
+        }
+        (height * width == sequence_length).then_some((height, width))
+    }
+
+    /// Returns the additive attention bias of shape `[sequence_length, n_keys]`, this is
+    /// `None` if the attention resolution or the number of keys do not match the regions.
+    pub fn attention_bias(
+        &self,
+        sequence_length: i64,
+        n_keys: i64,
+        kind: Kind,
+        device: Device,
+    ) -> Option<Tensor> {
+        if n_keys != self.n_tokens() {
+            return None;
+        }
+        let (height, width) = self.resolution(sequence_length)?;
+        let mut bias = vec![0f32; (sequence_length * n_keys) as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let masks: Vec<bool> =
+                    self.regions.iter().map(|(r, _)| r.contains(y, x, height, width)).collect();
+                if !masks.iter().any(|&m| m) {
+                    continue;
+                }
+                let row = ((y * width + x) * n_keys) as usize;
+                let mut offset = row;
+                for (&inside, &(_, n)) in masks.iter().zip(self.regions.iter()) {
+                    if !inside {
+                        bias[offset..offset + n as usize].fill(f32::NEG_INFINITY)
+                    }
+                    offset += n as usize
+                }
+            }
+        }
+        let bias = Tensor::from_slice(&bias).view((sequence_length, n_keys));
+        Some(bias.to_kind(kind).to_device(device))
+    }
+}
+
 #[derive(Debug)]
 struct CrossAttention {
-    to_q: nn::Linear,
-    to_k: nn::Linear,
-    to_v: nn::Linear,
-    to_out: nn::Linear,
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    to_out: Linear,
     heads: i64,
     scale: f64,
     slice_size: Option<i64>,
+    regional: Option<Arc<RegionalAttention>>,
+    // When set, the attention map is replaced with the identity so that each query only
+    // attends to itself, this is the perturbation used by perturbed attention guidance.
+    perturbed: bool,
+    // The var store path of the layer, e.g. `mid_block.attentions.0.transformer_blocks.0.attn1`.
+    path: String,
+    processor: Option<Arc<dyn AttentionProcessor>>,
 }
 
 impl CrossAttention {
@@ -72,11 +191,31 @@ impl CrossAttention {
         let inner_dim = dim_head * heads;
         let context_dim = context_dim.unwrap_or(query_dim);
         let scale = 1.0 / f64::sqrt(dim_head as f64);
-        let to_q = nn::linear(&vs / "to_q", query_dim, inner_dim, no_bias);
-        let to_k = nn::linear(&vs / "to_k", context_dim, inner_dim, no_bias);
-        let to_v = nn::linear(&vs / "to_v", context_dim, inner_dim, no_bias);
-        let to_out = nn::linear(&vs / "to_out" / 0, inner_dim, query_dim, Default::default());
-        Self { to_q, to_k, to_v, to_out, heads, scale, slice_size }
+        let to_q = Linear::new(&vs / "to_q", query_dim, inner_dim, no_bias);
+        let to_k = Linear::new(&vs / "to_k", context_dim, inner_dim, no_bias);
+        let to_v = Linear::new(&vs / "to_v", context_dim, inner_dim, no_bias);
+        let to_out = Linear::new(&vs / "to_out" / 0, inner_dim, query_dim, Default::default());
+        let path = vs.components().collect::<Vec<_>>().join(".");
+        Self {
+            to_q,
+            to_k,
+            to_v,
+            to_out,
+            heads,
+            scale,
+            slice_size,
+            regional: None,
+            perturbed: false,
+            path,
+            processor: None,
+        }
+    }
+
+    fn quantize_int8(&mut self) -> anyhow::Result<usize> {
+        for linear in [&mut self.to_q, &mut self.to_k, &mut self.to_v, &mut self.to_out] {
+            linear.quantize_int8()?
+        }
+        Ok(4)
     }
 
     fn reshape_heads_to_batch_dim(&self, xs: &Tensor) -> Tensor {
@@ -101,6 +240,7 @@ impl CrossAttention {
         sequence_length: i64,
         dim: i64,
         slice_size: i64,
+        bias: Option<&Tensor>,
     ) -> Tensor {
         let batch_size_attention = query.size()[0];
         let mut hidden_states = Tensor::zeros(
@@ -114,9 +254,12 @@ impl CrossAttention {
 
             let xs = query
                 .i(start_idx..end_idx)
-                .matmul(&(key.i(start_idx..end_idx).transpose(-1, -2) * self.scale))
-                .softmax(-1, Kind::Float)
-                .matmul(&value.i(start_idx..end_idx));
+                .matmul(&(key.i(start_idx..end_idx).transpose(-1, -2) * self.scale));
+            let xs = match bias {
+                Some(bias) => xs + bias,
+                None => xs,
+            };
+            let xs = xs.softmax(-1, Kind::Float).matmul(&value.i(start_idx..end_idx));
 
             let idx = Tensor::arange_start(start_idx, end_idx, (Kind::Int64, query.device()));
             let _ = hidden_states.index_put_(&[Some(idx), None, None], &xs, false);
@@ -125,38 +268,147 @@ impl CrossAttention {
         self.reshape_batch_dim_to_heads(&hidden_states)
     }
 
-    fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Tensor {
-        let xs = query
-            .matmul(&(key.transpose(-1, -2) * self.scale))
-            .softmax(-1, Kind::Float)
-            .matmul(value);
+    fn attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        bias: Option<&Tensor>,
+    ) -> Tensor {
+        let xs = query.matmul(&(key.transpose(-1, -2) * self.scale));
+        let xs = match bias {
+            Some(bias) => xs + bias,
+            None => xs,
+        };
+        let xs = xs.softmax(-1, Kind::Float).matmul(value);
         self.reshape_batch_dim_to_heads(&xs)
     }
 
     fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
-        let sequence_length = xs.size()[1];
-        let query = xs.apply(&self.to_q);
+        if self.perturbed {
+            return context.unwrap_or(xs).apply(&self.to_v).apply(&self.to_out);
+        }
+        let inputs = AttentionInputs { attn: self, hidden_states: xs, context };
+        match &self.processor {
+            None => DefaultAttentionProcessor.forward(&inputs),
+            Some(processor) => processor.forward(&inputs),
+        }
+    }
+
+    // Computes the attention from the projected query, key and value, and applies the
+    // output projection.
+    fn attend(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        cross_attention: bool,
+    ) -> Tensor {
+        let sequence_length = query.size()[1];
         let dim = *query.size().last().unwrap();
-        let context = context.unwrap_or(xs);
-        let key = context.apply(&self.to_k);
-        let value = context.apply(&self.to_v);
-        let query = self.reshape_heads_to_batch_dim(&query);
-        let key = self.reshape_heads_to_batch_dim(&key);
-        let value = self.reshape_heads_to_batch_dim(&value);
+        let query = self.reshape_heads_to_batch_dim(query);
+        let key = self.reshape_heads_to_batch_dim(key);
+        let value = self.reshape_heads_to_batch_dim(value);
+        let bias = match (&self.regional, cross_attention) {
+            (Some(regional), true) => regional.attention_bias(
+                sequence_length,
+                key.size()[1],
+                query.kind(),
+                query.device(),
+            ),
+            _ => None,
+        };
+        let bias = bias.as_ref();
         match self.slice_size {
-            None => self.attention(&query, &key, &value).apply(&self.to_out),
+            None => self.attention(&query, &key, &value, bias).apply(&self.to_out),
             Some(slice_size) => {
                 if query.size()[0] / slice_size <= 1 {
-                    self.attention(&query, &key, &value).apply(&self.to_out)
+                    self.attention(&query, &key, &value, bias).apply(&self.to_out)
                 } else {
-                    self.sliced_attention(&query, &key, &value, sequence_length, dim, slice_size)
-                        .apply(&self.to_out)
+                    self.sliced_attention(
+                        &query,
+                        &key,
+                        &value,
+                        sequence_length,
+                        dim,
+                        slice_size,
+                        bias,
+                    )
+                    .apply(&self.to_out)
                 }
             }
         }
     }
 }
 
+// Whether `prefix` is made of some leading components of `path`, an empty prefix matching
+// all the paths.
+fn path_matches(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// The inputs of an attention layer, as passed to an `AttentionProcessor`.
+pub struct AttentionInputs<'a> {
+    attn: &'a CrossAttention,
+    /// The input of the layer, with shape `[batch, seq_len, dim]`.
+    pub hidden_states: &'a Tensor,
+    /// The text embeddings for cross-attention layers, `None` for self-attention.
+    pub context: Option<&'a Tensor>,
+}
+
+impl<'a> AttentionInputs<'a> {
+    /// The var store path of the attention layer.
+    pub fn path(&self) -> &str {
+        &self.attn.path
+    }
+
+    pub fn is_cross_attention(&self) -> bool {
+        self.context.is_some()
+    }
+
+    /// Projects the hidden states to the queries, shape `[batch, seq_len, inner_dim]`.
+    pub fn query(&self) -> Tensor {
+        self.hidden_states.apply(&self.attn.to_q)
+    }
+
+    /// Projects the context, or the hidden states for self-attention, to the keys.
+    pub fn key(&self) -> Tensor {
+        self.context.unwrap_or(self.hidden_states).apply(&self.attn.to_k)
+    }
+
+    /// Projects the context, or the hidden states for self-attention, to the values.
+    pub fn value(&self) -> Tensor {
+        self.context.unwrap_or(self.hidden_states).apply(&self.attn.to_v)
+    }
+
+    /// Runs the attention of the layer on some projected queries, keys, and values, and
+    /// applies the output projection. The keys and values may have a different sequence
+    /// length from the ones returned by `key` and `value`, e.g. when some stored states
+    /// are concatenated to them.
+    pub fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Tensor {
+        self.attn.attend(query, key, value, self.is_cross_attention())
+    }
+}
+
+/// Overrides the computation of some attention layers, see
+/// `UNet2DConditionModel::set_attention_processor`. A processor can for example store
+/// the keys and values of a pass and inject them in a later one.
+pub trait AttentionProcessor: std::fmt::Debug + Send + Sync {
+    /// Returns the output of the attention layer, with the same shape as the hidden states.
+    fn forward(&self, inputs: &AttentionInputs) -> Tensor;
+}
+
+/// The attention processor used when none has been set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultAttentionProcessor;
+
+impl AttentionProcessor for DefaultAttentionProcessor {
+    fn forward(&self, inputs: &AttentionInputs) -> Tensor {
+        inputs.attention(&inputs.query(), &inputs.key(), &inputs.value())
+    }
+}
+
 /// A basic Transformer block.
 #[derive(Debug)]
 struct BasicTransformerBlock {
@@ -194,6 +446,22 @@ impl BasicTransformerBlock {
         Self { attn1, ff, attn2, norm1, norm2, norm3 }
     }
 
+    fn set_regional_attention(&mut self, regional: Option<Arc<RegionalAttention>>) {
+        self.attn2.regional = regional
+    }
+
+    fn set_perturbed_self_attention(&mut self, perturbed: bool) {
+        self.attn1.perturbed = perturbed
+    }
+
+    fn attention_layers_mut(&mut self) -> [&mut CrossAttention; 2] {
+        [&mut self.attn1, &mut self.attn2]
+    }
+
+    fn quantize_int8(&mut self) -> anyhow::Result<usize> {
+        Ok(self.attn1.quantize_int8()? + self.ff.quantize_int8()? + self.attn2.quantize_int8()?)
+    }
+
     fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
         let xs = self.attn1.forward(&xs.apply(&self.norm1), None) + xs;
         let xs = self.attn2.forward(&xs.apply(&self.norm2), context) + xs;
@@ -225,7 +493,7 @@ impl Default for SpatialTransformerConfig {
 #[derive(Debug)]
 enum Proj {
     Conv2D(nn::Conv2D),
-    Linear(nn::Linear),
+    Linear(Linear),
 }
 
 // Aka Transformer2DModel
@@ -251,7 +519,7 @@ impl SpatialTransformer {
         let norm = nn::group_norm(&vs / "norm", config.num_groups, in_channels, group_cfg);
         let conv_cfg = nn::ConvConfig { stride: 1, padding: 0, ..Default::default() };
         let proj_in = if config.use_linear_projection {
-            Proj::Linear(nn::linear(&vs / "proj_in", in_channels, inner_dim, Default::default()))
+            Proj::Linear(Linear::new(&vs / "proj_in", in_channels, inner_dim, Default::default()))
         } else {
             Proj::Conv2D(nn::conv2d(&vs / "proj_in", in_channels, inner_dim, 1, conv_cfg))
         };
@@ -269,13 +537,70 @@ impl SpatialTransformer {
             transformer_blocks.push(tb)
         }
         let proj_out = if config.use_linear_projection {
-            Proj::Linear(nn::linear(&vs / "proj_out", in_channels, inner_dim, Default::default()))
+            Proj::Linear(Linear::new(&vs / "proj_out", in_channels, inner_dim, Default::default()))
         } else {
             Proj::Conv2D(nn::conv2d(&vs / "proj_out", inner_dim, in_channels, 1, conv_cfg))
         };
         Self { norm, proj_in, transformer_blocks, proj_out, config }
     }
 
+    /// Restricts the cross-attention of the transformer blocks to some regions, or
+    /// removes the restriction when `regional` is `None`.
+    pub fn set_regional_attention(&mut self, regional: Option<Arc<RegionalAttention>>) {
+        for block in self.transformer_blocks.iter_mut() {
+            block.set_regional_attention(regional.clone())
+        }
+    }
+
+    /// Replaces the self-attention maps of the transformer blocks with the identity, as
+    /// used for the perturbed branch of perturbed attention guidance.
+    pub fn set_perturbed_self_attention(&mut self, perturbed: bool) {
+        for block in self.transformer_blocks.iter_mut() {
+            block.set_perturbed_self_attention(perturbed)
+        }
+    }
+
+    /// Switches the linear layers to int8 weights, see `UNet2DConditionModel::quantize_int8`.
+    /// Returns the number of quantized layers.
+    pub fn quantize_int8(&mut self) -> anyhow::Result<usize> {
+        let mut quantized = 0;
+        for proj in [&mut self.proj_in, &mut self.proj_out] {
+            if let Proj::Linear(linear) = proj {
+                linear.quantize_int8()?;
+                quantized += 1
+            }
+        }
+        for block in self.transformer_blocks.iter_mut() {
+            quantized += block.quantize_int8()?
+        }
+        Ok(quantized)
+    }
+
+    /// The paths of the attention layers of the transformer blocks.
+    pub fn attention_paths(&self) -> Vec<String> {
+        let blocks = self.transformer_blocks.iter();
+        blocks.flat_map(|b| [b.attn1.path.clone(), b.attn2.path.clone()]).collect()
+    }
+
+    /// Sets the processor of the attention layers whose path starts with the components
+    /// of `prefix`, `None` restores the default processor. Returns the number of layers that matched.
+    pub fn set_attention_processor(
+        &mut self,
+        prefix: &str,
+        processor: Option<Arc<dyn AttentionProcessor>>,
+    ) -> usize {
+        let mut matched = 0;
+        for block in self.transformer_blocks.iter_mut() {
+            for attn in block.attention_layers_mut() {
+                if path_matches(&attn.path, prefix) {
+                    attn.processor = processor.clone();
+                    matched += 1;
+                }
+            }
+        }
+        matched
+    }
+
     pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
         let (batch, _channel, height, weight) = xs.size4().unwrap();
         let residual = xs;
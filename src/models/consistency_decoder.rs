@@ -0,0 +1,302 @@
+//! # Consistency Decoder
+//!
+//! The consistency decoder released by OpenAI with DALL-E 3, a replacement for the
+//! decoder of the stable diffusion 1.x autoencoder giving sharper images, in particular
+//! for text, faces, and straight lines. Rather than a single forward pass, the image is
+//! generated by a small diffusion model conditioned on the upsampled latents, using two
+//! consistency model steps.
+//!
+//! The weights are the `decoder_unet` part of the diffusers `ConsistencyDecoderVAE`, e.g.
+//! https://huggingface.co/openai/consistency-decoder, the variables of the encoder in
+//! the same file are ignored: images are still encoded with the standard autoencoder.
+//!
+//! The second step samples some noise so the decoding is not deterministic, use
+//! `tch::manual_seed` to get reproducible images.
+use crate::models::embeddings::TimestepEmbedding;
+use crate::models::resnet::{Resample, ResnetBlock2D, ResnetBlock2DConfig, TimeEmbeddingNorm};
+use crate::models::vae::LatentDecoder;
+use crate::schedulers::betas_for_alpha_bar;
+use tch::{nn, Kind, Tensor};
+
+// The timesteps of the two decoding steps, these are the only ones supported upstream.
+const TIMESTEPS: [usize; 2] = [1008, 512];
+
+/// The configuration of the consistency decoder, the default values are the ones of the
+/// OpenAI decoder for the stable diffusion 1.x latents.
+#[derive(Debug, Clone)]
+pub struct ConsistencyDecoderConfig {
+    // down_block_types: ResnetDownsampleBlock2D
+    // up_block_types: ResnetUpsampleBlock2D
+    // time_embedding_type: learned, resnet_time_scale_shift: scale_shift
+    pub block_out_channels: Vec<i64>,
+    pub layers_per_block: i64,
+    pub latent_channels: i64,
+    pub norm_num_groups: i64,
+    pub norm_eps: f64,
+    /// The size of the learned time embedding.
+    pub num_train_timesteps: usize,
+    /// The ratio between the image and latent sizes of the autoencoder.
+    pub latent_upscale_factor: i64,
+    /// The scaling factor of the autoencoder latents.
+    pub scaling_factor: f64,
+    /// The per-channel statistics of the autoencoder latents, these are used to
+    /// normalize the latents before passing them to the decoder.
+    pub latent_means: Vec<f64>,
+    pub latent_stds: Vec<f64>,
+    pub sigma_data: f64,
+}
+
+impl Default for ConsistencyDecoderConfig {
+    fn default() -> Self {
+        Self {
+            block_out_channels: vec![320, 640, 1024, 1024],
+            layers_per_block: 3,
+            latent_channels: 4,
+            norm_num_groups: 32,
+            norm_eps: 1e-5,
+            num_train_timesteps: 1024,
+            latent_upscale_factor: 8,
+            scaling_factor: 0.18215,
+            latent_means: vec![0.38862467, 0.02253063, 0.07381133, -0.0171294],
+            latent_stds: vec![0.9654121, 1.0440036, 0.76147926, 0.77022034],
+            sigma_data: 0.5,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DownBlock {
+    resnets: Vec<ResnetBlock2D>,
+    downsampler: Option<ResnetBlock2D>,
+}
+
+#[derive(Debug)]
+struct UpBlock {
+    resnets: Vec<ResnetBlock2D>,
+    upsampler: Option<ResnetBlock2D>,
+}
+
+// The unconditional UNet of the decoder, its input is the noisy image concatenated
+// with the upsampled latents.
+#[derive(Debug)]
+struct DecoderUNet {
+    time_proj: nn::Embedding,
+    time_embedding: TimestepEmbedding,
+    conv_in: nn::Conv2D,
+    down_blocks: Vec<DownBlock>,
+    mid_resnets: Vec<ResnetBlock2D>,
+    up_blocks: Vec<UpBlock>,
+    conv_norm_out: nn::GroupNorm,
+    conv_out: nn::Conv2D,
+}
+
+impl DecoderUNet {
+    fn new(vs: nn::Path, config: &ConsistencyDecoderConfig) -> Self {
+        let bl_channels = config.block_out_channels.as_slice();
+        let n_blocks = bl_channels.len();
+        let time_embed_dim = bl_channels[0] * 4;
+        let conv_cfg = nn::ConvConfig { padding: 1, ..Default::default() };
+        let time_proj = nn::embedding(
+            &vs / "time_proj",
+            config.num_train_timesteps as i64,
+            bl_channels[0],
+            Default::default(),
+        );
+        let time_embedding =
+            TimestepEmbedding::new(&vs / "time_embedding", bl_channels[0], time_embed_dim);
+        let in_channels = 3 + config.latent_channels;
+        let conv_in = nn::conv2d(&vs / "conv_in", in_channels, bl_channels[0], 3, conv_cfg);
+        let resnet_cfg = |out_channels, resample| ResnetBlock2DConfig {
+            out_channels: Some(out_channels),
+            temb_channels: Some(time_embed_dim),
+            groups: config.norm_num_groups,
+            eps: config.norm_eps,
+            time_embedding_norm: TimeEmbeddingNorm::ScaleShift,
+            resample,
+            ..Default::default()
+        };
+
+        let vs_db = &vs / "down_blocks";
+        let down_blocks = (0..n_blocks)
+            .map(|i| {
+                let vs = &vs_db / i;
+                let in_channels = if i > 0 { bl_channels[i - 1] } else { bl_channels[0] };
+                let out_channels = bl_channels[i];
+                let resnets = (0..config.layers_per_block)
+                    .map(|j| {
+                        let in_channels = if j == 0 { in_channels } else { out_channels };
+                        let cfg = resnet_cfg(out_channels, None);
+                        ResnetBlock2D::new(&vs / "resnets" / j, in_channels, cfg)
+                    })
+                    .collect();
+                let downsampler = (i < n_blocks - 1).then(|| {
+                    let cfg = resnet_cfg(out_channels, Some(Resample::Down));
+                    ResnetBlock2D::new(&vs / "downsamplers" / 0, out_channels, cfg)
+                });
+                DownBlock { resnets, downsampler }
+            })
+            .collect();
+
+        let mid_channels = bl_channels[n_blocks - 1];
+        let mid_resnets = (0..2)
+            .map(|j| {
+                let cfg = resnet_cfg(mid_channels, None);
+                ResnetBlock2D::new(&vs / "mid_block" / "resnets" / j, mid_channels, cfg)
+            })
+            .collect();
+
+        let vs_ub = &vs / "up_blocks";
+        let num_layers = config.layers_per_block + 1;
+        let up_blocks = (0..n_blocks)
+            .map(|i| {
+                let vs = &vs_ub / i;
+                let prev_output_channels = bl_channels[n_blocks - i.max(1)];
+                let out_channels = bl_channels[n_blocks - 1 - i];
+                let in_channels = bl_channels[(n_blocks - 1 - i).saturating_sub(1)];
+                let resnets = (0..num_layers)
+                    .map(|j| {
+                        let res_skip_channels =
+                            if j == num_layers - 1 { in_channels } else { out_channels };
+                        let resnet_in_channels =
+                            if j == 0 { prev_output_channels } else { out_channels };
+                        let cfg = resnet_cfg(out_channels, None);
+                        let in_channels = resnet_in_channels + res_skip_channels;
+                        ResnetBlock2D::new(&vs / "resnets" / j, in_channels, cfg)
+                    })
+                    .collect();
+                let upsampler = (i < n_blocks - 1).then(|| {
+                    let cfg = resnet_cfg(out_channels, Some(Resample::Up));
+                    ResnetBlock2D::new(&vs / "upsamplers" / 0, out_channels, cfg)
+                });
+                UpBlock { resnets, upsampler }
+            })
+            .collect();
+
+        let group_cfg = nn::GroupNormConfig { eps: config.norm_eps, ..Default::default() };
+        let conv_norm_out = nn::group_norm(
+            &vs / "conv_norm_out",
+            config.norm_num_groups,
+            bl_channels[0],
+            group_cfg,
+        );
+        // The last three output channels are a variance prediction, unused for decoding.
+        let conv_out = nn::conv2d(&vs / "conv_out", bl_channels[0], 6, 3, conv_cfg);
+        Self {
+            time_proj,
+            time_embedding,
+            conv_in,
+            down_blocks,
+            mid_resnets,
+            up_blocks,
+            conv_norm_out,
+            conv_out,
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, timestep: usize) -> Tensor {
+        let timestep = Tensor::from_slice(&[timestep as i64]).to_device(xs.device());
+        let emb = timestep.apply(&self.time_proj).to_kind(xs.kind()).apply(&self.time_embedding);
+        let emb = Some(&emb);
+
+        let mut xs = xs.apply(&self.conv_in);
+        let mut skips = vec![xs.shallow_clone()];
+        for down_block in self.down_blocks.iter() {
+            for resnet in down_block.resnets.iter().chain(down_block.downsampler.iter()) {
+                xs = resnet.forward(&xs, emb);
+                skips.push(xs.shallow_clone());
+            }
+        }
+        for resnet in self.mid_resnets.iter() {
+            xs = resnet.forward(&xs, emb);
+        }
+        for up_block in self.up_blocks.iter() {
+            for resnet in up_block.resnets.iter() {
+                let skip = skips.pop().unwrap();
+                xs = resnet.forward(&Tensor::cat(&[&xs, &skip], 1), emb);
+            }
+            if let Some(upsampler) = &up_block.upsampler {
+                xs = upsampler.forward(&xs, emb);
+            }
+        }
+        xs.apply(&self.conv_norm_out).silu().apply(&self.conv_out)
+    }
+}
+
+/// The consistency decoder, a drop-in replacement for the autoencoder decoder through
+/// the `LatentDecoder` trait, e.g. with `StableDiffusion::set_decoder`.
+#[derive(Debug)]
+pub struct ConsistencyDecoder {
+    unet: DecoderUNet,
+    alphas_cumprod: Vec<f64>,
+    pub config: ConsistencyDecoderConfig,
+}
+
+// SAFETY: the decoder only holds the weight tensors of the UNet and some plain values,
+// the weights are not modified after construction and there is no interior mutability,
+// so concurrent decodes only read them, which libtorch supports.
+unsafe impl Sync for ConsistencyDecoder {}
+
+impl ConsistencyDecoder {
+    /// Creates the decoder, the weights are expected under `decoder_unet` as in the
+    /// diffusers checkpoints, e.g. with `vs.root()` for such a file.
+    pub fn new(vs: nn::Path, config: ConsistencyDecoderConfig) -> Self {
+        let unet = DecoderUNet::new(&vs / "decoder_unet", &config);
+        let betas = betas_for_alpha_bar(config.num_train_timesteps, 0.999);
+        let alphas: Tensor = 1.0 - betas;
+        let alphas_cumprod = Vec::<f64>::try_from(alphas.cumprod(0, Kind::Double)).unwrap();
+        Self { unet, alphas_cumprod, config }
+    }
+
+    // The scalings of the consistency model parameterization for a timestep: c_in is
+    // applied to the model input, c_out and c_skip combine the model output and the
+    // input sample into the denoised sample.
+    fn scalings(&self, timestep: usize) -> (f64, f64, f64) {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        let sigma_data2 = self.config.sigma_data * self.config.sigma_data;
+        let sigma2 = 1. / alpha_prod - 1.;
+        let sqrt_recip_alpha_prod = (1. / alpha_prod).sqrt();
+        let c_skip = sqrt_recip_alpha_prod * sigma_data2 / (sigma2 + sigma_data2);
+        let c_out = sigma2.sqrt() * self.config.sigma_data / (sigma2 + sigma_data2).sqrt();
+        let c_in = sqrt_recip_alpha_prod / (sigma2 + sigma_data2).sqrt();
+        (c_in, c_out, c_skip)
+    }
+}
+
+impl LatentDecoder for ConsistencyDecoder {
+    fn decode(&self, latents: &Tensor) -> Tensor {
+        // https://github.com/huggingface/diffusers/blob/v0.24.0/src/diffusers/models/autoencoders/consistency_decoder_vae.py#L306
+        let (kind, device) = (latents.kind(), latents.device());
+        let stats = |values: &[f64]| {
+            Tensor::from_slice(values).view([1, -1, 1, 1]).to_kind(kind).to_device(device)
+        };
+        let means = stats(&self.config.latent_means);
+        let stds = stats(&self.config.latent_stds);
+        let latents = (latents * self.config.scaling_factor - means) / stds;
+        let (b, _, h, w) = latents.size4().unwrap();
+        let upscale = self.config.latent_upscale_factor;
+        let latents = latents.upsample_nearest2d([h * upscale, w * upscale], None, None);
+
+        let alpha_prod = self.alphas_cumprod[TIMESTEPS[0]];
+        let mut xs = Tensor::randn([b, 3, h * upscale, w * upscale], (kind, device))
+            * (1. - alpha_prod).sqrt();
+        for (i, &timestep) in TIMESTEPS.iter().enumerate() {
+            let (c_in, c_out, c_skip) = self.scalings(timestep);
+            let model_input = Tensor::cat(&[&xs * c_in, latents.shallow_clone()], 1);
+            let model_output = self.unet.forward(&model_input, timestep).narrow(1, 0, 3);
+            let denoised = model_output * c_out + &xs * c_skip;
+            xs = match TIMESTEPS.get(i + 1) {
+                None => denoised,
+                Some(&next_timestep) => {
+                    let alpha_prod = self.alphas_cumprod[next_timestep];
+                    let noise = denoised.randn_like();
+                    denoised * alpha_prod.sqrt() + noise * (1. - alpha_prod).sqrt()
+                }
+            }
+        }
+        xs
+    }
+
+    fn scaling_factor(&self) -> f64 {
+        self.config.scaling_factor
+    }
+}
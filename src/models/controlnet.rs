@@ -1,8 +1,17 @@
 // https://github.com/huggingface/diffusers/blob/main/src/diffusers/models/controlnet.py
-use super::unet_2d::{BlockConfig, UNetDownBlock};
-use crate::models::embeddings::{TimestepEmbedding, Timesteps};
+use super::unet_2d::{
+    blocks_from_json, cross_attention_dim_from_json, text_time_embedding_from_json, BlockConfig,
+    UNet2DConditionModelConfig, UNetDownBlock,
+};
+use crate::models::embeddings::{
+    embed_timesteps, AddedCond, TextTimeEmbedding, TextTimeEmbeddingConfig, TimestepEmbedding,
+    Timesteps,
+};
+use crate::models::params;
 use crate::models::unet_2d_blocks::*;
-use tch::{nn, nn::Module, Kind, Tensor};
+use crate::utils::JsonConfig;
+use std::sync::Mutex;
+use tch::{nn, nn::Module, Tensor};
 
 #[derive(Debug)]
 pub struct ControlNetConditioningEmbedding {
@@ -54,6 +63,7 @@ pub struct ControlNetConfig {
     pub freq_shift: f64,
     pub blocks: Vec<BlockConfig>,
     pub conditioning_embedding_out_channels: Vec<i64>,
+    pub conditioning_channels: i64,
     pub layers_per_block: i64,
     pub downsample_padding: i64,
     pub mid_block_scale_factor: f64,
@@ -61,21 +71,29 @@ pub struct ControlNetConfig {
     pub norm_eps: f64,
     pub cross_attention_dim: i64,
     pub use_linear_projection: bool,
+    /// When set, the residuals are averaged over their spatial dimensions before being
+    /// returned. This is used by the shuffle ControlNets.
+    pub global_pool_conditions: bool,
+    /// The SDXL added conditioning, see `UNet2DConditionModelConfig::text_time_embedding`.
+    pub text_time_embedding: Option<TextTimeEmbeddingConfig>,
 }
 
 impl Default for ControlNetConfig {
     // https://huggingface.co/lllyasviel/sd-controlnet-canny/blob/main/config.json
     fn default() -> Self {
+        let bc = |out_channels, use_cross_attn| BlockConfig {
+            out_channels,
+            use_cross_attn,
+            attention_head_dim: 8,
+            cross_attention_dim: None,
+            transformer_layers_per_block: 1,
+        };
         Self {
             flip_sin_to_cos: true,
             freq_shift: 0.,
-            blocks: vec![
-                BlockConfig { out_channels: 320, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 640, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 1280, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 1280, use_cross_attn: false, attention_head_dim: 8 },
-            ],
+            blocks: vec![bc(320, true), bc(640, true), bc(1280, true), bc(1280, false)],
             conditioning_embedding_out_channels: vec![16, 32, 96, 256],
+            conditioning_channels: 3,
             layers_per_block: 2,
             downsample_padding: 1,
             mid_block_scale_factor: 1.,
@@ -85,7 +103,147 @@ impl Default for ControlNetConfig {
             // 768 in the actual config file.
             cross_attention_dim: 768,
             use_linear_projection: false,
+            global_pool_conditions: false,
+            text_time_embedding: None,
+        }
+    }
+}
+
+impl ControlNetConfig {
+    /// The SDXL ControlNets, e.g.
+    /// https://huggingface.co/diffusers/controlnet-canny-sdxl-1.0/blob/main/config.json
+    pub fn sdxl() -> Self {
+        let bc =
+            |out_channels, use_cross_attn, attention_head_dim, transformer_layers_per_block| {
+                BlockConfig {
+                    out_channels,
+                    use_cross_attn,
+                    attention_head_dim,
+                    cross_attention_dim: None,
+                    transformer_layers_per_block,
+                }
+            };
+        Self {
+            blocks: vec![bc(320, false, 5, 1), bc(640, true, 10, 2), bc(1280, true, 20, 10)],
+            cross_attention_dim: 2048,
+            use_linear_projection: true,
+            text_time_embedding: Some(TextTimeEmbeddingConfig {
+                addition_time_embed_dim: 256,
+                projection_class_embeddings_input_dim: 2816,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// The number of parameters of a ControlNet built with this configuration.
+    pub fn num_parameters(&self, in_channels: i64) -> i64 {
+        let b_channels = self.blocks[0].out_channels;
+        let bl_channels = self.blocks.last().unwrap().out_channels;
+        let mut n = params::unet_encoder(
+            in_channels,
+            &self.blocks,
+            self.layers_per_block,
+            self.cross_attention_dim,
+        );
+        if let Some(text_time) = self.text_time_embedding {
+            let input_dim = text_time.projection_class_embeddings_input_dim;
+            n += params::linear(input_dim, 4 * b_channels)
+                + params::linear(4 * b_channels, 4 * b_channels)
+        }
+        let emb_channels = &self.conditioning_embedding_out_channels;
+        n += params::conv2d(self.conditioning_channels, emb_channels[0], 3);
+        for w in emb_channels.windows(2) {
+            n += params::conv2d(w[0], w[0], 3) + params::conv2d(w[0], w[1], 3)
+        }
+        n += params::conv2d(*emb_channels.last().unwrap(), b_channels, 3);
+        // The zero convolutions applied to each residual.
+        n +=
+            params::conv2d(b_channels, b_channels, 1) + params::conv2d(bl_channels, bl_channels, 1);
+        for (i, block) in self.blocks.iter().enumerate() {
+            let n_convs = self.layers_per_block + i64::from(i + 1 != self.blocks.len());
+            n += n_convs * params::conv2d(block.out_channels, block.out_channels, 1)
         }
+        n
+    }
+
+    /// Reads a ControlNet configuration from the `config.json` file of a diffusers model, e.g.
+    /// https://huggingface.co/lllyasviel/sd-controlnet-canny/blob/main/config.json
+    ///
+    /// The SDXL ControlNets are supported, see `ControlNetConfig::sdxl`.
+    pub fn from_json<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
+        let json = JsonConfig::read(path)?;
+        let blocks = blocks_from_json(&json)?;
+        let default = Self::default();
+        let conditioning_embedding_out_channels =
+            match json.get("conditioning_embedding_out_channels") {
+                None => default.conditioning_embedding_out_channels,
+                Some(_) => json.i64_list("conditioning_embedding_out_channels")?,
+            };
+        if conditioning_embedding_out_channels.is_empty() {
+            anyhow::bail!("empty conditioning_embedding_out_channels")
+        }
+        Ok(Self {
+            flip_sin_to_cos: json.bool_or("flip_sin_to_cos", default.flip_sin_to_cos)?,
+            freq_shift: json.f64_or("freq_shift", default.freq_shift)?,
+            blocks,
+            conditioning_embedding_out_channels,
+            conditioning_channels: json
+                .i64_or("conditioning_channels", default.conditioning_channels)?,
+            layers_per_block: json.i64_or("layers_per_block", default.layers_per_block)?,
+            downsample_padding: json.i64_or("downsample_padding", default.downsample_padding)?,
+            mid_block_scale_factor: json
+                .f64_or("mid_block_scale_factor", default.mid_block_scale_factor)?,
+            norm_num_groups: json.i64_or("norm_num_groups", default.norm_num_groups)?,
+            norm_eps: json.f64_or("norm_eps", default.norm_eps)?,
+            cross_attention_dim: cross_attention_dim_from_json(&json, default.cross_attention_dim)?,
+            use_linear_projection: json
+                .bool_or("use_linear_projection", default.use_linear_projection)?,
+            global_pool_conditions: json
+                .bool_or("global_pool_conditions", default.global_pool_conditions)?,
+            text_time_embedding: text_time_embedding_from_json(&json)?,
+        })
+    }
+
+    /// Checks that the ControlNet residuals can be added to the given UNet, i.e. that
+    /// both models have been trained for the same base model.
+    pub fn check_compatible(&self, unet: &UNet2DConditionModelConfig) -> anyhow::Result<()> {
+        if self.cross_attention_dim != unet.cross_attention_dim {
+            anyhow::bail!(
+                "ControlNet cross_attention_dim {} does not match the UNet one {}, was it trained for a different base model?",
+                self.cross_attention_dim,
+                unet.cross_attention_dim
+            )
+        }
+        let channels = |b: &[BlockConfig]| b.iter().map(|b| b.out_channels).collect::<Vec<_>>();
+        if channels(&self.blocks) != channels(&unet.blocks)
+            || self.layers_per_block != unet.layers_per_block
+        {
+            anyhow::bail!(
+                "ControlNet blocks {:?} (layers per block {}) do not match the UNet ones {:?} ({})",
+                channels(&self.blocks),
+                self.layers_per_block,
+                channels(&unet.blocks),
+                unet.layers_per_block
+            )
+        }
+        let depths = |b: &[BlockConfig]| {
+            b.iter().map(|b| b.transformer_layers_per_block).collect::<Vec<_>>()
+        };
+        if depths(&self.blocks) != depths(&unet.blocks) {
+            anyhow::bail!(
+                "ControlNet transformer layers per block {:?} do not match the UNet ones {:?}",
+                depths(&self.blocks),
+                depths(&unet.blocks)
+            )
+        }
+        if self.text_time_embedding != unet.text_time_embedding {
+            anyhow::bail!(
+                "ControlNet added conditioning {:?} does not match the UNet one {:?}",
+                self.text_time_embedding,
+                unet.text_time_embedding
+            )
+        }
+        Ok(())
     }
 }
 
@@ -94,14 +252,24 @@ pub struct ControlNet {
     conv_in: nn::Conv2D,
     controlnet_mid_block: nn::Conv2D,
     controlnet_cond_embedding: ControlNetConditioningEmbedding,
+    cond_embedding_cache: Option<Mutex<Option<(Tensor, Tensor)>>>,
     time_proj: Timesteps,
     time_embedding: TimestepEmbedding,
+    add_embedding: Option<TextTimeEmbedding>,
     down_blocks: Vec<UNetDownBlock>,
     controlnet_down_blocks: Vec<nn::Conv2D>,
     mid_block: UNetMidBlock2DCrossAttn,
     pub config: ControlNetConfig,
 }
 
+// SAFETY: as for `UNet2DConditionModel`, the weights are only read by the forward
+// passes and the blocks shared with the UNet hold plain values besides them. The only
+// state changed through `&self` is the conditioning embedding cache, which sits behind
+// a `Mutex` so that concurrent forwards serialize on it. The cached tensors are a copy
+// of the conditioning and an embedding computed for the cache, neither gets written in
+// place, so handing out shallow clones of the embedding only shares reads.
+unsafe impl Sync for ControlNet {}
+
 impl ControlNet {
     pub fn new(vs: nn::Path, in_channels: i64, config: ControlNetConfig) -> Self {
         let n_blocks = config.blocks.len();
@@ -112,6 +280,15 @@ impl ControlNet {
             Timesteps::new(b_channels, config.flip_sin_to_cos, config.freq_shift, vs.device());
         let time_embedding =
             TimestepEmbedding::new(&vs / "time_embedding", b_channels, time_embed_dim);
+        let add_embedding = config.text_time_embedding.map(|text_time| {
+            TextTimeEmbedding::new(
+                &vs / "add_embedding",
+                text_time,
+                config.flip_sin_to_cos,
+                config.freq_shift,
+                time_embed_dim,
+            )
+        });
         let conv_cfg = nn::ConvConfig { stride: 1, padding: 1, ..Default::default() };
         let conv_in = nn::conv2d(&vs / "conv_in", in_channels, b_channels, 3, conv_cfg);
         let controlnet_mid_block = nn::conv2d(
@@ -124,14 +301,19 @@ impl ControlNet {
         let controlnet_cond_embedding = ControlNetConditioningEmbedding::new(
             &vs / "controlnet_cond_embedding",
             b_channels,
-            3,
+            config.conditioning_channels,
             &config.conditioning_embedding_out_channels,
         );
         let vs_db = &vs / "down_blocks";
         let down_blocks = (0..n_blocks)
             .map(|i| {
-                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } =
-                    config.blocks[i];
+                let BlockConfig {
+                    out_channels,
+                    use_cross_attn,
+                    attention_head_dim,
+                    cross_attention_dim,
+                    transformer_layers_per_block,
+                } = config.blocks[i];
 
                 let in_channels =
                     if i > 0 { config.blocks[i - 1].out_channels } else { b_channels };
@@ -147,9 +329,11 @@ impl ControlNet {
                     let config = CrossAttnDownBlock2DConfig {
                         downblock: db_cfg,
                         attn_num_head_channels: attention_head_dim,
-                        cross_attention_dim: config.cross_attention_dim,
+                        cross_attention_dim: cross_attention_dim
+                            .unwrap_or(config.cross_attention_dim),
                         sliced_attention_size: None,
                         use_linear_projection: config.use_linear_projection,
+                        transformer_layers_per_block,
                     };
                     let block = CrossAttnDownBlock2D::new(
                         &vs_db / i,
@@ -176,10 +360,15 @@ impl ControlNet {
         let mid_cfg = UNetMidBlock2DCrossAttnConfig {
             resnet_eps: config.norm_eps,
             output_scale_factor: config.mid_block_scale_factor,
-            cross_attn_dim: config.cross_attention_dim,
+            cross_attn_dim: config
+                .blocks
+                .last()
+                .and_then(|b| b.cross_attention_dim)
+                .unwrap_or(config.cross_attention_dim),
             attn_num_head_channels: bl_attention_head_dim,
             resnet_groups: Some(config.norm_num_groups),
             use_linear_projection: config.use_linear_projection,
+            transformer_layers_per_block: config.blocks[n_blocks - 1].transformer_layers_per_block,
             ..Default::default()
         };
         let mid_block = UNetMidBlock2DCrossAttn::new(
@@ -220,15 +409,67 @@ impl ControlNet {
             conv_in,
             controlnet_mid_block,
             controlnet_cond_embedding,
+            cond_embedding_cache: Some(Mutex::new(None)),
             controlnet_down_blocks,
             time_proj,
             time_embedding,
+            add_embedding,
             down_blocks,
             mid_block,
             config,
         }
     }
 
+    /// Enables or disables the caching of the conditioning embedding, enabled by default.
+    ///
+    /// The conditioning is usually the same for all the steps of a generation, so its
+    /// embedding is only computed on the first step and reused as long as `forward` gets
+    /// called with an equal conditioning tensor. The cached embedding is computed without
+    /// tracking gradients, the cache should be disabled to backpropagate through the
+    /// conditioning embedding.
+    pub fn set_cond_embedding_cache(&mut self, enabled: bool) {
+        self.cond_embedding_cache = if enabled { Some(Mutex::new(None)) } else { None }
+    }
+
+    /// Drops the cached conditioning embedding, if any.
+    pub fn clear_cond_embedding_cache(&self) {
+        if let Some(cache) = self.cond_embedding_cache.as_ref() {
+            *cache.lock().unwrap() = None
+        }
+    }
+
+    // The embedding of the conditioning, served from the cache when the conditioning
+    // is equal to the cached one. The comparison is much cheaper than the embedding
+    // convolutions, which run at the image resolution.
+    fn cond_embedding(&self, controlnet_cond: &Tensor) -> Tensor {
+        let cache = match self.cond_embedding_cache.as_ref() {
+            None => return controlnet_cond.apply(&self.controlnet_cond_embedding),
+            Some(cache) => cache,
+        };
+        let mut cache = cache.lock().unwrap();
+        if let Some((cond, embedding)) = cache.as_ref() {
+            if cond.size() == controlnet_cond.size()
+                && cond.kind() == controlnet_cond.kind()
+                && cond.device() == controlnet_cond.device()
+                && cond.equal(controlnet_cond)
+            {
+                return embedding.shallow_clone();
+            }
+        }
+        let embedding = tch::no_grad(|| controlnet_cond.apply(&self.controlnet_cond_embedding));
+        // The conditioning is copied so that in place changes invalidate the cache.
+        *cache = Some((controlnet_cond.copy(), embedding.shallow_clone()));
+        embedding
+    }
+
+    /// Returns the residuals to add to the UNet down blocks and mid block.
+    ///
+    /// `controlnet_cond` has shape `[cond_batch, channels, height, width]` and may hold a
+    /// different conditioning image per batch element. When `cond_batch` divides the batch
+    /// size of `xs`, e.g. when `xs` contains both guidance branches, the conditioning is
+    /// repeated along the batch dimension so that element `i` of each branch uses image `i`.
+    /// The conditioning values are expected in `[0, 1]`, see
+    /// `pipelines::controlnet::normalize_conditioning`.
     pub fn forward(
         &self,
         xs: &Tensor,
@@ -236,6 +477,47 @@ impl ControlNet {
         encoder_hidden_states: &Tensor,
         controlnet_cond: &Tensor,
         conditioning_scale: f64,
+    ) -> (Vec<Tensor>, Tensor) {
+        self.forward_(
+            xs,
+            timestep,
+            encoder_hidden_states,
+            controlnet_cond,
+            conditioning_scale,
+            None,
+        )
+    }
+
+    /// Same as `forward` for the SDXL ControlNets, the residuals are then passed to
+    /// `UNet2DConditionModel::forward_with_added_cond_and_residuals`. The other forward
+    /// passes panic for such a ControlNet.
+    pub fn forward_with_added_cond(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        controlnet_cond: &Tensor,
+        conditioning_scale: f64,
+        added_cond: &AddedCond,
+    ) -> (Vec<Tensor>, Tensor) {
+        self.forward_(
+            xs,
+            timestep,
+            encoder_hidden_states,
+            controlnet_cond,
+            conditioning_scale,
+            Some(added_cond),
+        )
+    }
+
+    fn forward_(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        controlnet_cond: &Tensor,
+        conditioning_scale: f64,
+        added_cond: Option<&AddedCond>,
     ) -> (Vec<Tensor>, Tensor) {
         let (bsize, _channels, _height, _width) = xs.size4().unwrap();
         let device = xs.device();
@@ -245,13 +527,34 @@ impl ControlNet {
         // - No guess mode.
 
         // 1. Time
-        let emb = (Tensor::ones([bsize], (Kind::Float, device)) * timestep)
-            .apply(&self.time_proj)
-            .apply(&self.time_embedding);
+        let timestep = Tensor::from(timestep);
+        let emb = embed_timesteps(&self.time_proj, &self.time_embedding, &timestep, bsize, device);
+        let emb = match (&self.add_embedding, added_cond) {
+            (None, None) => emb,
+            (Some(add_embedding), Some(added_cond)) => emb + add_embedding.forward(added_cond),
+            (Some(_), None) => {
+                panic!(
+                    "the controlnet uses the SDXL added conditioning, see forward_with_added_cond"
+                )
+            }
+            (None, Some(_)) => panic!("the controlnet does not use the SDXL added conditioning"),
+        };
 
         // 2. Pre-process.
         let xs = xs.apply(&self.conv_in);
-        let controlnet_cond = controlnet_cond.apply(&self.controlnet_cond_embedding);
+        // The conditioning can have one element per generated image while the input
+        // batch holds all the classifier free guidance branches, in which case the
+        // conditioning embedding is repeated for each branch.
+        let cond_bsize = controlnet_cond.size()[0];
+        if bsize % cond_bsize != 0 {
+            panic!("conditioning batch size {cond_bsize} incompatible with batch size {bsize}")
+        }
+        let controlnet_cond = self.cond_embedding(controlnet_cond);
+        let controlnet_cond = if cond_bsize != bsize {
+            controlnet_cond.repeat([bsize / cond_bsize, 1, 1, 1])
+        } else {
+            controlnet_cond
+        };
         let xs = xs + controlnet_cond;
 
         // 3. Down.
@@ -276,10 +579,20 @@ impl ControlNet {
             .controlnet_down_blocks
             .iter()
             .enumerate()
-            .map(|(i, block)| block.forward(&down_block_res_xs[i]) * conditioning_scale)
+            .map(|(i, block)| self.pool(block.forward(&down_block_res_xs[i])) * conditioning_scale)
             .collect::<Vec<_>>();
 
-        let xs = xs.apply(&self.controlnet_mid_block);
+        let xs = self.pool(xs.apply(&self.controlnet_mid_block));
         (controlnet_down_block_res_xs, xs * conditioning_scale)
     }
+
+    // The pooled residuals keep singleton spatial dimensions so that they broadcast
+    // when added to the UNet activations.
+    fn pool(&self, xs: Tensor) -> Tensor {
+        if self.config.global_pool_conditions {
+            xs.mean_dim(Some([2i64, 3].as_slice()), true, None)
+        } else {
+            xs
+        }
+    }
 }
@@ -61,3 +61,123 @@ impl Module for Timesteps {
         }
     }
 }
+
+/// Embeds the timesteps of a batch of `bsize` elements. When `timestep` has a single
+/// value, as in the denoising loops where all the elements share the same timestep, the
+/// embedding is only computed once and has shape `[1, time_embed_dim]`, it then gets
+/// broadcast over the batch by the resnet blocks. Otherwise `timestep` should have one
+/// value per batch element and the embedding has shape `[bsize, time_embed_dim]`.
+pub(crate) fn embed_timesteps(
+    time_proj: &Timesteps,
+    time_embedding: &TimestepEmbedding,
+    timestep: &Tensor,
+    bsize: i64,
+    device: Device,
+) -> Tensor {
+    let timestep = timestep.to_kind(Kind::Float).to_device(device);
+    let timesteps = if timestep.numel() == 1 {
+        timestep.reshape([1])
+    } else {
+        Tensor::ones([bsize], (Kind::Float, device)) * timestep
+    };
+    timesteps.apply(time_proj).apply(time_embedding)
+}
+
+/// The size and crop micro-conditioning of SDXL, `time_ids` in diffusers, with shape
+/// `[1, 6]`: the original image size, the top-left crop coordinates, and the target
+/// size, sizes being `(height, width)` and coordinates `(top, left)` in pixels.
+///
+/// The default is to use the generation size for both sizes and no crop, see
+/// `sdxl_default_time_ids`. A non-zero crop makes the model generate as if the image was
+/// cropped from a larger one, shifting the framing, while a small original size gives
+/// blurrier images as the model was trained on upscaled images with such sizes.
+pub fn sdxl_time_ids(
+    original_size: (i64, i64),
+    crop: (i64, i64),
+    target_size: (i64, i64),
+) -> Tensor {
+    let time_ids = [original_size.0, original_size.1, crop.0, crop.1, target_size.0, target_size.1];
+    Tensor::from_slice(&time_ids.map(|v| v as f32)).unsqueeze(0)
+}
+
+/// The SDXL `time_ids` for a `height x width` generation without any crop.
+pub fn sdxl_default_time_ids(height: i64, width: i64) -> Tensor {
+    sdxl_time_ids((height, width), (0, 0), (height, width))
+}
+
+/// The added conditioning of the SDXL UNet: each of the `time_ids` values gets a 256
+/// channels sinusoidal embedding, and these are concatenated after the pooled embedding
+/// of the second text encoder. For `pooled_text_embeds` of shape `[batch, 1280]` this
+/// gives the `[batch, 2816]` input of the UNet `add_embedding`.
+pub fn sdxl_added_cond_embeds(time_ids: &Tensor, pooled_text_embeds: &Tensor) -> Tensor {
+    // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/unet/config.json
+    // addition_time_embed_dim: 256, flip_sin_to_cos: true, freq_shift: 0
+    let add_time_proj = Timesteps::new(256, true, 0., pooled_text_embeds.device());
+    added_cond_embeds(&add_time_proj, time_ids, pooled_text_embeds)
+}
+
+fn added_cond_embeds(add_time_proj: &Timesteps, time_ids: &Tensor, text_embeds: &Tensor) -> Tensor {
+    let device = text_embeds.device();
+    let bsize = text_embeds.size()[0];
+    let time_ids = time_ids.to_kind(Kind::Float).to_device(device);
+    let n_ids = time_ids.size()[time_ids.dim() - 1];
+    let time_ids = time_ids.reshape([-1, n_ids]).expand([bsize, n_ids], false);
+    let time_embeds = time_ids.flatten(0, -1).apply(add_time_proj).reshape([bsize, -1]);
+    Tensor::cat(&[text_embeds.to_kind(Kind::Float), time_embeds], -1).to_kind(text_embeds.kind())
+}
+
+/// The configuration of the SDXL added conditioning, see `TextTimeEmbedding`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextTimeEmbeddingConfig {
+    /// The number of channels of the sinusoidal embedding of each time id, 256 for SDXL.
+    pub addition_time_embed_dim: i64,
+    /// The pooled text embedding dimension plus 6 times `addition_time_embed_dim`, 2816
+    /// for SDXL.
+    pub projection_class_embeddings_input_dim: i64,
+}
+
+/// The inputs of the SDXL added conditioning: the pooled embeddings of the second text
+/// encoder with shape `[batch, pooled_dim]`, and the time ids with shape `[1, 6]` or
+/// `[batch, 6]`, see `sdxl_time_ids`.
+#[derive(Debug)]
+pub struct AddedCond {
+    pub text_embeds: Tensor,
+    pub time_ids: Tensor,
+}
+
+/// The SDXL added conditioning, `addition_embed_type: "text_time"` in diffusers. Its
+/// output gets added to the timestep embedding.
+#[derive(Debug)]
+pub struct TextTimeEmbedding {
+    add_time_proj: Timesteps,
+    add_embedding: TimestepEmbedding,
+}
+
+impl TextTimeEmbedding {
+    /// The time ids embedding uses the `flip_sin_to_cos` and `freq_shift` values of the
+    /// UNet timestep embedding.
+    pub fn new(
+        vs: nn::Path,
+        config: TextTimeEmbeddingConfig,
+        flip_sin_to_cos: bool,
+        freq_shift: f64,
+        time_embed_dim: i64,
+    ) -> Self {
+        let add_time_proj = Timesteps::new(
+            config.addition_time_embed_dim,
+            flip_sin_to_cos,
+            freq_shift,
+            vs.device(),
+        );
+        let input_dim = config.projection_class_embeddings_input_dim;
+        let add_embedding = TimestepEmbedding::new(vs, input_dim, time_embed_dim);
+        Self { add_time_proj, add_embedding }
+    }
+
+    pub fn forward(&self, added_cond: &AddedCond) -> Tensor {
+        let embeds =
+            added_cond_embeds(&self.add_time_proj, &added_cond.time_ids, &added_cond.text_embeds);
+        embeds.to_kind(self.add_embedding.linear_1.ws.kind()).apply(&self.add_embedding)
+    }
+}
@@ -0,0 +1,353 @@
+//! # LoRA
+//!
+//! Low-Rank Adaptation (LoRA) weights in the kohya-ss format used by most community
+//! LoRAs. Each adapted layer has a pair of `lora_down.weight` and `lora_up.weight`
+//! tensors, of shapes `[rank, in]` and `[out, rank]` for linear layers, and the update
+//! of the layer weight is `up @ down`. Convolutions use `[rank, in, k, k]` and
+//! `[out, rank, 1, 1]` tensors and the same update once flattened.
+//!
+//! The layers are named after their diffusers variable path with underscores as
+//! separators and a prefix for the component, e.g.
+//! `lora_unet_down_blocks_0_attentions_0_proj_in` for the UNet and
+//! `lora_te_text_model_encoder_layers_0_self_attn_q_proj` for the text encoder.
+//!
+//! The updates are scaled by `alpha / rank`, the rank is inferred from the tensor shapes
+//! and alpha is read from the per-layer `alpha` scalar tensors. Some files omit these,
+//! alpha is then assumed to be the rank, i.e. the updates are not scaled.
+//!
+//! DoRA files (Weight-Decomposed Low-Rank Adaptation) are detected per layer by the
+//! presence of a `dora_scale` tensor next to the `lora_up` and `lora_down` ones, layers
+//! without it are plain LoRA layers. For DoRA layers, the adapted weight `W + up @ down`
+//! is normalized and rescaled by `dora_scale`, the magnitude vector learnt in training.
+//! The shape of `dora_scale` tells the axis of the norms: `[out, 1, ...]` for a norm per
+//! output channel, as trained by LyCORIS by default, and `[1, in, ...]` for a norm per
+//! input channel, as in the DoRA paper. Strengths other than 1 interpolate between the
+//! base and adapted weights.
+//!
+//! The updates are merged in the weights of the models so the inference cost does not
+//! change, either once with `merge_lora` or with `LoraAdapters` which keeps them around
+//! so that the strength of each LoRA can be changed later on.
+use std::collections::HashMap;
+use tch::{nn, Kind, Tensor};
+
+const UNET_PREFIX: &str = "lora_unet_";
+const TEXT_ENCODER_PREFIX: &str = "lora_te_";
+
+/// The low-rank update of a single layer.
+#[derive(Debug)]
+pub struct LoraModule {
+    pub up: Tensor,
+    pub down: Tensor,
+    /// The alpha of the layer if the file has one.
+    pub alpha: Option<f64>,
+    /// The DoRA magnitude of the layer, `None` for plain LoRA layers.
+    pub dora_scale: Option<Tensor>,
+}
+
+impl LoraModule {
+    pub fn rank(&self) -> i64 {
+        self.down.size()[0]
+    }
+
+    /// The factor applied to `up @ down`, `alpha / rank`.
+    pub fn scale(&self) -> f64 {
+        match self.alpha {
+            None => 1.,
+            Some(alpha) => alpha / self.rank() as f64,
+        }
+    }
+
+    /// The scaled low-rank update `up @ down` for a weight of shape `shape`, as a float
+    /// tensor. For DoRA layers, the actual update of the weight is given by `update`.
+    pub fn delta(&self, shape: &[i64]) -> anyhow::Result<Tensor> {
+        let up = self.up.to_kind(Kind::Float).flatten(1, -1);
+        let down = self.down.to_kind(Kind::Float).flatten(1, -1);
+        if up.size()[1] != down.size()[0] {
+            anyhow::bail!("mismatched ranks {:?} and {:?}", up.size(), down.size())
+        }
+        let delta = up.matmul(&down);
+        let numel: i64 = shape.iter().product();
+        if delta.numel() as i64 != numel {
+            anyhow::bail!("update of shape {:?} for a weight of shape {shape:?}", delta.size())
+        }
+        Ok(delta.reshape(shape) * self.scale())
+    }
+
+    /// The update to add to `weight`, as a float tensor. This is the scaled low-rank update
+    /// for LoRA layers, and depends on the value of the weight for DoRA layers.
+    pub fn update(&self, weight: &Tensor) -> anyhow::Result<Tensor> {
+        let shape = weight.size();
+        let delta = self.delta(&shape)?;
+        let dora_scale = match self.dora_scale.as_ref() {
+            None => return Ok(delta),
+            Some(dora_scale) => dora_scale.to_kind(Kind::Float).to_device(weight.device()),
+        };
+        let weight = weight.to_kind(Kind::Float);
+        let adapted = &weight + delta;
+        let scale_shape = dora_scale.size();
+        let axis = if scale_shape.len() == shape.len() && scale_shape[0] == shape[0] {
+            0
+        } else if scale_shape.len() == shape.len() && scale_shape[0] == 1 && shape.len() > 1 {
+            1
+        } else {
+            anyhow::bail!("dora_scale of shape {scale_shape:?} for a weight of shape {shape:?}")
+        };
+        if scale_shape.iter().enumerate().any(|(i, &d)| i != axis && d != 1) {
+            anyhow::bail!("dora_scale of shape {scale_shape:?} for a weight of shape {shape:?}")
+        }
+        let dims: Vec<i64> = (0..shape.len() as i64).filter(|&d| d != axis as i64).collect();
+        let norm = adapted.square().sum_dim_intlist(dims.as_slice(), true, Kind::Float).sqrt();
+        let adapted = adapted * dora_scale / (norm + f32::EPSILON as f64);
+        Ok(adapted - weight)
+    }
+}
+
+/// The layers adapted by a LoRA file, per component, indexed by their kohya-ss name
+/// without the component prefix.
+#[derive(Debug, Default)]
+pub struct Lora {
+    pub unet: HashMap<String, LoraModule>,
+    pub text_encoder: HashMap<String, LoraModule>,
+}
+
+impl Lora {
+    /// Reads a LoRA from a safetensors file.
+    pub fn read<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
+        Self::from_tensors(Tensor::read_safetensors(path)?)
+    }
+
+    pub fn from_tensors(tensors: Vec<(String, Tensor)>) -> anyhow::Result<Self> {
+        let mut ups = HashMap::new();
+        let mut downs = HashMap::new();
+        let mut alphas = HashMap::new();
+        let mut dora_scales = HashMap::new();
+        for (name, tensor) in tensors.into_iter() {
+            if let Some(module) = name.strip_suffix(".lora_up.weight") {
+                ups.insert(module.to_string(), tensor);
+            } else if let Some(module) = name.strip_suffix(".lora_down.weight") {
+                downs.insert(module.to_string(), tensor);
+            } else if let Some(module) = name.strip_suffix(".dora_scale") {
+                dora_scales.insert(module.to_string(), tensor);
+            } else if let Some(module) = name.strip_suffix(".alpha") {
+                if tensor.numel() != 1 {
+                    anyhow::bail!("alpha of {module} is not a scalar {:?}", tensor.size())
+                }
+                alphas.insert(module.to_string(), tensor.reshape([1]).double_value(&[0]));
+            } else {
+                anyhow::bail!("unexpected tensor {name} in LoRA")
+            }
+        }
+        let mut lora = Self::default();
+        for (module, up) in ups.into_iter() {
+            let down = match downs.remove(&module) {
+                Some(down) => down,
+                None => anyhow::bail!("missing lora_down weight for {module}"),
+            };
+            let alpha = alphas.remove(&module);
+            let dora_scale = dora_scales.remove(&module);
+            let lora_module = LoraModule { up, down, alpha, dora_scale };
+            if let Some(name) = module.strip_prefix(UNET_PREFIX) {
+                lora.unet.insert(name.to_string(), lora_module);
+            } else if let Some(name) = module.strip_prefix(TEXT_ENCODER_PREFIX) {
+                lora.text_encoder.insert(name.to_string(), lora_module);
+            } else {
+                anyhow::bail!("unsupported LoRA module {module}")
+            }
+        }
+        if let Some(module) = downs.keys().next() {
+            anyhow::bail!("missing lora_up weight for {module}")
+        }
+        if let Some(module) = dora_scales.keys().next() {
+            anyhow::bail!("dora_scale without lora weights for {module}")
+        }
+        Ok(lora)
+    }
+}
+
+/// Which components a LoRA gets applied to and how strongly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoraOptions {
+    /// The scale of the updates, 1 applies the LoRA as trained.
+    pub strength: f64,
+    /// Apply the `lora_unet_` layers.
+    pub apply_to_unet: bool,
+    /// Apply the `lora_te_` layers.
+    pub apply_to_text_encoder: bool,
+}
+
+impl Default for LoraOptions {
+    fn default() -> Self {
+        Self { strength: 1., apply_to_unet: true, apply_to_text_encoder: true }
+    }
+}
+
+// The variables of a var store indexed by their kohya-ss name, i.e. their path without
+// the `.weight` suffix and with underscores rather than dots.
+fn kohya_variables(vs: &nn::VarStore) -> HashMap<String, Tensor> {
+    let variables = vs.variables().into_iter();
+    variables
+        .filter_map(|(name, var)| Some((name.strip_suffix(".weight")?.replace('.', "_"), var)))
+        .collect()
+}
+
+/// Adds the updates of some LoRA layers, scaled by `strength`, to the weights of a var
+/// store. All the layers must match a weight of the var store, otherwise an error is
+/// returned before modifying any weight. Returns the number of updated weights.
+pub fn merge_lora(
+    vs: &nn::VarStore,
+    modules: &HashMap<String, LoraModule>,
+    strength: f64,
+) -> anyhow::Result<usize> {
+    let variables = kohya_variables(vs);
+    let mut missing: Vec<&str> =
+        modules.keys().filter(|m| !variables.contains_key(*m)).map(|m| m.as_str()).collect();
+    if !missing.is_empty() {
+        missing.sort();
+        anyhow::bail!("no weights matching the LoRA layers {}", missing.join(", "))
+    }
+    let updates = modules
+        .iter()
+        .map(|(name, module)| {
+            let var = &variables[name];
+            let delta = module.update(var).map_err(|e| e.context(name.clone()))?;
+            Ok((var, delta))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    tch::no_grad(|| {
+        for (var, delta) in updates.iter() {
+            let mut var = var.shallow_clone();
+            let _ = var.g_add_(&(delta * strength).to_kind(var.kind()).to_device(var.device()));
+        }
+    });
+    Ok(updates.len())
+}
+
+/// Reads a LoRA file and merges it in the UNet and text encoder var stores, depending on
+/// `options`. The layers of the components that are not selected are ignored.
+pub fn load_lora<P: AsRef<std::path::Path>>(
+    path: P,
+    unet_vs: &nn::VarStore,
+    text_encoder_vs: &nn::VarStore,
+    options: LoraOptions,
+) -> anyhow::Result<()> {
+    let lora = Lora::read(path)?;
+    if options.apply_to_unet {
+        merge_lora(unet_vs, &lora.unet, options.strength)?;
+    }
+    if options.apply_to_text_encoder {
+        merge_lora(text_encoder_vs, &lora.text_encoder, options.strength)?;
+    }
+    Ok(())
+}
+
+struct Adapter {
+    name: String,
+    strength: f64,
+    deltas: HashMap<String, Tensor>,
+}
+
+/// LoRAs applied to a var store with strengths that can be changed without reloading.
+///
+/// Rather than merging the updates once, the update of each layer at strength 1 is kept
+/// per LoRA, together with a copy of the base weights of the adapted layers. Changing the
+/// strength of a LoRA recomputes `base + sum(strength * delta)` for the layers adapted
+/// by this LoRA only, which is much faster than reloading the weights for strength
+/// sweeps. This requires memory for the deltas and base copies, about twice the size of
+/// the adapted weights.
+pub struct LoraAdapters {
+    variables: HashMap<String, Tensor>,
+    base: HashMap<String, Tensor>,
+    adapters: Vec<Adapter>,
+}
+
+impl std::fmt::Debug for LoraAdapters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let adapters: Vec<_> = self.adapters.iter().map(|a| (&a.name, a.strength)).collect();
+        f.debug_struct("LoraAdapters").field("adapters", &adapters).finish()
+    }
+}
+
+impl LoraAdapters {
+    /// Tracks the weights of a var store, these are updated in place so the models built
+    /// on this var store see the LoRAs.
+    pub fn new(vs: &nn::VarStore) -> Self {
+        Self { variables: kohya_variables(vs), base: HashMap::new(), adapters: vec![] }
+    }
+
+    /// Adds the LoRA layers `modules` under `name` and applies them with `strength`.
+    pub fn add_lora(
+        &mut self,
+        name: &str,
+        modules: &HashMap<String, LoraModule>,
+        strength: f64,
+    ) -> anyhow::Result<()> {
+        if self.adapters.iter().any(|a| a.name == name) {
+            anyhow::bail!("LoRA {name} has already been added")
+        }
+        let mut deltas = HashMap::new();
+        for (module_name, module) in modules.iter() {
+            let var = match self.variables.get(module_name) {
+                Some(var) => var,
+                None => anyhow::bail!("no weight matching the LoRA layer {module_name}"),
+            };
+            // DoRA updates depend on the weight, these are computed from the base weight
+            // rather than from the weight adapted by the other LoRAs.
+            let base = self.base.get(module_name).unwrap_or(var);
+            let delta = module.update(base).map_err(|e| e.context(module_name.clone()))?;
+            deltas.insert(module_name.clone(), delta.to_kind(var.kind()).to_device(var.device()));
+        }
+        for module_name in deltas.keys() {
+            if !self.base.contains_key(module_name) {
+                let base = tch::no_grad(|| self.variables[module_name].copy());
+                self.base.insert(module_name.clone(), base);
+            }
+        }
+        self.adapters.push(Adapter { name: name.to_string(), strength, deltas });
+        self.update(self.adapters.len() - 1);
+        Ok(())
+    }
+
+    /// The names and strengths of the LoRAs, in the order they were added.
+    pub fn loras(&self) -> Vec<(&str, f64)> {
+        self.adapters.iter().map(|a| (a.name.as_str(), a.strength)).collect()
+    }
+
+    /// The strength of a LoRA, `None` if there is no LoRA with this name.
+    pub fn lora_strength(&self, name: &str) -> Option<f64> {
+        self.adapters.iter().find(|a| a.name == name).map(|a| a.strength)
+    }
+
+    /// Changes the strength of a LoRA, 0 disables it.
+    pub fn set_lora_strength(&mut self, name: &str, strength: f64) -> anyhow::Result<()> {
+        let index = match self.adapters.iter().position(|a| a.name == name) {
+            Some(index) => index,
+            None => anyhow::bail!("unknown LoRA {name}"),
+        };
+        self.adapters[index].strength = strength;
+        self.update(index);
+        Ok(())
+    }
+
+    /// Removes a LoRA, the weights get back to their value without it.
+    pub fn remove_lora(&mut self, name: &str) -> anyhow::Result<()> {
+        self.set_lora_strength(name, 0.)?;
+        self.adapters.retain(|a| a.name != name);
+        Ok(())
+    }
+
+    // Recomputes the weights of the layers adapted by the LoRA at `index`.
+    fn update(&mut self, index: usize) {
+        tch::no_grad(|| {
+            for module_name in self.adapters[index].deltas.keys() {
+                let mut weight = self.base[module_name].to_kind(Kind::Float);
+                for adapter in self.adapters.iter() {
+                    if let Some(delta) = adapter.deltas.get(module_name) {
+                        weight += delta.to_kind(Kind::Float) * adapter.strength;
+                    }
+                }
+                let mut var = self.variables[module_name].shallow_clone();
+                var.copy_(&weight.to_kind(var.kind()))
+            }
+        })
+    }
+}
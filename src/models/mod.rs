@@ -3,9 +3,14 @@
 //! A collection of models to be used in a diffusion loop.
 
 pub mod attention;
+pub mod consistency_decoder;
 pub mod controlnet;
 pub mod embeddings;
+pub mod lora;
+mod params;
+pub mod quantize;
 pub mod resnet;
+pub mod t2i_adapter;
 pub mod unet_2d;
 pub mod unet_2d_blocks;
 pub mod vae;
@@ -0,0 +1,81 @@
+//! Parameter counts computed from the model configurations, without building the
+//! models. These mirror the layer layouts used in the model constructors.
+use super::unet_2d::BlockConfig;
+
+pub(crate) fn conv2d(in_channels: i64, out_channels: i64, kernel_size: i64) -> i64 {
+    in_channels * out_channels * kernel_size * kernel_size + out_channels
+}
+
+pub(crate) fn linear(in_dim: i64, out_dim: i64) -> i64 {
+    in_dim * out_dim + out_dim
+}
+
+pub(crate) fn norm(channels: i64) -> i64 {
+    2 * channels
+}
+
+pub(crate) fn resnet(in_channels: i64, out_channels: i64, temb_channels: Option<i64>) -> i64 {
+    let shortcut =
+        if in_channels != out_channels { conv2d(in_channels, out_channels, 1) } else { 0 };
+    norm(in_channels)
+        + conv2d(in_channels, out_channels, 3)
+        + norm(out_channels)
+        + conv2d(out_channels, out_channels, 3)
+        + shortcut
+        + temb_channels.map_or(0, |temb| linear(temb, out_channels))
+}
+
+// A spatial transformer with `depth` transformer blocks where the inner dimension matches
+// the number of channels, the projections have the same parameter count in the conv and
+// linear modes.
+pub(crate) fn spatial_transformer(channels: i64, context_dim: i64, depth: i64) -> i64 {
+    let c = channels;
+    let attn1 = 3 * c * c + linear(c, c);
+    let attn2 = c * c + 2 * context_dim * c + linear(c, c);
+    let ff = linear(c, 8 * c) + linear(4 * c, c);
+    let block = attn1 + attn2 + ff + 3 * norm(c);
+    norm(c) + linear(c, c) + depth * block + linear(c, c)
+}
+
+pub(crate) fn attention_block(channels: i64) -> i64 {
+    norm(channels) + 4 * linear(channels, channels)
+}
+
+// The time embedding, input convolution, and down blocks, these are shared between the
+// UNet and the ControlNet.
+pub(crate) fn unet_encoder(
+    in_channels: i64,
+    blocks: &[BlockConfig],
+    layers_per_block: i64,
+    cross_attention_dim: i64,
+) -> i64 {
+    let b_channels = blocks[0].out_channels;
+    let temb = Some(4 * b_channels);
+    let mut n = linear(b_channels, 4 * b_channels) + linear(4 * b_channels, 4 * b_channels);
+    n += conv2d(in_channels, b_channels, 3);
+    for (i, block) in blocks.iter().enumerate() {
+        let out_channels = block.out_channels;
+        let mut in_channels = if i > 0 { blocks[i - 1].out_channels } else { b_channels };
+        for _ in 0..layers_per_block {
+            n += resnet(in_channels, out_channels, temb);
+            if block.use_cross_attn {
+                let cross_attention_dim = block.cross_attention_dim.unwrap_or(cross_attention_dim);
+                let depth = block.transformer_layers_per_block;
+                n += spatial_transformer(out_channels, cross_attention_dim, depth)
+            }
+            in_channels = out_channels
+        }
+        if i + 1 < blocks.len() {
+            n += conv2d(out_channels, out_channels, 3)
+        }
+    }
+    let last_block = blocks.last().unwrap();
+    let bl_channels = last_block.out_channels;
+    let mid_cross_attention_dim = last_block.cross_attention_dim.unwrap_or(cross_attention_dim);
+    n + 2 * resnet(bl_channels, bl_channels, temb)
+        + spatial_transformer(
+            bl_channels,
+            mid_cross_attention_dim,
+            last_block.transformer_layers_per_block,
+        )
+}
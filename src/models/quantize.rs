@@ -0,0 +1,208 @@
+//! # Int8 Weight Quantization
+//!
+//! Per-channel symmetric int8 quantization of the weights of the linear and
+//! convolution layers. Each output channel gets its own scale, the absolute maximum
+//! of the channel weights divided by 127, and the weights are rounded to the nearest
+//! multiple of this scale.
+//!
+//! `UNet2DConditionModel::quantize_int8` runs the linear layers of the attention blocks
+//! with int8 weights on the CPU, the matrix multiplications using the int8 fbgemm kernels
+//! of libtorch with the activations quantized on the fly. This reduces the memory used
+//! by these weights by a factor of four and speeds up the largest matrix multiplications.
+//! Libtorch has no such kernels for the convolutions used by the models of this crate,
+//! so they keep their float weights.
+//!
+//! The other helpers only simulate the quantization or serialize quantized weights:
+//! `simulate_int8` rounds the weights of a var store in place, convolutions included, to
+//! evaluate the quality loss, and `save_int8` stores the weights as int8 tensors together
+//! with their scales, which makes the files four times smaller. `load_int8` dequantizes
+//! these weights back to float so neither the inference speed nor the memory use change.
+//!
+//! The rounding error is small compared to the weights themselves, so that the images
+//! generated with a quantized UNet remain close to the original ones, but fine details
+//! and textures change and the gap gets larger with fewer inference steps. Norm layers
+//! and biases are left untouched as they are small and more sensitive to rounding.
+use std::collections::HashMap;
+use tch::{nn, nn::Module, Device, Kind, Scalar, Tensor};
+
+/// A weight tensor quantized per output channel, the weight is `values * scales`.
+#[derive(Debug)]
+pub struct QuantizedTensor {
+    /// The int8 values, with the shape of the original weight.
+    pub values: Tensor,
+    /// The float32 scale of each output channel, with shape `[out_channels, 1, ...]`.
+    pub scales: Tensor,
+}
+
+impl QuantizedTensor {
+    /// Quantizes a weight whose first dimension is the output channel one.
+    pub fn quantize(weight: &Tensor) -> Self {
+        let weight = weight.to_kind(Kind::Float);
+        let dims: Vec<i64> = (1..weight.dim() as i64).collect();
+        let abs_max = weight.abs().amax(dims.as_slice(), true);
+        let scales = (abs_max / 127.).clamp_min(f32::MIN_POSITIVE as f64);
+        let values = (&weight / &scales).round().clamp(-127., 127.).to_kind(Kind::Int8);
+        Self { values, scales }
+    }
+
+    /// Returns the weight as a float32 tensor.
+    pub fn dequantize(&self) -> Tensor {
+        self.values.to_kind(Kind::Float) * &self.scales
+    }
+}
+
+// Linear and convolution weights have at least two dimensions, biases and norm
+// parameters only have one.
+fn is_quantizable(name: &str, tensor: &Tensor) -> bool {
+    name.ends_with("weight") && tensor.dim() >= 2
+}
+
+/// Quantizes the linear and convolution weights of a var store, the returned map uses
+/// the var store names.
+pub fn quantize_var_store(vs: &nn::VarStore) -> HashMap<String, QuantizedTensor> {
+    tch::no_grad(|| {
+        let variables = vs.variables().into_iter();
+        let variables = variables.filter(|(name, tensor)| is_quantizable(name, tensor));
+        variables.map(|(name, tensor)| (name, QuantizedTensor::quantize(&tensor))).collect()
+    })
+}
+
+/// Rounds in place the linear and convolution weights of a var store to their int8
+/// quantized values, the weights keep their kind and device. This gives the same outputs
+/// as a model loaded from quantized weights and can be used to evaluate the quality loss,
+/// the computations still use the float weights. Returns the number of quantized weights.
+pub fn simulate_int8(vs: &nn::VarStore) -> usize {
+    let quantized = quantize_var_store(vs);
+    tch::no_grad(|| {
+        for (name, mut var) in vs.variables() {
+            if let Some(q) = quantized.get(&name) {
+                var.copy_(&q.dequantize().to_kind(var.kind()))
+            }
+        }
+    });
+    quantized.len()
+}
+
+/// Saves the quantized weights of a var store in the safetensors format. Each quantized
+/// weight `name` is saved as int8 values `name` and float32 scales `name.int8_scales`,
+/// the other variables are saved unchanged.
+pub fn save_int8<P: AsRef<std::path::Path>>(vs: &nn::VarStore, path: P) -> anyhow::Result<()> {
+    let quantized = quantize_var_store(vs);
+    let mut tensors = vec![];
+    for (name, var) in vs.variables() {
+        match quantized.get(&name) {
+            None => tensors.push((name, var.to_device(tch::Device::Cpu))),
+            Some(q) => {
+                tensors.push((format!("{name}.int8_scales"), q.scales.to_device(tch::Device::Cpu)));
+                tensors.push((name, q.values.to_device(tch::Device::Cpu)));
+            }
+        }
+    }
+    Tensor::write_safetensors(&tensors, path)?;
+    Ok(())
+}
+
+/// Loads weights saved with `save_int8` in a var store, dequantizing them. The model then
+/// uses float weights, as with `simulate_int8`.
+pub fn load_int8<P: AsRef<std::path::Path>>(vs: &nn::VarStore, path: P) -> anyhow::Result<()> {
+    let mut tensors: HashMap<String, Tensor> =
+        Tensor::read_safetensors(path)?.into_iter().collect();
+    let mut dequantized = HashMap::new();
+    for (name, values) in tensors.iter() {
+        if let Some(scales) = tensors.get(&format!("{name}.int8_scales")) {
+            let q =
+                QuantizedTensor { values: values.shallow_clone(), scales: scales.shallow_clone() };
+            dequantized.insert(name.clone(), q.dequantize());
+        }
+    }
+    tensors.extend(dequantized);
+    for (name, var) in vs.variables() {
+        if let Some(tensor) = tensors.get_mut(&name) {
+            *tensor = tensor.to_kind(var.kind());
+        }
+    }
+    crate::checkpoint::load_var_store(vs, &tensors)
+}
+
+/// A linear layer with int8 weights, the matrix multiplication uses the fbgemm kernels of
+/// libtorch and is only available on the CPU. The inputs are quantized to uint8 on each
+/// call with a single scale, the weights are quantized per output channel.
+#[derive(Debug)]
+pub struct Int8Linear {
+    values: Tensor,
+    packed: Tensor,
+    col_offsets: Tensor,
+    scales: Tensor,
+    zeros: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl Int8Linear {
+    /// Quantizes the weight of a linear layer, this fails if the layer is not on the CPU
+    /// or if libtorch has not been built with fbgemm.
+    pub fn new(linear: &nn::Linear) -> anyhow::Result<Self> {
+        if linear.ws.device() != Device::Cpu {
+            anyhow::bail!("int8 linear layers are only supported on the cpu")
+        }
+        tch::no_grad(|| {
+            let QuantizedTensor { values, scales } = QuantizedTensor::quantize(&linear.ws);
+            let values = values.contiguous();
+            let packed = values.f_fbgemm_pack_quantized_matrix()?;
+            // The weights use no zero point so the column offsets are the row sums.
+            let col_offsets = values.f_sum_dim_intlist(1, false, Kind::Int)?;
+            let scales = scales.view([-1]);
+            let zeros = scales.zeros_like();
+            let bias = linear.bs.as_ref().map(|bs| bs.to_kind(Kind::Float));
+            Ok(Self { values, packed, col_offsets, scales, zeros, bias })
+        })
+    }
+}
+
+impl Module for Int8Linear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        // The kernel only supports a single weight scale, it runs with a unit scale and no
+        // bias, the channel scales and the bias being applied to its output.
+        let ys = xs.to_kind(Kind::Float).fbgemm_linear_int8_weight_fp32_activation(
+            &self.values,
+            &self.packed,
+            &self.col_offsets,
+            Scalar::float(1.),
+            Scalar::int(0),
+            &self.zeros,
+        );
+        let ys = ys * &self.scales;
+        let ys = match &self.bias {
+            None => ys,
+            Some(bias) => ys + bias,
+        };
+        ys.to_kind(xs.kind())
+    }
+}
+
+/// A linear layer that can be switched to int8 weights, see `Int8Linear`.
+#[derive(Debug)]
+pub(crate) struct Linear {
+    linear: nn::Linear,
+    int8: Option<Int8Linear>,
+}
+
+impl Linear {
+    pub(crate) fn new(vs: nn::Path, in_dim: i64, out_dim: i64, c: nn::LinearConfig) -> Self {
+        Self { linear: nn::linear(vs, in_dim, out_dim, c), int8: None }
+    }
+
+    /// Uses int8 weights from now on, later changes of the var store weights are ignored.
+    pub(crate) fn quantize_int8(&mut self) -> anyhow::Result<()> {
+        self.int8 = Some(Int8Linear::new(&self.linear)?);
+        Ok(())
+    }
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        match &self.int8 {
+            None => xs.apply(&self.linear),
+            Some(int8) => xs.apply(int8),
+        }
+    }
+}
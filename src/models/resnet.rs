@@ -6,6 +6,27 @@
 //! https://arxiv.org/abs/1512.03385
 use tch::{nn, Tensor};
 
+/// How the time embedding is combined with the features of a ResNet block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeEmbeddingNorm {
+    /// The projected embedding is added to the features before the second normalization.
+    #[default]
+    Default,
+    /// The projected embedding gives a scale and a shift applied to the features after
+    /// the second normalization.
+    ScaleShift,
+}
+
+/// A resampling of the features applied within a ResNet block, after the first
+/// normalization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resample {
+    /// Nearest neighbor upsampling by a factor 2.
+    Up,
+    /// Average pooling by a factor 2.
+    Down,
+}
+
 /// Configuration for a ResNet block.
 #[derive(Debug, Clone, Copy)]
 pub struct ResnetBlock2DConfig {
@@ -24,6 +45,9 @@ pub struct ResnetBlock2DConfig {
     // non_linearity: silu
     /// The final output is scaled by dividing by this value.
     pub output_scale_factor: f64,
+    pub time_embedding_norm: TimeEmbeddingNorm,
+    /// Resamples both the features and the skip connection when set.
+    pub resample: Option<Resample>,
 }
 
 impl Default for ResnetBlock2DConfig {
@@ -36,10 +60,23 @@ impl Default for ResnetBlock2DConfig {
             eps: 1e-6,
             use_in_shortcut: None,
             output_scale_factor: 1.,
+            time_embedding_norm: TimeEmbeddingNorm::Default,
+            resample: None,
         }
     }
 }
 
+/// Applies a 3x3 convolution with stride 1 and padding 1, the padding wraps around the
+/// borders rather than using zeros when `circular` is set.
+pub(crate) fn conv3x3(xs: &Tensor, conv: &nn::Conv2D, circular: bool) -> Tensor {
+    if circular {
+        let xs = xs.pad([1, 1, 1, 1], "circular", None);
+        xs.conv2d(&conv.ws, conv.bs.as_ref(), [1, 1], [0, 0], [1, 1], 1)
+    } else {
+        xs.apply(conv)
+    }
+}
+
 #[derive(Debug)]
 pub struct ResnetBlock2D {
     norm1: nn::GroupNorm,
@@ -48,6 +85,7 @@ pub struct ResnetBlock2D {
     conv2: nn::Conv2D,
     time_emb_proj: Option<nn::Linear>,
     conv_shortcut: Option<nn::Conv2D>,
+    circular_padding: bool,
     config: ResnetBlock2DConfig,
 }
 
@@ -68,25 +106,63 @@ impl ResnetBlock2D {
         } else {
             None
         };
+        let time_emb_out_channels = match config.time_embedding_norm {
+            TimeEmbeddingNorm::Default => out_channels,
+            TimeEmbeddingNorm::ScaleShift => 2 * out_channels,
+        };
         let time_emb_proj = config.temb_channels.map(|temb_channels| {
-            nn::linear(&vs / "time_emb_proj", temb_channels, out_channels, Default::default())
+            let vs = &vs / "time_emb_proj";
+            nn::linear(vs, temb_channels, time_emb_out_channels, Default::default())
         });
-        Self { norm1, conv1, norm2, conv2, time_emb_proj, config, conv_shortcut }
+        Self {
+            norm1,
+            conv1,
+            norm2,
+            conv2,
+            time_emb_proj,
+            config,
+            conv_shortcut,
+            circular_padding: false,
+        }
+    }
+
+    /// Uses circular rather than zero padding in the convolutions, so that an output
+    /// that tiles seamlessly can be generated.
+    pub fn set_circular_padding(&mut self, circular: bool) {
+        self.circular_padding = circular
     }
 
     pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Tensor {
-        let shortcut_xs = match &self.conv_shortcut {
-            Some(conv_shortcut) => xs.apply(conv_shortcut),
+        let resample = |xs: &Tensor| match self.config.resample {
             None => xs.shallow_clone(),
+            Some(Resample::Up) => {
+                let (_, _, h, w) = xs.size4().unwrap();
+                xs.upsample_nearest2d([2 * h, 2 * w], Some(2.), Some(2.))
+            }
+            Some(Resample::Down) => xs.avg_pool2d([2, 2], [2, 2], [0, 0], false, true, None),
         };
-        let xs = xs.apply(&self.norm1).silu().apply(&self.conv1);
-        let xs = match (temb, &self.time_emb_proj) {
+        let shortcut_xs = resample(xs);
+        let shortcut_xs = match &self.conv_shortcut {
+            Some(conv_shortcut) => shortcut_xs.apply(conv_shortcut),
+            None => shortcut_xs,
+        };
+        let xs = resample(&xs.apply(&self.norm1).silu());
+        let xs = conv3x3(&xs, &self.conv1, self.circular_padding);
+        let temb = match (temb, &self.time_emb_proj) {
             (Some(temb), Some(time_emb_proj)) => {
-                temb.silu().apply(time_emb_proj).unsqueeze(-1).unsqueeze(-1) + xs
+                Some(temb.silu().apply(time_emb_proj).unsqueeze(-1).unsqueeze(-1))
+            }
+            _ => None,
+        };
+        let xs = match (temb, self.config.time_embedding_norm) {
+            (None, _) => xs.apply(&self.norm2),
+            (Some(temb), TimeEmbeddingNorm::Default) => (temb + xs).apply(&self.norm2),
+            (Some(temb), TimeEmbeddingNorm::ScaleShift) => {
+                let chunks = temb.chunk(2, 1);
+                xs.apply(&self.norm2) * (&chunks[0] + 1.) + &chunks[1]
             }
-            _ => xs,
         };
-        let xs = xs.apply(&self.norm2).silu().apply(&self.conv2);
+        let xs = conv3x3(&xs.silu(), &self.conv2, self.circular_padding);
         (shortcut_xs + xs) / self.config.output_scale_factor
     }
 }
@@ -0,0 +1,152 @@
+//! T2I-Adapter
+//!
+//! T2I-Adapters are small convolutional networks extracting features from a
+//! conditioning image, e.g. a sketch or a depth map. These features are added to
+//! the outputs of the UNet down blocks, which makes for a much lighter alternative
+//! to ControlNet.
+//!
+//! https://arxiv.org/abs/2302.08453
+// https://github.com/huggingface/diffusers/blob/main/src/diffusers/models/adapter.py
+use tch::{nn, nn::Module, Tensor};
+
+#[derive(Debug, Clone)]
+pub struct T2IAdapterConfig {
+    pub in_channels: i64,
+    pub channels: Vec<i64>,
+    pub num_res_blocks: i64,
+    pub downscale_factor: i64,
+}
+
+impl Default for T2IAdapterConfig {
+    // https://huggingface.co/TencentARC/t2iadapter_canny_sd15v2/blob/main/config.json
+    fn default() -> Self {
+        Self {
+            in_channels: 3,
+            channels: vec![320, 640, 1280, 1280],
+            num_res_blocks: 2,
+            downscale_factor: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AdapterResnetBlock {
+    block1: nn::Conv2D,
+    block2: nn::Conv2D,
+}
+
+impl AdapterResnetBlock {
+    fn new(vs: nn::Path, channels: i64) -> Self {
+        let conv_cfg = nn::ConvConfig { padding: 1, ..Default::default() };
+        let block1 = nn::conv2d(&vs / "block1", channels, channels, 3, conv_cfg);
+        let block2 = nn::conv2d(&vs / "block2", channels, channels, 1, Default::default());
+        Self { block1, block2 }
+    }
+}
+
+impl Module for AdapterResnetBlock {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        xs + xs.apply(&self.block1).relu().apply(&self.block2)
+    }
+}
+
+#[derive(Debug)]
+struct AdapterBlock {
+    in_conv: Option<nn::Conv2D>,
+    resnets: Vec<AdapterResnetBlock>,
+    add_downsample: bool,
+}
+
+impl AdapterBlock {
+    fn new(
+        vs: nn::Path,
+        in_channels: i64,
+        out_channels: i64,
+        num_res_blocks: i64,
+        add_downsample: bool,
+    ) -> Self {
+        let in_conv = if in_channels != out_channels {
+            let conv =
+                nn::conv2d(&vs / "in_conv", in_channels, out_channels, 1, Default::default());
+            Some(conv)
+        } else {
+            None
+        };
+        let vs_r = &vs / "resnets";
+        let resnets =
+            (0..num_res_blocks).map(|i| AdapterResnetBlock::new(&vs_r / i, out_channels)).collect();
+        Self { in_conv, resnets, add_downsample }
+    }
+}
+
+impl Module for AdapterBlock {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        let xs = if self.add_downsample {
+            xs.avg_pool2d([2, 2], [2, 2], [0, 0], true, true, None)
+        } else {
+            xs.shallow_clone()
+        };
+        let mut xs = match &self.in_conv {
+            Some(in_conv) => xs.apply(in_conv),
+            None => xs,
+        };
+        for resnet in self.resnets.iter() {
+            xs = xs.apply(resnet)
+        }
+        xs
+    }
+}
+
+/// The "full" adapter architecture used by the stable diffusion 1.x adapters.
+#[derive(Debug)]
+pub struct T2IAdapter {
+    conv_in: nn::Conv2D,
+    body: Vec<AdapterBlock>,
+    pub config: T2IAdapterConfig,
+}
+
+// SAFETY: the adapter is a stack of convolutions with no state besides their weights,
+// which `forward` only reads, and its config.
+unsafe impl Sync for T2IAdapter {}
+
+impl T2IAdapter {
+    pub fn new(vs: nn::Path, config: T2IAdapterConfig) -> Self {
+        let vs = &vs / "adapter";
+        let in_channels = config.in_channels * config.downscale_factor * config.downscale_factor;
+        let conv_cfg = nn::ConvConfig { padding: 1, ..Default::default() };
+        let conv_in = nn::conv2d(&vs / "conv_in", in_channels, config.channels[0], 3, conv_cfg);
+        let vs_b = &vs / "body";
+        let body = config
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, &out_channels)| {
+                let in_channels = if i > 0 { config.channels[i - 1] } else { out_channels };
+                AdapterBlock::new(
+                    &vs_b / i,
+                    in_channels,
+                    out_channels,
+                    config.num_res_blocks,
+                    i > 0,
+                )
+            })
+            .collect();
+        Self { conv_in, body, config }
+    }
+
+    /// Returns the feature maps to be added to the output of each UNet down block,
+    /// multiplied by the adapter `conditioning_scale`.
+    ///
+    /// The conditioning image should have a shape `[batch, in_channels, height, width]`
+    /// with the same height and width as the generated image. When using classifier free
+    /// guidance, the batch dimension should match the one of the UNet input.
+    pub fn forward(&self, xs: &Tensor, conditioning_scale: f64) -> Vec<Tensor> {
+        let mut xs = xs.pixel_unshuffle(self.config.downscale_factor).apply(&self.conv_in);
+        let mut features = Vec::with_capacity(self.body.len());
+        for block in self.body.iter() {
+            xs = xs.apply(block);
+            features.push(&xs * conditioning_scale);
+        }
+        features
+    }
+}
@@ -2,18 +2,38 @@
 //!
 //! The 2D Unet models take as input a noisy sample and the current diffusion
 //! timestep and return a denoised version of the input.
-use crate::models::embeddings::{TimestepEmbedding, Timesteps};
+use crate::models::attention::{AttentionProcessor, RegionalAttention, SpatialTransformer};
+use crate::models::embeddings::{
+    embed_timesteps, AddedCond, TextTimeEmbedding, TextTimeEmbeddingConfig, TimestepEmbedding,
+    Timesteps,
+};
+use crate::models::params;
 use crate::models::unet_2d_blocks::*;
+use crate::utils::{tensor_bytes, JsonConfig, MemoryReport};
+use std::sync::Arc;
 use tch::{nn, Kind, Tensor};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BlockConfig {
     pub out_channels: i64,
     pub use_cross_attn: bool,
     pub attention_head_dim: i64,
+    /// The dimension of the encoder hidden states attended to by the cross-attention
+    /// layers of this block, the model `cross_attention_dim` is used when not set.
+    pub cross_attention_dim: Option<i64>,
+    /// The number of transformer blocks in each spatial transformer of this block, e.g.
+    /// 2 and 10 for the two cross-attention blocks of SDXL.
+    #[serde(default = "default_transformer_layers_per_block")]
+    pub transformer_layers_per_block: i64,
 }
 
-#[derive(Debug, Clone)]
+fn default_transformer_layers_per_block() -> i64 {
+    1
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UNet2DConditionModelConfig {
     pub center_input_sample: bool,
     pub flip_sin_to_cos: bool,
@@ -27,20 +47,26 @@ pub struct UNet2DConditionModelConfig {
     pub cross_attention_dim: i64,
     pub sliced_attention_size: Option<i64>,
     pub use_linear_projection: bool,
+    /// The SDXL added conditioning on the pooled text embeddings and the size and crop
+    /// time ids, `addition_embed_type: "text_time"` in diffusers.
+    #[serde(default)]
+    pub text_time_embedding: Option<TextTimeEmbeddingConfig>,
 }
 
 impl Default for UNet2DConditionModelConfig {
     fn default() -> Self {
+        let bc = |out_channels, use_cross_attn| BlockConfig {
+            out_channels,
+            use_cross_attn,
+            attention_head_dim: 8,
+            cross_attention_dim: None,
+            transformer_layers_per_block: 1,
+        };
         Self {
             center_input_sample: false,
             flip_sin_to_cos: true,
             freq_shift: 0.,
-            blocks: vec![
-                BlockConfig { out_channels: 320, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 640, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 1280, use_cross_attn: true, attention_head_dim: 8 },
-                BlockConfig { out_channels: 1280, use_cross_attn: false, attention_head_dim: 8 },
-            ],
+            blocks: vec![bc(320, true), bc(640, true), bc(1280, true), bc(1280, false)],
             layers_per_block: 2,
             downsample_padding: 1,
             mid_block_scale_factor: 1.,
@@ -49,10 +75,238 @@ impl Default for UNet2DConditionModelConfig {
             cross_attention_dim: 1280,
             sliced_attention_size: None,
             use_linear_projection: false,
+            text_time_embedding: None,
+        }
+    }
+}
+
+impl UNet2DConditionModelConfig {
+    /// Checks that the configuration describes a valid UNet.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.blocks.is_empty() {
+            anyhow::bail!("the unet config should have at least one block")
+        }
+        if self.layers_per_block < 1 {
+            anyhow::bail!("invalid layers_per_block {}", self.layers_per_block)
+        }
+        for block in self.blocks.iter() {
+            let c = block.out_channels;
+            if c <= 0 || self.norm_num_groups <= 0 || c % self.norm_num_groups != 0 {
+                anyhow::bail!(
+                    "block channels {c} is not a multiple of norm_num_groups {}",
+                    self.norm_num_groups
+                )
+            }
+            if block.attention_head_dim <= 0 || c % block.attention_head_dim != 0 {
+                anyhow::bail!(
+                    "block channels {c} is not a multiple of attention_head_dim {}",
+                    block.attention_head_dim
+                )
+            }
+            if block.transformer_layers_per_block < 1 {
+                anyhow::bail!(
+                    "invalid transformer_layers_per_block {}",
+                    block.transformer_layers_per_block
+                )
+            }
+        }
+        if self.sliced_attention_size.is_some_and(|s| s < 0) {
+            anyhow::bail!("invalid sliced_attention_size {:?}", self.sliced_attention_size)
+        }
+        Ok(())
+    }
+
+    /// The cross-attention dimension of the block with the given index, the mid block
+    /// uses the one of the last block.
+    pub fn block_cross_attention_dim(&self, index: usize) -> i64 {
+        self.blocks[index].cross_attention_dim.unwrap_or(self.cross_attention_dim)
+    }
+
+    /// The number of parameters of a UNet built with this configuration, multiply it by
+    /// the element size of the weight kind to get the memory used by the weights.
+    pub fn num_parameters(&self, in_channels: i64, out_channels: i64) -> i64 {
+        let blocks = &self.blocks;
+        let n_blocks = blocks.len();
+        let b_channels = blocks[0].out_channels;
+        let bl_channels = blocks.last().unwrap().out_channels;
+        let temb = Some(4 * b_channels);
+        let mut n = params::unet_encoder(
+            in_channels,
+            blocks,
+            self.layers_per_block,
+            self.cross_attention_dim,
+        );
+        for i in 0..n_blocks {
+            let block = blocks[n_blocks - 1 - i];
+            let prev_out_channels =
+                if i > 0 { blocks[n_blocks - i].out_channels } else { bl_channels };
+            let skip_channels =
+                blocks[if i == n_blocks - 1 { 0 } else { n_blocks - i - 2 }].out_channels;
+            let n_layers = self.layers_per_block + 1;
+            for j in 0..n_layers {
+                let res_in = if j == 0 { prev_out_channels } else { block.out_channels };
+                let res_skip = if j == n_layers - 1 { skip_channels } else { block.out_channels };
+                n += params::resnet(res_in + res_skip, block.out_channels, temb);
+                if block.use_cross_attn {
+                    let cross_attention_dim = self.block_cross_attention_dim(n_blocks - 1 - i);
+                    let depth = block.transformer_layers_per_block;
+                    n += params::spatial_transformer(block.out_channels, cross_attention_dim, depth)
+                }
+            }
+            if i < n_blocks - 1 {
+                n += params::conv2d(block.out_channels, block.out_channels, 3)
+            }
+        }
+        if let Some(text_time) = self.text_time_embedding {
+            let input_dim = text_time.projection_class_embeddings_input_dim;
+            n += params::linear(input_dim, 4 * b_channels)
+                + params::linear(4 * b_channels, 4 * b_channels)
         }
+        n + params::norm(b_channels) + params::conv2d(b_channels, out_channels, 3)
+    }
+
+    /// A rough estimate of the peak activation memory in bytes for latents of size
+    /// `latent_height` by `latent_width`. This only accounts for the self-attention
+    /// scores and probabilities of the highest resolution attention layer, which dominate
+    /// the memory usage for large images; attention slicing reduces this term.
+    /// `batch_size` should include the classifier free guidance doubling.
+    pub fn estimate_activation_bytes(
+        &self,
+        latent_height: i64,
+        latent_width: i64,
+        batch_size: i64,
+        kind: Kind,
+    ) -> i64 {
+        let (mut height, mut width) = (latent_height, latent_width);
+        let mut peak = 0;
+        let n_blocks = self.blocks.len();
+        for (i, block) in self.blocks.iter().enumerate() {
+            // The mid block attention runs at the resolution of the last block.
+            if block.use_cross_attn || i + 1 == n_blocks {
+                let seq_len = height * width;
+                let n_heads = batch_size * block.attention_head_dim;
+                let n_heads = match self.sliced_attention_size {
+                    None => n_heads,
+                    Some(0) => n_heads.min(block.attention_head_dim / 2),
+                    Some(slice_size) => n_heads.min(slice_size),
+                };
+                peak = peak.max(2 * n_heads * seq_len * seq_len)
+            }
+            (height, width) = ((height + 1) / 2, (width + 1) / 2)
+        }
+        peak * kind.elt_size_in_bytes() as i64
     }
 }
 
+// Builds the block configs from the `block_out_channels`, `down_block_types`,
+// `attention_head_dim`, `cross_attention_dim`, and `transformer_layers_per_block` keys,
+// this is shared with the ControlNet config parsing. The per-block cross-attention dimensions are only set when
+// `cross_attention_dim` is a list.
+pub(crate) fn blocks_from_json(json: &JsonConfig) -> anyhow::Result<Vec<BlockConfig>> {
+    let block_out_channels = json.i64_list("block_out_channels")?;
+    let down_block_types = json.str_list("down_block_types")?;
+    if down_block_types.len() != block_out_channels.len() {
+        anyhow::bail!(
+            "mismatch between block_out_channels {block_out_channels:?} and down_block_types {down_block_types:?}"
+        )
+    }
+    let n_blocks = block_out_channels.len();
+    let attention_head_dim = json.i64_per_block("attention_head_dim", n_blocks, 8)?;
+    let transformer_layers_per_block =
+        json.i64_per_block("transformer_layers_per_block", n_blocks, 1)?;
+    let cross_attention_dim = match json.get("cross_attention_dim") {
+        Some(v) if v.is_array() => {
+            json.i64_per_block("cross_attention_dim", n_blocks, 0)?.into_iter().map(Some).collect()
+        }
+        _ => vec![None; n_blocks],
+    };
+    let mut blocks = vec![];
+    for (i, block_type) in down_block_types.iter().enumerate() {
+        let use_cross_attn = match block_type.as_str() {
+            "CrossAttnDownBlock2D" => true,
+            "DownBlock2D" => false,
+            _ => anyhow::bail!("unsupported down block type {block_type}"),
+        };
+        blocks.push(BlockConfig {
+            out_channels: block_out_channels[i],
+            use_cross_attn,
+            attention_head_dim: attention_head_dim[i],
+            cross_attention_dim: cross_attention_dim[i],
+            transformer_layers_per_block: transformer_layers_per_block[i],
+        })
+    }
+    Ok(blocks)
+}
+
+// The added conditioning of the `addition_embed_type` key, only the SDXL `text_time` one is
+// supported. This is shared with the ControlNet config parsing.
+pub(crate) fn text_time_embedding_from_json(
+    json: &JsonConfig,
+) -> anyhow::Result<Option<TextTimeEmbeddingConfig>> {
+    match json.get("addition_embed_type") {
+        None => Ok(None),
+        Some(_) => match json.str("addition_embed_type")?.as_str() {
+            "text_time" => Ok(Some(TextTimeEmbeddingConfig {
+                addition_time_embed_dim: json.i64("addition_time_embed_dim")?,
+                projection_class_embeddings_input_dim: json
+                    .i64("projection_class_embeddings_input_dim")?,
+            })),
+            embed_type => anyhow::bail!("unsupported addition_embed_type {embed_type}"),
+        },
+    }
+}
+
+// The model cross-attention dimension, the last value when given per block.
+pub(crate) fn cross_attention_dim_from_json(
+    json: &JsonConfig,
+    default: i64,
+) -> anyhow::Result<i64> {
+    match json.get("cross_attention_dim") {
+        Some(v) if v.is_array() => match json.i64_list("cross_attention_dim")?.last() {
+            Some(&v) => Ok(v),
+            None => anyhow::bail!("empty cross_attention_dim list"),
+        },
+        _ => json.i64_or("cross_attention_dim", default),
+    }
+}
+
+/// Reads a UNet configuration from the `config.json` file of a diffusers model, e.g.
+/// https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/unet/config.json
+///
+/// The `attention_head_dim`, `cross_attention_dim`, and `transformer_layers_per_block` values
+/// can either be a single value used by all the blocks or a value per block. Missing optional
+/// keys use the diffusers default values. The SDXL `text_time` added conditioning is
+/// supported, e.g. for
+/// https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/unet/config.json
+pub fn unet_config_from_json<P: AsRef<std::path::Path>>(
+    path: P,
+) -> anyhow::Result<UNet2DConditionModelConfig> {
+    let path = path.as_ref();
+    let json = JsonConfig::read(path)?;
+    let blocks = blocks_from_json(&json)?;
+    let default = UNet2DConditionModelConfig::default();
+    let config = UNet2DConditionModelConfig {
+        center_input_sample: json.bool_or("center_input_sample", default.center_input_sample)?,
+        flip_sin_to_cos: json.bool_or("flip_sin_to_cos", default.flip_sin_to_cos)?,
+        freq_shift: json.f64_or("freq_shift", default.freq_shift)?,
+        blocks,
+        layers_per_block: json.i64_or("layers_per_block", default.layers_per_block)?,
+        downsample_padding: json.i64_or("downsample_padding", default.downsample_padding)?,
+        mid_block_scale_factor: json
+            .f64_or("mid_block_scale_factor", default.mid_block_scale_factor)?,
+        norm_num_groups: json.i64_or("norm_num_groups", default.norm_num_groups)?,
+        norm_eps: json.f64_or("norm_eps", default.norm_eps)?,
+        cross_attention_dim: cross_attention_dim_from_json(&json, default.cross_attention_dim)?,
+        sliced_attention_size: None,
+        use_linear_projection: json
+            .bool_or("use_linear_projection", default.use_linear_projection)?,
+        text_time_embedding: text_time_embedding_from_json(&json)?,
+    };
+    // Inconsistent values would otherwise only panic when building the blocks.
+    config.validate().map_err(|e| e.context(format!("invalid unet config {path:?}")))?;
+    Ok(config)
+}
+
 #[derive(Debug)]
 pub(crate) enum UNetDownBlock {
     Basic(DownBlock2D),
@@ -70,6 +324,7 @@ pub struct UNet2DConditionModel {
     conv_in: nn::Conv2D,
     time_proj: Timesteps,
     time_embedding: TimestepEmbedding,
+    add_embedding: Option<TextTimeEmbedding>,
     down_blocks: Vec<UNetDownBlock>,
     mid_block: UNetMidBlock2DCrossAttn,
     up_blocks: Vec<UNetUpBlock>,
@@ -78,6 +333,16 @@ pub struct UNet2DConditionModel {
     config: UNet2DConditionModelConfig,
 }
 
+// SAFETY: `Tensor` is only `!Sync` as it wraps a raw libtorch pointer, and libtorch
+// supports reading a tensor from several threads. The tensors of the UNet are its
+// weights, including the int8 ones of the quantized linear layers, which the forward
+// passes only read, the intermediate values including the FreeU channel scales and
+// frequency masks are new tensors for each call. The rest is
+// plain values, e.g. the FreeU factors, the perturbed attention flags and the slice
+// sizes, and `Arc`s of regional attention regions or of attention processors, which
+// are `Sync` per the `AttentionProcessor` bounds. These are only set through `&mut self`.
+unsafe impl Sync for UNet2DConditionModel {}
+
 impl UNet2DConditionModel {
     pub fn new(
         vs: nn::Path,
@@ -97,12 +362,26 @@ impl UNet2DConditionModel {
             Timesteps::new(b_channels, config.flip_sin_to_cos, config.freq_shift, vs.device());
         let time_embedding =
             TimestepEmbedding::new(&vs / "time_embedding", b_channels, time_embed_dim);
+        let add_embedding = config.text_time_embedding.map(|text_time| {
+            TextTimeEmbedding::new(
+                &vs / "add_embedding",
+                text_time,
+                config.flip_sin_to_cos,
+                config.freq_shift,
+                time_embed_dim,
+            )
+        });
 
         let vs_db = &vs / "down_blocks";
         let down_blocks = (0..n_blocks)
             .map(|i| {
-                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } =
-                    config.blocks[i];
+                let BlockConfig {
+                    out_channels,
+                    use_cross_attn,
+                    attention_head_dim,
+                    transformer_layers_per_block,
+                    ..
+                } = config.blocks[i];
 
                 // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
                 let sliced_attention_size = match config.sliced_attention_size {
@@ -124,9 +403,10 @@ impl UNet2DConditionModel {
                     let config = CrossAttnDownBlock2DConfig {
                         downblock: db_cfg,
                         attn_num_head_channels: attention_head_dim,
-                        cross_attention_dim: config.cross_attention_dim,
+                        cross_attention_dim: config.block_cross_attention_dim(i),
                         sliced_attention_size,
                         use_linear_projection: config.use_linear_projection,
+                        transformer_layers_per_block,
                     };
                     let block = CrossAttnDownBlock2D::new(
                         &vs_db / i,
@@ -152,10 +432,11 @@ impl UNet2DConditionModel {
         let mid_cfg = UNetMidBlock2DCrossAttnConfig {
             resnet_eps: config.norm_eps,
             output_scale_factor: config.mid_block_scale_factor,
-            cross_attn_dim: config.cross_attention_dim,
+            cross_attn_dim: config.block_cross_attention_dim(n_blocks - 1),
             attn_num_head_channels: bl_attention_head_dim,
             resnet_groups: Some(config.norm_num_groups),
             use_linear_projection: config.use_linear_projection,
+            transformer_layers_per_block: config.blocks[n_blocks - 1].transformer_layers_per_block,
             ..Default::default()
         };
         let mid_block = UNetMidBlock2DCrossAttn::new(
@@ -168,8 +449,13 @@ impl UNet2DConditionModel {
         let vs_ub = &vs / "up_blocks";
         let up_blocks = (0..n_blocks)
             .map(|i| {
-                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } =
-                    config.blocks[n_blocks - 1 - i];
+                let BlockConfig {
+                    out_channels,
+                    use_cross_attn,
+                    attention_head_dim,
+                    transformer_layers_per_block,
+                    ..
+                } = config.blocks[n_blocks - 1 - i];
 
                 // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
                 let sliced_attention_size = match config.sliced_attention_size {
@@ -194,9 +480,10 @@ impl UNet2DConditionModel {
                     let config = CrossAttnUpBlock2DConfig {
                         upblock: ub_cfg,
                         attn_num_head_channels: attention_head_dim,
-                        cross_attention_dim: config.cross_attention_dim,
+                        cross_attention_dim: config.block_cross_attention_dim(n_blocks - 1 - i),
                         sliced_attention_size,
                         use_linear_projection: config.use_linear_projection,
+                        transformer_layers_per_block,
                     };
                     let block = CrossAttnUpBlock2D::new(
                         &vs_ub / i,
@@ -229,6 +516,7 @@ impl UNet2DConditionModel {
             conv_in,
             time_proj,
             time_embedding,
+            add_embedding,
             down_blocks,
             mid_block,
             up_blocks,
@@ -240,6 +528,129 @@ impl UNet2DConditionModel {
 }
 
 impl UNet2DConditionModel {
+    /// Enables FreeU, https://arxiv.org/abs/2309.11497
+    ///
+    /// The backbone features of the first two up blocks are scaled by `b1` and `b2`
+    /// and the low frequencies of their skip connections are scaled by `s1` and `s2`.
+    /// Suggested values for stable diffusion v1.5 are s1=0.9, s2=0.2, b1=1.5, b2=1.6,
+    /// and for v2.1 s1=0.9, s2=0.2, b1=1.4, b2=1.6.
+    pub fn enable_freeu(&mut self, s1: f64, s2: f64, b1: f64, b2: f64) {
+        let scales = [FreeUScale { backbone: b1, skip: s1 }, FreeUScale { backbone: b2, skip: s2 }];
+        for (i, up_block) in self.up_blocks.iter_mut().enumerate() {
+            let upblock = match up_block {
+                UNetUpBlock::Basic(b) => b,
+                UNetUpBlock::CrossAttn(b) => &mut b.upblock,
+            };
+            upblock.freeu = scales.get(i).copied()
+        }
+    }
+
+    /// Disables FreeU, this is the default.
+    pub fn disable_freeu(&mut self) {
+        for up_block in self.up_blocks.iter_mut() {
+            match up_block {
+                UNetUpBlock::Basic(b) => b.freeu = None,
+                UNetUpBlock::CrossAttn(b) => b.upblock.freeu = None,
+            }
+        }
+    }
+
+    /// The kind of the model weights, e.g. `Kind::Half` for a half precision UNet.
+    pub fn kind(&self) -> Kind {
+        self.conv_in.ws.kind()
+    }
+
+    /// Restricts the cross-attention of each prompt to a region of the image, see
+    /// `RegionalAttention`. The encoder hidden states passed to the forward pass should
+    /// then be the concatenation of the prompt embeddings along the sequence dimension.
+    /// Use `None` to go back to the standard cross-attention.
+    pub fn set_regional_attention(&mut self, regional: Option<RegionalAttention>) {
+        let regional = regional.map(Arc::new);
+        for attention in self.spatial_transformers_mut() {
+            attention.set_regional_attention(regional.clone())
+        }
+    }
+
+    fn spatial_transformers(&self) -> Vec<&SpatialTransformer> {
+        let mut attentions: Vec<&SpatialTransformer> = vec![];
+        for down_block in self.down_blocks.iter() {
+            if let UNetDownBlock::CrossAttn(b) = down_block {
+                attentions.extend(b.attentions())
+            }
+        }
+        attentions.extend(self.mid_block.attentions());
+        for up_block in self.up_blocks.iter() {
+            if let UNetUpBlock::CrossAttn(b) = up_block {
+                attentions.extend(b.attentions.iter())
+            }
+        }
+        attentions
+    }
+
+    fn spatial_transformers_mut(&mut self) -> Vec<&mut SpatialTransformer> {
+        let mut attentions: Vec<&mut SpatialTransformer> = vec![];
+        for down_block in self.down_blocks.iter_mut() {
+            if let UNetDownBlock::CrossAttn(b) = down_block {
+                attentions.extend(b.attentions_mut())
+            }
+        }
+        attentions.extend(self.mid_block.attentions_mut());
+        for up_block in self.up_blocks.iter_mut() {
+            if let UNetUpBlock::CrossAttn(b) = up_block {
+                attentions.extend(b.attentions.iter_mut())
+            }
+        }
+        attentions
+    }
+
+    /// The paths of all the attention layers, e.g. `up_blocks.1.attentions.0.transformer_blocks.0.attn1`
+    /// for a self-attention layer and `...attn2` for a cross-attention one. These are the
+    /// paths of the layer weights in the var store.
+    pub fn attention_paths(&self) -> Vec<String> {
+        self.spatial_transformers().iter().flat_map(|a| a.attention_paths()).collect()
+    }
+
+    /// Installs a custom attention processor on the attention layers whose path starts with
+    /// `prefix`, e.g. `mid_block` or `down_blocks.1.attentions.0`, see `attention_paths`
+    /// for the available paths. `None` restores the default processor.
+    ///
+    /// An error is returned if no attention layer matches the prefix.
+    pub fn set_attention_processor(
+        &mut self,
+        prefix: &str,
+        processor: Option<Arc<dyn AttentionProcessor>>,
+    ) -> anyhow::Result<usize> {
+        let mut matched = 0;
+        for attention in self.spatial_transformers_mut() {
+            matched += attention.set_attention_processor(prefix, processor.clone())
+        }
+        if matched == 0 {
+            anyhow::bail!("no attention layer matches {prefix}")
+        }
+        Ok(matched)
+    }
+
+    /// Enables or disables the perturbed self-attention used by perturbed attention
+    /// guidance, see `pipelines::guidance::guided_prediction_pag`. As in the reference
+    /// implementation, only the self-attention layers of the mid block get perturbed.
+    pub fn set_perturbed_self_attention(&mut self, perturbed: bool) {
+        for attention in self.mid_block.attentions_mut() {
+            attention.set_perturbed_self_attention(perturbed)
+        }
+    }
+
+    /// Runs the linear layers of the attention blocks with int8 weights, see
+    /// `models::quantize`. This only works on the CPU with a libtorch built with fbgemm,
+    /// the convolutions keep their float weights. The int8 weights are computed once, so
+    /// LoRA weights have to be merged before. Returns the number of quantized layers.
+    pub fn quantize_int8(&mut self) -> anyhow::Result<usize> {
+        let mut quantized = 0;
+        for attention in self.spatial_transformers_mut() {
+            quantized += attention.quantize_int8()?
+        }
+        Ok(quantized)
+    }
+
     pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor) -> Tensor {
         self.forward_with_additional_residuals(xs, timestep, encoder_hidden_states, None, None)
     }
@@ -252,48 +663,246 @@ impl UNet2DConditionModel {
         down_block_additional_residuals: Option<&[Tensor]>,
         mid_block_additional_residual: Option<&Tensor>,
     ) -> Tensor {
-        let (bsize, _channels, height, width) = xs.size4().unwrap();
+        self.forward_(
+            xs,
+            &Tensor::from(timestep),
+            encoder_hidden_states,
+            None,
+            down_block_additional_residuals,
+            mid_block_additional_residual,
+            None,
+            None,
+        )
+    }
+
+    /// Runs a UNet with the SDXL added conditioning, see `TextTimeEmbedding`. The
+    /// other forward passes panic for such a UNet.
+    pub fn forward_with_added_cond(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        added_cond: &AddedCond,
+    ) -> Tensor {
+        self.forward_with_added_cond_and_residuals(
+            xs,
+            timestep,
+            encoder_hidden_states,
+            added_cond,
+            None,
+            None,
+        )
+    }
+
+    /// Same as `forward_with_added_cond` with the ControlNet residuals of
+    /// `forward_with_additional_residuals`.
+    pub fn forward_with_added_cond_and_residuals(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        added_cond: &AddedCond,
+        down_block_additional_residuals: Option<&[Tensor]>,
+        mid_block_additional_residual: Option<&Tensor>,
+    ) -> Tensor {
+        self.forward_(
+            xs,
+            &Tensor::from(timestep),
+            encoder_hidden_states,
+            Some(added_cond),
+            down_block_additional_residuals,
+            mid_block_additional_residual,
+            None,
+            None,
+        )
+    }
+
+    /// Runs the UNet adding the T2I-Adapter features to the output of each down
+    /// block, see `T2IAdapter::forward`.
+    pub fn forward_with_adapter_residuals(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        adapter_residuals: &[Tensor],
+    ) -> Tensor {
+        self.forward_(
+            xs,
+            &Tensor::from(timestep),
+            encoder_hidden_states,
+            None,
+            None,
+            None,
+            Some(adapter_residuals),
+            None,
+        )
+    }
+
+    /// Runs the down blocks and the mid block, returns the skip connections passed to
+    /// the up blocks and the mid block output. These are the tensors the ControlNet
+    /// residuals get added to, so the residuals should have the same shapes.
+    pub fn skip_connections(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+    ) -> (Vec<Tensor>, Tensor) {
+        let timestep = Tensor::from(timestep);
+        let (xs, emb, down_block_res_xs) =
+            self.forward_down(xs, &timestep, encoder_hidden_states, None, None, None);
+        let xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states));
+        (down_block_res_xs, xs)
+    }
+
+    /// Same as `forward` but also records the size of the activations at each
+    /// block boundary in `report`. This is meant as a diagnostic tool.
+    pub fn forward_with_memory_report(
+        &self,
+        xs: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+        report: &mut MemoryReport,
+    ) -> Tensor {
+        let timestep = Tensor::from(timestep);
+        self.forward_(xs, &timestep, encoder_hidden_states, None, None, None, None, Some(report))
+    }
+
+    /// A forward pass that can be traced with `trace`, the timestep is a float tensor
+    /// of shape `[]` or `[batch]` rather than a constant so that it remains an input of
+    /// the traced graph.
+    pub fn forward_traceable(
+        &self,
+        xs: &Tensor,
+        timestep: &Tensor,
+        encoder_hidden_states: &Tensor,
+    ) -> Tensor {
+        self.forward_(xs, timestep, encoder_hidden_states, None, None, None, None, None)
+    }
+
+    /// Traces `forward_traceable` on some example inputs, the resulting module takes as
+    /// inputs the latents, the timestep, and the encoder hidden states, and can be saved
+    /// to be run without this crate, e.g. from Python. The shapes are fixed when tracing,
+    /// as are options such as sliced attention, so the traced module should then be used
+    /// with inputs of the same shapes as the example ones.
+    pub fn trace(
+        &self,
+        xs: &Tensor,
+        timestep: &Tensor,
+        encoder_hidden_states: &Tensor,
+    ) -> anyhow::Result<tch::CModule> {
+        let inputs =
+            [xs.shallow_clone(), timestep.shallow_clone(), encoder_hidden_states.shallow_clone()];
+        let module = tch::CModule::create_by_tracing(
+            "UNet2DConditionModel",
+            "forward",
+            &inputs,
+            &mut |inputs| vec![self.forward_traceable(&inputs[0], &inputs[1], &inputs[2])],
+        )?;
+        Ok(module)
+    }
+
+    // Runs the down blocks, returns their output, the time embeddings, and the skip
+    // connections.
+    fn forward_down(
+        &self,
+        xs: &Tensor,
+        timestep: &Tensor,
+        encoder_hidden_states: &Tensor,
+        added_cond: Option<&AddedCond>,
+        down_intrablock_residuals: Option<&[Tensor]>,
+        mut report: Option<&mut MemoryReport>,
+    ) -> (Tensor, Tensor, Vec<Tensor>) {
+        let bsize = xs.size()[0];
         let device = xs.device();
-        let n_blocks = self.config.blocks.len();
-        let num_upsamplers = n_blocks - 1;
-        let default_overall_up_factor = 2i64.pow(num_upsamplers as u32);
-        let forward_upsample_size =
-            height % default_overall_up_factor != 0 || width % default_overall_up_factor != 0;
         // 0. center input if necessary
         let xs = if self.config.center_input_sample { xs * 2.0 - 1.0 } else { xs.shallow_clone() };
         // 1. time
-        let emb = (Tensor::ones([bsize], (Kind::Float, device)) * timestep)
-            .apply(&self.time_proj)
-            .apply(&self.time_embedding);
+        let emb = embed_timesteps(&self.time_proj, &self.time_embedding, timestep, bsize, device);
+        let emb = match (&self.add_embedding, added_cond) {
+            (None, None) => emb,
+            (Some(add_embedding), Some(added_cond)) => emb + add_embedding.forward(added_cond),
+            (Some(_), None) => {
+                panic!("the unet uses the SDXL added conditioning, see forward_with_added_cond")
+            }
+            (None, Some(_)) => panic!("the unet does not use the SDXL added conditioning"),
+        };
         // 2. pre-process
         let xs = xs.apply(&self.conv_in);
+        if let Some(report) = report.as_mut() {
+            report.record("conv_in", &xs, tensor_bytes(&xs))
+        }
         // 3. down
         let mut down_block_res_xs = vec![xs.shallow_clone()];
         let mut xs = xs;
-        for down_block in self.down_blocks.iter() {
+        for (i, down_block) in self.down_blocks.iter().enumerate() {
+            let intrablock_residual = down_intrablock_residuals.and_then(|r| r.get(i));
             let (_xs, res_xs) = match down_block {
-                UNetDownBlock::Basic(b) => b.forward(&xs, Some(&emb)),
-                UNetDownBlock::CrossAttn(b) => {
-                    b.forward(&xs, Some(&emb), Some(encoder_hidden_states))
+                UNetDownBlock::Basic(b) => {
+                    let (xs, mut res_xs) = b.forward(&xs, Some(&emb));
+                    match intrablock_residual {
+                        None => (xs, res_xs),
+                        Some(residual) => {
+                            let xs = xs + residual;
+                            if let Some(last) = res_xs.last_mut() {
+                                *last = xs.shallow_clone()
+                            }
+                            (xs, res_xs)
+                        }
+                    }
                 }
+                UNetDownBlock::CrossAttn(b) => b.forward_with_additional_residual(
+                    &xs,
+                    Some(&emb),
+                    Some(encoder_hidden_states),
+                    intrablock_residual,
+                ),
             };
             down_block_res_xs.extend(res_xs);
             xs = _xs;
+            if let Some(report) = report.as_mut() {
+                let live_bytes = down_block_res_xs.iter().map(tensor_bytes).sum::<i64>();
+                report.record(format!("down_blocks.{i}"), &xs, live_bytes)
+            }
         }
+        (xs, emb, down_block_res_xs)
+    }
 
-        let new_down_block_res_xs =
-            if let Some(down_block_additional_residuals) = down_block_additional_residuals {
-                let mut v = vec![];
-                // A previous version of this code had a bug because of the addition being made
-                // in place via += hence modifying the input of the mid block.
-                for (i, residuals) in down_block_additional_residuals.iter().enumerate() {
-                    v.push(&down_block_res_xs[i] + residuals)
-                }
-                v
-            } else {
-                down_block_res_xs
-            };
-        let mut down_block_res_xs = new_down_block_res_xs;
+    fn forward_(
+        &self,
+        xs: &Tensor,
+        timestep: &Tensor,
+        encoder_hidden_states: &Tensor,
+        added_cond: Option<&AddedCond>,
+        down_block_additional_residuals: Option<&[Tensor]>,
+        mid_block_additional_residual: Option<&Tensor>,
+        down_intrablock_residuals: Option<&[Tensor]>,
+        mut report: Option<&mut MemoryReport>,
+    ) -> Tensor {
+        let (_bsize, _channels, height, width) = xs.size4().unwrap();
+        let n_blocks = self.config.blocks.len();
+        let num_upsamplers = n_blocks - 1;
+        let default_overall_up_factor = 2i64.pow(num_upsamplers as u32);
+        let forward_upsample_size =
+            height % default_overall_up_factor != 0 || width % default_overall_up_factor != 0;
+        let (xs, emb, down_block_res_xs) = self.forward_down(
+            xs,
+            timestep,
+            encoder_hidden_states,
+            added_cond,
+            down_intrablock_residuals,
+            report.as_deref_mut(),
+        );
+
+        // The skip connections are consumed so that they get released as the up blocks
+        // use them, rather than being kept alongside the ones with the residuals added.
+        let mut down_block_res_xs = match down_block_additional_residuals {
+            // A previous version of this code had a bug because of the addition being made
+            // in place via += hence modifying the input of the mid block.
+            Some(residuals) => {
+                down_block_res_xs.into_iter().zip(residuals.iter()).map(|(x, r)| x + r).collect()
+            }
+            None => down_block_res_xs,
+        };
 
         // 4. mid
         let xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states));
@@ -301,6 +910,10 @@ impl UNet2DConditionModel {
             None => xs,
             Some(m) => m + xs,
         };
+        if let Some(report) = report.as_mut() {
+            let live_bytes = down_block_res_xs.iter().map(tensor_bytes).sum::<i64>();
+            report.record("mid_block", &xs, live_bytes + tensor_bytes(&xs))
+        }
         // 5. up
         let mut xs = xs;
         let mut upsample_size = None;
@@ -320,6 +933,13 @@ impl UNet2DConditionModel {
                     b.forward(&xs, &res_xs, Some(&emb), upsample_size, Some(encoder_hidden_states))
                 }
             };
+            // The skip connections consumed by this block are released before the snapshot,
+            // only the ones left for the next up blocks are counted as live.
+            drop(res_xs);
+            if let Some(report) = report.as_mut() {
+                let live_bytes = down_block_res_xs.iter().map(tensor_bytes).sum::<i64>();
+                report.record(format!("up_blocks.{i}"), &xs, live_bytes + tensor_bytes(&xs))
+            }
         }
         // 6. post-process
         xs.apply(&self.conv_norm_out).silu().apply(&self.conv_out)
@@ -3,8 +3,8 @@
 use crate::models::attention::{
     AttentionBlock, AttentionBlockConfig, SpatialTransformer, SpatialTransformerConfig,
 };
-use crate::models::resnet::{ResnetBlock2D, ResnetBlock2DConfig};
-use tch::{nn, nn::Module, Tensor};
+use crate::models::resnet::{conv3x3, ResnetBlock2D, ResnetBlock2DConfig};
+use tch::{nn, nn::Module, Kind, Tensor};
 
 #[derive(Debug)]
 struct Downsample2D {
@@ -50,13 +50,14 @@ impl Module for Downsample2D {
 #[derive(Debug)]
 struct Upsample2D {
     conv: nn::Conv2D,
+    circular_padding: bool,
 }
 
 impl Upsample2D {
     fn new(vs: nn::Path, in_channels: i64, out_channels: i64) -> Self {
         let config = nn::ConvConfig { padding: 1, ..Default::default() };
         let conv = nn::conv2d(&vs / "conv", in_channels, out_channels, 3, config);
-        Self { conv }
+        Self { conv, circular_padding: false }
     }
 }
 
@@ -72,7 +73,7 @@ impl Upsample2D {
             }
             Some((h, w)) => xs.upsample_nearest2d([h, w], None, None),
         };
-        xs.apply(&self.conv)
+        conv3x3(&xs, &self.conv, self.circular_padding)
     }
 }
 
@@ -137,7 +138,8 @@ impl DownEncoderBlock2D {
                 true,
                 out_channels,
                 config.downsample_padding,
-            ).into()
+            )
+            .into()
         } else {
             None
         };
@@ -217,6 +219,16 @@ impl UpDecoderBlock2D {
         };
         Self { resnets, upsampler, config }
     }
+
+    /// Uses circular padding in the convolutions, see `ResnetBlock2D::set_circular_padding`.
+    pub fn set_circular_padding(&mut self, circular: bool) {
+        for resnet in self.resnets.iter_mut() {
+            resnet.set_circular_padding(circular)
+        }
+        if let Some(upsampler) = self.upsampler.as_mut() {
+            upsampler.circular_padding = circular
+        }
+    }
 }
 
 impl Module for UpDecoderBlock2D {
@@ -294,6 +306,14 @@ impl UNetMidBlock2D {
         Self { resnet, attn_resnets, config }
     }
 
+    /// Uses circular padding in the convolutions, see `ResnetBlock2D::set_circular_padding`.
+    pub fn set_circular_padding(&mut self, circular: bool) {
+        self.resnet.set_circular_padding(circular);
+        for (_, resnet) in self.attn_resnets.iter_mut() {
+            resnet.set_circular_padding(circular)
+        }
+    }
+
     pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Tensor {
         let mut xs = self.resnet.forward(xs, temb);
         for (attn, resnet) in self.attn_resnets.iter() {
@@ -314,6 +334,7 @@ pub struct UNetMidBlock2DCrossAttnConfig {
     pub cross_attn_dim: i64,
     pub sliced_attention_size: Option<i64>,
     pub use_linear_projection: bool,
+    pub transformer_layers_per_block: i64,
 }
 
 impl Default for UNetMidBlock2DCrossAttnConfig {
@@ -327,6 +348,7 @@ impl Default for UNetMidBlock2DCrossAttnConfig {
             cross_attn_dim: 1280,
             sliced_attention_size: None, // Sliced attention disabled
             use_linear_projection: false,
+            transformer_layers_per_block: 1,
         }
     }
 }
@@ -358,7 +380,7 @@ impl UNetMidBlock2DCrossAttn {
         let resnet = ResnetBlock2D::new(&vs_resnets / "0", in_channels, resnet_cfg);
         let n_heads = config.attn_num_head_channels;
         let attn_cfg = SpatialTransformerConfig {
-            depth: 1,
+            depth: config.transformer_layers_per_block,
             num_groups: resnet_groups,
             context_dim: Some(config.cross_attn_dim),
             sliced_attention_size: config.sliced_attention_size,
@@ -379,6 +401,14 @@ impl UNetMidBlock2DCrossAttn {
         Self { resnet, attn_resnets, config }
     }
 
+    pub(crate) fn attentions(&self) -> impl Iterator<Item = &SpatialTransformer> {
+        self.attn_resnets.iter().map(|(attn, _)| attn)
+    }
+
+    pub(crate) fn attentions_mut(&mut self) -> impl Iterator<Item = &mut SpatialTransformer> {
+        self.attn_resnets.iter_mut().map(|(attn, _)| attn)
+    }
+
     pub fn forward(
         &self,
         xs: &Tensor,
@@ -454,7 +484,8 @@ impl DownBlock2D {
                 true,
                 out_channels,
                 config.downsample_padding,
-            ).into()
+            )
+            .into()
         } else {
             None
         };
@@ -488,6 +519,7 @@ pub struct CrossAttnDownBlock2DConfig {
     // attention_type: "default"
     pub sliced_attention_size: Option<i64>,
     pub use_linear_projection: bool,
+    pub transformer_layers_per_block: i64,
 }
 
 impl Default for CrossAttnDownBlock2DConfig {
@@ -498,6 +530,7 @@ impl Default for CrossAttnDownBlock2DConfig {
             cross_attention_dim: 1280,
             sliced_attention_size: None,
             use_linear_projection: false,
+            transformer_layers_per_block: 1,
         }
     }
 }
@@ -526,7 +559,7 @@ impl CrossAttnDownBlock2D {
         );
         let n_heads = config.attn_num_head_channels;
         let cfg = SpatialTransformerConfig {
-            depth: 1,
+            depth: config.transformer_layers_per_block,
             context_dim: Some(config.cross_attention_dim),
             num_groups: config.downblock.resnet_groups,
             sliced_attention_size: config.sliced_attention_size,
@@ -547,17 +580,43 @@ impl CrossAttnDownBlock2D {
         Self { downblock, attentions, config }
     }
 
+    pub(crate) fn attentions(&self) -> impl Iterator<Item = &SpatialTransformer> {
+        self.attentions.iter()
+    }
+
+    pub(crate) fn attentions_mut(&mut self) -> impl Iterator<Item = &mut SpatialTransformer> {
+        self.attentions.iter_mut()
+    }
+
     pub fn forward(
         &self,
         xs: &Tensor,
         temb: Option<&Tensor>,
         encoder_hidden_states: Option<&Tensor>,
+    ) -> (Tensor, Vec<Tensor>) {
+        self.forward_with_additional_residual(xs, temb, encoder_hidden_states, None)
+    }
+
+    /// Same as `forward` but adds `additional_residual` to the output of the last
+    /// layer, before the downsampling. This is used by T2I-Adapters.
+    pub fn forward_with_additional_residual(
+        &self,
+        xs: &Tensor,
+        temb: Option<&Tensor>,
+        encoder_hidden_states: Option<&Tensor>,
+        additional_residual: Option<&Tensor>,
     ) -> (Tensor, Vec<Tensor>) {
         let mut output_states = vec![];
         let mut xs = xs.shallow_clone();
-        for (resnet, attn) in self.downblock.resnets.iter().zip(self.attentions.iter()) {
+        let n_layers = self.attentions.len();
+        for (i, (resnet, attn)) in
+            self.downblock.resnets.iter().zip(self.attentions.iter()).enumerate()
+        {
             xs = resnet.forward(&xs, temb);
             xs = attn.forward(&xs, encoder_hidden_states);
+            if let (true, Some(residual)) = (i + 1 == n_layers, additional_residual) {
+                xs = &xs + residual
+            }
             output_states.push(xs.shallow_clone());
         }
         let xs = match &self.downblock.downsampler {
@@ -572,6 +631,48 @@ impl CrossAttnDownBlock2D {
     }
 }
 
+/// FreeU scaling factors applied to an up block.
+/// https://arxiv.org/abs/2309.11497
+#[derive(Debug, Clone, Copy)]
+pub struct FreeUScale {
+    /// Scaling factor for the first half of the backbone features channels.
+    pub backbone: f64,
+    /// Scaling factor for the low frequencies of the skip connections.
+    pub skip: f64,
+}
+
+// Scales the low frequency components of xs, the frequencies within the
+// threshold of the center are multiplied by scale.
+fn fourier_filter(xs: &Tensor, threshold: i64, scale: f64) -> Tensor {
+    let kind = xs.kind();
+    // The fft ops do not support half precision for non power of two sizes.
+    let xs = xs.to_kind(Kind::Float);
+    let (_b, _c, h, w) = xs.size4().unwrap();
+    let xs_freq = xs.fft_fftn(None::<&[i64]>, [-2, -1].as_slice(), "backward");
+    let xs_freq = xs_freq.fft_fftshift([-2, -1].as_slice());
+    let mask = Tensor::ones([1, 1, h, w], (Kind::Float, xs.device()));
+    // The window is clamped to the tensor bounds, these can be smaller than the window
+    // for the lowest resolution skip connections of small latents.
+    let window = |size: i64| {
+        let start = (size / 2 - threshold).max(0);
+        let end = (size / 2 + threshold).min(size);
+        (start, end - start)
+    };
+    let ((row, height), (col, width)) = (window(h), window(w));
+    let _ = mask.narrow(2, row, height).narrow(3, col, width).fill_(scale);
+    let xs_freq = (xs_freq * mask).fft_ifftshift([-2, -1].as_slice());
+    xs_freq.fft_ifftn(None::<&[i64]>, [-2, -1].as_slice(), "backward").real().to_kind(kind)
+}
+
+fn apply_freeu(xs: &Tensor, res_xs: &Tensor, freeu: FreeUScale) -> (Tensor, Tensor) {
+    let channels = xs.size()[1];
+    let channel_scale = Tensor::ones([channels], (xs.kind(), xs.device()));
+    let _ = channel_scale.narrow(0, 0, channels / 2).fill_(freeu.backbone);
+    let xs = xs * channel_scale.view([1, channels, 1, 1]);
+    let res_xs = fourier_filter(res_xs, 1, freeu.skip);
+    (xs, res_xs)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct UpBlock2DConfig {
     pub num_layers: i64,
@@ -599,6 +700,8 @@ impl Default for UpBlock2DConfig {
 pub struct UpBlock2D {
     pub resnets: Vec<ResnetBlock2D>,
     upsampler: Option<Upsample2D>,
+    /// When set, FreeU scaling is applied to the features before each resnet.
+    pub freeu: Option<FreeUScale>,
     pub config: UpBlock2DConfig,
 }
 
@@ -633,7 +736,17 @@ impl UpBlock2D {
         } else {
             None
         };
-        Self { resnets, upsampler, config }
+        Self { resnets, upsampler, freeu: None, config }
+    }
+
+    fn cat_res_xs(&self, xs: &Tensor, res_xs: &Tensor) -> Tensor {
+        match self.freeu {
+            None => Tensor::cat(&[xs, res_xs], 1),
+            Some(freeu) => {
+                let (xs, res_xs) = apply_freeu(xs, res_xs, freeu);
+                Tensor::cat(&[xs, res_xs], 1)
+            }
+        }
     }
 
     pub fn forward(
@@ -645,7 +758,7 @@ impl UpBlock2D {
     ) -> Tensor {
         let mut xs = xs.shallow_clone();
         for (index, resnet) in self.resnets.iter().enumerate() {
-            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1);
+            xs = self.cat_res_xs(&xs, &res_xs[res_xs.len() - index - 1]);
             xs = resnet.forward(&xs, temb);
         }
         match &self.upsampler {
@@ -663,6 +776,7 @@ pub struct CrossAttnUpBlock2DConfig {
     // attention_type: "default"
     pub sliced_attention_size: Option<i64>,
     pub use_linear_projection: bool,
+    pub transformer_layers_per_block: i64,
 }
 
 impl Default for CrossAttnUpBlock2DConfig {
@@ -673,6 +787,7 @@ impl Default for CrossAttnUpBlock2DConfig {
             cross_attention_dim: 1280,
             sliced_attention_size: None,
             use_linear_projection: false,
+            transformer_layers_per_block: 1,
         }
     }
 }
@@ -703,7 +818,7 @@ impl CrossAttnUpBlock2D {
         );
         let n_heads = config.attn_num_head_channels;
         let cfg = SpatialTransformerConfig {
-            depth: 1,
+            depth: config.transformer_layers_per_block,
             context_dim: Some(config.cross_attention_dim),
             num_groups: config.upblock.resnet_groups,
             sliced_attention_size: config.sliced_attention_size,
@@ -734,7 +849,7 @@ impl CrossAttnUpBlock2D {
     ) -> Tensor {
         let mut xs = xs.shallow_clone();
         for (index, resnet) in self.upblock.resnets.iter().enumerate() {
-            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1);
+            xs = self.upblock.cat_res_xs(&xs, &res_xs[res_xs.len() - index - 1]);
             xs = resnet.forward(&xs, temb);
             xs = self.attentions[index].forward(&xs, encoder_hidden_states);
         }
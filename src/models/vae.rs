@@ -3,10 +3,14 @@
 //! Auto-encoder models compress their input to a usually smaller latent space
 //! before expanding it back to its original shape. This results in the latent values
 //! compressing the original information.
+use crate::models::params;
+use crate::models::resnet::conv3x3;
 use crate::models::unet_2d_blocks::{
     DownEncoderBlock2D, DownEncoderBlock2DConfig, UNetMidBlock2D, UNetMidBlock2DConfig,
     UpDecoderBlock2D, UpDecoderBlock2DConfig,
 };
+use crate::pipelines::multidiffusion::TiledCanvas;
+use crate::utils::{tensor_bytes, JsonConfig, MemoryReport};
 use tch::{nn, nn::Module, Tensor};
 
 #[derive(Debug, Clone)]
@@ -123,6 +127,7 @@ struct Decoder {
     mid_block: UNetMidBlock2D,
     conv_norm_out: nn::GroupNorm,
     conv_out: nn::Conv2D,
+    circular_padding: bool,
     #[allow(dead_code)]
     config: DecoderConfig,
 }
@@ -176,26 +181,93 @@ impl Decoder {
         let conv_cfg = nn::ConvConfig { padding: 1, ..Default::default() };
         let conv_out =
             nn::conv2d(&vs / "conv_out", config.block_out_channels[0], out_channels, 3, conv_cfg);
-        Self { conv_in, up_blocks, mid_block, conv_norm_out, conv_out, config }
+        Self {
+            conv_in,
+            up_blocks,
+            mid_block,
+            conv_norm_out,
+            conv_out,
+            circular_padding: false,
+            config,
+        }
+    }
+
+    fn set_circular_padding(&mut self, circular: bool) {
+        self.circular_padding = circular;
+        self.mid_block.set_circular_padding(circular);
+        for up_block in self.up_blocks.iter_mut() {
+            up_block.set_circular_padding(circular)
+        }
+    }
+}
+
+impl Decoder {
+    fn forward_(&self, xs: &Tensor, mut report: Option<&mut MemoryReport>) -> Tensor {
+        let xs = conv3x3(xs, &self.conv_in, self.circular_padding);
+        let mut xs = self.mid_block.forward(&xs, None);
+        if let Some(report) = report.as_mut() {
+            report.record("vae.mid_block", &xs, tensor_bytes(&xs))
+        }
+        for (i, up_block) in self.up_blocks.iter().enumerate() {
+            xs = xs.apply(up_block);
+            if let Some(report) = report.as_mut() {
+                report.record(format!("vae.up_blocks.{i}"), &xs, tensor_bytes(&xs))
+            }
+        }
+        let xs = xs.apply(&self.conv_norm_out).silu();
+        let xs = conv3x3(&xs, &self.conv_out, self.circular_padding);
+        if let Some(report) = report.as_mut() {
+            report.record("vae.decode", &xs, tensor_bytes(&xs))
+        }
+        xs
     }
 }
 
 impl Module for Decoder {
     fn forward(&self, xs: &Tensor) -> Tensor {
-        let mut xs = self.mid_block.forward(&xs.apply(&self.conv_in), None);
-        for up_block in self.up_blocks.iter() {
-            xs = xs.apply(up_block)
+        self.forward_(xs, None)
+    }
+}
+
+/// The pixel value range used by an autoencoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputRange {
+    /// Values in `[-1, 1]`, as used by the stable diffusion autoencoders.
+    #[default]
+    MinusOneToOne,
+    ZeroToOne,
+}
+
+impl OutputRange {
+    /// Maps some decoded values to `[0, 1]`, clamping the values that fall out of the
+    /// range as decoders do not strictly enforce it.
+    pub fn to_unit_range(&self, xs: &Tensor) -> Tensor {
+        match self {
+            Self::MinusOneToOne => (xs / 2 + 0.5).clamp(0., 1.),
+            Self::ZeroToOne => xs.clamp(0., 1.),
         }
-        xs.apply(&self.conv_norm_out).silu().apply(&self.conv_out)
     }
 }
 
-#[derive(Debug, Clone)]
+/// The autoencoder configuration, the encoder and decoder both use one block per
+/// element of `block_out_channels`, in reverse order for the decoder, so that
+/// the number of down and up blocks always match.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AutoEncoderKLConfig {
     pub block_out_channels: Vec<i64>,
     pub layers_per_block: i64,
     pub latent_channels: i64,
     pub norm_num_groups: i64,
+    /// The latents are scaled by this factor so that they have roughly unit variance.
+    pub scaling_factor: f64,
+    /// An offset removed from the latents before scaling them, used by SD3 style models.
+    pub shift_factor: f64,
+    pub use_quant_conv: bool,
+    pub use_post_quant_conv: bool,
+    /// The range of the decoded images, and of the images expected by the encoder.
+    pub output_range: OutputRange,
 }
 
 impl Default for AutoEncoderKLConfig {
@@ -205,10 +277,90 @@ impl Default for AutoEncoderKLConfig {
             layers_per_block: 1,
             latent_channels: 4,
             norm_num_groups: 32,
+            scaling_factor: 0.18215,
+            shift_factor: 0.,
+            use_quant_conv: true,
+            use_post_quant_conv: true,
+            output_range: OutputRange::default(),
         }
     }
 }
 
+impl AutoEncoderKLConfig {
+    /// Checks that the configuration describes a valid autoencoder.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.block_out_channels.is_empty() {
+            anyhow::bail!("the vae config should have at least one block")
+        }
+        if self.layers_per_block < 1 {
+            anyhow::bail!("invalid layers_per_block {}", self.layers_per_block)
+        }
+        if self.latent_channels < 1 {
+            anyhow::bail!("invalid latent_channels {}", self.latent_channels)
+        }
+        for &c in self.block_out_channels.iter() {
+            if c <= 0 || c % self.norm_num_groups != 0 {
+                anyhow::bail!(
+                    "block channels {c} is not a multiple of norm_num_groups {}",
+                    self.norm_num_groups
+                )
+            }
+        }
+        Ok(())
+    }
+
+    /// The ratio between the image and latent resolutions.
+    pub fn downsampling_factor(&self) -> i64 {
+        1 << self.block_out_channels.len().saturating_sub(1)
+    }
+
+    /// The shape `[batch, latent_channels, height / f, width / f]` of the latents for
+    /// images of the given size, `f` being the downsampling factor. Returns an error if
+    /// the image size is not divisible by the downsampling factor.
+    pub fn latent_shape(&self, width: i64, height: i64, batch: i64) -> anyhow::Result<[i64; 4]> {
+        let factor = self.downsampling_factor();
+        if width <= 0 || height <= 0 || width % factor != 0 || height % factor != 0 {
+            anyhow::bail!(
+                "the image size {width}x{height} has to be positive and divisible by {factor}"
+            )
+        }
+        Ok([batch, self.latent_channels, height / factor, width / factor])
+    }
+
+    /// The number of parameters of the encoder, decoder, and quantization convolutions.
+    pub fn num_parameters(&self, in_channels: i64, out_channels: i64) -> i64 {
+        let channels = &self.block_out_channels;
+        let (c0, cl) = (channels[0], *channels.last().unwrap());
+        let latent = self.latent_channels;
+        let mid_block = 2 * params::resnet(cl, cl, None) + params::attention_block(cl);
+        let mut encoder = params::conv2d(in_channels, c0, 3);
+        for (i, &c) in channels.iter().enumerate() {
+            let in_c = if i > 0 { channels[i - 1] } else { c0 };
+            encoder += params::resnet(in_c, c, None)
+                + (self.layers_per_block - 1) * params::resnet(c, c, None);
+            if i + 1 < channels.len() {
+                encoder += params::conv2d(c, c, 3)
+            }
+        }
+        encoder += mid_block + params::norm(cl) + params::conv2d(cl, 2 * latent, 3);
+        let mut decoder = params::conv2d(latent, cl, 3) + mid_block;
+        for (i, &c) in channels.iter().rev().enumerate() {
+            let in_c = if i > 0 { channels[channels.len() - i] } else { cl };
+            decoder +=
+                params::resnet(in_c, c, None) + self.layers_per_block * params::resnet(c, c, None);
+            if i + 1 < channels.len() {
+                decoder += params::conv2d(c, c, 3)
+            }
+        }
+        decoder += params::norm(c0) + params::conv2d(c0, out_channels, 3);
+        let quant_conv =
+            if self.use_quant_conv { params::conv2d(2 * latent, 2 * latent, 1) } else { 0 };
+        let post_quant_conv =
+            if self.use_post_quant_conv { params::conv2d(latent, latent, 1) } else { 0 };
+        encoder + decoder + quant_conv + post_quant_conv
+    }
+}
+
 pub struct DiagonalGaussianDistribution {
     mean: Tensor,
     std: Tensor,
@@ -229,20 +381,31 @@ impl DiagonalGaussianDistribution {
         let sample = Tensor::randn_like(&self.mean).to(self.device);
         &self.mean + &self.std * sample
     }
+
+    /// The mode of the distribution, i.e. its mean.
+    pub fn mode(&self) -> Tensor {
+        self.mean.shallow_clone()
+    }
 }
 
 // https://github.com/huggingface/diffusers/blob/970e30606c2944e3286f56e8eb6d3dc6d1eb85f7/src/diffusers/models/vae.py#L485
-// This implementation is specific to the config used in stable-diffusion-v1-5
+// The default block layout is the one used in stable-diffusion-v1-5
 // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
 #[derive(Debug)]
 pub struct AutoEncoderKL {
     encoder: Encoder,
     decoder: Decoder,
-    quant_conv: nn::Conv2D,
-    post_quant_conv: nn::Conv2D,
+    quant_conv: Option<nn::Conv2D>,
+    post_quant_conv: Option<nn::Conv2D>,
     pub config: AutoEncoderKLConfig,
 }
 
+// SAFETY: the autoencoder holds the weights of its convolutions and norms, which the
+// encode and decode passes only read, and its config. Everything else, the tiles of a
+// tiled decode included, is allocated for each call, so concurrent passes only share
+// reads of the weights, which libtorch supports.
+unsafe impl Sync for AutoEncoderKL {}
+
 impl AutoEncoderKL {
     pub fn new(
         vs: nn::Path,
@@ -265,21 +428,176 @@ impl AutoEncoderKL {
         };
         let decoder = Decoder::new(&vs / "decoder", latent_channels, out_channels, decoder_cfg);
         let conv_cfg = Default::default();
-        let quant_conv =
-            nn::conv2d(&vs / "quant_conv", 2 * latent_channels, 2 * latent_channels, 1, conv_cfg);
-        let post_quant_conv =
-            nn::conv2d(&vs / "post_quant_conv", latent_channels, latent_channels, 1, conv_cfg);
+        let quant_conv = config.use_quant_conv.then(|| {
+            nn::conv2d(&vs / "quant_conv", 2 * latent_channels, 2 * latent_channels, 1, conv_cfg)
+        });
+        let post_quant_conv = config.use_post_quant_conv.then(|| {
+            nn::conv2d(&vs / "post_quant_conv", latent_channels, latent_channels, 1, conv_cfg)
+        });
         Self { encoder, decoder, quant_conv, post_quant_conv, config }
     }
 
+    /// Builds an autoencoder using the `config.json` file of a diffusers model, e.g.
+    /// https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
+    pub fn from_json<P: AsRef<std::path::Path>>(path: P, vs: nn::Path) -> anyhow::Result<Self> {
+        let json = JsonConfig::read(path)?;
+        let default = AutoEncoderKLConfig::default();
+        let block_out_channels = json.i64_list("block_out_channels")?;
+        for (key, block_type) in
+            [("down_block_types", "DownEncoderBlock2D"), ("up_block_types", "UpDecoderBlock2D")]
+        {
+            if json.get(key).is_none() {
+                continue;
+            }
+            let block_types = json.str_list(key)?;
+            if block_types.len() != block_out_channels.len() {
+                anyhow::bail!(
+                    "{key} has {} blocks but block_out_channels has {}, the encoder and decoder should have the same number of blocks",
+                    block_types.len(),
+                    block_out_channels.len()
+                )
+            }
+            if let Some(b) = block_types.iter().find(|b| b.as_str() != block_type) {
+                anyhow::bail!("unsupported block type {b} in {key}")
+            }
+        }
+        let config = AutoEncoderKLConfig {
+            block_out_channels,
+            layers_per_block: json.i64_or("layers_per_block", default.layers_per_block)?,
+            latent_channels: json.i64_or("latent_channels", default.latent_channels)?,
+            norm_num_groups: json.i64_or("norm_num_groups", default.norm_num_groups)?,
+            scaling_factor: json.f64_or("scaling_factor", default.scaling_factor)?,
+            shift_factor: json.f64_or("shift_factor", default.shift_factor)?,
+            use_quant_conv: json.bool_or("use_quant_conv", default.use_quant_conv)?,
+            use_post_quant_conv: json
+                .bool_or("use_post_quant_conv", default.use_post_quant_conv)?,
+            output_range: default.output_range,
+        };
+        let in_channels = json.i64_or("in_channels", 3)?;
+        let out_channels = json.i64_or("out_channels", 3)?;
+        config.validate()?;
+        Ok(Self::new(vs, in_channels, out_channels, config))
+    }
+
+    /// Converts a sample from the latent distribution to the latents used by the
+    /// diffusion models.
+    pub fn scale_latents(&self, xs: &Tensor) -> Tensor {
+        (xs - self.config.shift_factor) * self.config.scaling_factor
+    }
+
+    /// Converts latents produced by a diffusion model to values that can be decoded,
+    /// this is the inverse of `scale_latents`.
+    pub fn unscale_latents(&self, xs: &Tensor) -> Tensor {
+        xs / self.config.scaling_factor + self.config.shift_factor
+    }
+
     /// Returns the distribution in the latent space.
     pub fn encode(&self, xs: &Tensor) -> DiagonalGaussianDistribution {
-        let parameters = xs.apply(&self.encoder).apply(&self.quant_conv);
+        let parameters = xs.apply(&self.encoder).apply_opt(&self.quant_conv);
         DiagonalGaussianDistribution::new(&parameters)
     }
 
+    /// Encodes an image to the latents used by the diffusion model, i.e. with the
+    /// scaling factor applied. This uses the mode of the latent distribution so the
+    /// result is deterministic and can be computed once and reused, e.g. to run
+    /// many img2img variations from the same starting image.
+    pub fn encode_image(&self, xs: &Tensor) -> Tensor {
+        self.scale_latents(&self.encode(xs).mode())
+    }
+
+    /// Uses circular rather than zero padding in all the 3x3 convolutions of the decoder,
+    /// so that latents generated to tile seamlessly also decode to images that tile. The
+    /// encoder is not affected.
+    pub fn set_circular_padding(&mut self, circular: bool) {
+        self.decoder.set_circular_padding(circular)
+    }
+
     /// Takes as input some sampled values.
     pub fn decode(&self, xs: &Tensor) -> Tensor {
-        xs.apply(&self.post_quant_conv).apply(&self.decoder)
+        xs.apply_opt(&self.post_quant_conv).apply(&self.decoder)
+    }
+
+    /// Decodes some latents by splitting them in overlapping tiles of `tile_size` latent
+    /// pixels, this bounds the decoder memory usage for large images. The tiles are decoded
+    /// one at a time in row-major order and blended with triangular weights over the
+    /// `overlap` latent pixels shared with their neighbors, see `TiledCanvas::blend_weighted`.
+    pub fn decode_tiled(
+        &self,
+        xs: &Tensor,
+        tile_size: i64,
+        overlap: i64,
+    ) -> anyhow::Result<Tensor> {
+        let (_, _, height, width) = xs.size4()?;
+        let canvas = TiledCanvas::new(height, width, tile_size, overlap)?;
+        let scale = 1 << (self.config.block_out_channels.len() - 1);
+        canvas.blend_weighted(scale, |tile| Ok(self.decode(&tile.slice(xs))))
+    }
+
+    /// Converts some decoded images to `u8` pixel values on the cpu, values out of the
+    /// output range get clamped rather than wrapped around.
+    pub fn postprocess(&self, image: &Tensor) -> Tensor {
+        let image = self.config.output_range.to_unit_range(image).to_device(tch::Device::Cpu);
+        (image * 255.).to_kind(tch::Kind::Uint8)
+    }
+
+    /// Decodes the latents produced by a diffusion model to `u8` images on the cpu.
+    pub fn decode_to_image(&self, latents: &Tensor) -> Tensor {
+        self.postprocess(&self.decode(&self.unscale_latents(latents)))
+    }
+
+    /// Same as `decode` but also records the size of the activations at each
+    /// decoder block boundary in `report`.
+    pub fn decode_with_memory_report(&self, xs: &Tensor, report: &mut MemoryReport) -> Tensor {
+        self.decoder.forward_(&xs.apply_opt(&self.post_quant_conv), Some(report))
+    }
+}
+
+/// A model decoding the latents of a diffusion model to images, e.g. the `AutoEncoderKL`
+/// decoder or a lighter approximation of it. This lets pipelines swap decoders without
+/// special-casing each of them.
+pub trait LatentDecoder: Send + Sync {
+    /// Decodes unscaled latents, i.e. latents divided by `scaling_factor`, to images
+    /// with values in `[-1, 1]`.
+    fn decode(&self, latents: &Tensor) -> Tensor;
+
+    /// The factor the diffusion model latents have been multiplied by.
+    fn scaling_factor(&self) -> f64;
+
+    /// Decodes the latents produced by a diffusion model to `u8` images on the cpu.
+    fn decode_to_image(&self, latents: &Tensor) -> Tensor {
+        let image = LatentDecoder::decode(self, &(latents / self.scaling_factor()));
+        let image = (image / 2 + 0.5).clamp(0., 1.).to_device(tch::Device::Cpu);
+        (image * 255.).to_kind(tch::Kind::Uint8)
+    }
+}
+
+/// A model encoding images to the latents of a diffusion model, see `LatentDecoder`.
+pub trait ImageEncoder: Send + Sync {
+    /// Encodes images with values in `[-1, 1]` to latents scaled for the diffusion
+    /// model, deterministically.
+    fn encode(&self, images: &Tensor) -> Tensor;
+}
+
+impl LatentDecoder for AutoEncoderKL {
+    fn decode(&self, latents: &Tensor) -> Tensor {
+        let image = AutoEncoderKL::decode(self, &(latents + self.config.shift_factor));
+        match self.config.output_range {
+            OutputRange::MinusOneToOne => image,
+            OutputRange::ZeroToOne => image * 2 - 1,
+        }
+    }
+
+    fn scaling_factor(&self) -> f64 {
+        self.config.scaling_factor
+    }
+}
+
+impl ImageEncoder for AutoEncoderKL {
+    fn encode(&self, images: &Tensor) -> Tensor {
+        let images = match self.config.output_range {
+            OutputRange::MinusOneToOne => images.shallow_clone(),
+            OutputRange::ZeroToOne => images / 2 + 0.5,
+        };
+        self.encode_image(&images)
     }
 }
@@ -0,0 +1,55 @@
+//! # Animation Helpers
+//!
+//! Latent walks and prompt interpolations generate many frames, these helpers decode
+//! the frames lazily so that only the frame being processed is kept in memory.
+use crate::models::vae::LatentDecoder;
+use tch::{Kind, Tensor};
+
+/// Spherical linear interpolation between two latents, `t` goes from 0 (returns `v0`)
+/// to 1 (returns `v1`). Unlike a linear interpolation this preserves the norm of
+/// gaussian noise, so the intermediate latents remain valid initial noise.
+pub fn slerp(v0: &Tensor, v1: &Tensor, t: f64) -> Tensor {
+    let norm0 = v0.norm();
+    let norm1 = v1.norm();
+    let dot = (v0 * v1).sum(Kind::Float) / (&norm0 * &norm1);
+    let dot = f64::try_from(dot).unwrap_or(1.).clamp(-1., 1.);
+    if dot.abs() > 0.9995 {
+        return v0 * (1. - t) + v1 * t;
+    }
+    let theta = dot.acos();
+    let s0 = ((1. - t) * theta).sin() / theta.sin();
+    let s1 = (t * theta).sin() / theta.sin();
+    v0 * s0 + v1 * s1
+}
+
+/// An iterator decoding latents one at a time, see `decode_frames`.
+pub struct DecodedFrames<'a, I> {
+    decoder: &'a dyn LatentDecoder,
+    latents: I,
+}
+
+/// Returns an iterator yielding the decoded image for each of the latents, as `u8`
+/// tensors of shape `[batch, 3, height, width]` on the cpu. The latents are only pulled
+/// from `latents` when the next frame is requested, so this can be used with an iterator
+/// running the diffusion for each frame without ever holding all the frames at once.
+/// Any decoder can be used, e.g. the `AutoEncoderKL` one.
+pub fn decode_frames<I>(decoder: &dyn LatentDecoder, latents: I) -> DecodedFrames<'_, I::IntoIter>
+where
+    I: IntoIterator<Item = Tensor>,
+{
+    DecodedFrames { decoder, latents: latents.into_iter() }
+}
+
+impl<I: Iterator<Item = Tensor>> Iterator for DecodedFrames<'_, I> {
+    type Item = Tensor;
+
+    fn next(&mut self) -> Option<Tensor> {
+        let latents = self.latents.next()?;
+        let _no_grad_guard = tch::no_grad_guard();
+        Some(self.decoder.decode_to_image(&latents))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.latents.size_hint()
+    }
+}
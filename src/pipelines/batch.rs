@@ -0,0 +1,68 @@
+//! # Batch Runner
+//!
+//! Processes many generation jobs with a fixed pool of workers, e.g. one pipeline per
+//! GPU, and returns the results in submission order.
+//!
+//! Libtorch uses a single random number generator per device, so sampling noise from
+//! concurrent workers is not reproducible in general. Jobs should sample their initial
+//! noise with `utils::seeded_noise`, which seeds and samples atomically, for the results
+//! to only depend on the job and not on how jobs got scheduled. Schedulers adding noise
+//! at each step, e.g. the ancestral ones, remain non-deterministic.
+use std::sync::mpsc;
+use std::sync::Mutex;
+
+/// A pool of workers processing jobs concurrently, each worker runs on its own thread
+/// and owns its state, typically a `StableDiffusion` pipeline on a given device.
+pub struct BatchRunner<W> {
+    workers: Vec<W>,
+}
+
+impl<W: Send> BatchRunner<W> {
+    pub fn new(workers: Vec<W>) -> anyhow::Result<Self> {
+        if workers.is_empty() {
+            anyhow::bail!("a batch runner requires at least one worker")
+        }
+        Ok(Self { workers })
+    }
+
+    pub fn workers(&self) -> &[W] {
+        &self.workers
+    }
+
+    pub fn into_workers(self) -> Vec<W> {
+        self.workers
+    }
+
+    /// Runs `f` on each job and returns the results in the order of `jobs`. Each worker
+    /// pulls the next pending job as soon as it is done with the previous one, so
+    /// workers on faster devices process more jobs. A failing job does not stop the
+    /// other ones, its error is returned at its position.
+    pub fn run<J, R, F>(&mut self, jobs: Vec<J>, f: F) -> Vec<anyhow::Result<R>>
+    where
+        J: Send,
+        R: Send,
+        F: Fn(&mut W, J) -> anyhow::Result<R> + Sync,
+    {
+        let n_jobs = jobs.len();
+        let pending = Mutex::new(jobs.into_iter().enumerate());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::scope(|scope| {
+            for worker in self.workers.iter_mut() {
+                let (pending, sender, f) = (&pending, sender.clone(), &f);
+                scope.spawn(move || loop {
+                    let job = pending.lock().unwrap().next();
+                    let (index, job) = match job {
+                        None => break,
+                        Some(job) => job,
+                    };
+                    tch::no_grad(|| sender.send((index, f(worker, job)))).unwrap()
+                });
+            }
+        });
+        drop(sender);
+        let mut results: Vec<_> = receiver.into_iter().collect();
+        results.sort_by_key(|(index, _)| *index);
+        assert_eq!(results.len(), n_jobs);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
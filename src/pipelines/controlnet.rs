@@ -0,0 +1,221 @@
+//! # ControlNet Helpers
+//!
+//! Utilities to control how a ControlNet is applied over the denoising steps.
+use crate::utils::{resize, Interpolation};
+use tch::{Kind, Tensor};
+
+/// How a raw image gets converted to the conditioning image of a ControlNet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConditioningType {
+    /// The image is used as is, e.g. for depth or pose maps that have already been
+    /// computed.
+    Passthrough,
+    /// The edges detected by `canny` with the given hysteresis thresholds, these apply to
+    /// gradients of images with values in `[0, 255]`, 100 and 200 are common values.
+    Canny { low: f64, high: f64 },
+}
+
+impl ConditioningType {
+    /// Converts a raw image of shape `[batch, channels, h, w]` to a conditioning image
+    /// of shape `[batch, 3, h, w]` with values in `[0, 1]`.
+    pub fn preprocess(&self, image: &Tensor) -> anyhow::Result<Tensor> {
+        match *self {
+            Self::Passthrough => Ok(normalize_conditioning(image)),
+            Self::Canny { low, high } => {
+                if low > high {
+                    anyhow::bail!("the canny low threshold {low} is above the high one {high}")
+                }
+                let edges = canny(&normalize_conditioning(image), low, high);
+                Ok(edges.repeat([1, 3, 1, 1]))
+            }
+        }
+    }
+
+    /// Runs `preprocess` followed by `prepare_conditioning`.
+    pub fn prepare(
+        &self,
+        image: &Tensor,
+        height: i64,
+        width: i64,
+        auto_resize: Option<Interpolation>,
+    ) -> anyhow::Result<Tensor> {
+        let image = self.preprocess(image)?;
+        prepare_conditioning(&image, height, width, auto_resize)
+    }
+}
+
+// Returns the neighbors of each pixel at offset `(dy, dx)`, using zero padding.
+fn shift(xs: &Tensor, dy: i64, dx: i64) -> Tensor {
+    let (_, _, h, w) = xs.size4().unwrap();
+    xs.constant_pad_nd([1, 1, 1, 1]).narrow(2, 1 + dy, h).narrow(3, 1 + dx, w)
+}
+
+/// The Canny edge detector, `image` has shape `[batch, channels, h, w]` with values in
+/// `[0, 1]` and is converted to grayscale. The returned edge map has shape
+/// `[batch, 1, h, w]` and contains 1 on the edges and 0 elsewhere.
+///
+/// The image is smoothed with a gaussian blur, the gradient is computed with a Sobel
+/// filter, and its magnitude is kept only at its local maximums along the gradient
+/// direction. Pixels above `high` are edges, as well as pixels above `low` that are
+/// connected to an edge. The thresholds apply to the gradient of the image scaled to
+/// `[0, 255]`.
+pub fn canny(image: &Tensor, low: f64, high: f64) -> Tensor {
+    let options = (Kind::Float, image.device());
+    let gray = image.to_kind(Kind::Float).mean_dim(Some([1].as_slice()), true, Kind::Float) * 255.;
+    // Gaussian blur with sigma 1.4 over a 5x5 window.
+    let sigma = 1.4;
+    let xs = Tensor::arange_start(-2, 3, options);
+    let kernel = (-xs.square() / (2. * sigma * sigma)).exp();
+    let kernel = &kernel / kernel.sum(Kind::Float);
+    let kernel = (kernel.unsqueeze(1) * kernel.unsqueeze(0)).view([1, 1, 5, 5]);
+    let gray = gray.pad([2, 2, 2, 2], "reflect", None);
+    let gray = gray.conv2d(&kernel, None::<Tensor>, [1, 1], [0, 0], [1, 1], 1);
+    // Sobel gradient.
+    let sobel_x = Tensor::from_slice(&[-1f32, 0., 1., -2., 0., 2., -1., 0., 1.]);
+    let sobel_x = sobel_x.view([1, 1, 3, 3]).to_device(image.device());
+    let sobel_y = sobel_x.transpose(2, 3);
+    let gray = gray.pad([1, 1, 1, 1], "replicate", None);
+    let gx = gray.conv2d(&sobel_x, None::<Tensor>, [1, 1], [0, 0], [1, 1], 1);
+    let gy = gray.conv2d(&sobel_y, None::<Tensor>, [1, 1], [0, 0], [1, 1], 1);
+    let magnitude = (gx.square() + gy.square()).sqrt();
+    // Non-maximum suppression, the gradient direction is quantized to 0, 45, 90, or 135
+    // degrees with the y axis pointing down.
+    let angle = gy.atan2(&gx).rad2deg().remainder(180.);
+    let bin = ((angle / 45.).round().remainder(4.)).to_kind(Kind::Int64);
+    let mut is_max = Tensor::zeros_like(&magnitude).to_kind(Kind::Bool);
+    for (index, (dy, dx)) in [(0, 1), (1, 1), (1, 0), (1, -1)].into_iter().enumerate() {
+        let local_max = magnitude
+            .ge_tensor(&shift(&magnitude, dy, dx))
+            .logical_and(&magnitude.ge_tensor(&shift(&magnitude, -dy, -dx)));
+        is_max = is_max.logical_or(&(bin.eq(index as i64).logical_and(&local_max)));
+    }
+    let magnitude = magnitude * is_max.to_kind(Kind::Float);
+    // Hysteresis, the strong edges are grown over the weak ones until convergence.
+    let weak = magnitude.ge(low).to_kind(Kind::Float);
+    let mut edges = magnitude.ge(high).to_kind(Kind::Float);
+    loop {
+        let grown = edges.max_pool2d([3, 3], [1, 1], [1, 1], [1, 1], false) * &weak;
+        let unchanged = grown.equal(&edges);
+        edges = grown;
+        if unchanged {
+            break;
+        }
+    }
+    edges
+}
+
+/// Converts a conditioning image to the `[0, 1]` range expected by the ControlNet
+/// conditioning embedding. Note that this differs from the `[-1, 1]` range used for the
+/// autoencoder inputs.
+///
+/// `u8` images and float images with values above 1 are assumed to use the `[0, 255]`
+/// range and get rescaled, the result is then clamped to `[0, 1]`.
+pub fn normalize_conditioning(image: &Tensor) -> Tensor {
+    let is_u8 = image.kind() == Kind::Uint8;
+    let image = image.to_kind(Kind::Float);
+    let image = if is_u8 || image.max().double_value(&[]) > 1. { image / 255. } else { image };
+    image.clamp(0., 1.)
+}
+
+/// Normalizes a conditioning image of shape `[batch, channels, h, w]` with
+/// `normalize_conditioning` and checks that it matches the generated image size. On a
+/// mismatch, the image is resized with `auto_resize` when set, otherwise an error
+/// reporting both sizes is returned.
+pub fn prepare_conditioning(
+    image: &Tensor,
+    height: i64,
+    width: i64,
+    auto_resize: Option<Interpolation>,
+) -> anyhow::Result<Tensor> {
+    let image = &normalize_conditioning(image);
+    let (_, _, h, w) = image.size4()?;
+    match auto_resize {
+        _ if (h, w) == (height, width) => Ok(image.shallow_clone()),
+        Some(interpolation) => Ok(resize(image, height, width, interpolation)),
+        None => anyhow::bail!(
+            "conditioning image size {h}x{w} does not match the generation size {height}x{width}"
+        ),
+    }
+}
+
+/// The conditioning images fed to a ControlNet over the denoising steps, this can be
+/// used to switch from one conditioning to another partway through the denoising,
+/// e.g. to morph from one pose to another.
+#[derive(Debug)]
+pub struct ConditioningSchedule {
+    // The conditioning images with the index of the step from which they apply, sorted
+    // by step index, the first one always applies from step 0.
+    images: Vec<(usize, Tensor)>,
+}
+
+impl ConditioningSchedule {
+    /// Uses the same conditioning image for all the steps.
+    pub fn single(image: Tensor) -> Self {
+        Self { images: vec![(0, image)] }
+    }
+
+    /// Uses `image_a` for the steps before `switch_step` and `image_b` from this step
+    /// onwards. A `switch_step` of 0 results in only `image_b` being used, and a value
+    /// of at least `n_steps` results in only `image_a` being used.
+    pub fn switch(image_a: Tensor, image_b: Tensor, switch_step: usize, n_steps: usize) -> Self {
+        if switch_step == 0 {
+            Self::single(image_b)
+        } else if switch_step >= n_steps {
+            Self::single(image_a)
+        } else {
+            Self { images: vec![(0, image_a), (switch_step, image_b)] }
+        }
+    }
+
+    /// Builds a schedule from a list of `(start_step, image)` pairs, each image being
+    /// used from its start step until the start step of the next image.
+    pub fn from_steps(mut images: Vec<(usize, Tensor)>) -> anyhow::Result<Self> {
+        images.sort_by_key(|(step, _)| *step);
+        match images.first() {
+            None => anyhow::bail!("empty conditioning schedule"),
+            Some((step, _)) if *step != 0 => {
+                anyhow::bail!("the conditioning schedule should start at step 0, got {step}")
+            }
+            Some(_) => Ok(Self { images }),
+        }
+    }
+
+    /// The conditioning image to use for the step with index `step_index`.
+    pub fn conditioning(&self, step_index: usize) -> &Tensor {
+        let position = self.images.partition_point(|(step, _)| *step <= step_index);
+        &self.images[position.saturating_sub(1)].1
+    }
+}
+
+/// The fraction of the denoising schedule during which a ControlNet is applied, the
+/// ControlNet residuals are dropped outside of this window. This matches the
+/// `control_guidance_start` and `control_guidance_end` parameters from diffusers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControlGuidanceWindow {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Default for ControlGuidanceWindow {
+    fn default() -> Self {
+        Self { start: 0., end: 1. }
+    }
+}
+
+impl ControlGuidanceWindow {
+    pub fn new(start: f64, end: f64) -> anyhow::Result<Self> {
+        if !(0. ..=1.).contains(&start) || !(0. ..=1.).contains(&end) || start > end {
+            anyhow::bail!("invalid control guidance window [{start}, {end}]")
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Whether the ControlNet should be applied at step `step_index` of `n_steps`. The
+    /// ControlNet is never active when `start` and `end` are both 0.
+    pub fn is_active(&self, step_index: usize, n_steps: usize) -> bool {
+        let n_steps = n_steps.max(1) as f64;
+        let step_start = step_index as f64 / n_steps;
+        let step_end = (step_index + 1) as f64 / n_steps;
+        step_start >= self.start && step_end <= self.end
+    }
+}
@@ -0,0 +1,194 @@
+//! # Denoising Loop
+//!
+//! The denoising loop shared by the different pipelines. The pipeline specific parts,
+//! e.g. classifier free guidance, ControlNet residuals, or the inpainting mask, are
+//! provided as closures so that the loop itself only deals with the scheduler.
+//!
+//! Latents can be blended mid-denoising: run a first loop with `capture_after_step(n)`
+//! or `stop_after_step(n)`, combine the resulting latents with another run's using
+//! `blend_latents`, and continue the denoising of the blend with `resume_after_step(n)`.
+//! Schedulers keeping a history of model outputs, e.g. the multistep DPM-Solver++,
+//! start the resumed run without this history, the blend can instead be injected in a
+//! single run with `post_step`.
+use crate::schedulers::{EarlyStopping, Scheduler, Timestep};
+use tch::Tensor;
+
+type StepHook<'a> = Box<dyn FnMut(usize, Tensor) -> Tensor + 'a>;
+type StepCallback<'a> = Box<dyn FnMut(usize, &Tensor) -> anyhow::Result<()> + 'a>;
+type TimestepMap<'a> = Box<dyn FnMut(usize, f64) -> f64 + 'a>;
+
+/// The result of running a denoising loop.
+#[derive(Debug)]
+pub struct DenoiseOutput {
+    pub latents: Tensor,
+    /// The number of steps that have actually been run.
+    pub steps: usize,
+    /// Whether the loop stopped early because the latents converged.
+    pub converged: bool,
+    /// The latents after the step set with `capture_after_step`, if it has been run.
+    pub captured: Option<Tensor>,
+}
+
+/// Linearly interpolates between two partially denoised latents, `weight` goes from 0
+/// (returns `xs1`) to 1 (returns `xs2`). Both latents should have been denoised to the
+/// same step.
+pub fn blend_latents(xs1: &Tensor, xs2: &Tensor, weight: f64) -> Tensor {
+    xs1 * (1. - weight) + xs2 * weight
+}
+
+/// A denoising loop over the timesteps of a scheduler.
+///
+/// At each step, the latents are scaled by the scheduler and passed to the model closure
+/// together with the step index and the timestep to condition the model on, the
+/// scheduler timestep unless `timestep_map` is used. The closure returns the noise prediction
+/// used for the scheduler step, for classifier free guidance it is in charge of running
+/// both the unconditional and conditional branches.
+#[derive(Default)]
+pub struct DenoiseLoop<'a> {
+    start_step: usize,
+    stop_after_step: Option<usize>,
+    capture_after_step: Option<usize>,
+    early_stopping: Option<EarlyStopping>,
+    post_step: Option<StepHook<'a>>,
+    timestep_map: Option<TimestepMap<'a>>,
+    callbacks: Vec<StepCallback<'a>>,
+    pred_original_callbacks: Vec<StepCallback<'a>>,
+}
+
+impl<'a> DenoiseLoop<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skips the steps before `step`, as done by img2img where the initial latents are
+    /// already partially noised.
+    pub fn start_step(mut self, step: usize) -> Self {
+        self.start_step = step;
+        self
+    }
+
+    /// Continues a run stopped after the step with index `step`, the latents passed to
+    /// `run` are the ones returned by the stopped run, possibly blended with others.
+    pub fn resume_after_step(self, step: usize) -> Self {
+        self.start_step(step + 1)
+    }
+
+    /// Stops the loop once the step with index `step` has been run.
+    pub fn stop_after_step(mut self, step: Option<usize>) -> Self {
+        self.stop_after_step = step;
+        self
+    }
+
+    /// Keeps a copy of the latents after the step with index `step` in the output,
+    /// without stopping the loop.
+    pub fn capture_after_step(mut self, step: Option<usize>) -> Self {
+        self.capture_after_step = step;
+        self
+    }
+
+    /// Stops the loop when the latents change by less than `tolerance` between two steps,
+    /// see `EarlyStopping`.
+    pub fn early_stopping(mut self, tolerance: Option<f64>) -> Self {
+        self.early_stopping = tolerance.map(EarlyStopping::new);
+        self
+    }
+
+    /// Transforms the latents after each scheduler step, e.g. to paste back the unmasked
+    /// part of the original image when inpainting.
+    pub fn post_step<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(usize, Tensor) -> Tensor + 'a,
+    {
+        self.post_step = Some(Box::new(f));
+        self
+    }
+
+    /// Transforms the timestep passed to the model closure, `f` gets called with the step
+    /// index and the scheduler timestep. The scheduler still steps with its own timesteps,
+    /// so this only changes the noise level the model is told about, e.g. to study how
+    /// robust the model is to a wrong timestep conditioning.
+    pub fn timestep_map<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(usize, f64) -> f64 + 'a,
+    {
+        self.timestep_map = Some(Box::new(f));
+        self
+    }
+
+    /// Adds a callback called with the step index and the latents after each step, e.g.
+    /// to report progress or to save intermediary images.
+    pub fn on_step<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(usize, &Tensor) -> anyhow::Result<()> + 'a,
+    {
+        self.callbacks.push(Box::new(f));
+        self
+    }
+
+    /// Adds a callback called with the step index and the denoised latents predicted by
+    /// the scheduler at each step, x_0, e.g. to preview the image emerging from the noise.
+    /// This is only called for the schedulers implementing `pred_original_sample`.
+    pub fn on_pred_original_sample<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(usize, &Tensor) -> anyhow::Result<()> + 'a,
+    {
+        self.pred_original_callbacks.push(Box::new(f));
+        self
+    }
+
+    /// Runs the loop starting from `latents`, these should already be scaled by the
+    /// scheduler initial noise sigma or noised to the first timestep to be run.
+    pub fn run<S, F>(
+        mut self,
+        scheduler: &mut S,
+        latents: Tensor,
+        mut model: F,
+    ) -> anyhow::Result<DenoiseOutput>
+    where
+        S: Scheduler,
+        F: FnMut(usize, f64, &Tensor) -> anyhow::Result<Tensor>,
+    {
+        let timesteps = scheduler.timesteps().to_vec();
+        let mut latents = latents;
+        let mut steps = 0;
+        let mut converged = false;
+        let mut captured = None;
+        for (step_index, &timestep) in timesteps.iter().enumerate().skip(self.start_step) {
+            let model_input = scheduler.scale_model_input(latents.shallow_clone(), timestep);
+            let model_timestep = match self.timestep_map.as_mut() {
+                None => timestep.as_f64(),
+                Some(timestep_map) => timestep_map(step_index, timestep.as_f64()),
+            };
+            let noise_pred = model(step_index, model_timestep, &model_input)?;
+            if !self.pred_original_callbacks.is_empty() {
+                if let Some(pred) = scheduler.pred_original_sample(&noise_pred, timestep, &latents)
+                {
+                    for callback in self.pred_original_callbacks.iter_mut() {
+                        callback(step_index, &pred)?
+                    }
+                }
+            }
+            latents = scheduler.step(&noise_pred, timestep, &latents);
+            if let Some(post_step) = self.post_step.as_mut() {
+                latents = post_step(step_index, latents);
+            }
+            steps += 1;
+            if self.capture_after_step == Some(step_index) {
+                captured = Some(latents.copy())
+            }
+            for callback in self.callbacks.iter_mut() {
+                callback(step_index, &latents)?
+            }
+            if self.stop_after_step == Some(step_index) {
+                break;
+            }
+            if let Some(early_stopping) = self.early_stopping.as_mut() {
+                if early_stopping.converged(&latents) {
+                    converged = true;
+                    break;
+                }
+            }
+        }
+        Ok(DenoiseOutput { latents, steps, converged, captured })
+    }
+}
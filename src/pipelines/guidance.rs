@@ -0,0 +1,285 @@
+//! # Classifier Free Guidance
+//!
+//! Helpers to combine the unconditional and conditional predictions of a
+//! denoising model.
+use crate::utils::{resize, Interpolation};
+use tch::Tensor;
+
+/// Runs `model` on both the unconditional and conditional embeddings and combines
+/// the two predictions with classifier free guidance.
+///
+/// `xs` is the model input for a single guidance branch and `text_embeddings` the
+/// concatenation of the unconditional and conditional embeddings along the batch
+/// dimension. `model` gets called with a model input and the matching embeddings.
+///
+/// When `cfg_batching` is true, both branches are evaluated with a single model
+/// call on a batch twice as large. This is the fastest option but it also doubles
+/// the activation memory. When false, the model is called sequentially on each
+/// branch so that the peak memory is roughly halved at the cost of speed. Both
+/// modes return the same predictions as the models process batch elements
+/// independently. Sliced attention is applied within each model call, so combining
+/// it with sequential guidance further reduces the peak memory of the attention
+/// layers while the slice size still refers to the batch of a single call.
+///
+/// With a `guidance_scale` of 1, as used by the distilled few-step models, the guided
+/// prediction is the conditional one so only the conditional branch is run.
+pub fn guided_prediction<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    guidance_scale: f64,
+    cfg_batching: bool,
+    model: F,
+) -> Tensor
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    guided_prediction_rescaled(xs, text_embeddings, guidance_scale, 0., cfg_batching, model)
+}
+
+/// Same as `guided_prediction` but the guided prediction is then rescaled with
+/// `rescale_noise_cfg`, a `guidance_rescale` of 0 disables the rescaling.
+pub fn guided_prediction_rescaled<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    guidance_scale: f64,
+    guidance_rescale: f64,
+    cfg_batching: bool,
+    mut model: F,
+) -> Tensor
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    if guidance_scale == 1. {
+        // The rescaling is a no-op as the guided prediction is the conditional one.
+        return cond_prediction(xs, text_embeddings, &mut model);
+    }
+    let (pred_uncond, pred_text) = cfg_predictions(xs, text_embeddings, cfg_batching, &mut model);
+    let guided = &pred_uncond + (&pred_text - &pred_uncond) * guidance_scale;
+    if guidance_rescale > 0. {
+        rescale_noise_cfg(&guided, &pred_text, guidance_rescale)
+    } else {
+        guided
+    }
+}
+
+// Returns the conditional prediction only, this is the guided prediction for a guidance
+// scale of 1.
+fn cond_prediction<F>(xs: &Tensor, text_embeddings: &Tensor, model: &mut F) -> Tensor
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    model(xs, &text_embeddings.chunk(2, 0)[1])
+}
+
+// Returns the unconditional and conditional predictions.
+fn cfg_predictions<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    cfg_batching: bool,
+    model: &mut F,
+) -> (Tensor, Tensor)
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    if cfg_batching {
+        let xs = Tensor::cat(&[xs, xs], 0);
+        let preds = model(&xs, text_embeddings).chunk(2, 0);
+        (preds[0].shallow_clone(), preds[1].shallow_clone())
+    } else {
+        let embeddings = text_embeddings.chunk(2, 0);
+        (model(xs, &embeddings[0]), model(xs, &embeddings[1]))
+    }
+}
+
+/// Same as `guided_prediction_rescaled` but with a spatial guidance scale, the guidance
+/// term being scaled per latent pixel. This can be used to follow the prompt closely on
+/// the subject while relaxing the guidance on the background.
+///
+/// `guidance_scale` has shape `[height, width]`, `[1, height, width]`, or
+/// `[batch, 1, height, width]`, the height and width being the ones of the latents, see
+/// `guidance_scale_map` to build it from a mask.
+pub fn guided_prediction_masked<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    guidance_scale: &Tensor,
+    guidance_rescale: f64,
+    cfg_batching: bool,
+    mut model: F,
+) -> anyhow::Result<Tensor>
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    let guidance_scale = spatial_guidance_scale(guidance_scale, xs)?;
+    let (pred_uncond, pred_text) = cfg_predictions(xs, text_embeddings, cfg_batching, &mut model);
+    let guided = &pred_uncond + (&pred_text - &pred_uncond) * guidance_scale;
+    if guidance_rescale > 0. {
+        Ok(rescale_noise_cfg(&guided, &pred_text, guidance_rescale))
+    } else {
+        Ok(guided)
+    }
+}
+
+// Checks that a spatial guidance scale matches the latents and reshapes it so that it
+// broadcasts over the latent channels.
+fn spatial_guidance_scale(guidance_scale: &Tensor, xs: &Tensor) -> anyhow::Result<Tensor> {
+    let size = guidance_scale.size();
+    let xs_size = xs.size();
+    let (height, width) = (xs_size[xs_size.len() - 2], xs_size[xs_size.len() - 1]);
+    let guidance_scale = match size.as_slice() {
+        [h, w] => guidance_scale.view([1, 1, *h, *w]),
+        [1, h, w] => guidance_scale.view([1, 1, *h, *w]),
+        [b, 1, _, _] if *b == 1 || *b == xs_size[0] => guidance_scale.shallow_clone(),
+        _ => anyhow::bail!("unexpected shape for the guidance scale {size:?}"),
+    };
+    let scale_size = guidance_scale.size();
+    if scale_size[2..] != [height, width] {
+        anyhow::bail!(
+            "guidance scale resolution {:?} does not match the latent one {:?}",
+            &scale_size[2..],
+            [height, width]
+        )
+    }
+    Ok(guidance_scale.to_kind(xs.kind()).to_device(xs.device()))
+}
+
+/// Builds a spatial guidance scale for `guided_prediction_masked`, `inside` being used
+/// where `mask` is 1 and `outside` where it is 0. `mask` has shape `[batch, 1, h, w]`
+/// and values in `[0, 1]`, it gets resized to the latent resolution with bilinear
+/// interpolation so that the scale changes smoothly at the edges of soft masks.
+pub fn guidance_scale_map(
+    mask: &Tensor,
+    latent_height: i64,
+    latent_width: i64,
+    inside: f64,
+    outside: f64,
+) -> Tensor {
+    let mask = resize(mask, latent_height, latent_width, Interpolation::Bilinear);
+    mask * (inside - outside) + outside
+}
+
+/// Classifier free guidance combined with perturbed attention guidance (PAG). On top of
+/// the two guidance branches, the model is run a third time on the conditional embeddings
+/// with its self-attention perturbed, and the prediction is pushed away from this
+/// perturbed prediction with a weight of `pag_scale`. The perturbed prediction is skipped
+/// when `pag_scale` is 0 so that this is then the same as `guided_prediction_rescaled`,
+/// and the unconditional one when `guidance_scale` is 1.
+///
+/// `model` gets called with a model input, the matching embeddings, and whether the
+/// self-attention should be perturbed, see `UNet2DConditionModel::set_perturbed_self_attention`.
+///
+/// Self-Rectifying Diffusion Sampling with Perturbed-Attention Guidance, D. Ahn et al, 2024.
+/// https://arxiv.org/abs/2403.17377
+pub fn guided_prediction_pag<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    guidance_scale: f64,
+    guidance_rescale: f64,
+    pag_scale: f64,
+    cfg_batching: bool,
+    mut model: F,
+) -> Tensor
+where
+    F: FnMut(&Tensor, &Tensor, bool) -> Tensor,
+{
+    let mut unperturbed = |xs: &Tensor, embeddings: &Tensor| model(xs, embeddings, false);
+    let (pred_text, mut guided) = if guidance_scale == 1. {
+        let pred_text = cond_prediction(xs, text_embeddings, &mut unperturbed);
+        (pred_text.shallow_clone(), pred_text)
+    } else {
+        let (pred_uncond, pred_text) =
+            cfg_predictions(xs, text_embeddings, cfg_batching, &mut unperturbed);
+        let guided = &pred_uncond + (&pred_text - &pred_uncond) * guidance_scale;
+        (pred_text, guided)
+    };
+    if pag_scale != 0. {
+        let cond_embeddings = text_embeddings.chunk(2, 0)[1].shallow_clone();
+        let pred_perturbed = model(xs, &cond_embeddings, true);
+        guided += (&pred_text - pred_perturbed) * pag_scale;
+    }
+    if guidance_rescale > 0. {
+        rescale_noise_cfg(&guided, &pred_text, guidance_rescale)
+    } else {
+        guided
+    }
+}
+
+/// Classifier free guidance with separate scales for steering toward the prompt and away
+/// from the negative prompt, the prediction being
+/// `uncond + guidance_scale * (cond - uncond) - negative_guidance_scale * (neg - uncond)`.
+///
+/// Here `uncond` is the prediction for the empty prompt and `neg` the one for the negative
+/// prompt. `text_embeddings` is the concatenation of the unconditional, conditional, and
+/// negative embeddings along the batch dimension, so the model is run on three branches.
+/// With an empty negative prompt the last term vanishes and this is the same as
+/// `guided_prediction`. `cfg_batching` has the same meaning as for `guided_prediction`.
+pub fn guided_prediction_with_negative<F>(
+    xs: &Tensor,
+    text_embeddings: &Tensor,
+    guidance_scale: f64,
+    negative_guidance_scale: f64,
+    cfg_batching: bool,
+    mut model: F,
+) -> Tensor
+where
+    F: FnMut(&Tensor, &Tensor) -> Tensor,
+{
+    let preds = if cfg_batching {
+        let xs = Tensor::cat(&[xs, xs, xs], 0);
+        model(&xs, text_embeddings).chunk(3, 0)
+    } else {
+        let embeddings = text_embeddings.chunk(3, 0);
+        embeddings.iter().map(|embeddings| model(xs, embeddings)).collect()
+    };
+    let (pred_uncond, pred_text, pred_neg) = (&preds[0], &preds[1], &preds[2]);
+    pred_uncond + (pred_text - pred_uncond) * guidance_scale
+        - (pred_neg - pred_uncond) * negative_guidance_scale
+}
+
+/// Rescales a guided prediction so that its standard deviation matches the one of the
+/// conditional prediction, this fixes the overexposure caused by high guidance scales.
+/// The result is blended with the original guided prediction using `guidance_rescale`.
+///
+/// Common Diffusion Noise Schedules and Sample Steps are Flawed, S. Lin et al, 2023.
+/// https://arxiv.org/abs/2305.08891
+pub fn rescale_noise_cfg(
+    noise_cfg: &Tensor,
+    noise_pred_text: &Tensor,
+    guidance_rescale: f64,
+) -> Tensor {
+    let dims: Vec<i64> = (1..noise_cfg.dim() as i64).collect();
+    let std_text = noise_pred_text.std_dim(Some(dims.as_slice()), true, true);
+    let std_cfg = noise_cfg.std_dim(Some(dims.as_slice()), true, true);
+    let rescaled = noise_cfg * (std_text / std_cfg);
+    rescaled * guidance_rescale + noise_cfg * (1. - guidance_rescale)
+}
+
+/// A guidance rescale factor that can be restricted to the first steps of the
+/// diffusion, where the overall brightness of the image gets decided.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GuidanceRescale {
+    pub value: f64,
+    /// When set, the rescale factor decreases linearly to reach 0 at this step index.
+    pub end_step: Option<usize>,
+}
+
+impl GuidanceRescale {
+    /// The same rescale factor for all the steps.
+    pub fn constant(value: f64) -> Self {
+        Self { value, end_step: None }
+    }
+
+    /// A rescale factor tapering off linearly from `value` at the first step to 0 at
+    /// `end_step`.
+    pub fn until(value: f64, end_step: usize) -> Self {
+        Self { value, end_step: Some(end_step) }
+    }
+
+    /// The rescale factor to use for a given step index.
+    pub fn value_at(&self, step_index: usize) -> f64 {
+        match self.end_step {
+            None => self.value,
+            Some(end_step) if step_index >= end_step => 0.,
+            Some(end_step) => self.value * (1. - step_index as f64 / end_step as f64),
+        }
+    }
+}
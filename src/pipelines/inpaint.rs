@@ -0,0 +1,104 @@
+//! # Inpainting Helpers
+//!
+//! Utilities to initialize and constrain the latents of an inpainting diffusion
+//! loop. In the functions below, masks use 1 for the region to repaint and 0
+//! for the region to preserve.
+use crate::utils::{resize, Interpolation};
+use tch::{Kind, Tensor};
+
+/// How the masked region is initialized before running the diffusion, these
+/// match the "masked content" options of the AUTOMATIC1111 web ui.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InpaintFillMode {
+    /// Keep the original image content in the masked region. The result tends
+    /// to stay close to the original colors and composition.
+    #[default]
+    Original,
+    /// Replace the masked region by the average color of the preserved region
+    /// before encoding it. This removes the original content while keeping a
+    /// color palette consistent with its surroundings.
+    Fill,
+    /// Replace the masked latents by random noise. This gives the most freedom
+    /// to the model but requires a high denoising strength.
+    LatentNoise,
+    /// Replace the masked latents by zeros. As for `LatentNoise`, this requires
+    /// a high denoising strength to produce something meaningful.
+    LatentNothing,
+}
+
+impl InpaintFillMode {
+    /// Prepares the image to be encoded by the autoencoder. `image` has shape
+    /// `[batch, channels, height, width]` and `mask` has shape `[batch, 1, height, width]`.
+    pub fn prepare_image(&self, image: &Tensor, mask: &Tensor) -> Tensor {
+        match self {
+            Self::Fill => {
+                let keep: Tensor = 1 - mask;
+                let dims = [2i64, 3].as_slice();
+                let n_kept = keep.sum_dim_intlist(Some(dims), true, Kind::Float).clamp_min(1.);
+                let mean = (image * &keep).sum_dim_intlist(Some(dims), true, Kind::Float) / n_kept;
+                image * &keep + mean * mask
+            }
+            Self::Original | Self::LatentNoise | Self::LatentNothing => image.shallow_clone(),
+        }
+    }
+
+    /// Prepares the latents of the encoded image, `mask` should have been resized to
+    /// the latent resolution. The noise is only used by `LatentNoise`.
+    pub fn prepare_latents(&self, latents: &Tensor, mask: &Tensor, noise: &Tensor) -> Tensor {
+        match self {
+            Self::Original | Self::Fill => latents.shallow_clone(),
+            Self::LatentNoise => latents * (1 - mask) + noise * mask,
+            Self::LatentNothing => latents * (1 - mask),
+        }
+    }
+}
+
+/// Resizes an inpainting mask to the latent resolution using nearest interpolation, so
+/// that the mask stays binary.
+pub fn latent_mask(mask: &Tensor, latent_height: i64, latent_width: i64) -> Tensor {
+    resize(mask, latent_height, latent_width, Interpolation::Nearest)
+}
+
+/// Restores the original latents in the preserved region, this can be used over the
+/// last steps of the diffusion so that the preserved region exactly matches the
+/// original image and the boundary with the repainted region is sharper.
+pub fn keep_original_latents(latents: &Tensor, original_latents: &Tensor, mask: &Tensor) -> Tensor {
+    original_latents * (1 - mask) + latents * mask
+}
+
+/// Composites an image over the original one at full resolution, only keeping the
+/// image in the masked region. This is meant for soft inpainting with a regular UNet,
+/// e.g. img2img restricted to a mask, where the diffusion runs on the whole image but
+/// the preserved region still goes through the lossy VAE round trip. `image` and
+/// `original` have shape `[batch, 3, height, width]` and the same value range, `mask`
+/// has shape `[batch, 1, h, w]` with values in `[0, 1]` and is resized to the image
+/// resolution with bilinear interpolation so that soft masks blend smoothly.
+pub fn composite_with_original(image: &Tensor, original: &Tensor, mask: &Tensor) -> Tensor {
+    let (_, _, height, width) = image.size4().unwrap();
+    let mask = resize(&mask.to_device(image.device()), height, width, Interpolation::Bilinear);
+    keep_original_latents(image, &original.to_device(image.device()), &mask)
+}
+
+/// Returns an RGBA version of an inpainted image where the alpha channel is the
+/// inpainting mask, so that the repainted region can be composited over the original
+/// image. `image` has shape `[batch, 3, height, width]` with values in `[0, 1]` and
+/// `mask` has shape `[batch, 1, h, w]`, it is resized to the image resolution if needed.
+///
+/// The mask edges are feathered with a box blur of radius `feather` pixels, use 0 to
+/// keep a hard mask. When `premultiplied` is set, the color channels are multiplied by
+/// the alpha channel.
+pub fn rgba_with_mask(image: &Tensor, mask: &Tensor, feather: i64, premultiplied: bool) -> Tensor {
+    let (_, _, height, width) = image.size4().unwrap();
+    let alpha = mask.to_kind(Kind::Float).to_device(image.device());
+    // Bilinear resizing gives smoother edges than nearest for a soft alpha channel.
+    let alpha = resize(&alpha, height, width, Interpolation::Bilinear);
+    let alpha = if feather > 0 {
+        let k = 2 * feather + 1;
+        alpha.avg_pool2d([k, k], [1, 1], [feather, feather], false, false, None)
+    } else {
+        alpha
+    };
+    let alpha = alpha.clamp(0., 1.).to_kind(image.kind());
+    let image = if premultiplied { image * &alpha } else { image.shallow_clone() };
+    Tensor::cat(&[image, alpha], 1)
+}
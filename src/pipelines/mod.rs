@@ -1,3 +1,15 @@
 //! # Pipelines
 
+pub mod animation;
+pub mod batch;
+pub mod controlnet;
+pub mod denoise;
+pub mod guidance;
+pub mod hires;
+pub mod inpaint;
+pub mod multidiffusion;
+pub mod prompt_schedule;
+pub mod reference;
+pub mod regional;
+pub mod sdxl;
 pub mod stable_diffusion;
@@ -0,0 +1,155 @@
+//! # MultiDiffusion Tiling
+//!
+//! Helpers to denoise a latent canvas larger than the model resolution by running
+//! the model on overlapping tiles and averaging the results over the overlaps.
+//!
+//! MultiDiffusion: Fusing Diffusion Paths for Controlled Image Generation, O. Bar-Tal et al, 2023.
+//! https://arxiv.org/abs/2302.08113
+use tch::{Device, Kind, Tensor};
+
+/// A rectangular region of the latent canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+impl Tile {
+    /// Extracts the tile region from a tensor of shape `[batch, channels, height, width]`.
+    /// The returned tensor shares its storage with the input.
+    pub fn slice(&self, xs: &Tensor) -> Tensor {
+        xs.narrow(2, self.y, self.height).narrow(3, self.x, self.width)
+    }
+}
+
+/// A latent canvas split in overlapping tiles.
+#[derive(Debug, Clone)]
+pub struct TiledCanvas {
+    pub height: i64,
+    pub width: i64,
+    tiles: Vec<Tile>,
+}
+
+// The tile start positions along one dimension, the last tile is aligned with the end
+// of the canvas so that all the tiles have the same size.
+fn tile_starts(size: i64, tile_size: i64, stride: i64) -> Vec<i64> {
+    if size <= tile_size {
+        return vec![0];
+    }
+    let mut starts: Vec<i64> = (0..size - tile_size).step_by(stride as usize).collect();
+    starts.push(size - tile_size);
+    starts
+}
+
+// A triangular (Bartlett) window of length `n`, the values are positive everywhere so
+// that the canvas borders covered by a single tile keep a non-zero weight.
+fn bartlett_window(n: i64, options: (Kind, Device)) -> Tensor {
+    let xs = (Tensor::arange(n, options) + 0.5) * (2. / n as f64) - 1.;
+    1. - xs.abs()
+}
+
+/// The blending weight of each pixel of a tile of size `height` by `width`, this is the
+/// product of a triangular window along each dimension so the weight is the largest at
+/// the tile center and decreases linearly toward the edges.
+pub fn tile_weights(height: i64, width: i64, options: (Kind, Device)) -> Tensor {
+    let wy = bartlett_window(height, options).unsqueeze(1);
+    let wx = bartlett_window(width, options).unsqueeze(0);
+    (wy * wx).view([1, 1, height, width])
+}
+
+impl TiledCanvas {
+    /// Splits a latent canvas of `height` by `width` in tiles of size `tile_size` with
+    /// `overlap` latent pixels of overlap between neighboring tiles.
+    pub fn new(height: i64, width: i64, tile_size: i64, overlap: i64) -> anyhow::Result<Self> {
+        if tile_size <= 0 || overlap < 0 || overlap >= tile_size {
+            anyhow::bail!("invalid tile size {tile_size} or overlap {overlap}")
+        }
+        let stride = tile_size - overlap;
+        let (tile_h, tile_w) = (tile_size.min(height), tile_size.min(width));
+        let mut tiles = vec![];
+        for y in tile_starts(height, tile_size, stride) {
+            for x in tile_starts(width, tile_size, stride) {
+                tiles.push(Tile { x, y, width: tile_w, height: tile_h })
+            }
+        }
+        Ok(Self { height, width, tiles })
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    /// Samples the initial noise once for the whole canvas. The tiles are then sliced from
+    /// this tensor using `Tile::slice` so that overlapping tiles share the same noise,
+    /// sampling independent noise per tile would result in visible seams.
+    pub fn initial_noise(&self, batch_size: i64, channels: i64, device: Device) -> Tensor {
+        Tensor::randn([batch_size, channels, self.height, self.width], (Kind::Float, device))
+    }
+
+    /// Combines the denoised tiles into a full canvas, averaging the overlapping regions.
+    /// `tile_outputs` should contain one tensor per tile, in the order of `tiles`.
+    pub fn blend(&self, tile_outputs: &[Tensor]) -> anyhow::Result<Tensor> {
+        if tile_outputs.len() != self.tiles.len() {
+            anyhow::bail!("expected {} tile outputs, got {}", self.tiles.len(), tile_outputs.len())
+        }
+        let (b, c, _, _) = tile_outputs[0].size4()?;
+        let options = (tile_outputs[0].kind(), tile_outputs[0].device());
+        let values = Tensor::zeros([b, c, self.height, self.width], options);
+        let counts = Tensor::zeros([1, 1, self.height, self.width], options);
+        for (tile, xs) in self.tiles.iter().zip(tile_outputs.iter()) {
+            let _ = tile.slice(&values).g_add_(xs);
+            let _ = tile.slice(&counts).g_add_scalar_(1.);
+        }
+        Ok(values / counts)
+    }
+
+    /// Runs `f` on each of the tiles, in the order of `tiles`, and blends the results with
+    /// `tile_weights` weights. The weighted outputs and the weights are both accumulated
+    /// and the sum of the outputs is divided by the sum of the weights at the end, so that
+    /// the blending weights sum to 1 everywhere including where more than two tiles
+    /// overlap. The outputs of `f` can have a higher resolution than the canvas, e.g. for
+    /// decoding latent tiles with a VAE, `scale` being the ratio between the two.
+    pub fn blend_weighted<F>(&self, scale: i64, mut f: F) -> anyhow::Result<Tensor>
+    where
+        F: FnMut(&Tile) -> anyhow::Result<Tensor>,
+    {
+        let mut values: Option<Tensor> = None;
+        let mut weights: Option<Tensor> = None;
+        for tile in self.tiles.iter() {
+            let xs = f(tile)?;
+            let (b, c, h, w) = xs.size4()?;
+            if h != tile.height * scale || w != tile.width * scale {
+                anyhow::bail!("unexpected tile output size {h}x{w} for tile {tile:?}")
+            }
+            let options = (Kind::Float, xs.device());
+            let scaled = Tile {
+                x: tile.x * scale,
+                y: tile.y * scale,
+                width: tile.width * scale,
+                height: tile.height * scale,
+            };
+            let (height, width) = (self.height * scale, self.width * scale);
+            let values =
+                values.get_or_insert_with(|| Tensor::zeros([b, c, height, width], options));
+            let weights =
+                weights.get_or_insert_with(|| Tensor::zeros([1, 1, height, width], options));
+            let tile_weights = tile_weights(h, w, options);
+            let _ = scaled.slice(values).g_add_(&(xs.to_kind(Kind::Float) * &tile_weights));
+            let _ = scaled.slice(weights).g_add_(&tile_weights);
+        }
+        match (values, weights) {
+            (Some(values), Some(weights)) => Ok(values / weights),
+            _ => anyhow::bail!("the canvas has no tiles"),
+        }
+    }
+
+    /// The sum of the normalized blending weights of `blend_weighted` at each position of
+    /// the canvas, this is 1 everywhere up to rounding errors.
+    pub fn normalized_weight_map(&self, device: Device) -> anyhow::Result<Tensor> {
+        let options = (Kind::Float, device);
+        let ones = |tile: &Tile| Ok(Tensor::ones([1, 1, tile.height, tile.width], options));
+        self.blend_weighted(1, ones)
+    }
+}
@@ -0,0 +1,142 @@
+//! # Prompt Editing
+//!
+//! Parses prompts using the `[from:to:when]` prompt editing syntax of the AUTOMATIC1111
+//! web ui, where `from` is used for the steps before `when` and `to` afterwards. `when`
+//! is either a fraction of the steps when below 1, or a step index. Either side can be
+//! empty, `[from::when]` drops `from` at `when` and `[:to:when]` adds `to` at `when`.
+//!
+//! Brackets that do not follow this syntax are kept as is, e.g. the `[[blue]]`
+//! de-emphasis, and can appear inside an edit. Nested edits are not supported.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Edit { from: String, to: String, step: usize },
+}
+
+// Parses the content of a bracket pair, returns None when it is not a prompt edit.
+fn parse_edit(content: &str, n_steps: usize) -> Option<Segment> {
+    let mut parts = content.split(':');
+    let (from, to, when) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let when: f64 = when.trim().parse().ok()?;
+    if when < 0. {
+        return None;
+    }
+    let step = if when < 1. { (when * n_steps as f64) as usize } else { when as usize };
+    Some(Segment::Edit { from: from.to_string(), to: to.to_string(), step })
+}
+
+// The index of the `]` matching the `[` that starts `s`, if any.
+fn closing_bracket(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_segments(prompt: &str, n_steps: usize) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut rest = prompt;
+    while let Some(start) = rest.find('[') {
+        let end = match closing_bracket(&rest[start..]) {
+            None => {
+                // An unmatched bracket is kept as text, the brackets after it can still
+                // be edits.
+                segments.push(Segment::Text(rest[..=start].to_string()));
+                rest = &rest[start + 1..];
+                continue;
+            }
+            Some(end) => start + end,
+        };
+        let content = &rest[start + 1..end];
+        // Inner brackets that are not edits are kept, e.g. `[[blue]]` or `[[cat]:dog:5]`.
+        let inner = parse_segments(content, n_steps)?;
+        if inner.iter().any(|s| matches!(s, Segment::Edit { .. })) {
+            anyhow::bail!("nested prompt edits are not supported in {prompt}")
+        }
+        match parse_edit(content, n_steps) {
+            Some(edit) => {
+                segments.push(Segment::Text(rest[..start].to_string()));
+                segments.push(edit)
+            }
+            None => segments.push(Segment::Text(rest[..=end].to_string())),
+        }
+        rest = &rest[end + 1..];
+    }
+    segments.push(Segment::Text(rest.to_string()));
+    Ok(segments)
+}
+
+/// The prompts to use over the denoising steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptSchedule {
+    // Each prompt with the step index at which it stops being used, sorted by step.
+    // The last entry always ends at `n_steps`.
+    entries: Vec<(usize, String)>,
+}
+
+impl PromptSchedule {
+    /// Parses a prompt for a diffusion with `n_steps` steps. A prompt without any
+    /// edit results in a single entry.
+    pub fn parse(prompt: &str, n_steps: usize) -> anyhow::Result<Self> {
+        let segments = parse_segments(prompt, n_steps)?;
+        let mut boundaries: Vec<usize> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Edit { step, .. } if *step > 0 && *step < n_steps => Some(*step),
+                _ => None,
+            })
+            .collect();
+        boundaries.push(n_steps);
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        let entries = boundaries
+            .into_iter()
+            .map(|end_step| {
+                // The prompt in use for the steps just before `end_step`.
+                let step = end_step.saturating_sub(1);
+                let prompt: String = segments
+                    .iter()
+                    .map(|s| match s {
+                        Segment::Text(text) => text.as_str(),
+                        Segment::Edit { from, to, step: when } => {
+                            if step < *when {
+                                from.as_str()
+                            } else {
+                                to.as_str()
+                            }
+                        }
+                    })
+                    .collect();
+                (end_step, prompt)
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// The prompts together with the step index at which they stop being used.
+    pub fn entries(&self) -> &[(usize, String)] {
+        &self.entries
+    }
+
+    /// The index of the entry to use at a given step index.
+    pub fn index_at(&self, step_index: usize) -> usize {
+        self.entries
+            .iter()
+            .position(|(end_step, _)| step_index < *end_step)
+            .unwrap_or(self.entries.len() - 1)
+    }
+}
@@ -0,0 +1,118 @@
+//! # Reference-Only Control
+//!
+//! Steers the generation toward the style and content of a reference image without any
+//! additional weights. At each step, the noised reference latents are first run through
+//! the UNet while the keys and values of the self-attention layers are stored. The
+//! self-attention layers of the actual denoising pass then attend to both their own
+//! keys and values and the stored ones.
+use crate::models::attention::{AttentionInputs, AttentionProcessor};
+use crate::models::unet_2d::UNet2DConditionModel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tch::Tensor;
+
+/// What the reference attention does on the next UNet pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceMode {
+    /// Store the self-attention keys and values, this is used for the reference pass.
+    Write,
+    /// Attend to the stored keys and values in addition to the layer ones.
+    Read,
+    /// Regular attention.
+    Disabled,
+}
+
+/// An attention processor implementing reference-only control.
+///
+/// `reference_scale` blends the output of the self-attention layers attending to the
+/// reference with their regular output, 1 only using the former and 0 disabling the
+/// reference.
+#[derive(Debug)]
+pub struct ReferenceAttention {
+    reference_scale: f64,
+    mode: Mutex<ReferenceMode>,
+    stored: Mutex<HashMap<String, (Tensor, Tensor)>>,
+}
+
+impl ReferenceAttention {
+    pub fn new(reference_scale: f64) -> Arc<Self> {
+        Arc::new(Self {
+            reference_scale,
+            mode: Mutex::new(ReferenceMode::Disabled),
+            stored: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Installs the processor on all the attention layers of `unet`, only the
+    /// self-attention ones are affected.
+    pub fn install(self: &Arc<Self>, unet: &mut UNet2DConditionModel) -> anyhow::Result<()> {
+        let processor: Arc<dyn AttentionProcessor> = self.clone();
+        unet.set_attention_processor("", Some(processor))?;
+        Ok(())
+    }
+
+    /// Sets the mode for the next UNet passes. Switching to `Write` drops the keys and
+    /// values stored by the previous reference pass.
+    pub fn set_mode(&self, mode: ReferenceMode) {
+        if mode == ReferenceMode::Write {
+            self.stored.lock().unwrap().clear()
+        }
+        *self.mode.lock().unwrap() = mode
+    }
+
+    /// Runs the UNet on the noised reference latents in `Write` mode and switches to `Read`
+    /// mode. `reference_latents` should be noised to `timestep` and scaled by the scheduler.
+    pub fn store_reference(
+        &self,
+        unet: &UNet2DConditionModel,
+        reference_latents: &Tensor,
+        timestep: f64,
+        encoder_hidden_states: &Tensor,
+    ) {
+        self.set_mode(ReferenceMode::Write);
+        let _ = unet.forward(reference_latents, timestep, encoder_hidden_states);
+        self.set_mode(ReferenceMode::Read);
+    }
+}
+
+// Repeats the stored batch so that it matches the batch of the queries, e.g. when the
+// reference pass only used the conditional embeddings and the generation runs both
+// classifier free guidance branches at once.
+fn match_batch(xs: &Tensor, batch_size: i64) -> Tensor {
+    let stored_batch_size = xs.size()[0];
+    if stored_batch_size == batch_size {
+        xs.shallow_clone()
+    } else {
+        xs.repeat([batch_size / stored_batch_size, 1, 1])
+    }
+}
+
+impl AttentionProcessor for ReferenceAttention {
+    fn forward(&self, inputs: &AttentionInputs) -> Tensor {
+        let (query, key, value) = (inputs.query(), inputs.key(), inputs.value());
+        let mode = *self.mode.lock().unwrap();
+        if inputs.is_cross_attention() || mode == ReferenceMode::Disabled {
+            return inputs.attention(&query, &key, &value);
+        }
+        if mode == ReferenceMode::Write {
+            let mut stored = self.stored.lock().unwrap();
+            stored.insert(inputs.path().to_string(), (key.shallow_clone(), value.shallow_clone()));
+            return inputs.attention(&query, &key, &value);
+        }
+        let stored = self.stored.lock().unwrap();
+        let (ref_key, ref_value) = match stored.get(inputs.path()) {
+            None => return inputs.attention(&query, &key, &value),
+            Some(kv) => kv,
+        };
+        let batch_size = query.size()[0];
+        let key_ref = Tensor::cat(&[&key, &match_batch(ref_key, batch_size)], 1);
+        let value_ref = Tensor::cat(&[&value, &match_batch(ref_value, batch_size)], 1);
+        let with_reference = inputs.attention(&query, &key_ref, &value_ref);
+        if self.reference_scale == 1. {
+            with_reference
+        } else {
+            let without_reference = inputs.attention(&query, &key, &value);
+            with_reference * self.reference_scale + without_reference * (1. - self.reference_scale)
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! # Regional Prompting
+//!
+//! Applies different prompts to different rectangular regions of the image by masking
+//! the cross-attention of each prompt outside of its region, see
+//! `UNet2DConditionModel::set_regional_attention`.
+use crate::models::attention::{Region, RegionalAttention};
+use tch::Tensor;
+
+/// Builds the text embeddings and the regional attention for some prompts.
+///
+/// `prompt_embeddings` contains one embedding tensor of shape `[1, seq_len, dim]` per
+/// region, `uncond_embeddings` has shape `[1, uncond_seq_len, dim]` and is repeated so
+/// as to match each of the prompts, hence the prompt sequence lengths should be multiples
+/// of `uncond_seq_len`. The returned embeddings have the unconditional embeddings first
+/// and can be used for classifier free guidance.
+pub fn regional_embeddings(
+    uncond_embeddings: &Tensor,
+    prompt_embeddings: &[(Region, Tensor)],
+    latent_height: i64,
+    latent_width: i64,
+) -> anyhow::Result<(Tensor, RegionalAttention)> {
+    if prompt_embeddings.is_empty() {
+        anyhow::bail!("regional prompting requires at least one region")
+    }
+    let uncond_len = uncond_embeddings.size()[1];
+    let mut uncond = vec![];
+    let mut regions = vec![];
+    for (region, embeddings) in prompt_embeddings.iter() {
+        let seq_len = embeddings.size()[1];
+        if seq_len % uncond_len != 0 {
+            anyhow::bail!("prompt length {seq_len} is not a multiple of {uncond_len}")
+        }
+        uncond.push(uncond_embeddings.repeat([1, seq_len / uncond_len, 1]));
+        regions.push((*region, seq_len))
+    }
+    let prompts: Vec<&Tensor> = prompt_embeddings.iter().map(|(_, e)| e).collect();
+    let embeddings = Tensor::cat(&[Tensor::cat(&uncond, 1), Tensor::cat(&prompts, 1)], 0);
+    let regional = RegionalAttention::new(regions, latent_height, latent_width)?;
+    Ok((embeddings, regional))
+}
@@ -0,0 +1,188 @@
+//! # Stable Diffusion XL
+//!
+//! SDXL conditions its UNet on the penultimate hidden states of two text encoders,
+//! concatenated along the embedding dimension, and on an added conditioning made of the
+//! pooled embedding of the second text encoder and of the size and crop time ids, see
+//! `UNet2DConditionModel::forward_with_added_cond`. The models can be built with the
+//! `StableDiffusionConfig::sdxl` configuration.
+//!
+//! The size conditioning defaults to the generation size without any crop, other values
+//! can be set with `SizeConditioning`. A ControlNet can guide the generation, see
+//! `SdxlControl`.
+use crate::models::controlnet::ControlNet;
+use crate::models::embeddings::{sdxl_time_ids, AddedCond};
+use crate::models::unet_2d::UNet2DConditionModel;
+use crate::pipelines::denoise::DenoiseLoop;
+use crate::schedulers::Scheduler;
+use crate::transformers::clip;
+use std::sync::Arc;
+use tch::Tensor;
+
+/// The size and crop micro-conditioning, sizes are `(height, width)` and the crop
+/// coordinates `(top, left)` in pixels. The sizes default to the generation size and
+/// the crop to `(0, 0)`, as in diffusers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeConditioning {
+    pub original_size: Option<(i64, i64)>,
+    pub crop_coords_top_left: (i64, i64),
+    pub target_size: Option<(i64, i64)>,
+}
+
+impl SizeConditioning {
+    /// The time ids of a `height x width` generation, with shape `[1, 6]`.
+    pub fn time_ids(&self, height: i64, width: i64) -> Tensor {
+        let size = (height, width);
+        let original_size = self.original_size.unwrap_or(size);
+        sdxl_time_ids(original_size, self.crop_coords_top_left, self.target_size.unwrap_or(size))
+    }
+}
+
+/// The text conditioning for classifier free guidance, the negative prompt embeddings
+/// being followed by the prompt ones along the batch dimension.
+#[derive(Debug)]
+pub struct SdxlPromptEmbeds {
+    /// The UNet encoder hidden states, `[2, seq_len, 2048]` for SDXL.
+    pub context: Tensor,
+    /// The pooled embeddings of the second text encoder, `[2, 1280]` for SDXL.
+    pub pooled: Tensor,
+}
+
+impl SdxlPromptEmbeds {
+    /// The added conditioning of both guidance branches for a `height x width`
+    /// generation.
+    pub fn added_cond(&self, size: &SizeConditioning, height: i64, width: i64) -> AddedCond {
+        AddedCond {
+            text_embeds: self.pooled.shallow_clone(),
+            time_ids: size.time_ids(height, width),
+        }
+    }
+}
+
+/// The two text encoders of SDXL with their tokenizers, a CLIP ViT-L text model and an
+/// OpenCLIP ViT-bigG one with its text projection, see `clip::Config::sdxl_text_encoder_2`.
+pub struct SdxlTextEncoders {
+    pub tokenizer: Arc<clip::Tokenizer>,
+    pub text_encoder: Arc<clip::ClipTextTransformer>,
+    pub tokenizer_2: Arc<clip::Tokenizer>,
+    pub text_encoder_2: Arc<clip::ClipTextModelWithProjection>,
+}
+
+impl SdxlTextEncoders {
+    // Returns the context, `[1, seq_len, 2048]`, and the pooled embedding, `[1, 1280]`,
+    // on the device of the first text encoder.
+    fn encode(&self, prompt: &str) -> anyhow::Result<(Tensor, Tensor)> {
+        let tokens = |tokenizer: &clip::Tokenizer, device| -> anyhow::Result<Tensor> {
+            let tokens = tokenizer.encode_with(prompt, clip::PaddingStrategy::MaxLength, true)?;
+            let tokens: Vec<i64> = tokens.into_iter().map(|x| x as i64).collect();
+            Ok(Tensor::from_slice(&tokens).unsqueeze(0).to(device))
+        };
+        let device = self.text_encoder.device();
+        let (hidden_states, _) =
+            self.text_encoder.forward_hidden_states(&tokens(&self.tokenizer, device)?);
+        let context = &hidden_states[hidden_states.len().saturating_sub(2)];
+        let device_2 = self.text_encoder_2.text_model().device();
+        let output = self.text_encoder_2.forward(&tokens(&self.tokenizer_2, device_2)?);
+        let context_2 = output.penultimate_hidden_states.to_device(device);
+        let context = Tensor::cat(&[context, &context_2.to_kind(context.kind())], -1);
+        Ok((context, output.pooled_embeds.to_device(device)))
+    }
+
+    /// Embeds a prompt and a negative prompt, both truncated to the maximum length of the
+    /// text encoders. As with `force_zeros_for_empty_prompt` in the SDXL base model
+    /// configuration, an empty negative prompt gets zero embeddings.
+    pub fn encode_prompt(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+    ) -> anyhow::Result<SdxlPromptEmbeds> {
+        tch::no_grad(|| {
+            let (context, pooled) = self.encode(prompt)?;
+            let (uncond_context, uncond_pooled) = if negative_prompt.is_empty() {
+                (context.zeros_like(), pooled.zeros_like())
+            } else {
+                self.encode(negative_prompt)?
+            };
+            Ok(SdxlPromptEmbeds {
+                context: Tensor::cat(&[uncond_context, context], 0),
+                pooled: Tensor::cat(&[uncond_pooled, pooled], 0),
+            })
+        })
+    }
+}
+
+/// A ControlNet guiding the SDXL UNet, e.g. built with `ControlNetConfig::sdxl`. Its
+/// residuals are added to the UNet skip connections at each step.
+pub struct SdxlControl<'a> {
+    pub controlnet: &'a ControlNet,
+    /// The conditioning image, see `ControlNet::forward`.
+    pub conditioning: &'a Tensor,
+    pub conditioning_scale: f64,
+}
+
+/// The noise prediction with classifier free guidance for latents `xs` of a single
+/// guidance branch, both branches are evaluated with a single UNet call. With a
+/// `guidance_scale` of 1 only the conditional branch is run.
+pub fn guided_noise_pred(
+    unet: &UNet2DConditionModel,
+    control: Option<&SdxlControl>,
+    xs: &Tensor,
+    timestep: f64,
+    embeds: &SdxlPromptEmbeds,
+    added_cond: &AddedCond,
+    guidance_scale: f64,
+) -> Tensor {
+    let forward = |xs: &Tensor, context: &Tensor, added_cond: &AddedCond| match control {
+        None => unet.forward_with_added_cond(xs, timestep, context, added_cond),
+        Some(control) => {
+            let (down_residuals, mid_residual) = control.controlnet.forward_with_added_cond(
+                xs,
+                timestep,
+                context,
+                control.conditioning,
+                control.conditioning_scale,
+                added_cond,
+            );
+            unet.forward_with_added_cond_and_residuals(
+                xs,
+                timestep,
+                context,
+                added_cond,
+                Some(&down_residuals),
+                Some(&mid_residual),
+            )
+        }
+    };
+    if guidance_scale == 1. {
+        let cond = AddedCond {
+            text_embeds: added_cond.text_embeds.narrow(0, 1, 1),
+            time_ids: added_cond.time_ids.shallow_clone(),
+        };
+        return forward(xs, &embeds.context.narrow(0, 1, 1), &cond);
+    }
+    let xs = Tensor::cat(&[xs, xs], 0);
+    let noise_pred = forward(&xs, &embeds.context, added_cond).chunk(2, 0);
+    let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
+    noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * guidance_scale
+}
+
+/// Denoises `latents`, the initial noise scaled by the scheduler `init_noise_sigma`, for
+/// a `height x width` generation with the time ids of `size`.
+pub fn denoise<S: Scheduler>(
+    unet: &UNet2DConditionModel,
+    control: Option<&SdxlControl>,
+    scheduler: &mut S,
+    embeds: &SdxlPromptEmbeds,
+    size: &SizeConditioning,
+    height: i64,
+    width: i64,
+    guidance_scale: f64,
+    latents: Tensor,
+) -> anyhow::Result<Tensor> {
+    let added_cond = embeds.added_cond(size, height, width);
+    let output = DenoiseLoop::new().run(scheduler, latents, |_step_index, timestep, xs| {
+        Ok(tch::no_grad(|| {
+            guided_noise_pred(unet, control, xs, timestep, embeds, &added_cond, guidance_scale)
+        }))
+    })?;
+    Ok(output.latents)
+}
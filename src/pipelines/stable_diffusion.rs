@@ -1,17 +1,64 @@
-use crate::models::{unet_2d, vae};
-use crate::schedulers::ddim;
-use crate::schedulers::PredictionType;
+use crate::checkpoint;
+use crate::models::embeddings::TextTimeEmbeddingConfig;
+use crate::models::{consistency_decoder, lora, unet_2d, vae};
+use crate::schedulers::config::{PretrainedSchedulerConfig, SchedulerConfig};
+use crate::schedulers::{ddim, lcm, tcd};
+use crate::schedulers::{PredictionType, Scheduler};
 use crate::transformers::clip;
-use tch::{nn, Device};
+use std::sync::{Arc, Mutex};
+use tch::{nn, nn::Module, Device, Kind, Tensor};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StableDiffusionConfig {
     pub width: i64,
     pub height: i64,
     pub clip: clip::Config,
+    /// The second text encoder of SDXL, see `pipelines::sdxl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clip2: Option<clip::Config>,
     autoencoder: vae::AutoEncoderKLConfig,
     unet: unet_2d::UNet2DConditionModelConfig,
-    scheduler: ddim::DDIMSchedulerConfig,
+    scheduler: SchedulerConfig,
+    /// The kind of the model weights, `None` when not recorded.
+    #[serde(default, serialize_with = "serialize_kind", deserialize_with = "deserialize_kind")]
+    weights_kind: Option<Kind>,
+    /// The LoRAs applied to the model weights.
+    #[serde(default)]
+    loras: Vec<LoraConfig>,
+}
+
+/// A LoRA applied to the weights of a pipeline, as recorded in its json configuration.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoraConfig {
+    pub name: String,
+    pub strength: f64,
+}
+
+// The weights kind is recorded with the tch name of the kind, e.g. `Half`.
+fn serialize_kind<S: serde::Serializer>(
+    kind: &Option<Kind>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&kind.map(|kind| format!("{kind:?}")), serializer)
+}
+
+fn deserialize_kind<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Kind>, D::Error> {
+    let name: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    let name = match name {
+        None => return Ok(None),
+        Some(name) => name,
+    };
+    match [Kind::Half, Kind::BFloat16, Kind::Float, Kind::Double]
+        .into_iter()
+        .find(|kind| format!("{kind:?}") == name)
+    {
+        Some(kind) => Ok(Some(kind)),
+        None => Err(serde::de::Error::custom(format!("unsupported weights kind {name:?}"))),
+    }
 }
 
 impl StableDiffusionConfig {
@@ -24,6 +71,8 @@ impl StableDiffusionConfig {
             out_channels,
             use_cross_attn,
             attention_head_dim,
+            cross_attention_dim: None,
+            transformer_layers_per_block: 1,
         };
         // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/unet/config.json
         let unet = unet_2d::UNet2DConditionModelConfig {
@@ -39,12 +88,18 @@ impl StableDiffusionConfig {
             norm_num_groups: 32,
             sliced_attention_size,
             use_linear_projection: false,
+            text_time_embedding: None,
         };
         let autoencoder = vae::AutoEncoderKLConfig {
             block_out_channels: vec![128, 256, 512, 512],
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            scaling_factor: 0.18215,
+            shift_factor: 0.,
+            use_quant_conv: true,
+            use_post_quant_conv: true,
+            output_range: vae::OutputRange::MinusOneToOne,
         };
         let height = if let Some(height) = height {
             assert_eq!(height % 8, 0, "heigh has to be divisible by 8");
@@ -64,8 +119,11 @@ impl StableDiffusionConfig {
             width,
             height,
             clip: clip::Config::v1_5(),
+            clip2: None,
             autoencoder,
             scheduler: Default::default(),
+            weights_kind: None,
+            loras: vec![],
             unet,
         }
     }
@@ -80,6 +138,8 @@ impl StableDiffusionConfig {
             out_channels,
             use_cross_attn,
             attention_head_dim,
+            cross_attention_dim: None,
+            transformer_layers_per_block: 1,
         };
         // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/unet/config.json
         let unet = unet_2d::UNet2DConditionModelConfig {
@@ -100,6 +160,7 @@ impl StableDiffusionConfig {
             norm_num_groups: 32,
             sliced_attention_size,
             use_linear_projection: true,
+            text_time_embedding: None,
         };
         // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/vae/config.json
         let autoencoder = vae::AutoEncoderKLConfig {
@@ -107,8 +168,14 @@ impl StableDiffusionConfig {
             layers_per_block: 2,
             latent_channels: 4,
             norm_num_groups: 32,
+            scaling_factor: 0.18215,
+            shift_factor: 0.,
+            use_quant_conv: true,
+            use_post_quant_conv: true,
+            output_range: vae::OutputRange::MinusOneToOne,
         };
         let scheduler = ddim::DDIMSchedulerConfig { prediction_type, ..Default::default() };
+        let scheduler = SchedulerConfig::Ddim(scheduler);
 
         let height = if let Some(height) = height {
             assert_eq!(height % 8, 0, "heigh has to be divisible by 8");
@@ -124,7 +191,17 @@ impl StableDiffusionConfig {
             768
         };
 
-        Self { width, height, clip: clip::Config::v2_1(), autoencoder, scheduler, unet }
+        Self {
+            width,
+            height,
+            clip: clip::Config::v2_1(),
+            clip2: None,
+            autoencoder,
+            scheduler,
+            weights_kind: None,
+            loras: vec![],
+            unet,
+        }
     }
 
     pub fn v2_1(
@@ -147,6 +224,182 @@ impl StableDiffusionConfig {
         Self::v2_1_(sliced_attention_size, height, width, PredictionType::Epsilon)
     }
 
+    /// Stable Diffusion XL, the text conditioning uses a second text encoder and the UNet
+    /// the size and crop conditioning of `pipelines::sdxl`.
+    pub fn sdxl(
+        sliced_attention_size: Option<i64>,
+        height: Option<i64>,
+        width: Option<i64>,
+    ) -> Self {
+        let bc =
+            |out_channels, use_cross_attn, attention_head_dim, transformer_layers_per_block| {
+                unet_2d::BlockConfig {
+                    out_channels,
+                    use_cross_attn,
+                    attention_head_dim,
+                    cross_attention_dim: None,
+                    transformer_layers_per_block,
+                }
+            };
+        // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/unet/config.json
+        let unet = unet_2d::UNet2DConditionModelConfig {
+            blocks: vec![bc(320, false, 5, 1), bc(640, true, 10, 2), bc(1280, true, 20, 10)],
+            center_input_sample: false,
+            cross_attention_dim: 2048,
+            downsample_padding: 1,
+            flip_sin_to_cos: true,
+            freq_shift: 0.,
+            layers_per_block: 2,
+            mid_block_scale_factor: 1.,
+            norm_eps: 1e-5,
+            norm_num_groups: 32,
+            sliced_attention_size,
+            use_linear_projection: true,
+            text_time_embedding: Some(TextTimeEmbeddingConfig {
+                addition_time_embed_dim: 256,
+                projection_class_embeddings_input_dim: 2816,
+            }),
+        };
+        // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/vae/config.json
+        let autoencoder = vae::AutoEncoderKLConfig {
+            block_out_channels: vec![128, 256, 512, 512],
+            layers_per_block: 2,
+            latent_channels: 4,
+            norm_num_groups: 32,
+            scaling_factor: 0.13025,
+            shift_factor: 0.,
+            use_quant_conv: true,
+            use_post_quant_conv: true,
+            output_range: vae::OutputRange::MinusOneToOne,
+        };
+        let height = if let Some(height) = height {
+            assert_eq!(height % 8, 0, "heigh has to be divisible by 8");
+            height
+        } else {
+            1024
+        };
+
+        let width = if let Some(width) = width {
+            assert_eq!(width % 8, 0, "width has to be divisible by 8");
+            width
+        } else {
+            1024
+        };
+
+        Self {
+            width,
+            height,
+            clip: clip::Config::v1_5(),
+            clip2: Some(clip::Config::sdxl_text_encoder_2()),
+            autoencoder,
+            scheduler: Default::default(),
+            weights_kind: None,
+            loras: vec![],
+            unet,
+        }
+    }
+
+    /// Returns the full configuration as json: the image size, the configurations of
+    /// the text encoder, autoencoder, UNet, and scheduler, and the weights kind and LoRAs
+    /// when recorded. `StableDiffusion::config_json` records the scheduler, weights kind,
+    /// and LoRAs of a built pipeline.
+    pub fn config_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the config is always serializable")
+    }
+
+    /// Builds a configuration from the json returned by `config_json` or
+    /// `StableDiffusion::config_json`, so that a pipeline can be rebuilt with the exact
+    /// same settings. All the schedulers of `SchedulerKind` are supported, unknown keys
+    /// and schedulers are rejected and the values are validated.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_str(json)
+            .map_err(|e| anyhow::Error::new(e).context("invalid stable diffusion config"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Records the scheduler in use, see `Scheduler::scheduler_config`.
+    pub fn with_scheduler(mut self, scheduler: SchedulerConfig) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Records the kind of the model weights.
+    pub fn with_weights_kind(mut self, kind: Kind) -> Self {
+        self.weights_kind = Some(kind);
+        self
+    }
+
+    /// Records a LoRA applied to the model weights, replacing the previous strength of a
+    /// LoRA with the same name.
+    pub fn with_lora(mut self, name: &str, strength: f64) -> Self {
+        match self.loras.iter_mut().find(|lora| lora.name == name) {
+            Some(lora) => lora.strength = strength,
+            None => self.loras.push(LoraConfig { name: name.to_string(), strength }),
+        }
+        self
+    }
+
+    /// The recorded kind of the model weights.
+    pub fn weights_kind(&self) -> Option<Kind> {
+        self.weights_kind
+    }
+
+    /// The recorded LoRAs.
+    pub fn loras(&self) -> &[LoraConfig] {
+        &self.loras
+    }
+
+    /// Checks that the configurations of the components are valid and consistent.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.clip.validate()?;
+        if let Some(clip2) = self.clip2.as_ref() {
+            clip2.validate()?
+        }
+        match (self.unet.text_time_embedding, self.clip2.as_ref()) {
+            (None, None) => {}
+            (Some(text_time), Some(clip2)) => {
+                let context_dim = self.clip.embed_dim() + clip2.embed_dim();
+                if context_dim != self.unet.cross_attention_dim {
+                    anyhow::bail!(
+                        "the concatenated text encoder outputs have {context_dim} channels but the unet cross_attention_dim is {}",
+                        self.unet.cross_attention_dim
+                    )
+                }
+                let input_dim = clip2.projection_dim() + 6 * text_time.addition_time_embed_dim;
+                if input_dim != text_time.projection_class_embeddings_input_dim {
+                    anyhow::bail!(
+                        "the added conditioning has {input_dim} channels but the unet projection_class_embeddings_input_dim is {}",
+                        text_time.projection_class_embeddings_input_dim
+                    )
+                }
+            }
+            (Some(_), None) => anyhow::bail!("the unet added conditioning requires a clip2 config"),
+            (None, Some(_)) => anyhow::bail!("clip2 is only used with the unet added conditioning"),
+        }
+        self.autoencoder.validate()?;
+        self.unet.validate()?;
+        self.scheduler.validate()?;
+        self.autoencoder.latent_shape(self.width, self.height, 1)?;
+        Ok(())
+    }
+
+    pub fn unet_config(&self) -> &unet_2d::UNet2DConditionModelConfig {
+        &self.unet
+    }
+
+    /// The number of channels of the latent space, the UNet outputs use this number of
+    /// channels and so do its inputs except for inpainting models.
+    pub fn latent_channels(&self) -> i64 {
+        self.autoencoder.latent_channels
+    }
+
+    /// The latent shape for a batch of images of the configured size, see
+    /// `AutoEncoderKLConfig::latent_shape`.
+    pub fn latent_shape(&self, batch: i64) -> anyhow::Result<[i64; 4]> {
+        self.autoencoder.latent_shape(self.width, self.height, batch)
+    }
+
     pub fn build_vae(
         &self,
         vae_weights: &str,
@@ -155,25 +408,154 @@ impl StableDiffusionConfig {
         let mut vs_ae = nn::VarStore::new(device);
         // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
         let autoencoder = vae::AutoEncoderKL::new(vs_ae.root(), 3, 3, self.autoencoder.clone());
-        vs_ae.load(vae_weights)?;
+        load_weights(&mut vs_ae, vae_weights)?;
         Ok(autoencoder)
     }
 
+    /// Builds the consistency decoder, a sharper replacement for the autoencoder decoder
+    /// to be set with `StableDiffusion::set_decoder`. It is only compatible with the
+    /// stable diffusion 1.x latents.
+    pub fn build_consistency_decoder(
+        &self,
+        weights: &str,
+        device: Device,
+    ) -> anyhow::Result<consistency_decoder::ConsistencyDecoder> {
+        let mut vs = nn::VarStore::new(device);
+        // https://huggingface.co/openai/consistency-decoder/blob/main/config.json
+        let config = consistency_decoder::ConsistencyDecoderConfig {
+            scaling_factor: self.autoencoder.scaling_factor,
+            ..Default::default()
+        };
+        let decoder = consistency_decoder::ConsistencyDecoder::new(vs.root(), config);
+        load_weights(&mut vs, weights)?;
+        Ok(decoder)
+    }
+
     pub fn build_unet(
         &self,
         unet_weights: &str,
         device: Device,
         in_channels: i64,
     ) -> anyhow::Result<unet_2d::UNet2DConditionModel> {
+        Ok(self.build_unet_with_var_store(unet_weights, device, in_channels)?.0)
+    }
+
+    /// Same as `build_unet` but also returns the var store holding the UNet weights, e.g.
+    /// to merge a LoRA with `models::lora::merge_lora`.
+    pub fn build_unet_with_var_store(
+        &self,
+        unet_weights: &str,
+        device: Device,
+        in_channels: i64,
+    ) -> anyhow::Result<(unet_2d::UNet2DConditionModel, nn::VarStore)> {
         let mut vs_unet = nn::VarStore::new(device);
-        let unet =
-            unet_2d::UNet2DConditionModel::new(vs_unet.root(), in_channels, 4, self.unet.clone());
-        vs_unet.load(unet_weights)?;
+        let unet = unet_2d::UNet2DConditionModel::new(
+            vs_unet.root(),
+            in_channels,
+            self.latent_channels(),
+            self.unet.clone(),
+        );
+        load_weights(&mut vs_unet, unet_weights)?;
+        Ok((unet, vs_unet))
+    }
+
+    /// Same as `build_unet` but the linear layers of the attention blocks run with int8
+    /// weights, see `UNet2DConditionModel::quantize_int8`. This requires the CPU device.
+    pub fn build_unet_int8(
+        &self,
+        unet_weights: &str,
+        device: Device,
+        in_channels: i64,
+    ) -> anyhow::Result<unet_2d::UNet2DConditionModel> {
+        let mut unet = self.build_unet(unet_weights, device, in_channels)?;
+        unet.quantize_int8()?;
         Ok(unet)
     }
 
+    /// The scheduler configuration, DDIM unless another scheduler has been recorded with
+    /// `with_scheduler`.
+    pub fn scheduler(&self) -> &SchedulerConfig {
+        &self.scheduler
+    }
+
+    /// The DDIM configuration with the noise schedule and prediction type in use.
+    pub fn scheduler_config(&self) -> ddim::DDIMSchedulerConfig {
+        self.scheduler.ddim_config()
+    }
+
+    /// Uses the noise schedule and prediction type of a model `scheduler_config.json`
+    /// file rather than the built-in ones.
+    pub fn with_scheduler_config(mut self, config: &PretrainedSchedulerConfig) -> Self {
+        self.scheduler = SchedulerConfig::Ddim(config.ddim_config());
+        self
+    }
+
+    /// Uses the noise schedule and prediction type of a diffusers model folder, read from
+    /// its `scheduler/scheduler_config.json` file. The configuration is returned unchanged
+    /// when the folder has no scheduler config.
+    pub fn with_model_dir<P: AsRef<std::path::Path>>(self, model_dir: P) -> anyhow::Result<Self> {
+        match PretrainedSchedulerConfig::from_model_dir(model_dir)? {
+            None => Ok(self),
+            Some(config) => Ok(self.with_scheduler_config(&config)),
+        }
+    }
+
+    /// Overrides the prediction type of the scheduler, see
+    /// `schedulers::config::check_prediction_type` to check it against the model one.
+    pub fn with_prediction_type(mut self, prediction_type: PredictionType) -> Self {
+        self.scheduler = self.scheduler.with_prediction_type(prediction_type);
+        self
+    }
+
+    pub fn prediction_type(&self) -> PredictionType {
+        self.scheduler.prediction_type()
+    }
+
     pub fn build_scheduler(&self, n_steps: usize) -> ddim::DDIMScheduler {
-        ddim::DDIMScheduler::new(n_steps, self.scheduler)
+        ddim::DDIMScheduler::new(n_steps, self.scheduler_config())
+    }
+
+    /// An LCM scheduler with the noise schedule and prediction type in use, for the
+    /// few-step sampling of LCM and turbo models or of models with an LCM-LoRA. A recorded
+    /// LCM configuration is used as is.
+    pub fn build_lcm_scheduler(&self, n_steps: usize) -> lcm::LCMScheduler {
+        let config = match self.scheduler {
+            SchedulerConfig::Lcm(config) => config,
+            _ => {
+                let ddim = self.scheduler_config();
+                lcm::LCMSchedulerConfig {
+                    beta_start: ddim.beta_start,
+                    beta_end: ddim.beta_end,
+                    beta_schedule: ddim.beta_schedule,
+                    prediction_type: ddim.prediction_type,
+                    train_timesteps: ddim.train_timesteps,
+                    ..Default::default()
+                }
+            }
+        };
+        lcm::LCMScheduler::new(n_steps, config)
+    }
+
+    /// A TCD scheduler with the noise schedule and prediction type in use and the given
+    /// stochasticity `eta`, for the models using a TCD-LoRA. The other settings of a
+    /// recorded TCD configuration are kept.
+    pub fn build_tcd_scheduler(&self, n_steps: usize, eta: f64) -> tcd::TCDScheduler {
+        let config = match self.scheduler {
+            SchedulerConfig::Tcd(config) => tcd::TCDSchedulerConfig { eta, ..config },
+            _ => {
+                let ddim = self.scheduler_config();
+                tcd::TCDSchedulerConfig {
+                    beta_start: ddim.beta_start,
+                    beta_end: ddim.beta_end,
+                    beta_schedule: ddim.beta_schedule,
+                    prediction_type: ddim.prediction_type,
+                    train_timesteps: ddim.train_timesteps,
+                    eta,
+                    ..Default::default()
+                }
+            }
+        };
+        tcd::TCDScheduler::new(n_steps, config)
     }
 
     pub fn build_clip_transformer(
@@ -181,9 +563,301 @@ impl StableDiffusionConfig {
         clip_weights: &str,
         device: tch::Device,
     ) -> anyhow::Result<clip::ClipTextTransformer> {
+        Ok(self.build_clip_transformer_with_var_store(clip_weights, device)?.0)
+    }
+
+    /// Same as `build_clip_transformer` but also returns the var store holding the text
+    /// encoder weights.
+    /// Builds the second text encoder of SDXL, with its text projection.
+    pub fn build_clip2_transformer(
+        &self,
+        clip2_weights: &str,
+        device: tch::Device,
+    ) -> anyhow::Result<clip::ClipTextModelWithProjection> {
+        let clip2 = match self.clip2.as_ref() {
+            Some(clip2) => clip2,
+            None => anyhow::bail!("the config has no second text encoder"),
+        };
         let mut vs = tch::nn::VarStore::new(device);
-        let text_model = clip::ClipTextTransformer::new(vs.root(), &self.clip);
-        vs.load(clip_weights)?;
+        let text_model = clip::ClipTextModelWithProjection::new(vs.root(), clip2);
+        load_weights(&mut vs, clip2_weights)?;
         Ok(text_model)
     }
+
+    pub fn build_clip_transformer_with_var_store(
+        &self,
+        clip_weights: &str,
+        device: tch::Device,
+    ) -> anyhow::Result<(clip::ClipTextTransformer, nn::VarStore)> {
+        let mut vs = tch::nn::VarStore::new(device);
+        let text_model = clip::ClipTextTransformer::new(vs.root(), &self.clip);
+        load_weights(&mut vs, clip_weights)?;
+        Ok((text_model, vs))
+    }
+
+    /// Builds the text encoder, autoencoder, and UNet from a single safetensors file
+    /// bundling the weights of the three components. Both the original CompVis naming
+    /// and the prefixed diffusers naming are supported. For training checkpoints that
+    /// also include EMA weights, `variant` selects the UNet weights to be used.
+    ///
+    /// The variant actually used is returned with the models, this is
+    /// `WeightVariant::Regular` when `WeightVariant::PreferEma` was requested for a
+    /// checkpoint without EMA weights so that callers can warn about the fallback.
+    ///
+    /// The file is read one tensor at a time, see `checkpoint::load_merged_safetensors`.
+    pub fn build_from_merged_safetensors(
+        &self,
+        weights: &str,
+        variant: checkpoint::WeightVariant,
+        clip_device: Device,
+        vae_device: Device,
+        unet_device: Device,
+        unet_in_channels: i64,
+    ) -> anyhow::Result<(
+        clip::ClipTextTransformer,
+        vae::AutoEncoderKL,
+        unet_2d::UNet2DConditionModel,
+        checkpoint::WeightVariant,
+    )> {
+        let vs = nn::VarStore::new(clip_device);
+        let text_model = clip::ClipTextTransformer::new(vs.root(), &self.clip);
+        let vs_ae = nn::VarStore::new(vae_device);
+        let autoencoder = vae::AutoEncoderKL::new(vs_ae.root(), 3, 3, self.autoencoder.clone());
+        let vs_unet = nn::VarStore::new(unet_device);
+        let unet = unet_2d::UNet2DConditionModel::new(
+            vs_unet.root(),
+            unet_in_channels,
+            self.latent_channels(),
+            self.unet.clone(),
+        );
+        let n_blocks = self.autoencoder.block_out_channels.len();
+        let variant = checkpoint::load_merged_safetensors(
+            weights, variant, &self.unet, n_blocks, &vs, &vs_ae, &vs_unet,
+        )?;
+        Ok((text_model, autoencoder, unet, variant))
+    }
+}
+
+// Safetensors files are read one tensor at a time to avoid holding the whole file in
+// memory, the other formats are loaded through libtorch.
+fn load_weights(vs: &mut nn::VarStore, path: &str) -> anyhow::Result<()> {
+    if path.ends_with(".safetensors") {
+        checkpoint::SafeTensorsReader::open(path)?.load_var_store(vs)
+    } else {
+        Ok(vs.load(path)?)
+    }
+}
+
+/// The components of a stable diffusion pipeline.
+///
+/// The components can be built independently, e.g. with `StableDiffusionConfig::build_unet`
+/// or from custom weights, and then assembled with `from_parts`. The autoencoder, text
+/// encoder, and tokenizer are reference counted so that they can be shared between
+/// pipelines using different UNets.
+///
+/// The embedding of the empty prompt is computed on first use and cached, it gets
+/// invalidated when the text encoder or the tokenizer is replaced.
+///
+/// Latents are decoded with the autoencoder unless another decoder is set with
+/// `set_decoder`, the autoencoder is still used to encode images.
+pub struct StableDiffusion<S = ddim::DDIMScheduler> {
+    unet: unet_2d::UNet2DConditionModel,
+    vae: Arc<vae::AutoEncoderKL>,
+    decoder: Arc<dyn vae::LatentDecoder>,
+    text_encoder: Arc<clip::ClipTextTransformer>,
+    tokenizer: Arc<clip::Tokenizer>,
+    scheduler: S,
+    uncond_embeddings: Mutex<Option<Tensor>>,
+}
+
+impl<S> StableDiffusion<S> {
+    pub fn from_parts(
+        unet: unet_2d::UNet2DConditionModel,
+        vae: Arc<vae::AutoEncoderKL>,
+        text_encoder: Arc<clip::ClipTextTransformer>,
+        tokenizer: Arc<clip::Tokenizer>,
+        scheduler: S,
+    ) -> Self {
+        Self {
+            unet,
+            decoder: vae.clone(),
+            vae,
+            text_encoder,
+            tokenizer,
+            scheduler,
+            uncond_embeddings: Mutex::new(None),
+        }
+    }
+
+    /// Returns the components, e.g. to reuse the shared ones with another UNet.
+    pub fn into_parts(
+        self,
+    ) -> (
+        unet_2d::UNet2DConditionModel,
+        Arc<vae::AutoEncoderKL>,
+        Arc<clip::ClipTextTransformer>,
+        Arc<clip::Tokenizer>,
+        S,
+    ) {
+        (self.unet, self.vae, self.text_encoder, self.tokenizer, self.scheduler)
+    }
+
+    pub fn unet(&self) -> &unet_2d::UNet2DConditionModel {
+        &self.unet
+    }
+
+    pub fn unet_mut(&mut self) -> &mut unet_2d::UNet2DConditionModel {
+        &mut self.unet
+    }
+
+    pub fn vae(&self) -> &Arc<vae::AutoEncoderKL> {
+        &self.vae
+    }
+
+    pub fn decoder(&self) -> &Arc<dyn vae::LatentDecoder> {
+        &self.decoder
+    }
+
+    /// Replaces the decoder used by `decode_latents`, e.g. with a faster approximation of
+    /// the autoencoder decoder for previews.
+    pub fn set_decoder(&mut self, decoder: Arc<dyn vae::LatentDecoder>) {
+        self.decoder = decoder
+    }
+
+    /// Decodes the latents produced by the diffusion model to `u8` images on the cpu.
+    pub fn decode_latents(&self, latents: &Tensor) -> Tensor {
+        tch::no_grad(|| self.decoder.decode_to_image(latents))
+    }
+
+    /// Encodes images with values in `[-1, 1]` to latents, e.g. for img2img.
+    pub fn encode_images(&self, images: &Tensor) -> Tensor {
+        tch::no_grad(|| vae::ImageEncoder::encode(self.vae.as_ref(), images))
+    }
+
+    /// The shape of the latents for `batch` images of size `width` by `height`, this uses
+    /// the downsampling factor of the autoencoder and fails if the size is not a multiple
+    /// of it.
+    pub fn latent_shape(&self, width: i64, height: i64, batch: i64) -> anyhow::Result<[i64; 4]> {
+        self.vae.config.latent_shape(width, height, batch)
+    }
+
+    pub fn text_encoder(&self) -> &Arc<clip::ClipTextTransformer> {
+        &self.text_encoder
+    }
+
+    pub fn tokenizer(&self) -> &Arc<clip::Tokenizer> {
+        &self.tokenizer
+    }
+
+    /// Replaces the text encoder, e.g. with one using a different number of layers, this
+    /// drops the cached unconditional embedding.
+    pub fn set_text_encoder(&mut self, text_encoder: Arc<clip::ClipTextTransformer>) {
+        self.text_encoder = text_encoder;
+        self.clear_uncond_embeddings();
+    }
+
+    /// Replaces the tokenizer, this drops the cached unconditional embedding.
+    pub fn set_tokenizer(&mut self, tokenizer: Arc<clip::Tokenizer>) {
+        self.tokenizer = tokenizer;
+        self.clear_uncond_embeddings();
+    }
+
+    /// Drops the cached unconditional embedding so that it gets recomputed on next use.
+    pub fn clear_uncond_embeddings(&self) {
+        *self.uncond_embeddings.lock().unwrap() = None;
+    }
+
+    /// Returns the embedding of the empty prompt, with shape `[1, seq_len, embed_dim]`.
+    /// It is only computed on the first call, later calls return the cached tensor.
+    pub fn uncond_embeddings(&self) -> anyhow::Result<Tensor> {
+        let mut cache = self.uncond_embeddings.lock().unwrap();
+        if let Some(embeddings) = cache.as_ref() {
+            return Ok(embeddings.shallow_clone());
+        }
+        let tokens = self.tokenizer.encode("")?;
+        let tokens: Vec<i64> = tokens.into_iter().map(|x| x as i64).collect();
+        let tokens = Tensor::from_slice(&tokens).unsqueeze(0).to(self.text_encoder.device());
+        let embeddings = tch::no_grad(|| self.text_encoder.forward(&tokens));
+        *cache = Some(embeddings.shallow_clone());
+        Ok(embeddings)
+    }
+
+    pub fn scheduler(&self) -> &S {
+        &self.scheduler
+    }
+
+    pub fn scheduler_mut(&mut self) -> &mut S {
+        &mut self.scheduler
+    }
+
+    /// Returns the text embeddings for classifier free guidance, i.e. the negative prompt
+    /// embeddings followed by the prompt ones along the batch dimension. The embeddings
+    /// are on the text encoder device. An empty negative prompt uses the cached
+    /// unconditional embedding rather than running the text encoder again.
+    pub fn encode_prompt(&self, prompt: &str, negative_prompt: &str) -> anyhow::Result<Tensor> {
+        let device = self.text_encoder.device();
+        let embed = |chunks: clip::TokenChunks| {
+            let n_chunks = chunks.len() as i64;
+            let tokens: Vec<i64> = chunks.into_iter().flatten().map(|x| x as i64).collect();
+            let tokens = Tensor::from_slice(&tokens).view((n_chunks, -1)).to(device);
+            self.text_encoder.forward_chunks(&tokens)
+        };
+        if negative_prompt.is_empty() {
+            let tokens = self.tokenizer.encode_chunks(prompt)?;
+            let n_chunks = tokens.len() as i64;
+            // The negative prompt gets padded with empty chunks to match the prompt.
+            let uncond_embeddings = self.uncond_embeddings()?.repeat([1, n_chunks, 1]);
+            let text_embeddings = embed(tokens);
+            return Ok(Tensor::cat(&[uncond_embeddings, text_embeddings], 0));
+        }
+        let (tokens, uncond_tokens) = self.tokenizer.encode_chunks_pair(prompt, negative_prompt)?;
+        let uncond_embeddings = embed(uncond_tokens);
+        let text_embeddings = embed(tokens);
+        Ok(Tensor::cat(&[uncond_embeddings, text_embeddings], 0))
+    }
+
+    /// Embeds a batch of prompts with a single text encoder pass, returning a tensor of
+    /// shape `[n_prompts, max_len, embed_dim]` on the text encoder device. The prompts are
+    /// padded to the maximum length supported by the text encoder and truncated if longer,
+    /// use `encode_prompt` for prompts that should not be truncated.
+    pub fn encode_prompts<P: AsRef<str>>(&self, prompts: &[P]) -> anyhow::Result<Tensor> {
+        if prompts.is_empty() {
+            anyhow::bail!("encode_prompts requires at least one prompt")
+        }
+        let tokens =
+            self.tokenizer.encode_batch(prompts, clip::PaddingStrategy::MaxLength, true)?;
+        let n_prompts = tokens.len() as i64;
+        let tokens: Vec<i64> = tokens.into_iter().flatten().map(|x| x as i64).collect();
+        let tokens = Tensor::from_slice(&tokens).view((n_prompts, -1));
+        Ok(self.text_encoder.forward(&tokens.to(self.text_encoder.device())))
+    }
+}
+
+impl<S: Scheduler> StableDiffusion<S> {
+    /// Returns the json configuration of the pipeline, i.e. `config` with the scheduler
+    /// in use, the kind of the UNet weights, and the LoRAs of `loras` recorded. `config`
+    /// is the configuration the components were built with, the json can be loaded back
+    /// with `StableDiffusionConfig::from_json`.
+    pub fn config_json(
+        &self,
+        config: &StableDiffusionConfig,
+        loras: Option<&lora::LoraAdapters>,
+    ) -> String {
+        let mut config = config.clone().with_weights_kind(self.unet.kind());
+        if let Some(scheduler) = self.scheduler.scheduler_config() {
+            config = config.with_scheduler(scheduler)
+        }
+        for (name, strength) in loras.map(|l| l.loras()).unwrap_or_default() {
+            config = config.with_lora(name, strength)
+        }
+        config.config_json()
+    }
+
+    /// Clears the state kept between generations, i.e. the cached unconditional embedding
+    /// and the scheduler history, e.g. before reusing the pipeline in a long running
+    /// server after its configuration changed.
+    pub fn reset(&mut self) {
+        self.clear_uncond_embeddings();
+        self.scheduler.reset()
+    }
 }
@@ -0,0 +1,83 @@
+//! # Align Your Steps
+//!
+//! Sigma schedules optimized for a given model, from Align Your Steps: Optimizing
+//! Sampling Schedules in Diffusion Models, Sabour et al. 2024.
+//! https://research.nvidia.com/labs/toronto-ai/AlignYourSteps/
+//!
+//! The paper publishes 10 step schedules, other step counts are obtained by interpolating
+//! the log sigmas. These schedules mostly help at low step counts, e.g. 10 to 20 steps.
+
+/// The published 10 step schedule for Stable Diffusion 1.5.
+pub const SD15_SIGMAS: [f64; 11] =
+    [14.615, 6.475, 3.861, 2.697, 1.886, 1.396, 0.963, 0.652, 0.399, 0.152, 0.029];
+
+/// The published 10 step schedule for Stable Diffusion XL.
+pub const SDXL_SIGMAS: [f64; 11] =
+    [14.615, 6.315, 3.771, 2.181, 1.342, 0.862, 0.555, 0.380, 0.234, 0.113, 0.029];
+
+/// The model an Align Your Steps schedule has been optimized for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AysSchedule {
+    StableDiffusion15,
+    StableDiffusionXl,
+}
+
+impl AysSchedule {
+    /// The published sigmas, from the noisiest to the least noisy.
+    pub fn reference_sigmas(&self) -> &'static [f64] {
+        match self {
+            Self::StableDiffusion15 => &SD15_SIGMAS,
+            Self::StableDiffusionXl => &SDXL_SIGMAS,
+        }
+    }
+
+    /// Returns the `inference_steps + 1` sigmas for the given number of steps, the last
+    /// one being 0 as the final step fully denoises the sample. With 10 steps these are
+    /// the published sigmas, otherwise the log sigmas are linearly interpolated.
+    pub fn sigmas(&self, inference_steps: usize) -> Vec<f64> {
+        let reference = self.reference_sigmas();
+        let mut sigmas = if inference_steps + 1 == reference.len() {
+            reference.to_vec()
+        } else {
+            loglinear_interp(reference, inference_steps + 1)
+        };
+        if let Some(last) = sigmas.last_mut() {
+            *last = 0.
+        }
+        sigmas
+    }
+}
+
+// Resamples a decreasing sequence of sigmas to `n` values, linearly interpolating the
+// log sigmas over evenly spaced positions.
+fn loglinear_interp(sigmas: &[f64], n: usize) -> Vec<f64> {
+    let last = (sigmas.len() - 1) as f64;
+    (0..n)
+        .map(|i| {
+            let x = if n == 1 { 0. } else { i as f64 * last / (n - 1) as f64 };
+            let index = (x.floor() as usize).min(sigmas.len() - 2);
+            let frac = x - index as f64;
+            let log_sigma = sigmas[index].ln() * (1. - frac) + sigmas[index + 1].ln() * frac;
+            log_sigma.exp()
+        })
+        .collect()
+}
+
+/// Converts sigmas to continuous training timesteps by interpolating the log of the
+/// training sigmas, `train_sigmas` being increasing with the timestep.
+pub(crate) fn sigmas_to_timesteps(sigmas: &[f64], train_sigmas: &[f64]) -> Vec<f64> {
+    let log_train_sigmas: Vec<f64> = train_sigmas.iter().map(|s| s.ln()).collect();
+    let max_index = log_train_sigmas.len() - 1;
+    sigmas
+        .iter()
+        .map(|sigma| {
+            let log_sigma = sigma.max(f64::MIN_POSITIVE).ln();
+            let high = log_train_sigmas.iter().position(|&s| s >= log_sigma).unwrap_or(max_index);
+            let high = high.max(1);
+            let (low_value, high_value) = (log_train_sigmas[high - 1], log_train_sigmas[high]);
+            let frac = ((log_sigma - low_value) / (high_value - low_value)).clamp(0., 1.);
+            (high - 1) as f64 + frac
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+//! # Pretrained Scheduler Configurations
+//!
+//! Diffusers model folders ship a `scheduler/scheduler_config.json` file naming the
+//! scheduler the model was released with together with its noise schedule, e.g.
+//! https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/scheduler/scheduler_config.json
+//!
+//! Using the noise schedule and prediction type from this file avoids the washed out
+//! images obtained when sampling a v-prediction model as an epsilon one.
+use super::{
+    ddim, ddpm, dpmsolver_multistep, euler_ancestral_discrete, euler_discrete, heun_discrete,
+    k_dpm_2_ancestral_discrete, k_dpm_2_discrete, lcm, lms_discrete, pndm, tcd, BetaSchedule,
+    PredictionType, TimestepSpacing,
+};
+use crate::utils::JsonConfig;
+
+/// The schedulers of this crate, identified by their diffusers class name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerKind {
+    Ddim,
+    Ddpm,
+    DpmSolverMultistep,
+    EulerAncestralDiscrete,
+    EulerDiscrete,
+    HeunDiscrete,
+    KDpm2AncestralDiscrete,
+    KDpm2Discrete,
+    Lcm,
+    LmsDiscrete,
+    Pndm,
+    Tcd,
+}
+
+impl SchedulerKind {
+    /// Returns the scheduler for a diffusers class name, e.g. `DDIMScheduler`.
+    pub fn from_class_name(class_name: &str) -> Option<Self> {
+        let kind = match class_name {
+            "DDIMScheduler" => Self::Ddim,
+            "DDPMScheduler" => Self::Ddpm,
+            "DPMSolverMultistepScheduler" => Self::DpmSolverMultistep,
+            "EulerAncestralDiscreteScheduler" => Self::EulerAncestralDiscrete,
+            "EulerDiscreteScheduler" => Self::EulerDiscrete,
+            "HeunDiscreteScheduler" => Self::HeunDiscrete,
+            "KDPM2AncestralDiscreteScheduler" => Self::KDpm2AncestralDiscrete,
+            "KDPM2DiscreteScheduler" => Self::KDpm2Discrete,
+            "LCMScheduler" => Self::Lcm,
+            "LMSDiscreteScheduler" => Self::LmsDiscrete,
+            "PNDMScheduler" => Self::Pndm,
+            "TCDScheduler" => Self::Tcd,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+// Defines `SchedulerConfig` with a variant per scheduler, named as in `SchedulerKind`,
+// together with the accessors to the noise schedule fields shared by all the schedulers.
+macro_rules! scheduler_configs {
+    ($($kind:ident, $class_name:literal, $config:ty;)*) => {
+        /// The configuration of any of the schedulers of this crate, serialized with the
+        /// diffusers class name of the scheduler as `type`.
+        #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+        #[serde(tag = "type")]
+        pub enum SchedulerConfig {
+            $(#[serde(rename = $class_name)] $kind($config),)*
+        }
+
+        $(impl From<$config> for SchedulerConfig {
+            fn from(config: $config) -> Self {
+                Self::$kind(config)
+            }
+        })*
+
+        impl SchedulerConfig {
+            /// The scheduler this configuration is for.
+            pub fn kind(&self) -> SchedulerKind {
+                match self {
+                    $(Self::$kind(_) => SchedulerKind::$kind,)*
+                }
+            }
+
+            pub fn prediction_type(&self) -> PredictionType {
+                match self {
+                    $(Self::$kind(c) => c.prediction_type,)*
+                }
+            }
+
+            /// Overrides the prediction type of the scheduler.
+            pub fn with_prediction_type(mut self, prediction_type: PredictionType) -> Self {
+                match &mut self {
+                    $(Self::$kind(c) => c.prediction_type = prediction_type,)*
+                }
+                self
+            }
+
+            // The betas and number of training timesteps.
+            fn noise_schedule(&self) -> (f64, f64, BetaSchedule, usize) {
+                match self {
+                    $(Self::$kind(c) => {
+                        (c.beta_start, c.beta_end, c.beta_schedule, c.train_timesteps)
+                    })*
+                }
+            }
+        }
+    };
+}
+
+scheduler_configs!(
+    Ddim, "DDIMScheduler", ddim::DDIMSchedulerConfig;
+    Ddpm, "DDPMScheduler", ddpm::DDPMSchedulerConfig;
+    DpmSolverMultistep, "DPMSolverMultistepScheduler",
+        dpmsolver_multistep::DPMSolverMultistepSchedulerConfig;
+    EulerAncestralDiscrete, "EulerAncestralDiscreteScheduler",
+        euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig;
+    EulerDiscrete, "EulerDiscreteScheduler", euler_discrete::EulerDiscreteSchedulerConfig;
+    HeunDiscrete, "HeunDiscreteScheduler", heun_discrete::HeunDiscreteSchedulerConfig;
+    KDpm2AncestralDiscrete, "KDPM2AncestralDiscreteScheduler",
+        k_dpm_2_ancestral_discrete::KDPM2AncestralDiscreteSchedulerConfig;
+    KDpm2Discrete, "KDPM2DiscreteScheduler", k_dpm_2_discrete::KDPM2DiscreteSchedulerConfig;
+    Lcm, "LCMScheduler", lcm::LCMSchedulerConfig;
+    LmsDiscrete, "LMSDiscreteScheduler", lms_discrete::LMSDiscreteSchedulerConfig;
+    Pndm, "PNDMScheduler", pndm::PNDMSchedulerConfig;
+    Tcd, "TCDScheduler", tcd::TCDSchedulerConfig;
+);
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self::Ddim(Default::default())
+    }
+}
+
+impl SchedulerConfig {
+    /// The DDIM configuration with the same noise schedule and prediction type, the
+    /// configuration itself for DDIM.
+    pub fn ddim_config(&self) -> ddim::DDIMSchedulerConfig {
+        if let Self::Ddim(config) = self {
+            return *config;
+        }
+        let (beta_start, beta_end, beta_schedule, train_timesteps) = self.noise_schedule();
+        ddim::DDIMSchedulerConfig {
+            beta_start,
+            beta_end,
+            beta_schedule,
+            train_timesteps,
+            prediction_type: self.prediction_type(),
+            ..Default::default()
+        }
+    }
+
+    /// Checks that the noise schedule and, for DDIM, the other parameters are in range.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Self::Ddim(config) = self {
+            return config.validate();
+        }
+        let (beta_start, beta_end, _, train_timesteps) = self.noise_schedule();
+        if !(0. < beta_start && beta_start <= beta_end && beta_end < 1.) {
+            anyhow::bail!(
+                "invalid betas {beta_start} and {beta_end}, expected 0 < beta_start <= beta_end < 1"
+            )
+        }
+        if train_timesteps == 0 {
+            anyhow::bail!("invalid train_timesteps {train_timesteps}")
+        }
+        Ok(())
+    }
+}
+
+/// The content of a `scheduler_config.json` file, keys missing from the file get the
+/// diffusers default values.
+#[derive(Debug, Clone)]
+pub struct PretrainedSchedulerConfig {
+    /// The diffusers class name, e.g. `PNDMScheduler`.
+    pub class_name: String,
+    /// The matching scheduler of this crate, `None` for schedulers not implemented here.
+    pub kind: Option<SchedulerKind>,
+    pub beta_start: f64,
+    pub beta_end: f64,
+    pub beta_schedule: BetaSchedule,
+    pub train_timesteps: usize,
+    pub prediction_type: PredictionType,
+    pub steps_offset: usize,
+    pub timestep_spacing: TimestepSpacing,
+}
+
+fn beta_schedule_from_str(s: &str) -> anyhow::Result<BetaSchedule> {
+    match s {
+        "linear" => Ok(BetaSchedule::Linear),
+        "scaled_linear" => Ok(BetaSchedule::ScaledLinear),
+        "squaredcos_cap_v2" => Ok(BetaSchedule::SquaredcosCapV2),
+        _ => anyhow::bail!("unsupported beta schedule {s:?}"),
+    }
+}
+
+impl PretrainedSchedulerConfig {
+    /// Reads a `scheduler_config.json` file.
+    pub fn from_json<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
+        let json = JsonConfig::read(path)?;
+        let class_name = json.str("_class_name")?;
+        let kind = SchedulerKind::from_class_name(&class_name);
+        let train_timesteps = json.i64_or("num_train_timesteps", 1000)?;
+        let steps_offset = json.i64_or("steps_offset", 0)?;
+        if train_timesteps <= 0 || steps_offset < 0 {
+            anyhow::bail!(
+                "invalid num_train_timesteps {train_timesteps} or steps_offset {steps_offset}"
+            )
+        }
+        Ok(Self {
+            class_name,
+            kind,
+            beta_start: json.f64_or("beta_start", 0.0001)?,
+            beta_end: json.f64_or("beta_end", 0.02)?,
+            beta_schedule: beta_schedule_from_str(&json.str_or("beta_schedule", "linear")?)?,
+            train_timesteps: train_timesteps as usize,
+            prediction_type: PredictionType::from_name(
+                &json.str_or("prediction_type", "epsilon")?,
+            )?,
+            steps_offset: steps_offset as usize,
+            timestep_spacing: TimestepSpacing::from_name(
+                &json.str_or("timestep_spacing", "linspace")?,
+            )?,
+        })
+    }
+
+    /// Reads the `scheduler/scheduler_config.json` file of a diffusers model folder,
+    /// `None` is returned when the folder does not have one, e.g. for single file
+    /// checkpoints.
+    ///
+    /// The prediction type is only recorded in the scheduler config, the UNet config
+    /// does not have it, and the scheduler defaults to `epsilon` when it is not set.
+    pub fn from_model_dir<P: AsRef<std::path::Path>>(model_dir: P) -> anyhow::Result<Option<Self>> {
+        let path = model_dir.as_ref().join("scheduler").join("scheduler_config.json");
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::from_json(path)?))
+    }
+
+    /// The DDIM configuration using this noise schedule and prediction type, this is what
+    /// the stable diffusion pipelines use whatever the scheduler named in the file.
+    pub fn ddim_config(&self) -> ddim::DDIMSchedulerConfig {
+        ddim::DDIMSchedulerConfig {
+            beta_start: self.beta_start,
+            beta_end: self.beta_end,
+            beta_schedule: self.beta_schedule,
+            steps_offset: self.steps_offset,
+            prediction_type: self.prediction_type,
+            train_timesteps: self.train_timesteps,
+            ..Default::default()
+        }
+    }
+
+    /// The Euler configuration using this noise schedule, prediction type, and timestep
+    /// spacing.
+    pub fn euler_discrete_config(&self) -> euler_discrete::EulerDiscreteSchedulerConfig {
+        euler_discrete::EulerDiscreteSchedulerConfig {
+            beta_start: self.beta_start,
+            beta_end: self.beta_end,
+            beta_schedule: self.beta_schedule,
+            train_timesteps: self.train_timesteps,
+            prediction_type: self.prediction_type,
+            timestep_spacing: self.timestep_spacing,
+            ..Default::default()
+        }
+    }
+
+    /// The LCM configuration using this noise schedule and prediction type, the LCM
+    /// specific settings keep their default values.
+    pub fn lcm_config(&self) -> lcm::LCMSchedulerConfig {
+        lcm::LCMSchedulerConfig {
+            beta_start: self.beta_start,
+            beta_end: self.beta_end,
+            beta_schedule: self.beta_schedule,
+            train_timesteps: self.train_timesteps,
+            prediction_type: self.prediction_type,
+            ..Default::default()
+        }
+    }
+
+    /// The TCD configuration using this noise schedule and prediction type, the TCD
+    /// specific settings keep their default values.
+    pub fn tcd_config(&self) -> tcd::TCDSchedulerConfig {
+        tcd::TCDSchedulerConfig {
+            beta_start: self.beta_start,
+            beta_end: self.beta_end,
+            beta_schedule: self.beta_schedule,
+            train_timesteps: self.train_timesteps,
+            prediction_type: self.prediction_type,
+            ..Default::default()
+        }
+    }
+}
+
+/// Checks a prediction type set by the user against the one of the model, as returned
+/// by `PretrainedSchedulerConfig::from_model_dir`. Sampling a model with the wrong
+/// prediction type gives noise or washed out images, e.g. running stable diffusion 2.1
+/// as an epsilon model, so a warning message is returned on mismatches for the caller
+/// to display. The user choice is kept as some fine-tuned models ship a wrong config.
+pub fn check_prediction_type(
+    requested: PredictionType,
+    detected: Option<PredictionType>,
+) -> Option<String> {
+    match detected {
+        Some(detected) if detected != requested => Some(format!(
+            "prediction type {requested:?} does not match the model one {detected:?}, the samples will likely be noise"
+        )),
+        _ => None,
+    }
+}
@@ -11,7 +11,8 @@ use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
 use tch::{kind, Kind, Tensor};
 
 /// The configuration for the DDIM scheduler.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DDIMSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -31,6 +32,30 @@ pub struct DDIMSchedulerConfig {
     pub train_timesteps: usize,
 }
 
+impl DDIMSchedulerConfig {
+    /// Checks that the noise schedule and the other parameters are in range.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0. < self.beta_start && self.beta_start <= self.beta_end && self.beta_end < 1.) {
+            anyhow::bail!(
+                "invalid betas {} and {}, expected 0 < beta_start <= beta_end < 1",
+                self.beta_start,
+                self.beta_end
+            )
+        }
+        if !(0. ..=1.).contains(&self.eta) {
+            anyhow::bail!("eta should be between 0 and 1, got {}", self.eta)
+        }
+        if self.train_timesteps == 0 || self.steps_offset >= self.train_timesteps {
+            anyhow::bail!(
+                "invalid train_timesteps {} or steps_offset {}",
+                self.train_timesteps,
+                self.steps_offset
+            )
+        }
+        Ok(())
+    }
+}
+
 impl Default for DDIMSchedulerConfig {
     fn default() -> Self {
         Self {
@@ -85,44 +110,96 @@ impl DDIMScheduler {
         Self { alphas_cumprod, timesteps, step_ratio, init_noise_sigma: 1., config }
     }
 
+    /// The minimum number of inference steps for which this scheduler produces reasonable
+    /// samples, see `validate_inference_steps`.
+    ///
+    /// DDIM degrades quickly below 10 steps.
+    pub fn min_recommended_steps(&self) -> usize {
+        10
+    }
+
+    /// The signal to noise ratio of the training noise schedule at `timestep`, this can be
+    /// used for SNR based weightings, e.g. to scale the guidance per step.
+    pub fn snr(&self, timestep: usize) -> f64 {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        alpha_prod / (1. - alpha_prod)
+    }
+
+    /// The signal to noise ratios for all the training timesteps.
+    pub fn snr_array(&self) -> Vec<f64> {
+        super::snr_from_alphas_cumprod(&self.alphas_cumprod)
+    }
+
     pub fn timesteps(&self) -> &[usize] {
         self.timesteps.as_slice()
     }
 
+    /// Replaces the inference schedule with some custom timesteps. The timesteps have
+    /// to be integers within the training range and in non-increasing order, a timestep
+    /// can be repeated. Each step goes from its timestep to the next smaller timestep
+    /// of the schedule.
+    pub fn set_timesteps_custom(&mut self, timesteps: &[f64]) -> anyhow::Result<()> {
+        super::check_custom_timesteps(timesteps, self.config.train_timesteps, true)?;
+        self.timesteps = timesteps.iter().map(|&t| t as usize).collect();
+        Ok(())
+    }
+
     ///  Ensures interchangeability with schedulers that need to scale the denoising model input
     /// depending on the current timestep.
     pub fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Tensor {
         sample
     }
 
+    // The alpha products of a timestep, the final timestep of a schedule with an offset
+    // can be past the training timesteps.
+    fn alpha_prod(&self, timestep: usize) -> f64 {
+        let timestep = if timestep >= self.alphas_cumprod.len() { timestep - 1 } else { timestep };
+        self.alphas_cumprod[timestep]
+    }
+
+    /// The predicted denoised sample, x_0, for a model output at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let alpha_prod_t = self.alpha_prod(timestep);
+        let beta_prod_t = 1. - alpha_prod_t;
+        match self.config.prediction_type {
+            PredictionType::Epsilon => {
+                (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt()
+            }
+            PredictionType::VPrediction => {
+                alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output
+            }
+            PredictionType::Sample => model_output.shallow_clone(),
+        }
+    }
+
     /// Performs a backward step during inference.
     pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
-        let timestep = if timestep >= self.alphas_cumprod.len() { timestep - 1 } else { timestep };
         // https://github.com/huggingface/diffusers/blob/6e099e2c8ce4c4f5c7318e970a8c093dc5c7046e/src/diffusers/schedulers/scheduling_ddim.py#L195
-        let prev_timestep = if timestep > self.step_ratio { timestep - self.step_ratio } else { 0 };
+        // The previous timestep is the next one in the schedule, for the default schedule
+        // this is `timestep - step_ratio`.
+        let prev_timestep = match self.timesteps.iter().find(|&&t| t < timestep) {
+            Some(&t) => t,
+            None => timestep.saturating_sub(self.step_ratio),
+        };
 
-        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let alpha_prod_t = self.alpha_prod(timestep);
         let alpha_prod_t_prev = self.alphas_cumprod[prev_timestep];
         let beta_prod_t = 1. - alpha_prod_t;
         let beta_prod_t_prev = 1. - alpha_prod_t_prev;
 
-        let (pred_original_sample, pred_epsilon) = match self.config.prediction_type {
-            PredictionType::Epsilon => {
-                let pred_original_sample =
-                    (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt();
-                (pred_original_sample, model_output.shallow_clone())
-            }
+        let pred_original_sample = self.pred_original_sample(model_output, timestep, sample);
+        let pred_epsilon = match self.config.prediction_type {
+            PredictionType::Epsilon => model_output.shallow_clone(),
             PredictionType::VPrediction => {
-                let pred_original_sample =
-                    alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output;
-                let pred_epsilon = alpha_prod_t.sqrt() * model_output + beta_prod_t.sqrt() * sample;
-                (pred_original_sample, pred_epsilon)
+                alpha_prod_t.sqrt() * model_output + beta_prod_t.sqrt() * sample
             }
             PredictionType::Sample => {
-                let pred_original_sample = model_output.shallow_clone();
-                let pred_epsilon =
-                    (sample - alpha_prod_t.sqrt() * &pred_original_sample) / beta_prod_t.sqrt();
-                (pred_original_sample, pred_epsilon)
+                (sample - alpha_prod_t.sqrt() * &pred_original_sample) / beta_prod_t.sqrt()
             }
         };
 
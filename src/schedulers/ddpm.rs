@@ -1,7 +1,8 @@
 use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
 use tch::{kind, Kind, Tensor};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DDPMVarianceType {
     FixedSmall,
     FixedSmallLog,
@@ -16,7 +17,8 @@ impl Default for DDPMVarianceType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DDPMSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -116,6 +118,23 @@ impl DDPMScheduler {
         }
     }
 
+    /// DDPM is an ancestral sampler designed for long schedules, it needs many steps to
+    /// converge.
+    pub fn min_recommended_steps(&self) -> usize {
+        50
+    }
+
+    /// The signal to noise ratio `alpha_bar / (1 - alpha_bar)` at a training timestep.
+    pub fn snr(&self, timestep: usize) -> f64 {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        alpha_prod / (1. - alpha_prod)
+    }
+
+    /// The signal to noise ratio at each of the training timesteps, indexed by timestep.
+    pub fn snr_array(&self) -> Vec<f64> {
+        super::snr_from_alphas_cumprod(&self.alphas_cumprod)
+    }
+
     pub fn timesteps(&self) -> &[usize] {
         self.timesteps.as_slice()
     }
@@ -126,6 +145,32 @@ impl DDPMScheduler {
         sample
     }
 
+    /// The predicted denoised sample, x_0, for a model output at the given timestep. This
+    /// is clipped to `[-1, 1]` when `clip_sample` is set.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let beta_prod_t = 1. - alpha_prod_t;
+        let pred_original_sample = match self.config.prediction_type {
+            PredictionType::Epsilon => {
+                (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt()
+            }
+            PredictionType::Sample => model_output.shallow_clone(),
+            PredictionType::VPrediction => {
+                alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output
+            }
+        };
+        if self.config.clip_sample {
+            pred_original_sample.clamp(-1., 1.)
+        } else {
+            pred_original_sample
+        }
+    }
+
     pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
         let prev_t = timestep as isize - self.step_ratio as isize;
 
@@ -140,20 +185,8 @@ impl DDPMScheduler {
         let current_beta_t = 1. - current_alpha_t;
 
         // 2. compute predicted original sample from predicted noise also called "predicted x_0" of formula (15)
-        let mut pred_original_sample = match self.config.prediction_type {
-            PredictionType::Epsilon => {
-                (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt()
-            }
-            PredictionType::Sample => model_output.shallow_clone(),
-            PredictionType::VPrediction => {
-                alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output
-            }
-        };
-
         // 3. clip predicted x_0
-        if self.config.clip_sample {
-            pred_original_sample = pred_original_sample.clamp(-1., 1.);
-        }
+        let pred_original_sample = self.pred_original_sample(model_output, timestep, sample);
 
         // 4. Compute coefficients for pred_original_sample x_0 and current sample x_t
         // See formula (7) from https://arxiv.org/pdf/2006.11239.pdf
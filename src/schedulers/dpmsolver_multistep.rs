@@ -1,10 +1,12 @@
-use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use super::ays;
+use super::{betas_for_alpha_bar, BetaSchedule, PredictionType, RestartConfig};
 use std::iter;
 use tch::{kind, Kind, Tensor};
 
 /// The algorithm type for the solver.
 ///
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DPMSolverAlgorithmType {
     /// Implements the algorithms defined in <https://arxiv.org/abs/2211.01095>.
     #[default]
@@ -16,14 +18,16 @@ pub enum DPMSolverAlgorithmType {
 /// The solver type for the second-order solver.
 /// The solver type slightly affects the sample quality, especially for
 /// small number of steps.
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DPMSolverType {
     #[default]
     Midpoint,
     Heun,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DPMSolverMultistepSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -56,6 +60,8 @@ pub struct DPMSolverMultistepSchedulerConfig {
     /// Whether to use lower-order solvers in the final steps. Only valid for < 15 inference steps. We empirically
     /// find this can stabilize the sampling of DPM-Solver for `steps < 15`, especially for steps <= 10.
     pub lower_order_final: bool,
+    /// Restart sampling, this adds restart segments to the schedule.
+    pub restart: Option<RestartConfig>,
 }
 
 impl Default for DPMSolverMultistepSchedulerConfig {
@@ -73,6 +79,7 @@ impl Default for DPMSolverMultistepSchedulerConfig {
             algorithm_type: DPMSolverAlgorithmType::DPMSolverPlusPlus,
             solver_type: DPMSolverType::Midpoint,
             lower_order_final: true,
+            restart: None,
         }
     }
 }
@@ -86,9 +93,53 @@ pub struct DPMSolverMultistepScheduler {
     lower_order_nums: usize,
     model_outputs: Vec<Tensor>,
     timesteps: Vec<usize>,
+    // The timestep reached by the update of each step, this is below the timestep of the
+    // next step when noise gets added back for restart sampling.
+    prev_timesteps: Vec<usize>,
+    // The index of the next step, timesteps can repeat with restart sampling.
+    step_index: usize,
     pub config: DPMSolverMultistepSchedulerConfig,
 }
 
+// Adds the restart segments to a schedule, `train_sigmas` being the variance exploding
+// sigmas of the training timesteps. Returns the new timesteps together with the timestep
+// reached by each step, which is below the next timestep before a restart.
+fn restart_schedule(
+    timesteps: Vec<usize>,
+    restart: &RestartConfig,
+    train_sigmas: &[f64],
+) -> (Vec<usize>, Vec<usize>) {
+    let n_steps = timesteps.len();
+    // The first non-final timestep at or below sigma_min, where the restarts happen.
+    let restart_index = (1..n_steps).find(|&i| train_sigmas[timesteps[i]] <= restart.sigma_min);
+    let mut new_timesteps = vec![];
+    let mut prev_timesteps = vec![];
+    for (i, &timestep) in timesteps.iter().enumerate() {
+        let prev_timestep = timesteps.get(i + 1).copied().unwrap_or(0);
+        new_timesteps.push(timestep);
+        prev_timesteps.push(prev_timestep);
+        if restart_index != Some(i + 1) {
+            continue;
+        }
+        let segment = restart.segment_sigmas(train_sigmas[prev_timestep]);
+        // The segment sigmas get rounded to training timesteps, these have to stay above
+        // the timestep where the restarts happen.
+        let mut segment_timesteps: Vec<usize> = ays::sigmas_to_timesteps(&segment, train_sigmas)
+            .into_iter()
+            .map(|t| t.round() as usize)
+            .filter(|&t| t > prev_timestep)
+            .collect();
+        segment_timesteps.dedup();
+        for _ in 0..restart.repeats {
+            for (j, &timestep) in segment_timesteps.iter().enumerate() {
+                new_timesteps.push(timestep);
+                prev_timesteps.push(segment_timesteps.get(j + 1).copied().unwrap_or(prev_timestep));
+            }
+        }
+    }
+    (new_timesteps, prev_timesteps)
+}
+
 impl DPMSolverMultistepScheduler {
     pub fn new(inference_steps: usize, config: DPMSolverMultistepSchedulerConfig) -> Self {
         let betas = match config.beta_schedule {
@@ -127,6 +178,18 @@ impl DPMSolverMultistepScheduler {
         // https://github.com/huggingface/diffusers/blob/e4fe9413121b78c4c1f109b50f0f3cc1c320a1a2/src/diffusers/schedulers/scheduling_dpmsolver_multistep.py#L206-L208
         let model_outputs = iter::repeat_with(Tensor::new).take(config.solver_order).collect();
 
+        let (timesteps, prev_timesteps) = match &config.restart {
+            Some(restart) => {
+                let train_sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+                let train_sigmas: Vec<f64> = train_sigmas.try_into().unwrap();
+                restart_schedule(timesteps, restart, &train_sigmas)
+            }
+            None => {
+                let prev_timesteps = timesteps.iter().skip(1).copied().chain([0]).collect();
+                (timesteps, prev_timesteps)
+            }
+        };
+
         Self {
             alphas_cumprod: alphas_cumprod.try_into().unwrap(),
             alpha_t: alpha_t.try_into().unwrap(),
@@ -136,6 +199,8 @@ impl DPMSolverMultistepScheduler {
             lower_order_nums: 0,
             model_outputs,
             timesteps,
+            prev_timesteps,
+            step_index: 0,
             config,
         }
     }
@@ -335,10 +400,48 @@ impl DPMSolverMultistepScheduler {
         }
     }
 
+    /// Clears the history of model outputs so that the scheduler can be reused for a new
+    /// generation.
+    pub fn reset(&mut self) {
+        self.lower_order_nums = 0;
+        self.step_index = 0;
+        for model_output in self.model_outputs.iter_mut() {
+            *model_output = Tensor::new()
+        }
+    }
+
+    /// The number of steps below which the higher order updates barely get used as the
+    /// first and last steps fall back to lower orders.
+    pub fn min_recommended_steps(&self) -> usize {
+        usize::max(5, 2 * self.config.solver_order)
+    }
+
+    /// The signal to noise ratio of the noise schedule at the given timestep, this is
+    /// `(alpha_t / sigma_t)^2` using the notations of the DPM-Solver paper.
+    pub fn snr(&self, timestep: usize) -> f64 {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        alpha_prod / (1. - alpha_prod)
+    }
+
+    /// The signal to noise ratios over all the training timesteps.
+    pub fn snr_array(&self) -> Vec<f64> {
+        super::snr_from_alphas_cumprod(&self.alphas_cumprod)
+    }
+
     pub fn timesteps(&self) -> &[usize] {
         self.timesteps.as_slice()
     }
 
+    // The index of `timestep` in the schedule, searching from the next step first as the
+    // timesteps of restart segments repeat.
+    fn index_for_timestep(&self, timestep: usize) -> usize {
+        let start = self.step_index.min(self.timesteps.len());
+        match self.timesteps[start..].iter().position(|&t| t == timestep) {
+            Some(index) => start + index,
+            None => self.timesteps.iter().position(|&t| t == timestep).unwrap(),
+        }
+    }
+
     ///  Ensures interchangeability with schedulers that need to scale the denoising model input
     /// depending on the current timestep.
     pub fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Tensor {
@@ -347,10 +450,10 @@ impl DPMSolverMultistepScheduler {
 
     pub fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
         // https://github.com/huggingface/diffusers/blob/e4fe9413121b78c4c1f109b50f0f3cc1c320a1a2/src/diffusers/schedulers/scheduling_dpmsolver_multistep.py#L457
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let step_index = self.index_for_timestep(timestep);
+        self.step_index = step_index + 1;
 
-        let prev_timestep =
-            if step_index == self.timesteps.len() - 1 { 0 } else { self.timesteps[step_index + 1] };
+        let prev_timestep = self.prev_timesteps[step_index];
         let lower_order_final = (step_index == self.timesteps.len() - 1)
             && self.config.lower_order_final
             && self.timesteps.len() < 15;
@@ -394,7 +497,21 @@ impl DPMSolverMultistepScheduler {
             self.lower_order_nums += 1;
         }
 
-        prev_sample
+        // Restart sampling, bring the sample back to the noise level of the next step. The
+        // previous model outputs belong to the trajectory before the added noise, so the
+        // next update is a first order one.
+        match self.timesteps.get(step_index + 1) {
+            Some(&next_timestep) if next_timestep > prev_timestep => {
+                self.lower_order_nums = 0;
+                let (alpha_next, alpha_prev) =
+                    (self.alpha_t[next_timestep], self.alpha_t[prev_timestep]);
+                let sigma_next = self.sigma_t[next_timestep] / alpha_next;
+                let sigma_prev = self.sigma_t[prev_timestep] / alpha_prev;
+                let restart_noise = alpha_next * (sigma_next.powi(2) - sigma_prev.powi(2)).sqrt();
+                (alpha_next / alpha_prev) * &prev_sample + prev_sample.randn_like() * restart_noise
+            }
+            _ => prev_sample,
+        }
     }
 
     pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: usize) -> Tensor {
@@ -1,7 +1,8 @@
 use super::{interp, BetaSchedule, PredictionType};
 use tch::{kind, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct EulerAncestralDiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -89,6 +90,12 @@ impl EulerAncestralDiscreteScheduler {
         }
     }
 
+    /// Fresh noise is injected at each step, so more steps are needed than for the
+    /// deterministic Euler scheduler.
+    pub fn min_recommended_steps(&self) -> usize {
+        15
+    }
+
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
@@ -101,19 +108,30 @@ impl EulerAncestralDiscreteScheduler {
         sample / (sigma.powi(2) + 1.).sqrt()
     }
 
-    pub fn step(&self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
-        let sigma = self.sigmas[step_index];
-
-        // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
-        let pred_original_sample = match self.config.prediction_type {
+    /// The predicted denoised sample, x_0, for a model output at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: f64,
+        sample: &Tensor,
+    ) -> Tensor {
+        let sigma = self.sigmas[self.timesteps.iter().position(|&t| t == timestep).unwrap()];
+        match self.config.prediction_type {
             PredictionType::Epsilon => sample - sigma * model_output,
             PredictionType::VPrediction => {
                 model_output * (-sigma / (sigma.powi(2) + 1.).sqrt())
                     + (sample / (sigma.powi(2) + 1.))
             }
             _ => unimplemented!("Prediction type must be one of `epsilon` or `v_prediction`"),
-        };
+        }
+    }
+
+    pub fn step(&self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
+        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let sigma = self.sigmas[step_index];
+
+        // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
+        let pred_original_sample = self.pred_original_sample(model_output, timestep, sample);
 
         let sigma_from = self.sigmas[step_index];
         let sigma_to = self.sigmas[step_index + 1];
@@ -1,7 +1,10 @@
-use super::{interp, BetaSchedule, PredictionType};
+use super::ays::{self, AysSchedule};
+pub use super::RestartConfig;
+use super::{interp, BetaSchedule, PredictionType, TimestepSpacing};
 use tch::{kind, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct EulerDiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -13,6 +16,62 @@ pub struct EulerDiscreteSchedulerConfig {
     pub train_timesteps: usize,
     /// prediction type of the scheduler function
     pub prediction_type: PredictionType,
+    /// How the timesteps are spread, this is ignored when using `ays_schedule`.
+    pub timestep_spacing: TimestepSpacing,
+    /// Use the Align Your Steps sigmas of a model rather than evenly spaced timesteps.
+    pub ays_schedule: Option<AysSchedule>,
+    /// Restart sampling, this adds restart segments to the schedule.
+    pub restart: Option<RestartConfig>,
+}
+
+// Adds the restart segments to a schedule, `sigmas` having one more value than
+// `timesteps`. Returns the new timesteps and sigmas together with the sigma reached by
+// the update of each step, which is below the next sigma before a restart.
+fn restart_schedule(
+    timesteps: Vec<f64>,
+    sigmas: Vec<f64>,
+    restart: &RestartConfig,
+    train_sigmas: &[f64],
+) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    let n_steps = timesteps.len();
+    // The first non-final sigma at or below sigma_min, where the restarts happen.
+    let restart_index = (1..n_steps).find(|&i| sigmas[i] <= restart.sigma_min);
+    let mut new_timesteps = vec![];
+    let mut new_sigmas = vec![];
+    let mut targets = vec![];
+    for i in 0..n_steps {
+        new_timesteps.push(timesteps[i]);
+        new_sigmas.push(sigmas[i]);
+        targets.push(sigmas[i + 1]);
+        if restart_index != Some(i + 1) || restart.steps == 0 {
+            continue;
+        }
+        let low = sigmas[i + 1];
+        let segment = restart.segment_sigmas(low);
+        let segment_timesteps = ays::sigmas_to_timesteps(&segment, train_sigmas);
+        for _ in 0..restart.repeats {
+            for (j, (&timestep, &sigma)) in segment_timesteps.iter().zip(segment.iter()).enumerate()
+            {
+                new_timesteps.push(timestep);
+                new_sigmas.push(sigma);
+                targets.push(segment.get(j + 1).copied().unwrap_or(low));
+            }
+        }
+    }
+    new_sigmas.push(*sigmas.last().unwrap());
+    (new_timesteps, new_sigmas, targets)
+}
+
+// Interpolates the sigmas of some timesteps from the training ones, with a final zero.
+fn sigmas_for_timesteps(timesteps: &[f64], train_sigmas: &[f64]) -> Vec<f64> {
+    let timesteps = Tensor::from_slice(timesteps).to_kind(Kind::Float);
+    let sigmas = interp(
+        &timesteps, // x-coordinates at which to evaluate the interpolated values
+        Tensor::range(0, train_sigmas.len() as i64 - 1, kind::FLOAT_CPU),
+        Tensor::from_slice(train_sigmas),
+    );
+    let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[0.0])], 0);
+    sigmas.try_into().unwrap()
 }
 
 impl Default for EulerDiscreteSchedulerConfig {
@@ -23,6 +82,9 @@ impl Default for EulerDiscreteSchedulerConfig {
             beta_schedule: BetaSchedule::ScaledLinear,
             train_timesteps: 1000,
             prediction_type: PredictionType::Epsilon,
+            timestep_spacing: TimestepSpacing::Linspace,
+            ays_schedule: None,
+            restart: None,
         }
     }
 }
@@ -35,7 +97,14 @@ impl Default for EulerDiscreteSchedulerConfig {
 pub struct EulerDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    // The sigma reached by the update of each step, this is below the sigma of the next
+    // step when noise gets added back for restart sampling.
+    step_targets: Vec<f64>,
     init_noise_sigma: f64,
+    // The index of the next step, timesteps can repeat with restart sampling.
+    step_index: usize,
+    // The sigmas of the training timesteps.
+    train_sigmas: Vec<f64>,
     pub config: EulerDiscreteSchedulerConfig,
 }
 
@@ -63,48 +132,118 @@ impl EulerDiscreteScheduler {
         let alphas: Tensor = 1. - betas;
         let alphas_cumprod = alphas.cumprod(0, Kind::Double);
 
-        let timesteps = Tensor::linspace(
-            (config.train_timesteps - 1) as f64,
-            0.,
-            inference_steps as i64,
-            kind::FLOAT_CPU,
-        );
-
-        let sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
-        let sigmas = interp(
-            &timesteps, // x-coordinates at which to evaluate the interpolated values
-            Tensor::range(0, sigmas.size1().unwrap() - 1, kind::FLOAT_CPU),
-            sigmas,
-        );
-        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[0.0])], 0);
+        let train_sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+        let train_sigmas: Vec<f64> = train_sigmas.try_into().unwrap();
+        let (timesteps, sigmas): (Vec<f64>, Vec<f64>) = match config.ays_schedule {
+            Some(ays_schedule) => {
+                let sigmas = ays_schedule.sigmas(inference_steps);
+                let timesteps = ays::sigmas_to_timesteps(&sigmas[..inference_steps], &train_sigmas);
+                (timesteps, sigmas)
+            }
+            None => {
+                let timesteps =
+                    config.timestep_spacing.timesteps(inference_steps, config.train_timesteps);
+                let sigmas = sigmas_for_timesteps(&timesteps, &train_sigmas);
+                (timesteps, sigmas)
+            }
+        };
+        let mut scheduler = Self {
+            timesteps: vec![],
+            sigmas: vec![],
+            step_targets: vec![],
+            init_noise_sigma: 0.,
+            step_index: 0,
+            train_sigmas,
+            config,
+        };
+        scheduler.set_schedule(timesteps, sigmas);
+        scheduler
+    }
 
+    // Uses a schedule, `sigmas` having a final zero sigma after the ones of the
+    // timesteps, and adds the restart segments if enabled.
+    fn set_schedule(&mut self, timesteps: Vec<f64>, sigmas: Vec<f64>) {
         // standard deviation of the initial noise distribution
-        let init_noise_sigma: f64 = sigmas.max().try_into().unwrap();
+        self.init_noise_sigma = sigmas.iter().copied().fold(0., f64::max);
+        let (timesteps, sigmas, step_targets) = match &self.config.restart {
+            Some(restart) => restart_schedule(timesteps, sigmas, restart, &self.train_sigmas),
+            None => {
+                let step_targets = sigmas[1..].to_vec();
+                (timesteps, sigmas, step_targets)
+            }
+        };
+        self.timesteps = timesteps;
+        self.sigmas = sigmas;
+        self.step_targets = step_targets;
+        self.step_index = 0;
+    }
 
-        Self {
-            timesteps: timesteps.try_into().unwrap(),
-            sigmas: sigmas.try_into().unwrap(),
-            init_noise_sigma,
-            config,
-        }
+    /// Replaces the inference schedule with some custom timesteps, these can be
+    /// fractional but have to be within the training range and in non-increasing order.
+    /// The sigmas are interpolated from the training ones, a repeated timestep gives a
+    /// step that leaves the sample unchanged. Restart segments are added if enabled.
+    pub fn set_timesteps_custom(&mut self, timesteps: &[f64]) -> anyhow::Result<()> {
+        super::check_custom_timesteps(timesteps, self.config.train_timesteps, false)?;
+        let sigmas = sigmas_for_timesteps(timesteps, &self.train_sigmas);
+        self.set_schedule(timesteps.to_vec(), sigmas);
+        Ok(())
+    }
+
+    /// The minimum recommended number of inference steps.
+    pub fn min_recommended_steps(&self) -> usize {
+        10
     }
 
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
 
+    // The index of `timestep` in the schedule, searching from the next step first as the
+    // timesteps of restart segments repeat.
+    fn index_for_timestep(&self, timestep: f64) -> usize {
+        let start = self.step_index.min(self.timesteps.len());
+        match self.timesteps[start..].iter().position(|&t| t == timestep) {
+            Some(index) => start + index,
+            None => self.timesteps.iter().position(|&t| t == timestep).unwrap(),
+        }
+    }
+
+    /// Restarts the schedule from its first step.
+    pub fn reset(&mut self) {
+        self.step_index = 0
+    }
+
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let step_index = self.index_for_timestep(timestep);
         let sigma = self.sigmas[step_index];
 
         // https://github.com/huggingface/diffusers/blob/2bd53a940c60d13421d9e8887af96b30a53c1b95/src/diffusers/schedulers/scheduling_euler_discrete.py#L133
         sample / (sigma.powi(2) + 1.).sqrt()
     }
 
-    pub fn step(&self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
+    /// The predicted denoised sample, x_0, for a model output at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: f64,
+        sample: &Tensor,
+    ) -> Tensor {
+        let sigma = self.sigmas[self.index_for_timestep(timestep)];
+        match self.config.prediction_type {
+            PredictionType::Epsilon => sample - sigma * model_output,
+            PredictionType::VPrediction => {
+                model_output * (-sigma / (sigma.powi(2) + 1.).sqrt())
+                    + (sample / (sigma.powi(2) + 1.))
+            }
+            _ => unimplemented!("Prediction type must be one of `epsilon` or `v_prediction`"),
+        }
+    }
+
+    pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let (s_churn, s_tmin, s_tmax, s_noise) = (0.0, 0.0, f64::INFINITY, 1.0);
 
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let step_index = self.index_for_timestep(timestep);
+        self.step_index = step_index + 1;
         let sigma = self.sigmas[step_index];
 
         let gamma = if s_tmin <= sigma && sigma <= s_tmax {
@@ -135,9 +274,18 @@ impl EulerDiscreteScheduler {
 
         // 2. Convert to an ODE derivative
         let derivative = (&sample - pred_original_sample) / sigma_hat;
-        let dt = self.sigmas[step_index + 1] - sigma_hat;
+        let target = self.step_targets[step_index];
+        let dt = target - sigma_hat;
+        let prev_sample = sample + derivative * dt;
 
-        sample + derivative * dt
+        // Restart sampling, bring the sample back to the noise level of the next step.
+        let next_sigma = self.sigmas[step_index + 1];
+        if next_sigma > target {
+            let restart_noise = (next_sigma.powi(2) - target.powi(2)).sqrt();
+            &prev_sample + prev_sample.randn_like() * restart_noise
+        } else {
+            prev_sample
+        }
     }
 
     pub fn init_noise_sigma(&self) -> f64 {
@@ -145,7 +293,7 @@ impl EulerDiscreteScheduler {
     }
 
     pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let step_index = self.index_for_timestep(timestep);
         let sigma = self.sigmas[step_index];
 
         original_samples + noise * sigma
@@ -1,7 +1,8 @@
 use super::{interp, BetaSchedule, PredictionType};
 use tch::{kind, IndexOp, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct HeunDiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -112,6 +113,20 @@ impl HeunDiscreteScheduler {
         }
     }
 
+    /// Clears the state kept between the two halves of a Heun step, e.g. when a
+    /// generation got interrupted midway.
+    pub fn reset(&mut self) {
+        self.prev_derivative = None;
+        self.dt = None;
+        self.sample = None
+    }
+
+    /// This is lower than for single evaluation schedulers as each Heun step evaluates the
+    /// model twice.
+    pub fn min_recommended_steps(&self) -> usize {
+        5
+    }
+
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
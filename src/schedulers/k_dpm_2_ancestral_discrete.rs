@@ -1,7 +1,8 @@
 use super::{interp, BetaSchedule, PredictionType};
 use tch::{kind, IndexOp, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct KDPM2AncestralDiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -216,6 +217,17 @@ impl KDPM2AncestralDiscreteScheduler {
         t.view(sigma.size().as_slice())
     }
 
+    /// Clears the sample kept between the two model evaluations of a step.
+    pub fn reset(&mut self) {
+        self.sample = None
+    }
+
+    /// The minimum recommended number of inference steps, ancestral sampling requires more
+    /// steps than the non-ancestral variant.
+    pub fn min_recommended_steps(&self) -> usize {
+        10
+    }
+
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
@@ -1,7 +1,8 @@
 use super::{interp, BetaSchedule, PredictionType};
 use tch::{kind, IndexOp, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct KDPM2DiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -173,6 +174,16 @@ impl KDPM2DiscreteScheduler {
         t.view(sigma.size().as_slice())
     }
 
+    /// Clears the sample kept between the two model evaluations of a step.
+    pub fn reset(&mut self) {
+        self.sample = None
+    }
+
+    /// Each step evaluates the model twice so fewer steps are needed.
+    pub fn min_recommended_steps(&self) -> usize {
+        5
+    }
+
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
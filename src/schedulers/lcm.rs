@@ -0,0 +1,177 @@
+//! # Latent Consistency Model Scheduler
+//!
+//! The multistep consistency sampling used by Latent Consistency Models (LCM). At each
+//! step the model prediction is turned into a denoised sample, and the next step starts
+//! from this sample noised again to the next timestep. This produces good images in 1
+//! to 8 steps with models distilled for it: LCM models, standard models with an LCM-LoRA
+//! merged, see `models::lora`, and adversarially distilled models such as SD-Turbo.
+//!
+//! These models are distilled with the guidance baked in, so they are sampled without
+//! classifier free guidance, i.e. with a guidance scale of 1, in which case
+//! `pipelines::guidance` only runs the conditional branch. The LCM models conditioned on
+//! a guidance scale embedding, `time_cond_proj_dim` in the UNet config, are not
+//! supported.
+//!
+//! Latent Consistency Models: Synthesizing High-Resolution Images with Few-Step
+//! Inference, S. Luo et al, 2023. https://arxiv.org/abs/2310.04378
+use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use tch::{kind, Kind, Tensor};
+
+/// The configuration for the LCM scheduler.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LCMSchedulerConfig {
+    /// The value of beta at the beginning of training.
+    pub beta_start: f64,
+    /// The value of beta at the end of training.
+    pub beta_end: f64,
+    /// How beta evolved during training.
+    pub beta_schedule: BetaSchedule,
+    /// The number of steps of the schedule used for distillation, the inference timesteps
+    /// are taken from this schedule.
+    pub original_inference_steps: usize,
+    /// The factor applied to the timesteps when computing the consistency boundary
+    /// condition scalings.
+    pub timestep_scaling: f64,
+    /// The prediction type of the model.
+    pub prediction_type: PredictionType,
+    /// The number of diffusion steps used to train the model.
+    pub train_timesteps: usize,
+}
+
+impl Default for LCMSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085,
+            beta_end: 0.012,
+            beta_schedule: BetaSchedule::ScaledLinear,
+            original_inference_steps: 50,
+            timestep_scaling: 10.,
+            prediction_type: PredictionType::Epsilon,
+            train_timesteps: 1000,
+        }
+    }
+}
+
+/// The LCM scheduler.
+#[derive(Debug, Clone)]
+pub struct LCMScheduler {
+    timesteps: Vec<usize>,
+    alphas_cumprod: Vec<f64>,
+    pub config: LCMSchedulerConfig,
+}
+
+// set_alpha_to_one: True, steps_offset: 0, clip_sample: False
+impl LCMScheduler {
+    /// Creates a new LCM scheduler, `inference_steps` should be at most the number of
+    /// steps of the distillation schedule.
+    pub fn new(inference_steps: usize, config: LCMSchedulerConfig) -> Self {
+        let betas = match config.beta_schedule {
+            BetaSchedule::ScaledLinear => Tensor::linspace(
+                config.beta_start.sqrt(),
+                config.beta_end.sqrt(),
+                config.train_timesteps as i64,
+                kind::FLOAT_CPU,
+            )
+            .square(),
+            BetaSchedule::Linear => Tensor::linspace(
+                config.beta_start,
+                config.beta_end,
+                config.train_timesteps as i64,
+                kind::FLOAT_CPU,
+            ),
+            BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999),
+        };
+        let alphas: Tensor = 1.0 - betas;
+        let alphas_cumprod = Vec::<f64>::try_from(alphas.cumprod(0, Kind::Double)).unwrap();
+
+        // The distillation schedule in decreasing order, e.g. 999, 979, ..., 19 for 50
+        // steps, the inference timesteps are evenly spaced indexes in this schedule.
+        let original_steps = config.original_inference_steps.max(1);
+        let k = config.train_timesteps / original_steps;
+        let original_timesteps: Vec<usize> =
+            (1..=original_steps).rev().map(|i| i * k - 1).collect();
+        let inference_steps = inference_steps.clamp(1, original_steps);
+        let timesteps = (0..inference_steps)
+            .map(|i| original_timesteps[i * original_steps / inference_steps])
+            .collect();
+        Self { timesteps, alphas_cumprod, config }
+    }
+
+    /// The minimum number of inference steps for which this scheduler produces reasonable
+    /// samples, see `validate_inference_steps`.
+    ///
+    /// A single step is enough for LCM models, more steps add details.
+    pub fn min_recommended_steps(&self) -> usize {
+        1
+    }
+
+    pub fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    /// Ensures interchangeability with schedulers that need to scale the denoising model input
+    /// depending on the current timestep.
+    pub fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Tensor {
+        sample
+    }
+
+    // The scalings of the consistency function boundary condition, these make the
+    // denoised sample equal to the input at timestep 0.
+    fn boundary_scalings(&self, timestep: usize) -> (f64, f64) {
+        let sigma_data = 0.5;
+        let t = timestep as f64 * self.config.timestep_scaling;
+        let c_skip = sigma_data * sigma_data / (t * t + sigma_data * sigma_data);
+        let c_out = t / (t * t + sigma_data * sigma_data).sqrt();
+        (c_skip, c_out)
+    }
+
+    /// The denoised sample, x_0, predicted by the consistency function for a model output
+    /// at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let beta_prod_t = 1. - alpha_prod_t;
+        let pred_original_sample = match self.config.prediction_type {
+            PredictionType::Epsilon => {
+                (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt()
+            }
+            PredictionType::VPrediction => {
+                alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output
+            }
+            PredictionType::Sample => model_output.shallow_clone(),
+        };
+        let (c_skip, c_out) = self.boundary_scalings(timestep);
+        c_out * pred_original_sample + c_skip * sample
+    }
+
+    /// Performs a backward step during inference: the denoised sample is noised to the
+    /// next timestep of the schedule, the last step returns the denoised sample.
+    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
+        // https://github.com/huggingface/diffusers/blob/v0.24.0/src/diffusers/schedulers/scheduling_lcm.py#L458
+        let denoised = self.pred_original_sample(model_output, timestep, sample);
+        let step_index = self.timesteps.iter().position(|&t| t == timestep);
+        let prev_timestep = step_index.and_then(|i| self.timesteps.get(i + 1));
+        match prev_timestep {
+            None => denoised,
+            Some(&prev_timestep) => {
+                let alpha_prod_t_prev = self.alphas_cumprod[prev_timestep];
+                let noise = denoised.randn_like();
+                alpha_prod_t_prev.sqrt() * denoised + (1. - alpha_prod_t_prev).sqrt() * noise
+            }
+        }
+    }
+
+    pub fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Tensor {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        alpha_prod.sqrt() * original + (1. - alpha_prod).sqrt() * noise
+    }
+
+    pub fn init_noise_sigma(&self) -> f64 {
+        1.
+    }
+}
@@ -2,7 +2,8 @@ use super::integrate::integrate;
 use super::{interp, BetaSchedule, PredictionType};
 use tch::{kind, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LMSDiscreteSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -91,6 +92,17 @@ impl LMSDiscreteScheduler {
         }
     }
 
+    /// Clears the history of derivatives so that the scheduler can be reused for a new
+    /// generation.
+    pub fn reset(&mut self) {
+        self.derivatives.clear()
+    }
+
+    /// The linear multistep method needs a few steps to warm up its history of derivatives.
+    pub fn min_recommended_steps(&self) -> usize {
+        2 * self.config.order
+    }
+
     pub fn timesteps(&self) -> &[f64] {
         self.timesteps.as_slice()
     }
@@ -128,19 +140,30 @@ impl LMSDiscreteScheduler {
         integration_out.integral
     }
 
-    pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
-        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
-        let sigma = self.sigmas[step_index];
-
-        // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
-        let pred_original_sample = match self.config.prediction_type {
+    /// The predicted denoised sample, x_0, for a model output at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: f64,
+        sample: &Tensor,
+    ) -> Tensor {
+        let sigma = self.sigmas[self.timesteps.iter().position(|&t| t == timestep).unwrap()];
+        match self.config.prediction_type {
             PredictionType::Epsilon => sample - sigma * model_output,
             PredictionType::VPrediction => {
                 model_output * (-sigma / (sigma.powi(2) + 1.).sqrt())
                     + (sample / (sigma.powi(2) + 1.))
             }
             _ => unimplemented!("Prediction type must be one of `epsilon` or `v_prediction`"),
-        };
+        }
+    }
+
+    pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
+        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let sigma = self.sigmas[step_index];
+
+        // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
+        let pred_original_sample = self.pred_original_sample(model_output, timestep, sample);
 
         // 2. Convert to an ODE derivative
         let derivative = (sample - pred_original_sample) / sigma;
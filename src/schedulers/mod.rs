@@ -5,6 +5,8 @@
 
 use tch::{IndexOp, Kind, Tensor};
 
+pub mod ays;
+pub mod config;
 pub mod ddim;
 pub mod ddpm;
 pub mod dpmsolver_multistep;
@@ -14,12 +16,59 @@ pub mod heun_discrete;
 mod integrate;
 pub mod k_dpm_2_ancestral_discrete;
 pub mod k_dpm_2_discrete;
+pub mod lcm;
 pub mod lms_discrete;
 pub mod pndm;
+pub mod tcd;
+
+/// Restart sampling, from Restart Sampling for Improving Generative Processes, Xu et al.
+/// 2023. https://arxiv.org/abs/2306.14878
+///
+/// Once the sampling reaches `sigma_min`, noise is added back to bring the samples to
+/// `sigma_max` and the `[sigma_max, sigma_min]` interval is sampled again with `steps`
+/// steps. This is repeated `repeats` times before continuing with the main schedule, so
+/// the model gets evaluated `steps * repeats` additional times. The added noise
+/// contracts the errors accumulated by the deterministic steps.
+///
+/// The sigmas use the variance exploding formulation of the Euler scheduler. This is
+/// supported by `EulerDiscreteScheduler` and `DPMSolverMultistepScheduler`, the latter
+/// falls back to a first order update after each restart as its previous model outputs
+/// belong to the trajectory before the added noise.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestartConfig {
+    pub sigma_min: f64,
+    pub sigma_max: f64,
+    pub steps: usize,
+    pub repeats: usize,
+}
+
+impl Default for RestartConfig {
+    // The values used for stable diffusion in the paper.
+    fn default() -> Self {
+        Self { sigma_min: 0.1, sigma_max: 2., steps: 3, repeats: 2 }
+    }
+}
+
+impl RestartConfig {
+    // The sigmas of a restart segment, log-linearly spaced from `sigma_max` down to
+    // `sigma`, `sigma` excluded. The sigmas are the ones of the variance exploding
+    // formulation, `sqrt((1 - alpha_bar) / alpha_bar)`.
+    pub(crate) fn segment_sigmas(&self, sigma: f64) -> Vec<f64> {
+        let high = self.sigma_max.max(sigma);
+        (0..self.steps)
+            .map(|j| {
+                let frac = j as f64 / self.steps as f64;
+                (high.ln() * (1. - frac) + sigma.ln() * frac).exp()
+            })
+            .collect()
+    }
+}
 
 /// This represents how beta ranges from its minimum value to the maximum
 /// during training.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BetaSchedule {
     /// Linear interpolation.
     Linear,
@@ -29,26 +78,349 @@ pub enum BetaSchedule {
     SquaredcosCapV2,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PredictionType {
     Epsilon,
     VPrediction,
     Sample,
 }
 
+impl PredictionType {
+    /// Parses the diffusers `prediction_type` config value.
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "epsilon" => Ok(Self::Epsilon),
+            "v_prediction" => Ok(Self::VPrediction),
+            "sample" => Ok(Self::Sample),
+            _ => anyhow::bail!("unsupported prediction type {name:?}"),
+        }
+    }
+}
+
+/// How the inference timesteps are spread over the training timesteps, see Table 2 of
+/// Common Diffusion Noise Schedules and Sample Steps are Flawed, S. Lin et al, 2023.
+/// https://arxiv.org/abs/2305.08891
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestepSpacing {
+    /// Evenly spaced from the last training timestep to 0, both included.
+    #[default]
+    Linspace,
+    /// Multiples of `train_timesteps / inference_steps` starting from 0, the last
+    /// training timestep is never used.
+    Leading,
+    /// Evenly spaced from the last training timestep, the last step does not reach 0.
+    Trailing,
+    /// The spacing of the SGM repo used for SDXL, evenly spaced over `inference_steps + 1`
+    /// values from the last training timestep to 0, 0 being dropped.
+    SgmUniform,
+}
+
+impl TimestepSpacing {
+    /// Parses the diffusers `timestep_spacing` config value.
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "linspace" => Ok(Self::Linspace),
+            "leading" => Ok(Self::Leading),
+            "trailing" => Ok(Self::Trailing),
+            "sgm_uniform" => Ok(Self::SgmUniform),
+            _ => anyhow::bail!("unsupported timestep spacing {name:?}"),
+        }
+    }
+
+    /// Returns the `inference_steps` timesteps in decreasing order.
+    pub fn timesteps(&self, inference_steps: usize, train_timesteps: usize) -> Vec<f64> {
+        let (n, last) = (inference_steps as f64, (train_timesteps - 1) as f64);
+        let steps = (0..inference_steps).map(|i| i as f64);
+        match self {
+            Self::Linspace if inference_steps == 1 => vec![last],
+            Self::Linspace => steps.map(|i| last * (1. - i / (n - 1.))).collect(),
+            Self::Leading => {
+                let step_ratio = (train_timesteps / inference_steps) as f64;
+                steps.rev().map(|i| i * step_ratio).collect()
+            }
+            Self::Trailing => {
+                let step_ratio = train_timesteps as f64 / n;
+                steps.map(|i| (train_timesteps as f64 - i * step_ratio).round() - 1.).collect()
+            }
+            Self::SgmUniform => steps.map(|i| last * (1. - i / n)).collect(),
+        }
+    }
+}
+
+/// Checks that a number of inference steps is at least the minimum recommended by a
+/// scheduler, as returned by its `min_recommended_steps` method. Below this minimum the
+/// samples are usually very noisy.
+///
+/// When `strict` is set, an error is returned for step counts below the minimum. Otherwise
+/// a warning message is returned so that the caller can display it.
+pub fn validate_inference_steps(
+    inference_steps: usize,
+    min_recommended_steps: usize,
+    strict: bool,
+) -> anyhow::Result<Option<String>> {
+    if inference_steps >= min_recommended_steps {
+        return Ok(None);
+    }
+    let msg = format!(
+        "{inference_steps} inference steps is below the scheduler recommended minimum of {min_recommended_steps}"
+    );
+    if strict {
+        anyhow::bail!(msg)
+    }
+    Ok(Some(msg))
+}
+
+/// Returns the index of the first step to run when starting the denoising from some
+/// noise added at an absolute `start_timestep`, as in SDEdit, rather than from a
+/// strength. This is the index of the first timestep of the schedule that is at most
+/// `start_timestep`, so the noise level only matches the first step exactly when
+/// `start_timestep` is part of the schedule.
+///
+/// An error is returned if `start_timestep` is not a valid training timestep or if it is
+/// below all the timesteps of the schedule.
+pub fn start_index_for_timestep(
+    timesteps: &[usize],
+    start_timestep: usize,
+    train_timesteps: usize,
+) -> anyhow::Result<usize> {
+    if start_timestep >= train_timesteps {
+        anyhow::bail!("start timestep {start_timestep} should be below {train_timesteps}")
+    }
+    match timesteps.iter().position(|&t| t <= start_timestep) {
+        Some(index) => Ok(index),
+        None => {
+            anyhow::bail!("start timestep {start_timestep} is below all the scheduler timesteps")
+        }
+    }
+}
+
+/// A timestep as used by the schedulers, either an integer training timestep or a
+/// continuous one for the Karras style schedulers.
+pub trait Timestep: Copy {
+    /// The timestep value to be passed to the UNet.
+    fn as_f64(self) -> f64;
+}
+
+impl Timestep for usize {
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Timestep for f64 {
+    fn as_f64(self) -> f64 {
+        self
+    }
+}
+
+/// The operations shared by all the schedulers, this is what generic denoising loops
+/// such as `pipelines::denoise::DenoiseLoop` rely on. Each scheduler also provides
+/// these as inherent methods so that the trait does not have to be imported.
+pub trait Scheduler {
+    type Timestep: Timestep;
+
+    fn timesteps(&self) -> &[Self::Timestep];
+
+    fn init_noise_sigma(&self) -> f64;
+
+    fn scale_model_input(&self, sample: Tensor, timestep: Self::Timestep) -> Tensor;
+
+    fn step(&mut self, model_output: &Tensor, timestep: Self::Timestep, sample: &Tensor) -> Tensor;
+
+    /// Clears the state carried between steps, e.g. the history of multistep solvers, so
+    /// that a scheduler can be reused for a new generation. Stateless schedulers do not
+    /// have anything to clear.
+    fn reset(&mut self) {}
+
+    /// The predicted denoised sample, x_0, for a model output, `None` for the schedulers
+    /// that do not expose it.
+    fn pred_original_sample(
+        &self,
+        _model_output: &Tensor,
+        _timestep: Self::Timestep,
+        _sample: &Tensor,
+    ) -> Option<Tensor> {
+        None
+    }
+
+    /// Replaces the inference schedule with some custom timesteps, e.g. to repeat or skip
+    /// some of them. The timesteps have to be within the training range and in
+    /// non-increasing order. Schedulers that cannot follow an arbitrary schedule return
+    /// an error.
+    fn set_timesteps_custom(&mut self, _timesteps: &[f64]) -> anyhow::Result<()> {
+        anyhow::bail!("custom timesteps are not supported by this scheduler")
+    }
+
+    /// The configuration of the scheduler, `None` for schedulers defined outside of this
+    /// crate.
+    fn scheduler_config(&self) -> Option<config::SchedulerConfig> {
+        None
+    }
+}
+
+// Checks a custom schedule as passed to `Scheduler::set_timesteps_custom`, `integers`
+// is set for the schedulers using integer timesteps.
+pub(crate) fn check_custom_timesteps(
+    timesteps: &[f64],
+    train_timesteps: usize,
+    integers: bool,
+) -> anyhow::Result<()> {
+    if timesteps.is_empty() {
+        anyhow::bail!("empty timestep schedule")
+    }
+    let last = (train_timesteps - 1) as f64;
+    for &t in timesteps.iter() {
+        if !(0. ..=last).contains(&t) {
+            anyhow::bail!("invalid timestep {t}, expected a value in [0, {last}]")
+        }
+        if integers && t.fract() != 0. {
+            anyhow::bail!("invalid timestep {t}, expected an integer")
+        }
+    }
+    if let Some(w) = timesteps.windows(2).find(|w| w[1] > w[0]) {
+        anyhow::bail!("timesteps should be decreasing, got {} followed by {}", w[0], w[1])
+    }
+    Ok(())
+}
+
+// The optional methods implemented by a scheduler, `reset` for schedulers keeping some
+// state between steps, `pred_original_sample` for the ones exposing x_0, and
+// `set_timesteps_custom` for the ones accepting arbitrary schedules.
+macro_rules! scheduler_method {
+    ($scheduler:ty, $timestep:ty, reset) => {
+        fn reset(&mut self) {
+            <$scheduler>::reset(self)
+        }
+    };
+    ($scheduler:ty, $timestep:ty, pred_original_sample) => {
+        fn pred_original_sample(
+            &self,
+            model_output: &Tensor,
+            timestep: $timestep,
+            sample: &Tensor,
+        ) -> Option<Tensor> {
+            Some(<$scheduler>::pred_original_sample(self, model_output, timestep, sample))
+        }
+    };
+    ($scheduler:ty, $timestep:ty, set_timesteps_custom) => {
+        fn set_timesteps_custom(&mut self, timesteps: &[f64]) -> anyhow::Result<()> {
+            <$scheduler>::set_timesteps_custom(self, timesteps)
+        }
+    };
+}
+
+macro_rules! impl_scheduler {
+    ($scheduler:ty, $timestep:ty $(, $method:ident)*) => {
+        impl Scheduler for $scheduler {
+            type Timestep = $timestep;
+
+            fn timesteps(&self) -> &[$timestep] {
+                <$scheduler>::timesteps(self)
+            }
+
+            fn init_noise_sigma(&self) -> f64 {
+                <$scheduler>::init_noise_sigma(self)
+            }
+
+            fn scale_model_input(&self, sample: Tensor, timestep: $timestep) -> Tensor {
+                <$scheduler>::scale_model_input(self, sample, timestep)
+            }
+
+            fn step(
+                &mut self,
+                model_output: &Tensor,
+                timestep: $timestep,
+                sample: &Tensor,
+            ) -> Tensor {
+                <$scheduler>::step(self, model_output, timestep, sample)
+            }
+
+            fn scheduler_config(&self) -> Option<config::SchedulerConfig> {
+                Some(self.config.clone().into())
+            }
+
+            $(scheduler_method!($scheduler, $timestep, $method);)*
+        }
+    };
+}
+
+impl_scheduler!(ddim::DDIMScheduler, usize, pred_original_sample, set_timesteps_custom);
+impl_scheduler!(ddpm::DDPMScheduler, usize, pred_original_sample);
+impl_scheduler!(dpmsolver_multistep::DPMSolverMultistepScheduler, usize, reset);
+impl_scheduler!(
+    euler_ancestral_discrete::EulerAncestralDiscreteScheduler,
+    f64,
+    pred_original_sample
+);
+impl_scheduler!(
+    euler_discrete::EulerDiscreteScheduler,
+    f64,
+    reset,
+    pred_original_sample,
+    set_timesteps_custom
+);
+impl_scheduler!(heun_discrete::HeunDiscreteScheduler, f64, reset);
+impl_scheduler!(k_dpm_2_ancestral_discrete::KDPM2AncestralDiscreteScheduler, f64, reset);
+impl_scheduler!(k_dpm_2_discrete::KDPM2DiscreteScheduler, f64, reset);
+impl_scheduler!(lcm::LCMScheduler, usize, pred_original_sample);
+impl_scheduler!(lms_discrete::LMSDiscreteScheduler, f64, reset, pred_original_sample);
+impl_scheduler!(pndm::PNDMScheduler, usize, reset);
+impl_scheduler!(tcd::TCDScheduler, usize, pred_original_sample);
+
+/// Detects when the latents stop changing between denoising steps so that the loop
+/// can be stopped early.
+///
+/// This only makes sense for deterministic solvers that converge, e.g. DPM-Solver
+/// multistep, Euler, or DDIM. Ancestral samplers such as Euler ancestral or DDPM add
+/// fresh noise at each step so the latents never settle.
+#[derive(Debug)]
+pub struct EarlyStopping {
+    tolerance: f64,
+    previous: Option<Tensor>,
+}
+
+impl EarlyStopping {
+    /// `tolerance` is compared to the root mean square of the difference between the
+    /// latents of two consecutive steps.
+    pub fn new(tolerance: f64) -> Self {
+        Self { tolerance, previous: None }
+    }
+
+    /// Records the latents after a step and returns true when the change with the
+    /// latents of the previous step is below the tolerance.
+    pub fn converged(&mut self, latents: &Tensor) -> bool {
+        let converged = match &self.previous {
+            None => false,
+            Some(previous) => {
+                let delta = (latents - previous).square().mean(Kind::Float).sqrt();
+                f64::try_from(delta).is_ok_and(|delta| delta < self.tolerance)
+            }
+        };
+        self.previous = Some(latents.copy());
+        converged
+    }
+}
+
+/// The signal to noise ratio `alpha_bar_t / (1 - alpha_bar_t)` for each training timestep.
+pub(crate) fn snr_from_alphas_cumprod(alphas_cumprod: &[f64]) -> Vec<f64> {
+    alphas_cumprod.iter().map(|a| a / (1. - a)).collect()
+}
+
 /// Create a beta schedule that discretizes the given alpha_t_bar function, which defines the cumulative product of
 /// `(1-beta)` over time from `t = [0,1]`.
 ///
 /// Contains a function `alpha_bar` that takes an argument `t` and transforms it to the cumulative product of `(1-beta)`
 /// up to that part of the diffusion process.
 pub(crate) fn betas_for_alpha_bar(num_diffusion_timesteps: usize, max_beta: f64) -> Tensor {
-    let alpha_bar = |time_step: usize| {
-        f64::cos((time_step as f64 + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2)
+    let alpha_bar = |time_step: f64| {
+        f64::cos((time_step + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2)
     };
     let mut betas = Vec::with_capacity(num_diffusion_timesteps);
     for i in 0..num_diffusion_timesteps {
-        let t1 = i / num_diffusion_timesteps;
-        let t2 = (i + 1) / num_diffusion_timesteps;
+        let t1 = i as f64 / num_diffusion_timesteps as f64;
+        let t2 = (i + 1) as f64 / num_diffusion_timesteps as f64;
         betas.push((1.0 - alpha_bar(t2) / alpha_bar(t1)).min(max_beta));
     }
     Tensor::from_slice(&betas)
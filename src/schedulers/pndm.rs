@@ -1,7 +1,8 @@
 use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
 use tch::{kind, Kind, Tensor};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PNDMSchedulerConfig {
     /// The value of beta at the beginning of training.
     pub beta_start: f64,
@@ -103,6 +104,19 @@ impl PNDMScheduler {
         }
     }
 
+    /// Clears the warm up state and the history of model outputs, `ets`, so that the
+    /// scheduler can be reused for a new generation.
+    pub fn reset(&mut self) {
+        self.counter = 0;
+        self.cur_sample = None;
+        self.ets.clear()
+    }
+
+    /// The PLMS method starts with a warm up phase of lower order steps.
+    pub fn min_recommended_steps(&self) -> usize {
+        10
+    }
+
     pub fn timesteps(&self) -> &[usize] {
         self.timesteps.as_slice()
     }
@@ -0,0 +1,186 @@
+//! # Trajectory Consistency Distillation Scheduler
+//!
+//! The strategic stochastic sampling of Trajectory Consistency Distillation (TCD), a
+//! few-step sampler for the models distilled with TCD, usually through a TCD-LoRA. It
+//! uses the same timesteps as the LCM scheduler, but each step predicts the sample at
+//! an intermediary timestep `s = (1 - eta) * t_prev` on the probability flow trajectory
+//! rather than the fully denoised sample, and only the remaining part of the noise, from
+//! `s` to `t_prev`, is sampled again.
+//!
+//! `eta`, called gamma in the paper, sets the amount of stochasticity: 0 gives
+//! deterministic sampling with more details, higher values smoother images. The
+//! diffusers default is 0.3. As for LCM, the models are sampled with a guidance scale
+//! of 1.
+//!
+//! Trajectory Consistency Distillation, J. Zheng et al, 2024.
+//! https://arxiv.org/abs/2402.19159
+use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use tch::{kind, Kind, Tensor};
+
+/// The configuration for the TCD scheduler.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TCDSchedulerConfig {
+    /// The value of beta at the beginning of training.
+    pub beta_start: f64,
+    /// The value of beta at the end of training.
+    pub beta_end: f64,
+    /// How beta evolved during training.
+    pub beta_schedule: BetaSchedule,
+    /// The number of steps of the schedule used for distillation, the inference timesteps
+    /// are taken from this schedule.
+    pub original_inference_steps: usize,
+    /// The stochasticity of the sampling, between 0 (deterministic) and 1.
+    pub eta: f64,
+    /// The prediction type of the model.
+    pub prediction_type: PredictionType,
+    /// The number of diffusion steps used to train the model.
+    pub train_timesteps: usize,
+}
+
+impl Default for TCDSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085,
+            beta_end: 0.012,
+            beta_schedule: BetaSchedule::ScaledLinear,
+            original_inference_steps: 50,
+            eta: 0.3,
+            prediction_type: PredictionType::Epsilon,
+            train_timesteps: 1000,
+        }
+    }
+}
+
+/// The TCD scheduler.
+#[derive(Debug, Clone)]
+pub struct TCDScheduler {
+    timesteps: Vec<usize>,
+    alphas_cumprod: Vec<f64>,
+    pub config: TCDSchedulerConfig,
+}
+
+// set_alpha_to_one: True, steps_offset: 0, clip_sample: False
+impl TCDScheduler {
+    /// Creates a new TCD scheduler, `inference_steps` should be at most the number of
+    /// steps of the distillation schedule.
+    pub fn new(inference_steps: usize, config: TCDSchedulerConfig) -> Self {
+        let betas = match config.beta_schedule {
+            BetaSchedule::ScaledLinear => Tensor::linspace(
+                config.beta_start.sqrt(),
+                config.beta_end.sqrt(),
+                config.train_timesteps as i64,
+                kind::FLOAT_CPU,
+            )
+            .square(),
+            BetaSchedule::Linear => Tensor::linspace(
+                config.beta_start,
+                config.beta_end,
+                config.train_timesteps as i64,
+                kind::FLOAT_CPU,
+            ),
+            BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999),
+        };
+        let alphas: Tensor = 1.0 - betas;
+        let alphas_cumprod = Vec::<f64>::try_from(alphas.cumprod(0, Kind::Double)).unwrap();
+
+        // Evenly spaced indexes in the distillation schedule, as for LCM.
+        let original_steps = config.original_inference_steps.max(1);
+        let k = config.train_timesteps / original_steps;
+        let original_timesteps: Vec<usize> =
+            (1..=original_steps).rev().map(|i| i * k - 1).collect();
+        let inference_steps = inference_steps.clamp(1, original_steps);
+        let timesteps = (0..inference_steps)
+            .map(|i| original_timesteps[i * original_steps / inference_steps])
+            .collect();
+        Self { timesteps, alphas_cumprod, config }
+    }
+
+    /// The minimum number of inference steps for which this scheduler produces reasonable
+    /// samples, see `validate_inference_steps`.
+    ///
+    /// TCD models give coherent images from 2 steps, 4 to 8 steps add details.
+    pub fn min_recommended_steps(&self) -> usize {
+        2
+    }
+
+    pub fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    /// Ensures interchangeability with schedulers that need to scale the denoising model input
+    /// depending on the current timestep.
+    pub fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Tensor {
+        sample
+    }
+
+    // The predicted denoised sample and noise.
+    fn predictions(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> (Tensor, Tensor) {
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let beta_prod_t = 1. - alpha_prod_t;
+        match self.config.prediction_type {
+            PredictionType::Epsilon => {
+                let pred_original_sample =
+                    (sample - beta_prod_t.sqrt() * model_output) / alpha_prod_t.sqrt();
+                (pred_original_sample, model_output.shallow_clone())
+            }
+            PredictionType::VPrediction => (
+                alpha_prod_t.sqrt() * sample - beta_prod_t.sqrt() * model_output,
+                alpha_prod_t.sqrt() * model_output + beta_prod_t.sqrt() * sample,
+            ),
+            PredictionType::Sample => {
+                let pred_epsilon =
+                    (sample - alpha_prod_t.sqrt() * model_output) / beta_prod_t.sqrt();
+                (model_output.shallow_clone(), pred_epsilon)
+            }
+        }
+    }
+
+    /// The predicted denoised sample, x_0, for a model output at the given timestep.
+    pub fn pred_original_sample(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        self.predictions(model_output, timestep, sample).0
+    }
+
+    /// Performs a backward step during inference.
+    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
+        // https://github.com/huggingface/diffusers/blob/v0.27.0/src/diffusers/schedulers/scheduling_tcd.py#L525
+        let step_index = self.timesteps.iter().position(|&t| t == timestep);
+        let prev_timestep = step_index.and_then(|i| self.timesteps.get(i + 1));
+        let is_last_step = prev_timestep.is_none();
+        let prev_timestep = prev_timestep.copied().unwrap_or(0);
+        let timestep_s = ((1. - self.config.eta) * prev_timestep as f64).floor() as usize;
+
+        let (pred_original_sample, pred_epsilon) = self.predictions(model_output, timestep, sample);
+        let alpha_prod_s = self.alphas_cumprod[timestep_s];
+        let pred_noised_sample =
+            alpha_prod_s.sqrt() * pred_original_sample + (1. - alpha_prod_s).sqrt() * pred_epsilon;
+        if self.config.eta > 0. && !is_last_step {
+            // Noises the sample from timestep s back to the previous timestep.
+            let alpha_prod_t_prev = self.alphas_cumprod[prev_timestep];
+            let ratio = alpha_prod_t_prev / alpha_prod_s;
+            let noise = pred_noised_sample.randn_like();
+            ratio.sqrt() * pred_noised_sample + (1. - ratio).sqrt() * noise
+        } else {
+            pred_noised_sample
+        }
+    }
+
+    pub fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Tensor {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        alpha_prod.sqrt() * original + (1. - alpha_prod).sqrt() * noise
+    }
+
+    pub fn init_noise_sigma(&self) -> f64 {
+        1.
+    }
+}
@@ -8,7 +8,8 @@ use std::collections::{HashMap, HashSet};
 use std::io::BufRead;
 use tch::{nn, nn::Module, Device, Kind, Tensor};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Activation {
     QuickGelu,
     Gelu,
@@ -23,22 +24,55 @@ impl Module for Activation {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The tokenizer flavor used with a text encoder. Both use the same vocabulary and the
+/// same start and end of text token ids, 49406 and 49407, but they pad differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerVariant {
+    /// The original OpenAI CLIP tokenizer as used by stable diffusion 1.x, the padding
+    /// uses the end of text token.
+    OpenAiClip,
+    /// The OpenCLIP tokenizer as used by stable diffusion 2.x and SDXL, the padding uses
+    /// the `!` token with id 0.
+    OpenClip,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     vocab_size: i64,
     embed_dim: i64,         // aka config.hidden_size
     activation: Activation, // aka config.hidden_act
     intermediate_size: i64,
     max_position_embeddings: usize,
-    // The character to use for padding, use EOS when not set.
-    pad_with: Option<String>,
+    tokenizer_variant: TokenizerVariant,
     num_hidden_layers: i64,
     num_attention_heads: i64,
-    #[allow(dead_code)]
     projection_dim: i64,
+    // Mask the padding tokens in the self-attention layers on top of the causal mask.
+    use_attention_mask: bool,
 }
 
 impl Config {
+    /// Checks that the dimensions are consistent.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let dims = [self.vocab_size, self.embed_dim, self.intermediate_size, self.projection_dim];
+        if dims.iter().any(|&d| d <= 0) || self.max_position_embeddings == 0 {
+            anyhow::bail!("the clip dimensions should be positive")
+        }
+        if self.num_hidden_layers < 1 {
+            anyhow::bail!("invalid num_hidden_layers {}", self.num_hidden_layers)
+        }
+        if self.num_attention_heads <= 0 || self.embed_dim % self.num_attention_heads != 0 {
+            anyhow::bail!(
+                "embed_dim {} is not a multiple of num_attention_heads {}",
+                self.embed_dim,
+                self.num_attention_heads
+            )
+        }
+        Ok(())
+    }
+
     // The config details can be found in the "text_config" section of this json file:
     // https://huggingface.co/openai/clip-vit-large-patch14/blob/main/config.json
     pub fn v1_5() -> Self {
@@ -47,11 +81,12 @@ impl Config {
             embed_dim: 768,
             intermediate_size: 3072,
             max_position_embeddings: 77,
-            pad_with: None,
+            tokenizer_variant: TokenizerVariant::OpenAiClip,
             num_hidden_layers: 12,
             num_attention_heads: 12,
             projection_dim: 768,
             activation: Activation::QuickGelu,
+            use_attention_mask: false,
         }
     }
 
@@ -62,13 +97,70 @@ impl Config {
             embed_dim: 1024,
             intermediate_size: 4096,
             max_position_embeddings: 77,
-            pad_with: Some("!".to_string()),
+            tokenizer_variant: TokenizerVariant::OpenClip,
             num_hidden_layers: 23,
             num_attention_heads: 16,
             projection_dim: 512,
             activation: Activation::Gelu,
+            use_attention_mask: false,
+        }
+    }
+
+    // The second text encoder of SDXL, OpenCLIP ViT-bigG/14.
+    // https://huggingface.co/stabilityai/stable-diffusion-xl-base-1.0/blob/main/text_encoder_2/config.json
+    pub fn sdxl_text_encoder_2() -> Self {
+        Self {
+            vocab_size: 49408,
+            embed_dim: 1280,
+            intermediate_size: 5120,
+            max_position_embeddings: 77,
+            tokenizer_variant: TokenizerVariant::OpenClip,
+            num_hidden_layers: 32,
+            num_attention_heads: 20,
+            projection_dim: 1280,
+            activation: Activation::Gelu,
+            use_attention_mask: false,
         }
     }
+
+    /// The dimension of the text model hidden states.
+    pub fn embed_dim(&self) -> i64 {
+        self.embed_dim
+    }
+
+    /// The output dimension of the text projection.
+    pub fn projection_dim(&self) -> i64 {
+        self.projection_dim
+    }
+
+    pub fn tokenizer_variant(&self) -> TokenizerVariant {
+        self.tokenizer_variant
+    }
+
+    /// Overrides the tokenizer variant, e.g. for fine-tuned models that changed the padding.
+    pub fn with_tokenizer_variant(mut self, tokenizer_variant: TokenizerVariant) -> Self {
+        self.tokenizer_variant = tokenizer_variant;
+        self
+    }
+
+    /// The id of the end of text token, the last one of the vocabulary.
+    pub fn end_of_text_token(&self) -> i64 {
+        self.vocab_size - 1
+    }
+
+    /// Enables masking the padding tokens in the text model self-attention layers, this
+    /// is disabled by default.
+    ///
+    /// As the causal mask already prevents the prompt tokens from attending to the padding
+    /// that follows them, this only changes the embeddings of the padding positions. These
+    /// are still attended to by the UNet so this matters for checkpoints whose text encoder
+    /// was used with an attention mask during training, e.g. models fine-tuned with the
+    /// diffusers training scripts when the text encoder attention mask option is set. The
+    /// original stable diffusion checkpoints were trained without one.
+    pub fn with_attention_mask(mut self, use_attention_mask: bool) -> Self {
+        self.use_attention_mask = use_attention_mask;
+        self
+    }
 }
 
 const BYTES_TO_UNICODE: [(u8, char); 256] = [
@@ -333,6 +425,20 @@ const BYTES_TO_UNICODE: [(u8, char); 256] = [
 const PAT: &str =
     r"<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+";
 
+/// How tokenized sequences get padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingStrategy {
+    /// Pad the sequences to the maximum length supported by the text model.
+    MaxLength,
+    /// Pad the sequences to the length of the longest sequence in the batch.
+    Longest,
+    /// Do not pad the sequences.
+    None,
+}
+
+/// A prompt tokenized as multiple chunks, each chunk having a fixed length.
+pub type TokenChunks = Vec<Vec<usize>>;
+
 // This is mostly a Rust rewrite of the original Python CLIP code.
 // https://github.com/openai/CLIP/blob/main/clip/simple_tokenizer.py
 /// A tokenizer for CLIP.
@@ -452,13 +558,39 @@ impl Tokenizer {
         word.iter().filter_map(|x| self.encoder.get(x)).copied().collect()
     }
 
-    pub fn encode_pad(&self, s: &str, pad_size_to: Option<usize>) -> anyhow::Result<Vec<usize>> {
+    // Tokenizes a string without adding the start and end of text tokens.
+    fn bpe_tokens(&self, s: &str) -> Vec<usize> {
         let s = s.to_lowercase();
-        let mut bpe_tokens: Vec<usize> = vec![self.start_of_text_token];
+        let mut bpe_tokens = vec![];
         for token in self.re.captures_iter(&s) {
             let token = token.get(0).unwrap().as_str();
             bpe_tokens.extend(self.bpe(token))
         }
+        bpe_tokens
+    }
+
+    /// The id of the token used for padding, this depends on the tokenizer variant.
+    pub fn pad_token(&self) -> anyhow::Result<usize> {
+        match self.config.tokenizer_variant {
+            TokenizerVariant::OpenAiClip => Ok(self.end_of_text_token),
+            TokenizerVariant::OpenClip => match self.encoder.get("!") {
+                None => anyhow::bail!("no encoding for padding character !"),
+                Some(v) => Ok(*v),
+            },
+        }
+    }
+
+    pub fn start_of_text_token(&self) -> usize {
+        self.start_of_text_token
+    }
+
+    pub fn end_of_text_token(&self) -> usize {
+        self.end_of_text_token
+    }
+
+    pub fn encode_pad(&self, s: &str, pad_size_to: Option<usize>) -> anyhow::Result<Vec<usize>> {
+        let mut bpe_tokens: Vec<usize> = vec![self.start_of_text_token];
+        bpe_tokens.extend(self.bpe_tokens(s));
         match pad_size_to {
             None => bpe_tokens.push(self.end_of_text_token),
             Some(pad_size_to) => {
@@ -467,13 +599,7 @@ impl Tokenizer {
                     std::cmp::min(bpe_tokens.len(), pad_size_to - 1),
                     Default::default,
                 );
-                let pad_with = match &self.config.pad_with {
-                    None => self.end_of_text_token,
-                    Some(pad_with) => match self.encoder.get(pad_with) {
-                        None => anyhow::bail!("no encoding for padding character {}", pad_with),
-                        Some(v) => *v,
-                    },
-                };
+                let pad_with = self.pad_token()?;
                 while bpe_tokens.len() < pad_size_to {
                     bpe_tokens.push(pad_with)
                 }
@@ -482,17 +608,165 @@ impl Tokenizer {
         Ok(bpe_tokens)
     }
 
+    /// Tokenizes a string with the given padding strategy. When `truncation` is set, the
+    /// sequences longer than the maximum length supported by the text model are truncated,
+    /// the last token being kept as the end of text token.
+    ///
+    /// A single sequence being its own longest sequence, `PaddingStrategy::Longest` does not
+    /// pad here, see `encode_batch`.
+    pub fn encode_with(
+        &self,
+        s: &str,
+        padding: PaddingStrategy,
+        truncation: bool,
+    ) -> anyhow::Result<Vec<usize>> {
+        let max_len = self.config.max_position_embeddings;
+        let mut tokens = vec![self.start_of_text_token];
+        tokens.extend(self.bpe_tokens(s));
+        tokens.push(self.end_of_text_token);
+        if truncation && tokens.len() > max_len {
+            tokens.truncate(max_len - 1);
+            tokens.push(self.end_of_text_token);
+        }
+        if padding == PaddingStrategy::MaxLength && tokens.len() < max_len {
+            tokens.resize(max_len, self.pad_token()?)
+        }
+        Ok(tokens)
+    }
+
+    /// Tokenizes a batch of strings, see `encode_with`.
+    pub fn encode_batch<S: AsRef<str>>(
+        &self,
+        ss: &[S],
+        padding: PaddingStrategy,
+        truncation: bool,
+    ) -> anyhow::Result<Vec<Vec<usize>>> {
+        let mut batch = ss
+            .iter()
+            .map(|s| self.encode_with(s.as_ref(), padding, truncation))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if padding == PaddingStrategy::Longest {
+            let longest = batch.iter().map(|t| t.len()).max().unwrap_or(0);
+            let pad_with = self.pad_token()?;
+            for tokens in batch.iter_mut() {
+                tokens.resize(longest, pad_with)
+            }
+        }
+        Ok(batch)
+    }
+
     /// The main tokenization entry point, takes as input a string and returns the list of tokens.
     pub fn encode(&self, s: &str) -> anyhow::Result<Vec<usize>> {
         self.encode_pad(s, Some(self.config.max_position_embeddings))
     }
 
+    /// Tokenizes a prompt of arbitrary length as a list of chunks that can each be
+    /// processed by the text model, similar to the AUTOMATIC1111 long prompt handling.
+    ///
+    /// Each chunk contains up to `max_position_embeddings - 2` tokens surrounded by the
+    /// start and end of text tokens and is padded to `max_position_embeddings`. When a
+    /// chunk is full, it is cut after the last comma if there is one among its final
+    /// tokens so that comma separated concepts are not split between chunks. The `BREAK`
+    /// keyword, upper case and as a standalone word, forces the start of a new chunk.
+    /// Leading, trailing or repeated `BREAK`s do not add empty chunks, an empty prompt
+    /// still gives a single empty chunk.
+    pub fn encode_chunks(&self, s: &str) -> anyhow::Result<TokenChunks> {
+        // How far back to look for a comma when a chunk is full.
+        const COMMA_LOOKBACK: usize = 20;
+        let chunk_len = self.config.max_position_embeddings - 2;
+        let comma_token = self.encoder.get(",</w>").copied();
+        let pad_with = self.pad_token()?;
+        static BREAK_RE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+        let break_re =
+            BREAK_RE.get_or_init(|| regex::Regex::new(r"\s*\bBREAK\b\s*").expect("valid regex"));
+        let mut segments: Vec<&str> =
+            break_re.split(s).filter(|segment| !segment.trim().is_empty()).collect();
+        if segments.is_empty() {
+            segments.push("")
+        }
+        let mut chunks = vec![];
+        for segment in segments {
+            let mut tokens = self.bpe_tokens(segment).into_iter().peekable();
+            let mut chunk: Vec<usize> = vec![];
+            let mut last_comma = None;
+            while let Some(token) = tokens.next() {
+                chunk.push(token);
+                if Some(token) == comma_token {
+                    last_comma = Some(chunk.len())
+                }
+                if chunk.len() == chunk_len && tokens.peek().is_some() {
+                    let rest = match last_comma {
+                        Some(c) if chunk_len - c <= COMMA_LOOKBACK => chunk.split_off(c),
+                        _ => vec![],
+                    };
+                    chunks.push(std::mem::replace(&mut chunk, rest));
+                    last_comma = None;
+                }
+            }
+            chunks.push(chunk)
+        }
+        let chunks = chunks
+            .into_iter()
+            .map(|tokens| {
+                let mut chunk = Vec::with_capacity(chunk_len + 2);
+                chunk.push(self.start_of_text_token);
+                chunk.extend(tokens);
+                chunk.push(self.end_of_text_token);
+                chunk.resize(chunk_len + 2, pad_with);
+                chunk
+            })
+            .collect();
+        Ok(chunks)
+    }
+
+    /// Returns the attention mask for a tokenized sequence, i.e. 1 for all the tokens up
+    /// to the first end of text token included and 0 for the padding after it.
+    pub fn attention_mask(&self, tokens: &[usize]) -> Vec<i64> {
+        let len = match tokens.iter().position(|&t| t == self.end_of_text_token) {
+            None => tokens.len(),
+            Some(p) => p + 1,
+        };
+        (0..tokens.len()).map(|i| i64::from(i < len)).collect()
+    }
+
+    /// Tokenizes a prompt and a negative prompt as chunks, see `encode_chunks`. Both
+    /// prompts are chunked independently and the one with the fewest chunks is then
+    /// padded with empty chunks so that their embeddings can be batched together for
+    /// classifier free guidance.
+    pub fn encode_chunks_pair(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+    ) -> anyhow::Result<(TokenChunks, TokenChunks)> {
+        let mut chunks = self.encode_chunks(prompt)?;
+        let mut negative_chunks = self.encode_chunks(negative_prompt)?;
+        let n_chunks = usize::max(chunks.len(), negative_chunks.len());
+        let empty_chunk = self.encode_pad("", Some(self.config.max_position_embeddings))?;
+        chunks.resize(n_chunks, empty_chunk.clone());
+        negative_chunks.resize(n_chunks, empty_chunk);
+        Ok((chunks, negative_chunks))
+    }
+
     /// The inverse of the tokenization process, takes as input a list of tokens and returns a
     /// string that produces this tokenization.
     pub fn decode(&self, tokens: &[usize]) -> String {
         let s: String = tokens.iter().map(|token| self.decoder[token].as_str()).collect();
         s.replace("</w>", " ")
     }
+
+    /// Returns each token of a prompt together with its string piece, including the start
+    /// and end of text tokens but without padding. This is meant to help debugging how a
+    /// prompt gets tokenized, the end of word marker is kept as `</w>` in the pieces.
+    pub fn tokenize_debug(&self, prompt: &str) -> anyhow::Result<Vec<(usize, String)>> {
+        let tokens = self.encode_pad(prompt, None)?;
+        tokens
+            .into_iter()
+            .map(|token| match self.decoder.get(&token) {
+                None => anyhow::bail!("no piece for token {token}"),
+                Some(piece) => Ok((token, piece.clone())),
+            })
+            .collect()
+    }
 }
 
 // CLIP Text Model
@@ -660,6 +934,17 @@ impl ClipEncoder {
         }
         xs
     }
+
+    // Returns the output of each of the layers.
+    fn forward_hidden_states(&self, xs: &Tensor, causal_attention_mask: &Tensor) -> Vec<Tensor> {
+        let mut hidden_states = Vec::with_capacity(self.layers.len());
+        let mut xs = xs.shallow_clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, causal_attention_mask);
+            hidden_states.push(xs.shallow_clone())
+        }
+        hidden_states
+    }
 }
 
 /// A CLIP transformer based model.
@@ -668,8 +953,16 @@ pub struct ClipTextTransformer {
     embeddings: ClipTextEmbeddings,
     encoder: ClipEncoder,
     final_layer_norm: nn::LayerNorm,
+    use_attention_mask: bool,
+    end_of_text_token: i64,
 }
 
+// SAFETY: besides the layer weights, the transformer holds the constant position ids,
+// only used to index the position embedding, and plain values. The causal and padding
+// attention masks are built for each call, so concurrent forwards only read the tensors
+// stored here, which libtorch supports.
+unsafe impl Sync for ClipTextTransformer {}
+
 impl ClipTextTransformer {
     pub fn new(vs: nn::Path, c: &Config) -> Self {
         let vs = &vs / "text_model";
@@ -677,7 +970,9 @@ impl ClipTextTransformer {
         let encoder = ClipEncoder::new(&vs / "encoder", c);
         let final_layer_norm =
             nn::layer_norm(&vs / "final_layer_norm", vec![c.embed_dim], Default::default());
-        Self { embeddings, encoder, final_layer_norm }
+        let use_attention_mask = c.use_attention_mask;
+        let end_of_text_token = c.end_of_text_token();
+        Self { embeddings, encoder, final_layer_norm, use_attention_mask, end_of_text_token }
     }
 
     // https://github.com/huggingface/transformers/blob/674f750a57431222fa2832503a108df3badf1564/src/transformers/models/clip/modeling_clip.py#L678
@@ -685,10 +980,86 @@ impl ClipTextTransformer {
         let mut mask = Tensor::ones([bsz, seq_len, seq_len], (Kind::Float, device));
         mask.fill_(f32::MIN as f64).triu_(1).unsqueeze(1)
     }
+
+    /// Runs the text model masking the padding tokens, `attention_mask` has shape
+    /// `[batch, seq_len]` and uses 1 for the tokens to attend to and 0 for padding.
+    pub fn forward_with_attention_mask(&self, xs: &Tensor, attention_mask: &Tensor) -> Tensor {
+        let (bsz, seq_len) = xs.size2().unwrap();
+        let xs = self.embeddings.forward(xs);
+        let attention_mask = Self::build_attention_mask(bsz, seq_len, attention_mask, xs.device());
+        let xs = self.encoder.forward(&xs, &attention_mask);
+        xs.apply(&self.final_layer_norm)
+    }
+
+    // The causal mask combined with a padding mask.
+    fn build_attention_mask(
+        bsz: i64,
+        seq_len: i64,
+        attention_mask: &Tensor,
+        device: Device,
+    ) -> Tensor {
+        let causal_attention_mask = Self::build_causal_attention_mask(bsz, seq_len, device);
+        // [bsz, seq_len] -> [bsz, 1, 1, seq_len], masking the padded keys.
+        let padding_mask: Tensor =
+            1. - attention_mask.to_kind(Kind::Float).view((bsz, 1, 1, seq_len));
+        let padding_mask = padding_mask * f32::MIN as f64;
+        (causal_attention_mask + padding_mask.to_device(device)).clamp_min(f32::MIN as f64)
+    }
+
+    // Returns the position of the first end of text token with shape `[bsz, 1]`. With the
+    // OpenAI tokenizer the padding also uses this token, so only the first occurrence is
+    // the actual end of the prompt.
+    fn eot_positions(&self, xs: &Tensor) -> Tensor {
+        xs.eq(self.end_of_text_token).to_kind(Kind::Int).argmax(-1, true)
+    }
+
+    /// Returns the output of each of the encoder layers, before the final layer norm,
+    /// together with the output of the final layer norm. Some pipelines use the hidden
+    /// states of the penultimate layer rather than the final output.
+    pub fn forward_hidden_states(&self, xs: &Tensor) -> (Vec<Tensor>, Tensor) {
+        let (bsz, seq_len) = xs.size2().unwrap();
+        let device = xs.device();
+        let attention_mask = if self.use_attention_mask {
+            let positions = Tensor::arange(seq_len, (Kind::Int64, device)).unsqueeze(0);
+            let attention_mask = positions.le_tensor(&self.eot_positions(xs));
+            Self::build_attention_mask(bsz, seq_len, &attention_mask, device)
+        } else {
+            Self::build_causal_attention_mask(bsz, seq_len, device)
+        };
+        let xs = self.embeddings.forward(xs);
+        let hidden_states = self.encoder.forward_hidden_states(&xs, &attention_mask);
+        let last_hidden_state = match hidden_states.last() {
+            Some(xs) => xs.apply(&self.final_layer_norm),
+            None => xs.apply(&self.final_layer_norm),
+        };
+        (hidden_states, last_hidden_state)
+    }
+
+    /// Embeds some prompt chunks as returned by `Tokenizer::encode_chunks`, `xs` has
+    /// shape `[n_chunks, seq_len]`. The embeddings of the different chunks are
+    /// concatenated along the sequence dimension, resulting in a single embedding
+    /// of shape `[1, n_chunks * seq_len, embed_dim]`.
+    pub fn forward_chunks(&self, xs: &Tensor) -> Tensor {
+        let xs = self.forward(xs);
+        let embed_dim = xs.size()[2];
+        xs.reshape([1, -1, embed_dim])
+    }
+
+    /// The device on which the model weights are stored.
+    pub fn device(&self) -> Device {
+        self.embeddings.position_ids.device()
+    }
 }
 
 impl Module for ClipTextTransformer {
     fn forward(&self, xs: &Tensor) -> Tensor {
+        if self.use_attention_mask {
+            // The mask covers all the tokens up to the first end of text token.
+            let (_bsz, seq_len) = xs.size2().unwrap();
+            let positions = Tensor::arange(seq_len, (Kind::Int64, xs.device())).unsqueeze(0);
+            let attention_mask = positions.le_tensor(&self.eot_positions(xs));
+            return self.forward_with_attention_mask(xs, &attention_mask);
+        }
         let (bsz, seq_len) = xs.size2().unwrap();
         let xs = self.embeddings.forward(xs);
         let causal_attention_mask = Self::build_causal_attention_mask(bsz, seq_len, xs.device());
@@ -696,3 +1067,58 @@ impl Module for ClipTextTransformer {
         xs.apply(&self.final_layer_norm)
     }
 }
+
+/// The outputs of a CLIP text model with a text projection.
+#[derive(Debug)]
+pub struct ClipTextOutput {
+    /// The hidden states of the penultimate encoder layer, `[batch, seq_len, embed_dim]`,
+    /// these are used as the UNet context by SDXL.
+    pub penultimate_hidden_states: Tensor,
+    /// The output of the final layer norm at the end of text token position projected
+    /// with the text projection, `[batch, projection_dim]`.
+    pub pooled_embeds: Tensor,
+}
+
+/// A CLIP text model followed by a linear text projection of the pooled output, as used
+/// for the second text encoder of SDXL where the pooled embedding is part of the added
+/// time conditioning.
+#[derive(Debug)]
+pub struct ClipTextModelWithProjection {
+    text_model: ClipTextTransformer,
+    text_projection: nn::Linear,
+}
+
+// SAFETY: this adds a text projection to a `ClipTextTransformer`, which is `Sync` for
+// the reasons given above, and `forward` only reads the projection weights.
+unsafe impl Sync for ClipTextModelWithProjection {}
+
+impl ClipTextModelWithProjection {
+    pub fn new(vs: nn::Path, c: &Config) -> Self {
+        let text_model = ClipTextTransformer::new(vs.clone(), c);
+        let no_bias = nn::LinearConfig { bias: false, ..Default::default() };
+        let text_projection =
+            nn::linear(&vs / "text_projection", c.embed_dim, c.projection_dim, no_bias);
+        Self { text_model, text_projection }
+    }
+
+    pub fn text_model(&self) -> &ClipTextTransformer {
+        &self.text_model
+    }
+
+    /// Embeds some tokens with shape `[batch, seq_len]`.
+    pub fn forward(&self, xs: &Tensor) -> ClipTextOutput {
+        let (hidden_states, last_hidden_state) = self.text_model.forward_hidden_states(xs);
+        let n_layers = hidden_states.len();
+        let penultimate_hidden_states = if n_layers >= 2 {
+            hidden_states[n_layers - 2].shallow_clone()
+        } else {
+            last_hidden_state.shallow_clone()
+        };
+        let embed_dim = last_hidden_state.size()[2];
+        let eot_positions = self.text_model.eot_positions(xs).unsqueeze(-1);
+        let eot_positions = eot_positions.expand([-1, 1, embed_dim], false);
+        let pooled = last_hidden_state.gather(1, &eot_positions, false).squeeze_dim(1);
+        let pooled_embeds = pooled.apply(&self.text_projection);
+        ClipTextOutput { penultimate_hidden_states, pooled_embeds }
+    }
+}
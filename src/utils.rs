@@ -1,7 +1,7 @@
 // A simple wrapper around File::open adding details about the
 // problematic file.
 use std::path::Path;
-use tch::Device;
+use tch::{Device, Kind, Tensor};
 
 pub(crate) fn file_open<P: AsRef<Path>>(path: P) -> anyhow::Result<std::fs::File> {
     std::fs::File::open(path.as_ref()).map_err(|e| {
@@ -30,3 +30,564 @@ impl DeviceSetup {
         }
     }
 }
+
+/// A snapshot of the activation memory at some block boundary of a forward pass.
+#[derive(Debug, Clone)]
+pub struct MemorySnapshot {
+    pub label: String,
+    pub shape: Vec<i64>,
+    /// The size in bytes of the activation produced by the block.
+    pub activation_bytes: i64,
+    /// The size in bytes of all the activations that are kept alive at this point,
+    /// e.g. for the UNet this includes the skip connections used by the up blocks.
+    pub live_bytes: i64,
+    /// The memory in use on the current CUDA device as reported by the CUDA runtime,
+    /// this includes the weights and the blocks cached by the libtorch allocator. `None`
+    /// when the activation is not on a CUDA device or the runtime cannot be queried.
+    pub cuda_used_bytes: Option<i64>,
+}
+
+/// Diagnostic report collecting activation memory snapshots during a forward pass.
+///
+/// This is only populated by the `*_with_memory_report` variants of the model
+/// forward functions so that the default path has no overhead. It can be used to
+/// decide on sliced attention or tiling settings for a given VRAM budget.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    pub snapshots: Vec<MemorySnapshot>,
+}
+
+pub(crate) fn tensor_bytes(xs: &Tensor) -> i64 {
+    (xs.numel() * xs.kind().elt_size_in_bytes()) as i64
+}
+
+// tch does not expose the allocator statistics so the CUDA runtime loaded by libtorch is
+// queried directly, this returns the memory in use on the current device.
+#[cfg(target_os = "linux")]
+fn cuda_used_bytes() -> Option<i64> {
+    use std::ffi::{c_char, c_void};
+    type MemGetInfo = unsafe extern "C" fn(*mut usize, *mut usize) -> i32;
+    extern "C" {
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    }
+    static MEM_GET_INFO: std::sync::OnceLock<Option<MemGetInfo>> = std::sync::OnceLock::new();
+    let mem_get_info = MEM_GET_INFO.get_or_init(|| {
+        // SAFETY: a null handle, RTLD_DEFAULT, looks the symbol up in the libraries loaded
+        // with the program. When found, cudaMemGetInfo has the `MemGetInfo` signature.
+        let ptr = unsafe { dlsym(std::ptr::null_mut(), c"cudaMemGetInfo".as_ptr()) };
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { std::mem::transmute::<*mut c_void, MemGetInfo>(ptr) })
+        }
+    });
+    let mem_get_info = (*mem_get_info)?;
+    let (mut free, mut total) = (0, 0);
+    // SAFETY: both pointers are valid for writes, 0 is cudaSuccess.
+    let status = unsafe { mem_get_info(&mut free, &mut total) };
+    (status == 0).then(|| (total - free) as i64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cuda_used_bytes() -> Option<i64> {
+    None
+}
+
+impl MemoryReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record<S: Into<String>>(&mut self, label: S, xs: &Tensor, live_bytes: i64) {
+        let cuda_used_bytes = match xs.device() {
+            Device::Cuda(index) => {
+                tch::Cuda::synchronize(index as i64);
+                cuda_used_bytes()
+            }
+            _ => None,
+        };
+        self.snapshots.push(MemorySnapshot {
+            label: label.into(),
+            shape: xs.size(),
+            activation_bytes: tensor_bytes(xs),
+            live_bytes,
+            cuda_used_bytes,
+        })
+    }
+
+    /// Returns the snapshot with the largest amount of live memory.
+    pub fn peak(&self) -> Option<&MemorySnapshot> {
+        self.snapshots.iter().max_by_key(|s| s.live_bytes)
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mib = |b: i64| b as f64 / (1024. * 1024.);
+        for s in self.snapshots.iter() {
+            write!(
+                f,
+                "{:<16} {:?} activation: {:.2}MiB live: {:.2}MiB",
+                s.label,
+                s.shape,
+                mib(s.activation_bytes),
+                mib(s.live_bytes)
+            )?;
+            match s.cuda_used_bytes {
+                None => writeln!(f)?,
+                Some(b) => writeln!(f, " cuda used: {:.2}MiB", mib(b))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the wall-clock duration of the denoising steps to estimate the remaining
+/// generation time.
+///
+/// The first step is usually slower because of warmup, e.g. cuDNN autotuning, so it
+/// is excluded from the average once other steps have been recorded.
+#[derive(Debug, Clone)]
+pub struct StepTimer {
+    total_steps: usize,
+    window: usize,
+    durations: Vec<std::time::Duration>,
+    last: std::time::Instant,
+}
+
+impl StepTimer {
+    /// Creates a timer for a loop of `total_steps` steps, the average step duration
+    /// is computed over the last `window` steps. The timer starts immediately.
+    pub fn new(total_steps: usize, window: usize) -> Self {
+        Self {
+            total_steps,
+            window: window.max(1),
+            durations: vec![],
+            last: std::time::Instant::now(),
+        }
+    }
+
+    /// Restarts the timing of the current step, e.g. after some work that should not be
+    /// accounted for.
+    pub fn restart(&mut self) {
+        self.last = std::time::Instant::now()
+    }
+
+    /// Marks the end of a step and returns its duration.
+    pub fn step(&mut self) -> std::time::Duration {
+        let now = std::time::Instant::now();
+        let duration = now - self.last;
+        self.last = now;
+        self.durations.push(duration);
+        duration
+    }
+
+    pub fn steps_done(&self) -> usize {
+        self.durations.len()
+    }
+
+    /// The moving average of the step durations, `None` if no step has completed.
+    pub fn average_step_duration(&self) -> Option<std::time::Duration> {
+        let durations = match self.durations.as_slice() {
+            [] => return None,
+            [d] => std::slice::from_ref(d),
+            [_warmup, rest @ ..] => rest,
+        };
+        let durations = &durations[durations.len().saturating_sub(self.window)..];
+        Some(durations.iter().sum::<std::time::Duration>() / durations.len() as u32)
+    }
+
+    /// The estimated time needed to complete the remaining steps.
+    pub fn eta(&self) -> Option<std::time::Duration> {
+        let remaining = self.total_steps.saturating_sub(self.steps_done());
+        self.average_step_duration().map(|d| d * remaining as u32)
+    }
+}
+
+impl std::fmt::Display for StepTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.steps_done(), self.total_steps)?;
+        if let (Some(avg), Some(eta)) = (self.average_step_duration(), self.eta()) {
+            write!(f, " {:.2}s/step, eta {:.1}s", avg.as_secs_f64(), eta.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+// The linear approximation of the stable diffusion 1.x and 2.x decoders, mapping each of
+// the 4 latent channels to RGB values.
+const LATENT_RGB_FACTORS: [[f32; 3]; 4] = [
+    [0.3512, 0.2297, 0.3227],
+    [0.3250, 0.4974, 0.2350],
+    [-0.2829, 0.1762, 0.2721],
+    [-0.2120, -0.2616, -0.7177],
+];
+
+/// Returns a rough RGB approximation of some latents without running the VAE decoder.
+///
+/// The latents should be the ones used by the diffusion model, i.e. before unscaling,
+/// with shape `[batch, 4, h, w]`. The returned images have shape `[batch, 3, h, w]` so
+/// are 8 times smaller than the decoded ones, and use the same `[-1, 1]` range as the
+/// VAE outputs. This is very cheap and mostly useful for previews and thumbnails.
+pub fn latents_to_rgb_preview(latents: &Tensor) -> Tensor {
+    let factors = Tensor::from_slice(LATENT_RGB_FACTORS.as_flattened())
+        .view((4, 3))
+        .to_device(latents.device());
+    let latents = latents.to_kind(tch::Kind::Float);
+    // [b, 4, h, w] x [4, 3] -> [b, 3, h, w]
+    Tensor::einsum("bchw,cd->bdhw", &[&latents, &factors], None::<i64>)
+}
+
+/// The interpolation mode used when resizing images, masks, or conditioning maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// Keeps the original values, masks stay binary and label maps keep their labels.
+    Nearest,
+    /// Smooth interpolation, suited to natural images and depth or normal maps.
+    #[default]
+    Bilinear,
+    Bicubic,
+    /// Lanczos resampling with 3 lobes, sharper than bicubic and antialiased when
+    /// downscaling. Like bicubic it can overshoot the input value range near edges.
+    Lanczos,
+}
+
+/// Resizes a float tensor of shape `[batch, channels, height, width]`.
+pub fn resize(xs: &Tensor, height: i64, width: i64, interpolation: Interpolation) -> Tensor {
+    if xs.size()[2..] == [height, width] {
+        return xs.shallow_clone();
+    }
+    match interpolation {
+        Interpolation::Nearest => xs.upsample_nearest2d([height, width], None, None),
+        Interpolation::Bilinear => xs.upsample_bilinear2d([height, width], false, None, None),
+        Interpolation::Bicubic => xs.upsample_bicubic2d([height, width], false, None, None),
+        Interpolation::Lanczos => {
+            let size = xs.size();
+            let (in_height, in_width) = (size[2], size[3]);
+            let options = (xs.kind(), xs.device());
+            let weights_h = lanczos_weights(in_height, height).to_kind(options.0).to(options.1);
+            let weights_w = lanczos_weights(in_width, width).to_kind(options.0).to(options.1);
+            weights_h.matmul(xs).matmul(&weights_w.tr())
+        }
+    }
+}
+
+// The weights of a one dimensional Lanczos resampling with 3 lobes as a dense
+// `[out_size, in_size]` matrix. The kernel is stretched when downscaling so that all the
+// input pixels contribute, and the pixels past the borders are clamped to the edge ones.
+fn lanczos_weights(in_size: i64, out_size: i64) -> Tensor {
+    const LOBES: f64 = 3.;
+    let sinc = |x: f64| {
+        if x == 0. {
+            1.
+        } else {
+            let x = std::f64::consts::PI * x;
+            x.sin() / x
+        }
+    };
+    let ratio = in_size as f64 / out_size as f64;
+    let kernel_scale = ratio.max(1.);
+    let radius = LOBES * kernel_scale;
+    let mut weights = vec![0f32; (in_size * out_size) as usize];
+    for (i, row) in weights.chunks_mut(in_size as usize).enumerate() {
+        let center = (i as f64 + 0.5) * ratio - 0.5;
+        let start = (center - radius).floor() as i64 + 1;
+        let end = (center + radius).ceil() as i64;
+        let taps: Vec<(i64, f64)> = (start..end)
+            .map(|j| {
+                let x = (j as f64 - center) / kernel_scale;
+                (j.clamp(0, in_size - 1), sinc(x) * sinc(x / LOBES))
+            })
+            .collect();
+        let total: f64 = taps.iter().map(|(_, w)| w).sum();
+        for (j, w) in taps {
+            row[j as usize] += (w / total) as f32
+        }
+    }
+    Tensor::from_slice(&weights).view((out_size, in_size))
+}
+
+/// Rounds an image size up to the next multiple of `multiple`, e.g. 64 for the stable
+/// diffusion models. This can be used to generate at any size by generating at the
+/// padded size and then cropping the result with `crop`.
+pub fn padded_size(height: i64, width: i64, multiple: i64) -> (i64, i64) {
+    let round_up = |v: i64| (v + multiple - 1) / multiple * multiple;
+    (round_up(height), round_up(width))
+}
+
+/// Where the crop window is placed when using `crop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CropAnchor {
+    #[default]
+    Center,
+    TopLeft,
+}
+
+/// Crops images of shape `[..., h, w]` to `height` by `width`, the target size should
+/// not exceed the image size.
+pub fn crop(xs: &Tensor, height: i64, width: i64, anchor: CropAnchor) -> Tensor {
+    let size = xs.size();
+    let (h, w) = (size[size.len() - 2], size[size.len() - 1]);
+    let (top, left) = match anchor {
+        CropAnchor::Center => ((h - height) / 2, (w - width) / 2),
+        CropAnchor::TopLeft => (0, 0),
+    };
+    xs.narrow(-2, top, height).narrow(-1, left, width)
+}
+
+/// Seeds the random number generators used by libtorch, on the CPU and on all the
+/// CUDA devices when available, so that the noise sampled afterwards is reproducible.
+///
+/// The examples call this once per generated image with `seed + sample_index`, so
+/// sample `i` of a run can be regenerated alone by using `seed + i` as the seed with
+/// a single sample. Note that CUDA kernels are not always deterministic so outputs may
+/// still differ slightly between runs on GPU.
+pub fn set_seed(seed: i64) {
+    tch::manual_seed(seed);
+    if tch::Cuda::is_available() {
+        tch::Cuda::manual_seed_all(seed as u64)
+    }
+}
+
+/// Samples the initial noise for a batch with one seed per image, image `i` of the
+/// batch gets the same noise as a batch of size one generated with `seeds[i]`. This
+/// reseeds the random number generators with `set_seed` for each image.
+///
+/// `shape` is the full shape of the batch, e.g. as returned by `latent_shape`, and
+/// there must be exactly one seed per image: seeds are not cycled so that a missing
+/// seed is not silently replaced by a duplicate. Concurrent calls are serialized so that
+/// the noise does not depend on other threads using this function.
+pub fn seeded_noise(
+    seeds: &[i64],
+    shape: [i64; 4],
+    options: (Kind, Device),
+) -> anyhow::Result<Tensor> {
+    let [batch, c, h, w] = shape;
+    if seeds.len() as i64 != batch {
+        anyhow::bail!("got {} seeds for a batch of {batch} images", seeds.len())
+    }
+    static SEED_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let noise: Vec<Tensor> = seeds
+        .iter()
+        .map(|&seed| {
+            set_seed(seed);
+            Tensor::randn([1, c, h, w], options)
+        })
+        .collect();
+    Ok(Tensor::cat(&noise, 0))
+}
+
+/// Matches the color histogram of `image` to the one of `reference`, independently for
+/// each channel. Both images have shape `[c, h, w]` or `[b, c, h, w]` with the same
+/// number of channels but can have different sizes, batches are matched as a whole.
+///
+/// The pixels of each channel are ranked and the pixel with rank `i` gets the value at
+/// the same quantile of the reference, i.e. the cumulative distributions are matched
+/// exactly. This is useful to undo the color shifts introduced by img2img, the result
+/// keeps the kind of `image`.
+pub fn match_histogram(image: &Tensor, reference: &Tensor) -> Tensor {
+    let shape = image.size();
+    let channels = shape[shape.len() - 3];
+    let to_channels = |xs: &Tensor| xs.to_kind(Kind::Float).movedim(-3, 0).reshape([channels, -1]);
+    let xs = to_channels(image);
+    let (reference_sorted, _) = to_channels(reference).sort(1, false);
+    let (n, m) = (xs.size()[1], reference_sorted.size()[1]);
+    let step = (m - 1) as f64 / (n - 1).max(1) as f64;
+    let positions = Tensor::arange(n, (Kind::Float, xs.device())) * step;
+    let matched = reference_sorted.index_select(1, &positions.round().to_kind(Kind::Int64));
+    let xs = xs.zeros_like().scatter(1, &xs.argsort(1, false), &matched);
+    let mut moved_shape = shape.clone();
+    let channel_dim = moved_shape.remove(shape.len() - 3);
+    moved_shape.insert(0, channel_dim);
+    xs.reshape(moved_shape).movedim(0, -3).to_kind(image.kind())
+}
+
+/// Adds a per-channel constant offset to some initial noise of shape `[b, c, h, w]`,
+/// i.e. `noise + offset * randn([b, c, 1, 1])`.
+///
+/// Stable diffusion tends to produce images of medium brightness as the noise never
+/// shifts the mean of a channel much. Models fine-tuned with a noise offset can generate
+/// very dark or very bright images when the same trick is used at inference. For other
+/// models this has little effect, so it is harmless to enable. An offset of 0 returns
+/// the noise unchanged, typical values are around 0.05 to 0.1.
+pub fn apply_noise_offset(noise: &Tensor, offset: f64) -> Tensor {
+    if offset == 0. {
+        return noise.shallow_clone();
+    }
+    let (b, c, _, _) = noise.size4().unwrap();
+    noise + Tensor::randn([b, c, 1, 1], (noise.kind(), noise.device())) * offset
+}
+
+/// Arranges some images in a grid with `n_cols` columns, the images are stitched
+/// row by row with `padding` pixels of spacing between them.
+///
+/// The images should all have the same shape, either `[c, h, w]` or `[1, c, h, w]`.
+/// The returned tensor has shape `[c, rows * (h + padding) - padding, ...]`.
+pub fn image_grid(images: &[Tensor], n_cols: usize, padding: i64) -> anyhow::Result<Tensor> {
+    if images.is_empty() || n_cols == 0 {
+        anyhow::bail!("image_grid requires at least one image and one column")
+    }
+    let images = images
+        .iter()
+        .map(|i| if i.dim() == 4 { i.squeeze_dim(0) } else { i.shallow_clone() })
+        .collect::<Vec<_>>();
+    let (c, h, w) = images[0].size3()?;
+    for image in images.iter() {
+        if image.size3()? != (c, h, w) {
+            anyhow::bail!("mismatching image sizes {:?} {:?}", images[0].size(), image.size())
+        }
+    }
+    let n_rows = images.len().div_ceil(n_cols);
+    let (n_rows, n_cols_i64) = (n_rows as i64, n_cols as i64);
+    let grid = Tensor::zeros(
+        [c, n_rows * (h + padding) - padding, n_cols_i64 * (w + padding) - padding],
+        (images[0].kind(), images[0].device()),
+    );
+    for (index, image) in images.iter().enumerate() {
+        let (row, col) = ((index / n_cols) as i64, (index % n_cols) as i64);
+        grid.narrow(1, row * (h + padding), h).narrow(2, col * (w + padding), w).copy_(image);
+    }
+    Ok(grid)
+}
+
+/// Generates an image for each combination of `xs` and `ys`, e.g. seeds and guidance
+/// scales, and stitches the results in a single grid image. The values from `xs`
+/// vary along the columns and the ones from `ys` along the rows.
+///
+/// No text is drawn on the grid, this crate does not render fonts. The column and row
+/// labels, built from the `Debug` representation of the axis values, are returned with
+/// the grid and displaying them is left to the caller, e.g. in an html page or with an
+/// image library.
+pub fn unlabeled_xy_grid<X, Y, F>(
+    xs: &[X],
+    ys: &[Y],
+    padding: i64,
+    mut f: F,
+) -> anyhow::Result<(Tensor, Vec<String>, Vec<String>)>
+where
+    X: std::fmt::Debug,
+    Y: std::fmt::Debug,
+    F: FnMut(&X, &Y) -> anyhow::Result<Tensor>,
+{
+    let mut images = Vec::with_capacity(xs.len() * ys.len());
+    for y in ys.iter() {
+        for x in xs.iter() {
+            images.push(f(x, y)?)
+        }
+    }
+    let grid = image_grid(&images, xs.len(), padding)?;
+    let x_labels = xs.iter().map(|x| format!("{x:?}")).collect();
+    let y_labels = ys.iter().map(|y| format!("{y:?}")).collect();
+    Ok((grid, x_labels, y_labels))
+}
+
+/// A `config.json` file as shipped with the diffusers models, with some helpers
+/// to extract typed values and report the file and key on errors.
+pub(crate) struct JsonConfig {
+    path: String,
+    value: serde_json::Value,
+}
+
+impl JsonConfig {
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let file = file_open(&path)?;
+        let path = path.as_ref().to_string_lossy().to_string();
+        let value: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| anyhow::Error::new(e).context(format!("error parsing {path:?}")))?;
+        if !value.is_object() {
+            anyhow::bail!("{path:?} does not contain a json object")
+        }
+        Ok(Self { path, value })
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.value.get(key).filter(|v| !v.is_null())
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> anyhow::Error {
+        anyhow::anyhow!("{:?}: expected {expected} for {key:?}, got {:?}", self.path, self.get(key))
+    }
+
+    pub(crate) fn required(&self, key: &str) -> anyhow::Result<&serde_json::Value> {
+        self.get(key).ok_or_else(|| anyhow::anyhow!("{:?}: missing key {key:?}", self.path))
+    }
+
+    pub(crate) fn i64(&self, key: &str) -> anyhow::Result<i64> {
+        self.required(key)?.as_i64().ok_or_else(|| self.invalid(key, "an integer"))
+    }
+
+    pub(crate) fn i64_or(&self, key: &str, default: i64) -> anyhow::Result<i64> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(_) => self.i64(key),
+        }
+    }
+
+    pub(crate) fn f64_or(&self, key: &str, default: f64) -> anyhow::Result<f64> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(v) => v.as_f64().ok_or_else(|| self.invalid(key, "a number")),
+        }
+    }
+
+    pub(crate) fn bool_or(&self, key: &str, default: bool) -> anyhow::Result<bool> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(v) => v.as_bool().ok_or_else(|| self.invalid(key, "a boolean")),
+        }
+    }
+
+    pub(crate) fn str(&self, key: &str) -> anyhow::Result<String> {
+        let value = self.required(key)?.as_str().ok_or_else(|| self.invalid(key, "a string"))?;
+        Ok(value.to_string())
+    }
+
+    pub(crate) fn str_or(&self, key: &str, default: &str) -> anyhow::Result<String> {
+        match self.get(key) {
+            None => Ok(default.to_string()),
+            Some(_) => self.str(key),
+        }
+    }
+
+    pub(crate) fn i64_list(&self, key: &str) -> anyhow::Result<Vec<i64>> {
+        let list = self.required(key)?.as_array().ok_or_else(|| self.invalid(key, "a list"))?;
+        list.iter()
+            .map(|v| v.as_i64().ok_or_else(|| self.invalid(key, "a list of integers")))
+            .collect()
+    }
+
+    pub(crate) fn str_list(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        let list = self.required(key)?.as_array().ok_or_else(|| self.invalid(key, "a list"))?;
+        list.iter()
+            .map(|v| {
+                v.as_str().map(String::from).ok_or_else(|| self.invalid(key, "a list of strings"))
+            })
+            .collect()
+    }
+
+    /// Reads a value that can either be a single integer shared by all the blocks
+    /// or a list with one integer per block.
+    pub(crate) fn i64_per_block(
+        &self,
+        key: &str,
+        n_blocks: usize,
+        default: i64,
+    ) -> anyhow::Result<Vec<i64>> {
+        match self.get(key) {
+            None => Ok(vec![default; n_blocks]),
+            Some(v) if v.is_array() => {
+                let values = self.i64_list(key)?;
+                if values.len() != n_blocks {
+                    anyhow::bail!(
+                        "{:?}: expected {n_blocks} values for {key:?}, got {}",
+                        self.path,
+                        values.len()
+                    )
+                }
+                Ok(values)
+            }
+            Some(_) => Ok(vec![self.i64(key)?; n_blocks]),
+        }
+    }
+}
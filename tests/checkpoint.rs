@@ -0,0 +1,148 @@
+use diffusers::checkpoint::{
+    merge_add_difference, merge_weighted_sum, select_weight_variant, SafeTensorsReader,
+    WeightVariant,
+};
+use tch::{nn, Device, Kind, Tensor};
+
+fn load_as(file_kind: Kind, model_kind: Kind, file_name: &str) {
+    tch::manual_seed(42);
+    let mut vs = nn::VarStore::new(Device::Cpu);
+    let _linear = nn::linear(vs.root() / "linear", 16, 8, Default::default());
+    vs.set_kind(model_kind);
+    let weight = Tensor::randn([8, 16], (Kind::Float, Device::Cpu));
+    let bias = Tensor::randn([8], (Kind::Float, Device::Cpu));
+    let path = std::env::temp_dir().join(file_name);
+    let tensors = [
+        ("linear.weight".to_string(), weight.to_kind(file_kind)),
+        ("linear.bias".to_string(), bias.to_kind(file_kind)),
+    ];
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    SafeTensorsReader::open(&path).unwrap().load_var_store(&vs).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let variables = vs.variables();
+    for (name, expected) in [("linear.weight", weight), ("linear.bias", bias)] {
+        let var = &variables[name];
+        assert_eq!(var.kind(), model_kind);
+        // The values went through the least precise of the two kinds, bfloat16 only has
+        // 8 bits of mantissa.
+        let diff = (var.to_kind(Kind::Float) - &expected).abs() / (expected.abs() + 1.);
+        let diff = diff.max().double_value(&[]);
+        assert!(diff < 1e-2, "{name}: {diff}");
+    }
+}
+
+#[test]
+fn load_f16_in_f32_model() {
+    load_as(Kind::Half, Kind::Float, "diffusers-test-f16-f32.safetensors")
+}
+
+#[test]
+fn load_f32_in_f16_model() {
+    load_as(Kind::Float, Kind::Half, "diffusers-test-f32-f16.safetensors")
+}
+
+#[test]
+fn load_bf16_in_f32_model() {
+    load_as(Kind::BFloat16, Kind::Float, "diffusers-test-bf16-f32.safetensors")
+}
+
+#[test]
+fn load_int8_in_f32_model_fails() {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let _linear = nn::linear(vs.root() / "linear", 4, 4, Default::default());
+    let path = std::env::temp_dir().join("diffusers-test-i8-f32.safetensors");
+    let tensors = [
+        ("linear.weight".to_string(), Tensor::ones([4, 4], (Kind::Int8, Device::Cpu))),
+        ("linear.bias".to_string(), Tensor::ones([4], (Kind::Int8, Device::Cpu))),
+    ];
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    let result = SafeTensorsReader::open(&path).unwrap().load_var_store(&vs);
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.is_err());
+}
+
+// A var-store with a half precision weight, a single precision bias, and integer
+// position ids, as found in mixed precision checkpoints.
+fn mixed_var_store(seed: i64, out_dims: i64) -> nn::VarStore {
+    tch::manual_seed(seed);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let linear = nn::linear(vs.root() / "linear", 4, out_dims, Default::default());
+    let mut ws = linear.ws.shallow_clone();
+    tch::no_grad(|| ws.set_data(&ws.to_kind(Kind::Half)));
+    let mut position_ids = vs.root().zeros_no_train("position_ids", &[1, 5]);
+    position_ids.set_data(&Tensor::arange(5, (Kind::Int64, Device::Cpu)).view([1, 5]));
+    vs
+}
+
+#[test]
+fn merge_checkpoints() {
+    let (base, a, b) = (mixed_var_store(1, 3), mixed_var_store(2, 3), mixed_var_store(3, 3));
+    let vars = |vs: &nn::VarStore| vs.variables();
+    let (base_vars, a_vars, b_vars) = (vars(&base), vars(&a), vars(&b));
+    let weighted = merge_weighted_sum(&a, &b, 0.3).unwrap().variables();
+    let add_difference = merge_add_difference(&base, &a, &b, 0.5).unwrap().variables();
+    for name in ["linear.weight", "linear.bias", "position_ids"] {
+        let f = |vars: &std::collections::HashMap<String, Tensor>| vars[name].to_kind(Kind::Float);
+        for (merged, expected) in [
+            (&weighted[name], f(&a_vars) * 0.3 + f(&b_vars) * 0.7),
+            (&add_difference[name], f(&base_vars) + (f(&a_vars) - f(&b_vars)) * 0.5),
+        ] {
+            assert_eq!(merged.kind(), a_vars[name].kind(), "{name}");
+            assert!(!merged.requires_grad(), "{name}");
+            let diff = (merged.to_kind(Kind::Float) - expected).abs().max().double_value(&[]);
+            assert!(diff < 1e-2, "{name}: {diff}");
+        }
+    }
+    assert_eq!(weighted["linear.weight"].kind(), Kind::Half);
+    assert_eq!(weighted["linear.bias"].kind(), Kind::Float);
+    assert!(weighted["position_ids"].equal(&a_vars["position_ids"]));
+
+    // Mismatched variable names.
+    let extra = mixed_var_store(4, 3);
+    let _other = nn::linear(extra.root() / "other", 4, 4, Default::default());
+    assert!(merge_weighted_sum(&a, &extra, 0.5).is_err());
+    assert!(merge_add_difference(&base, &a, &extra, 0.5).is_err());
+    // Mismatched shapes.
+    let wide = mixed_var_store(5, 6);
+    assert!(merge_weighted_sum(&a, &wide, 0.5).is_err());
+    assert!(merge_add_difference(&wide, &a, &b, 0.5).is_err());
+    // Integer variables cannot be blended.
+    let mut position_ids = b_vars["position_ids"].shallow_clone();
+    tch::no_grad(|| position_ids.set_data(&(&position_ids + 1)));
+    assert!(merge_weighted_sum(&a, &b, 0.5).is_err());
+}
+
+#[test]
+fn weight_variant_fallback() {
+    let regular = Tensor::from_slice(&[1f32, 2.]);
+    let ema = Tensor::from_slice(&[3f32, 4.]);
+    let tensors = |with_ema: bool| {
+        let mut tensors = vec![
+            ("model.diffusion_model.out.0.weight".to_string(), regular.copy()),
+            ("first_stage_model.decoder.conv_in.bias".to_string(), regular.copy()),
+        ];
+        if with_ema {
+            tensors.push(("model_ema.diffusion_modelout0weight".to_string(), ema.copy()));
+        }
+        tensors
+    };
+    let unet_weight = |tensors: &[(String, Tensor)]| tensors[0].1.shallow_clone();
+
+    // Without EMA weights, preferring them silently falls back to the regular ones and
+    // the returned variant reports it.
+    let (selected, variant) =
+        select_weight_variant(tensors(false), WeightVariant::PreferEma).unwrap();
+    assert_eq!(variant, WeightVariant::Regular);
+    assert!(unet_weight(&selected).equal(&regular));
+    assert!(select_weight_variant(tensors(false), WeightVariant::Ema).is_err());
+
+    let (selected, variant) =
+        select_weight_variant(tensors(true), WeightVariant::PreferEma).unwrap();
+    assert_eq!(variant, WeightVariant::Ema);
+    assert_eq!(selected.len(), 2);
+    assert!(unet_weight(&selected).equal(&ema));
+    let (selected, variant) = select_weight_variant(tensors(true), WeightVariant::Regular).unwrap();
+    assert_eq!(variant, WeightVariant::Regular);
+    assert!(unet_weight(&selected).equal(&regular));
+}
@@ -0,0 +1,103 @@
+// Tiny randomly initialized models shared by the test binaries, each binary only uses
+// some of them.
+#![allow(dead_code)]
+use diffusers::models::controlnet::{ControlNet, ControlNetConfig};
+use diffusers::models::embeddings::TextTimeEmbeddingConfig;
+use diffusers::models::unet_2d::{BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig};
+use diffusers::models::vae::{AutoEncoderKL, AutoEncoderKLConfig};
+use tch::{nn, Device, Kind, Tensor};
+
+pub const CROSS_ATTENTION_DIM: i64 = 32;
+pub const SEQ_LEN: i64 = 7;
+
+pub fn tiny_blocks() -> Vec<BlockConfig> {
+    let bc = |out_channels, use_cross_attn| BlockConfig {
+        out_channels,
+        use_cross_attn,
+        attention_head_dim: 2,
+        cross_attention_dim: None,
+        transformer_layers_per_block: 1,
+    };
+    vec![bc(16, true), bc(32, false)]
+}
+
+pub fn tiny_unet_config() -> UNet2DConditionModelConfig {
+    UNet2DConditionModelConfig {
+        blocks: tiny_blocks(),
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        ..Default::default()
+    }
+}
+
+pub fn tiny_unet(vs: &nn::VarStore) -> UNet2DConditionModel {
+    UNet2DConditionModel::new(vs.root(), 4, 4, tiny_unet_config())
+}
+
+// The SDXL block layout: no attention in the first block and two transformer layers in the
+// cross-attention one.
+pub fn tiny_sdxl_blocks() -> Vec<BlockConfig> {
+    let bc = |out_channels, use_cross_attn, transformer_layers_per_block| BlockConfig {
+        out_channels,
+        use_cross_attn,
+        attention_head_dim: 2,
+        cross_attention_dim: None,
+        transformer_layers_per_block,
+    };
+    vec![bc(16, false, 1), bc(32, true, 2)]
+}
+
+// The pooled text embeddings of the tiny SDXL models have this number of channels.
+pub const POOLED_DIM: i64 = 8;
+
+// Each time id gets a 4 channels embedding.
+pub fn tiny_text_time_embedding() -> TextTimeEmbeddingConfig {
+    TextTimeEmbeddingConfig {
+        addition_time_embed_dim: 4,
+        projection_class_embeddings_input_dim: POOLED_DIM + 6 * 4,
+    }
+}
+
+pub fn tiny_sdxl_unet_config() -> UNet2DConditionModelConfig {
+    UNet2DConditionModelConfig {
+        blocks: tiny_sdxl_blocks(),
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        use_linear_projection: true,
+        text_time_embedding: Some(tiny_text_time_embedding()),
+        ..Default::default()
+    }
+}
+
+pub fn tiny_vae(vs: &nn::VarStore) -> AutoEncoderKL {
+    let config = AutoEncoderKLConfig {
+        block_out_channels: vec![16, 32],
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        ..Default::default()
+    };
+    AutoEncoderKL::new(vs.root(), 3, 3, config)
+}
+
+pub fn tiny_controlnet(vs: &nn::VarStore) -> ControlNet {
+    tiny_controlnet_with_pooling(vs, false)
+}
+
+pub fn tiny_controlnet_with_pooling(vs: &nn::VarStore, global_pool_conditions: bool) -> ControlNet {
+    let config = ControlNetConfig {
+        blocks: tiny_blocks(),
+        conditioning_embedding_out_channels: vec![4, 8, 8, 16],
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        global_pool_conditions,
+        ..Default::default()
+    };
+    ControlNet::new(vs.root(), 4, config)
+}
+
+pub fn randn(shape: &[i64]) -> Tensor {
+    Tensor::randn(shape, (Kind::Float, Device::Cpu))
+}
@@ -0,0 +1,236 @@
+use diffusers::models::controlnet::ControlNetConfig;
+use diffusers::models::unet_2d::unet_config_from_json;
+use diffusers::pipelines::stable_diffusion::StableDiffusionConfig;
+use diffusers::schedulers::ays::AysSchedule;
+use diffusers::schedulers::config::{
+    check_prediction_type, PretrainedSchedulerConfig, SchedulerConfig,
+};
+use diffusers::schedulers::{
+    ddim, ddpm, dpmsolver_multistep, euler_ancestral_discrete, euler_discrete, heun_discrete,
+    k_dpm_2_ancestral_discrete, k_dpm_2_discrete, lcm, lms_discrete, pndm, tcd, PredictionType,
+    Scheduler, TimestepSpacing,
+};
+use tch::Kind;
+
+// The default configuration of each scheduler.
+fn scheduler_configs() -> Vec<SchedulerConfig> {
+    vec![
+        ddim::DDIMSchedulerConfig::default().into(),
+        ddpm::DDPMSchedulerConfig::default().into(),
+        dpmsolver_multistep::DPMSolverMultistepSchedulerConfig {
+            restart: Some(Default::default()),
+            ..Default::default()
+        }
+        .into(),
+        euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig::default().into(),
+        euler_discrete::EulerDiscreteSchedulerConfig {
+            timestep_spacing: TimestepSpacing::Trailing,
+            ays_schedule: Some(AysSchedule::StableDiffusion15),
+            ..Default::default()
+        }
+        .into(),
+        heun_discrete::HeunDiscreteSchedulerConfig::default().into(),
+        k_dpm_2_ancestral_discrete::KDPM2AncestralDiscreteSchedulerConfig::default().into(),
+        k_dpm_2_discrete::KDPM2DiscreteSchedulerConfig::default().into(),
+        lcm::LCMSchedulerConfig::default().into(),
+        lms_discrete::LMSDiscreteSchedulerConfig::default().into(),
+        pndm::PNDMSchedulerConfig::default().into(),
+        tcd::TCDSchedulerConfig::default().into(),
+    ]
+}
+
+#[test]
+fn config_json_round_trip() {
+    for config in [
+        StableDiffusionConfig::v1_5(None, None, None),
+        StableDiffusionConfig::v2_1(Some(4), Some(512), Some(640)),
+        StableDiffusionConfig::v2_1_inpaint(None, None, None),
+        StableDiffusionConfig::sdxl(None, None, None),
+    ] {
+        let json = config.config_json();
+        let rebuilt = StableDiffusionConfig::from_json(&json).unwrap();
+        assert_eq!(rebuilt.config_json(), json);
+    }
+    let mut kinds = vec![];
+    for scheduler in scheduler_configs() {
+        let kind = scheduler.kind();
+        let config = StableDiffusionConfig::v1_5(None, None, None)
+            .with_scheduler(scheduler)
+            .with_weights_kind(Kind::BFloat16)
+            .with_lora("style", 0.7);
+        let json = config.config_json();
+        let rebuilt = StableDiffusionConfig::from_json(&json).unwrap();
+        assert_eq!(rebuilt.scheduler().kind(), kind);
+        assert_eq!(rebuilt.weights_kind(), Some(Kind::BFloat16));
+        assert_eq!(rebuilt.config_json(), json, "{kind:?}");
+        kinds.push(kind)
+    }
+    assert_eq!(kinds.len(), 12);
+    assert!((0..kinds.len()).all(|i| !kinds[..i].contains(&kinds[i])), "{kinds:?}");
+}
+
+#[test]
+fn config_json_records_the_scheduler_in_use() {
+    let sd_config = StableDiffusionConfig::v2_1(None, None, None);
+    let scheduler = sd_config.build_tcd_scheduler(4, 0.3);
+    let config = sd_config
+        .clone()
+        .with_scheduler(scheduler.scheduler_config().unwrap())
+        .with_weights_kind(Kind::Half)
+        .with_lora("lcm", 1.)
+        .with_lora("style", 0.5)
+        .with_lora("lcm", 0.8);
+    let json: serde_json::Value = serde_json::from_str(&config.config_json()).unwrap();
+    assert_eq!(json["scheduler"]["type"], "TCDScheduler");
+    assert_eq!(json["scheduler"]["eta"], 0.3);
+    assert_eq!(json["scheduler"]["prediction_type"], "v_prediction");
+    assert_eq!(json["weights_kind"], "Half");
+    assert_eq!(
+        json["loras"],
+        serde_json::json!([{"name": "lcm", "strength": 0.8}, {"name": "style", "strength": 0.5}])
+    );
+    // Nothing gets recorded by default.
+    let json: serde_json::Value = serde_json::from_str(&sd_config.config_json()).unwrap();
+    assert_eq!(json["scheduler"]["type"], "DDIMScheduler");
+    assert_eq!(json["weights_kind"], serde_json::Value::Null);
+    assert_eq!(json["loras"], serde_json::json!([]));
+}
+
+#[test]
+fn config_json_validation() {
+    let json = StableDiffusionConfig::v1_5(None, None, None).config_json();
+    let unknown_scheduler = json.replace("\"DDIMScheduler\"", "\"FooScheduler\"");
+    let err = StableDiffusionConfig::from_json(&unknown_scheduler).unwrap_err();
+    assert!(format!("{err:#}").contains("FooScheduler"), "{err:#}");
+    let invalid_size = json.replace("\"width\": 512", "\"width\": 500");
+    assert!(StableDiffusionConfig::from_json(&invalid_size).is_err());
+    let invalid_eta = json.replace("\"eta\": 0.0", "\"eta\": 2.0");
+    assert!(StableDiffusionConfig::from_json(&invalid_eta).is_err());
+    let invalid_kind = json.replace("\"weights_kind\": null", "\"weights_kind\": \"Int8\"");
+    assert_ne!(invalid_kind, json);
+    let err = StableDiffusionConfig::from_json(&invalid_kind).unwrap_err();
+    assert!(format!("{err:#}").contains("Int8"), "{err:#}");
+    // The noise schedule of the other schedulers is validated too.
+    let lcm = StableDiffusionConfig::v1_5(None, None, None)
+        .with_scheduler(lcm::LCMSchedulerConfig { beta_end: 2., ..Default::default() }.into());
+    assert!(StableDiffusionConfig::from_json(&lcm.config_json()).is_err());
+}
+
+#[test]
+fn prediction_type_from_model_dir() {
+    let model_dir = std::env::temp_dir().join("diffusers-test-model-dir");
+    std::fs::create_dir_all(model_dir.join("scheduler")).unwrap();
+    let scheduler_config = r#"{
+        "_class_name": "DDIMScheduler",
+        "beta_end": 0.012,
+        "beta_schedule": "scaled_linear",
+        "beta_start": 0.00085,
+        "prediction_type": "v_prediction",
+        "steps_offset": 1
+    }"#;
+    std::fs::write(model_dir.join("scheduler").join("scheduler_config.json"), scheduler_config)
+        .unwrap();
+    let detected = PretrainedSchedulerConfig::from_model_dir(&model_dir);
+    let config = StableDiffusionConfig::v1_5(None, None, None).with_model_dir(&model_dir);
+    std::fs::remove_dir_all(&model_dir).unwrap();
+
+    let detected = detected.unwrap().unwrap().prediction_type;
+    assert_eq!(detected, PredictionType::VPrediction);
+    assert_eq!(config.unwrap().prediction_type(), PredictionType::VPrediction);
+    assert!(check_prediction_type(PredictionType::VPrediction, Some(detected)).is_none());
+    assert!(check_prediction_type(PredictionType::Epsilon, Some(detected)).is_some());
+    assert!(check_prediction_type(PredictionType::Epsilon, None).is_none());
+
+    // Folders without a scheduler config keep the built-in settings.
+    let missing = std::env::temp_dir().join("diffusers-test-missing-model-dir");
+    assert!(PretrainedSchedulerConfig::from_model_dir(&missing).unwrap().is_none());
+    let config = StableDiffusionConfig::v2_1(None, None, None).with_model_dir(&missing).unwrap();
+    assert_eq!(config.prediction_type(), PredictionType::VPrediction);
+}
+
+#[test]
+fn invalid_unet_config_json() {
+    let path = std::env::temp_dir().join("diffusers-test-unet-config.json");
+    let config = |norm_num_groups| {
+        format!(
+            r#"{{
+                "block_out_channels": [32, 64],
+                "down_block_types": ["CrossAttnDownBlock2D", "DownBlock2D"],
+                "attention_head_dim": 8,
+                "cross_attention_dim": 32,
+                "norm_num_groups": {norm_num_groups}
+            }}"#
+        )
+    };
+    std::fs::write(&path, config(8)).unwrap();
+    let valid = unet_config_from_json(&path);
+    // 7 groups do not divide the block channels, this is reported when parsing.
+    std::fs::write(&path, config(7)).unwrap();
+    let invalid = unet_config_from_json(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(valid.unwrap().norm_num_groups, 8);
+    let err = invalid.unwrap_err();
+    assert!(format!("{err:#}").contains("norm_num_groups 7"), "{err:#}");
+}
+
+#[test]
+fn sdxl_unet_config_json() {
+    let path = std::env::temp_dir().join("diffusers-test-sdxl-unet-config.json");
+    // The keys of the SDXL base UNet config that differ from the defaults.
+    let config = r#"{
+        "block_out_channels": [320, 640, 1280],
+        "down_block_types": ["DownBlock2D", "CrossAttnDownBlock2D", "CrossAttnDownBlock2D"],
+        "attention_head_dim": [5, 10, 20],
+        "cross_attention_dim": 2048,
+        "transformer_layers_per_block": [1, 2, 10],
+        "use_linear_projection": true,
+        "addition_embed_type": "text_time",
+        "addition_time_embed_dim": 256,
+        "projection_class_embeddings_input_dim": 2816
+    }"#;
+    std::fs::write(&path, config).unwrap();
+    let parsed = unet_config_from_json(&path);
+    std::fs::write(&path, config.replace("text_time", "text_image")).unwrap();
+    let unsupported = unet_config_from_json(&path);
+    std::fs::remove_file(&path).unwrap();
+    let parsed = serde_json::to_value(parsed.unwrap()).unwrap();
+    let sdxl = StableDiffusionConfig::sdxl(None, None, None);
+    assert_eq!(parsed, serde_json::to_value(sdxl.unet_config()).unwrap());
+    let err = unsupported.unwrap_err();
+    assert!(format!("{err:#}").contains("text_image"), "{err:#}");
+
+    // The UNet added conditioning requires the second text encoder.
+    let mut json: serde_json::Value = serde_json::from_str(&sdxl.config_json()).unwrap();
+    json.as_object_mut().unwrap().remove("clip2");
+    assert!(StableDiffusionConfig::from_json(&json.to_string()).is_err());
+}
+
+#[test]
+fn sdxl_controlnet_config_json() {
+    let path = std::env::temp_dir().join("diffusers-test-sdxl-controlnet-config.json");
+    let config = r#"{
+        "block_out_channels": [320, 640, 1280],
+        "down_block_types": ["DownBlock2D", "CrossAttnDownBlock2D", "CrossAttnDownBlock2D"],
+        "attention_head_dim": [5, 10, 20],
+        "cross_attention_dim": 2048,
+        "transformer_layers_per_block": [1, 2, 10],
+        "use_linear_projection": true,
+        "addition_embed_type": "text_time",
+        "addition_time_embed_dim": 256,
+        "projection_class_embeddings_input_dim": 2816,
+        "conditioning_embedding_out_channels": [16, 32, 96, 256]
+    }"#;
+    std::fs::write(&path, config).unwrap();
+    let parsed = ControlNetConfig::from_json(&path);
+    std::fs::remove_file(&path).unwrap();
+    let parsed = parsed.unwrap();
+    let sdxl = ControlNetConfig::sdxl();
+    let blocks = |config: &ControlNetConfig| serde_json::to_value(&config.blocks).unwrap();
+    assert_eq!(blocks(&parsed), blocks(&sdxl));
+    assert_eq!(parsed.cross_attention_dim, sdxl.cross_attention_dim);
+    assert_eq!(parsed.use_linear_projection, sdxl.use_linear_projection);
+    assert_eq!(parsed.text_time_embedding, sdxl.text_time_embedding);
+    parsed.check_compatible(StableDiffusionConfig::sdxl(None, None, None).unet_config()).unwrap();
+    let v1_5 = StableDiffusionConfig::v1_5(None, None, None);
+    assert!(parsed.check_compatible(v1_5.unet_config()).is_err());
+}
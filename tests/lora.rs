@@ -0,0 +1,216 @@
+use diffusers::models::lora::{load_lora, merge_lora, Lora, LoraAdapters, LoraOptions};
+use std::collections::HashMap;
+use tch::{nn, Device, Kind, Tensor};
+
+const RANK: i64 = 2;
+
+// Var stores with a couple of linear layers at the paths used by the UNet and by the
+// clip text encoder.
+fn unet_var_store() -> nn::VarStore {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let attn = vs.root() / "down_blocks" / 0 / "attentions" / 0;
+    let attn = attn / "transformer_blocks" / 0 / "attn1";
+    let _to_q = nn::linear(&attn / "to_q", 8, 8, Default::default());
+    let _to_k = nn::linear(&attn / "to_k", 8, 8, Default::default());
+    vs
+}
+
+fn text_encoder_var_store() -> nn::VarStore {
+    let vs = nn::VarStore::new(Device::Cpu);
+    let attn = vs.root() / "text_model" / "encoder" / "layers" / 0 / "self_attn";
+    let _q_proj = nn::linear(&attn / "q_proj", 8, 8, Default::default());
+    vs
+}
+
+fn write_lora(file_name: &str) -> std::path::PathBuf {
+    let modules = [
+        "lora_unet_down_blocks_0_attentions_0_transformer_blocks_0_attn1_to_q",
+        "lora_unet_down_blocks_0_attentions_0_transformer_blocks_0_attn1_to_k",
+        "lora_te_text_model_encoder_layers_0_self_attn_q_proj",
+    ];
+    let mut tensors = vec![];
+    for module in modules {
+        let up = Tensor::randn([8, RANK], (Kind::Float, Device::Cpu));
+        let down = Tensor::randn([RANK, 8], (Kind::Float, Device::Cpu));
+        tensors.push((format!("{module}.lora_up.weight"), up));
+        tensors.push((format!("{module}.lora_down.weight"), down));
+        tensors.push((format!("{module}.alpha"), Tensor::from(RANK as f32)));
+    }
+    let path = std::env::temp_dir().join(file_name);
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    path
+}
+
+fn snapshot(vs: &nn::VarStore) -> Vec<(String, Tensor)> {
+    let mut variables: Vec<_> = vs.variables().into_iter().map(|(n, v)| (n, v.copy())).collect();
+    variables.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+    variables
+}
+
+fn changed(before: &[(String, Tensor)], vs: &nn::VarStore) -> Vec<String> {
+    let variables = vs.variables();
+    let changed = before.iter().filter(|(name, before)| !before.equal(&variables[name]));
+    changed.map(|(name, _)| name.clone()).collect()
+}
+
+#[test]
+fn lora_text_encoder_only() {
+    tch::manual_seed(42);
+    let unet_vs = unet_var_store();
+    let text_encoder_vs = text_encoder_var_store();
+    let unet_before = snapshot(&unet_vs);
+    let text_encoder_before = snapshot(&text_encoder_vs);
+    let path = write_lora("diffusers-test-lora-te-only.safetensors");
+    let options = LoraOptions { apply_to_unet: false, ..Default::default() };
+    let result = load_lora(&path, &unet_vs, &text_encoder_vs, options);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+    assert!(changed(&unet_before, &unet_vs).is_empty());
+    assert_eq!(
+        changed(&text_encoder_before, &text_encoder_vs),
+        ["text_model.encoder.layers.0.self_attn.q_proj.weight"]
+    );
+}
+
+#[test]
+fn lora_unet_only() {
+    tch::manual_seed(42);
+    let unet_vs = unet_var_store();
+    let text_encoder_vs = text_encoder_var_store();
+    let unet_before = snapshot(&unet_vs);
+    let text_encoder_before = snapshot(&text_encoder_vs);
+    let path = write_lora("diffusers-test-lora-unet-only.safetensors");
+    let options = LoraOptions { apply_to_text_encoder: false, ..Default::default() };
+    let result = load_lora(&path, &unet_vs, &text_encoder_vs, options);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+    assert_eq!(changed(&unet_before, &unet_vs).len(), 2);
+    assert!(changed(&text_encoder_before, &text_encoder_vs).is_empty());
+}
+
+// A LoRA for the text encoder q_proj layer, with an optional alpha.
+fn q_proj_lora(rank: i64, alpha: Option<f32>) -> (Lora, Tensor) {
+    let module = "lora_te_text_model_encoder_layers_0_self_attn_q_proj";
+    let up = Tensor::randn([8, rank], (Kind::Float, Device::Cpu));
+    let down = Tensor::randn([rank, 8], (Kind::Float, Device::Cpu));
+    let product = up.matmul(&down);
+    let mut tensors = vec![
+        (format!("{module}.lora_up.weight"), up),
+        (format!("{module}.lora_down.weight"), down),
+    ];
+    if let Some(alpha) = alpha {
+        tensors.push((format!("{module}.alpha"), Tensor::from(alpha)));
+    }
+    (Lora::from_tensors(tensors).unwrap(), product)
+}
+
+fn check_merge(rank: i64, alpha: Option<f32>, expected_scale: f64) {
+    tch::manual_seed(42);
+    let (lora, product) = q_proj_lora(rank, alpha);
+    let module = &lora.text_encoder["text_model_encoder_layers_0_self_attn_q_proj"];
+    assert_eq!(module.rank(), rank);
+    assert_eq!(module.scale(), expected_scale);
+    let vs = text_encoder_var_store();
+    let name = "text_model.encoder.layers.0.self_attn.q_proj.weight";
+    let before = vs.variables()[name].copy();
+    assert_eq!(merge_lora(&vs, &lora.text_encoder, 0.5).unwrap(), 1);
+    let expected = before + product * expected_scale * 0.5;
+    let diff = (&vs.variables()[name] - expected).abs().max().double_value(&[]);
+    assert!(diff < 1e-5, "{diff}");
+}
+
+#[test]
+fn lora_alpha_scales_the_update() {
+    check_merge(4, Some(1.), 0.25);
+    check_merge(2, Some(8.), 4.);
+}
+
+#[test]
+fn lora_without_alpha_uses_the_rank() {
+    check_merge(4, None, 1.);
+}
+
+#[test]
+fn lora_strength_sweep() {
+    tch::manual_seed(42);
+    let vs = unet_var_store();
+    let path = write_lora("diffusers-test-lora-sweep.safetensors");
+    let lora1 = Lora::read(&path);
+    std::fs::remove_file(&path).unwrap();
+    let lora1 = lora1.unwrap();
+    let (lora2, _) = q_proj_lora(2, None);
+    let name = "down_blocks.0.attentions.0.transformer_blocks.0.attn1.to_q.weight";
+    let module = "down_blocks_0_attentions_0_transformer_blocks_0_attn1_to_q";
+    let base = vs.variables()[name].copy();
+    let delta1 = lora1.unet[module].delta(&[8, 8]).unwrap();
+    // The second LoRA adapts a layer with the same shape, renamed to match the UNet.
+    let lora2_modules = lora2.text_encoder.into_values().map(|m| (module.to_string(), m));
+    let lora2_modules: HashMap<_, _> = lora2_modules.collect();
+    let delta2 = lora2_modules[module].delta(&[8, 8]).unwrap();
+
+    let mut adapters = LoraAdapters::new(&vs);
+    adapters.add_lora("lora1", &lora1.unet, 1.).unwrap();
+    adapters.add_lora("lora2", &lora2_modules, 0.).unwrap();
+    for (strength1, strength2) in [(0., 0.), (0.5, 0.), (1., 1.), (-1., 0.3), (0., 2.)] {
+        adapters.set_lora_strength("lora1", strength1).unwrap();
+        adapters.set_lora_strength("lora2", strength2).unwrap();
+        assert_eq!(adapters.lora_strength("lora1"), Some(strength1));
+        let expected = &base + &delta1 * strength1 + &delta2 * strength2;
+        let diff = (&vs.variables()[name] - expected).abs().max().double_value(&[]);
+        assert!(diff < 1e-5, "{strength1} {strength2}: {diff}");
+    }
+    adapters.remove_lora("lora2").unwrap();
+    adapters.set_lora_strength("lora1", 0.).unwrap();
+    assert!(vs.variables()[name].allclose(&base, 1e-5, 1e-6, false));
+    assert!(adapters.set_lora_strength("lora2", 1.).is_err());
+}
+
+// Merges a DoRA layer in the q_proj weight and compares with the DoRA formula computed
+// with norms over `norm_dim`, 1 for a norm per output channel and 0 per input channel.
+fn check_dora(dora_scale_shape: [i64; 2], norm_dim: i64) {
+    tch::manual_seed(42);
+    let module = "lora_te_text_model_encoder_layers_0_self_attn_q_proj";
+    let up = Tensor::randn([8, 2], (Kind::Float, Device::Cpu));
+    let down = Tensor::randn([2, 8], (Kind::Float, Device::Cpu));
+    let dora_scale = Tensor::rand(dora_scale_shape, (Kind::Float, Device::Cpu)) + 0.5;
+    let delta = up.matmul(&down) * 0.5;
+    let tensors = vec![
+        (format!("{module}.lora_up.weight"), up),
+        (format!("{module}.lora_down.weight"), down),
+        (format!("{module}.alpha"), Tensor::from(1f32)),
+        (format!("{module}.dora_scale"), dora_scale.copy()),
+    ];
+    let lora = Lora::from_tensors(tensors).unwrap();
+    let vs = text_encoder_var_store();
+    let name = "text_model.encoder.layers.0.self_attn.q_proj.weight";
+    let base = vs.variables()[name].copy();
+    merge_lora(&vs, &lora.text_encoder, 1.).unwrap();
+    let adapted = &base + delta;
+    let norm = adapted.square().sum_dim_intlist([norm_dim].as_slice(), true, Kind::Float).sqrt();
+    let expected = adapted * dora_scale / norm;
+    let diff = (&vs.variables()[name] - expected).abs().max().double_value(&[]);
+    assert!(diff < 1e-5, "{diff}");
+}
+
+#[test]
+fn dora_norm_per_output_channel() {
+    check_dora([8, 1], 1)
+}
+
+#[test]
+fn dora_norm_per_input_channel() {
+    check_dora([1, 8], 0)
+}
+
+#[test]
+fn dora_scale_with_a_wrong_shape_fails() {
+    let module = "lora_te_text_model_encoder_layers_0_self_attn_q_proj";
+    let tensors = vec![
+        (format!("{module}.lora_up.weight"), Tensor::ones([8, 2], (Kind::Float, Device::Cpu))),
+        (format!("{module}.lora_down.weight"), Tensor::ones([2, 8], (Kind::Float, Device::Cpu))),
+        (format!("{module}.dora_scale"), Tensor::ones([4, 1], (Kind::Float, Device::Cpu))),
+    ];
+    let lora = Lora::from_tensors(tensors).unwrap();
+    let vs = text_encoder_var_store();
+    assert!(merge_lora(&vs, &lora.text_encoder, 1.).is_err());
+}
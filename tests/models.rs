@@ -0,0 +1,579 @@
+// Forward passes of tiny randomly initialized models on CPU, these check the shape
+// arithmetic of the blocks without requiring any pretrained weights.
+mod common;
+
+use common::{
+    randn, tiny_controlnet, tiny_controlnet_with_pooling, tiny_sdxl_blocks, tiny_sdxl_unet_config,
+    tiny_text_time_embedding, tiny_unet, tiny_unet_config, tiny_vae, CROSS_ATTENTION_DIM,
+    POOLED_DIM, SEQ_LEN,
+};
+use diffusers::models::consistency_decoder::{ConsistencyDecoder, ConsistencyDecoderConfig};
+use diffusers::models::controlnet::{ControlNet, ControlNetConfig};
+use diffusers::models::embeddings::{
+    sdxl_added_cond_embeds, sdxl_default_time_ids, sdxl_time_ids, AddedCond,
+};
+use diffusers::models::quantize::{load_int8, save_int8, simulate_int8};
+use diffusers::models::unet_2d::{BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig};
+use diffusers::models::vae::{LatentDecoder, OutputRange};
+use diffusers::pipelines::denoise::DenoiseLoop;
+use diffusers::pipelines::guidance;
+use diffusers::pipelines::sdxl::{self, SdxlControl, SdxlPromptEmbeds, SizeConditioning};
+use diffusers::schedulers::ddim::{DDIMScheduler, DDIMSchedulerConfig};
+use diffusers::utils::MemoryReport;
+use tch::{nn, Device, Kind, Tensor};
+
+#[test]
+fn unet_forward() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let ys = tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states));
+    assert_eq!(ys.size(), [2, 4, 16, 24]);
+}
+
+#[test]
+fn unet_memory_report() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let mut report = MemoryReport::new();
+    let ys = tch::no_grad(|| {
+        unet.forward_with_memory_report(&xs, 999., &encoder_hidden_states, &mut report)
+    });
+    assert!(ys.allclose(
+        &tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states)),
+        1e-6,
+        1e-6,
+        false
+    ));
+    let labels: Vec<_> = report.snapshots.iter().map(|s| s.label.as_str()).collect();
+    assert_eq!(
+        labels,
+        ["conv_in", "down_blocks.0", "down_blocks.1", "mid_block", "up_blocks.0", "up_blocks.1"]
+    );
+    // The skip connections stop being counted once consumed, the last up block uses the
+    // remaining ones.
+    let last = report.snapshots.last().unwrap();
+    assert_eq!(last.live_bytes, last.activation_bytes);
+    let up_0 = &report.snapshots[4];
+    assert!(up_0.live_bytes > up_0.activation_bytes);
+    assert_eq!(report.peak().unwrap().label, "mid_block");
+    assert!(report.snapshots.iter().all(|s| s.cuda_used_bytes.is_none()));
+}
+
+#[test]
+fn quantized_unet_forward() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let ys = tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states));
+    assert!(simulate_int8(&vs) > 0);
+    let quantized_ys = tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states));
+    assert_eq!(quantized_ys.size(), ys.size());
+    assert!(bool::try_from(quantized_ys.isfinite().all()).unwrap());
+    assert!(!quantized_ys.equal(&ys));
+    let error = f64::try_from((&quantized_ys - &ys).abs().max()).unwrap();
+    let scale = f64::try_from(ys.abs().max()).unwrap();
+    assert!(error < 0.1 * scale, "{error} {scale}");
+}
+
+#[test]
+fn int8_unet_forward() -> anyhow::Result<()> {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut unet = tiny_unet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let ys = tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states));
+    // Four spatial transformers with convolution projections, each with two attention
+    // layers of four linear layers and a feed-forward of two.
+    assert_eq!(unet.quantize_int8()?, 40);
+    let int8_ys = tch::no_grad(|| unet.forward(&xs, 999., &encoder_hidden_states));
+    assert_eq!(int8_ys.size(), ys.size());
+    assert!(bool::try_from(int8_ys.isfinite().all())?);
+    assert!(!int8_ys.equal(&ys));
+    let error = f64::try_from((&int8_ys - &ys).abs().max())?;
+    let scale = f64::try_from(ys.abs().max())?;
+    assert!(error < 0.1 * scale, "{error} {scale}");
+    Ok(())
+}
+
+#[test]
+fn save_load_int8() -> anyhow::Result<()> {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let _unet = tiny_unet(&vs);
+    let path = std::env::temp_dir().join("diffusers-test-unet-int8.safetensors");
+    save_int8(&vs, &path)?;
+    let saved: std::collections::HashMap<_, _> =
+        Tensor::read_safetensors(&path)?.into_iter().collect();
+    // The quantized weights are the only int8 tensors and each has its scales.
+    let int8_names: Vec<_> =
+        saved.iter().filter(|(_, t)| t.kind() == Kind::Int8).map(|(n, _)| n.clone()).collect();
+    let n_quantized = simulate_int8(&vs);
+    assert_eq!(int8_names.len(), n_quantized);
+    for name in int8_names.iter() {
+        assert_eq!(saved[&format!("{name}.int8_scales")].kind(), Kind::Float);
+    }
+    assert_eq!(saved.len(), vs.variables().len() + n_quantized);
+
+    // Loading gives the weights rounded in place, in a freshly initialized model.
+    tch::manual_seed(43);
+    let loaded_vs = nn::VarStore::new(Device::Cpu);
+    let _loaded_unet = tiny_unet(&loaded_vs);
+    load_int8(&loaded_vs, &path)?;
+    let loaded = loaded_vs.variables();
+    for (name, var) in vs.variables() {
+        assert_eq!(loaded[&name].kind(), Kind::Float, "{name}");
+        assert!(loaded[&name].equal(&var), "{name}");
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[test]
+fn vae_encode_decode() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let vae = tiny_vae(&vs);
+    let image = randn(&[1, 3, 32, 48]);
+    let latents = tch::no_grad(|| vae.encode_image(&image));
+    let latent_shape = vae.config.latent_shape(48, 32, 1).unwrap();
+    assert_eq!(latents.size(), latent_shape);
+    let decoded = tch::no_grad(|| vae.decode(&latents));
+    assert_eq!(decoded.size(), [1, 3, 32, 48]);
+}
+
+#[test]
+fn vae_postprocess_clamps() {
+    let to_vec = |xs: &Tensor| Vec::<u8>::try_from(xs.flatten(0, -1)).unwrap();
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut vae = tiny_vae(&vs);
+    // Out of range values saturate rather than wrap around when converted to u8.
+    let values = [-3f32, -1., 0., 1., 3.];
+    let image = Tensor::from_slice(&values).view([1, 1, 1, 5]);
+    let unit = OutputRange::MinusOneToOne.to_unit_range(&image);
+    assert_eq!(Vec::<f32>::try_from(unit.flatten(0, -1)).unwrap(), [0., 0., 0.5, 1., 1.]);
+    assert_eq!(to_vec(&vae.postprocess(&image)), [0, 0, 127, 255, 255]);
+
+    vae.config.output_range = OutputRange::ZeroToOne;
+    let values = [-3f32, 0., 0.5, 1., 3.];
+    let image = Tensor::from_slice(&values).view([1, 1, 1, 5]);
+    assert_eq!(to_vec(&vae.postprocess(&image)), [0, 0, 127, 255, 255]);
+}
+
+#[test]
+fn controlnet_forward() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let controlnet = tiny_controlnet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let cond = Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu));
+    let (down, mid) =
+        tch::no_grad(|| controlnet.forward(&xs, 999., &encoder_hidden_states, &cond, 1.));
+    // conv_in, one resnet per block, and the downsampler of the first block.
+    assert_eq!(down.len(), 4);
+    assert_eq!(mid.size(), [2, 32, 8, 12]);
+}
+
+#[test]
+fn controlnet_global_pool_conditions() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let controlnet = tiny_controlnet(&vs);
+    let mut pooled_vs = nn::VarStore::new(Device::Cpu);
+    let pooled_controlnet = tiny_controlnet_with_pooling(&pooled_vs, true);
+    pooled_vs.copy(&vs).unwrap();
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let cond = Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu));
+    let forward = |controlnet: &ControlNet| {
+        let (mut down, mid) =
+            tch::no_grad(|| controlnet.forward(&xs, 999., &encoder_hidden_states, &cond, 1.));
+        down.push(mid);
+        down
+    };
+    let residuals = forward(&controlnet);
+    let pooled_residuals = forward(&pooled_controlnet);
+    assert_eq!(residuals.len(), pooled_residuals.len());
+    for (residual, pooled) in residuals.iter().zip(pooled_residuals.iter()) {
+        let (b, c, _, _) = residual.size4().unwrap();
+        assert_eq!(pooled.size(), [b, c, 1, 1]);
+        let mean = residual.mean_dim(Some([2i64, 3].as_slice()), true, None);
+        assert!(pooled.allclose(&mean, 1e-5, 1e-5, false));
+    }
+}
+
+#[test]
+fn controlnet_batched_conditioning() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let controlnet = tiny_controlnet(&vs);
+    let forward = |xs: &Tensor, encoder_hidden_states: &Tensor, cond: &Tensor| {
+        let (mut down, mid) =
+            tch::no_grad(|| controlnet.forward(xs, 999., encoder_hidden_states, cond, 1.));
+        down.push(mid);
+        down
+    };
+    // A distinct conditioning image per batch element.
+    let cond = Tensor::rand([3, 3, 128, 192], (Kind::Float, Device::Cpu));
+    // Without guidance, then with the unconditional and conditional branches batched
+    // together, the conditioning being repeated for each branch.
+    for bsize in [3, 6] {
+        let xs = randn(&[bsize, 4, 16, 24]);
+        let encoder_hidden_states = randn(&[bsize, SEQ_LEN, CROSS_ATTENTION_DIM]);
+        let residuals = forward(&xs, &encoder_hidden_states, &cond);
+        for i in 0..bsize {
+            let single = forward(
+                &xs.narrow(0, i, 1),
+                &encoder_hidden_states.narrow(0, i, 1),
+                &cond.narrow(0, i % 3, 1),
+            );
+            for (residual, single) in residuals.iter().zip(single.iter()) {
+                let residual = residual.narrow(0, i, 1);
+                assert!(residual.allclose(single, 1e-4, 1e-4, false), "{bsize} {i}");
+            }
+        }
+        // Changing the last conditioning image only changes the elements using it.
+        let other_cond = Tensor::cat(&[cond.narrow(0, 0, 2), cond.narrow(0, 2, 1) * 0.5], 0);
+        let other_residuals = forward(&xs, &encoder_hidden_states, &other_cond);
+        for i in 0..bsize {
+            let unchanged = residuals.iter().zip(other_residuals.iter()).all(|(r1, r2)| {
+                r1.narrow(0, i, 1).allclose(&r2.narrow(0, i, 1), 1e-6, 1e-6, false)
+            });
+            assert_eq!(unchanged, i % 3 != 2, "{bsize} {i}");
+        }
+    }
+}
+
+#[test]
+fn denoising_loop() {
+    tch::manual_seed(42);
+    let unet_vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&unet_vs);
+    let vae_vs = nn::VarStore::new(Device::Cpu);
+    let vae = tiny_vae(&vae_vs);
+    let mut scheduler = DDIMScheduler::new(3, DDIMSchedulerConfig::default());
+    let text_embeddings = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let latents = randn(&[1, 4, 8, 8]) * scheduler.init_noise_sigma();
+    let output = tch::no_grad(|| {
+        DenoiseLoop::new().run(&mut scheduler, latents, |_step_index, timestep, xs| {
+            Ok(guidance::guided_prediction(xs, &text_embeddings, 7.5, true, |xs, embeddings| {
+                unet.forward(xs, timestep, embeddings)
+            }))
+        })
+    })
+    .unwrap();
+    assert_eq!(output.steps, 3);
+    let latents = output.latents;
+    assert_eq!(latents.size(), [1, 4, 8, 8]);
+    let image = tch::no_grad(|| vae.decode(&vae.unscale_latents(&latents)));
+    assert_eq!(image.size(), [1, 3, 16, 16]);
+}
+
+// The block layout of stable diffusion 1.5, with fewer channels.
+fn tiny_sd15_blocks() -> Vec<BlockConfig> {
+    let bc = |out_channels, use_cross_attn| BlockConfig {
+        out_channels,
+        use_cross_attn,
+        attention_head_dim: 2,
+        cross_attention_dim: None,
+        transformer_layers_per_block: 1,
+    };
+    vec![bc(8, true), bc(16, true), bc(32, true), bc(32, false)]
+}
+
+#[test]
+fn controlnet_residuals_match_unet_skip_connections() {
+    tch::manual_seed(42);
+    let unet_vs = nn::VarStore::new(Device::Cpu);
+    let unet_config = UNet2DConditionModelConfig {
+        blocks: tiny_sd15_blocks(),
+        norm_num_groups: 4,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        ..Default::default()
+    };
+    let unet = UNet2DConditionModel::new(unet_vs.root(), 4, 4, unet_config);
+    let controlnet_vs = nn::VarStore::new(Device::Cpu);
+    let controlnet_config = ControlNetConfig {
+        blocks: tiny_sd15_blocks(),
+        conditioning_embedding_out_channels: vec![4, 4, 8, 8],
+        norm_num_groups: 4,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        ..Default::default()
+    };
+    let controlnet = ControlNet::new(controlnet_vs.root(), 4, controlnet_config);
+
+    // Latents for 512x512 and 512x768 images.
+    for (height, width) in [(64, 64), (64, 96)] {
+        let xs = randn(&[2, 4, height, width]);
+        let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+        let cond = Tensor::rand([1, 3, height * 8, width * 8], (Kind::Float, Device::Cpu));
+        let (skips, unet_mid) =
+            tch::no_grad(|| unet.skip_connections(&xs, 999., &encoder_hidden_states));
+        let (down, mid) =
+            tch::no_grad(|| controlnet.forward(&xs, 999., &encoder_hidden_states, &cond, 1.));
+        assert_eq!(down.len(), skips.len());
+        for (i, (residual, skip)) in down.iter().zip(skips.iter()).enumerate() {
+            assert_eq!(residual.size(), skip.size(), "down residual {i}");
+        }
+        assert_eq!(mid.size(), unet_mid.size());
+    }
+}
+
+#[test]
+fn controlnet_cond_embedding_cache() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut controlnet = tiny_controlnet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let cond1 = Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu));
+    let cond2 = Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu));
+    // The second step reuses the embedding of the first one, the third one has to
+    // recompute it as the conditioning changed.
+    let steps = [(&cond1, 999.), (&cond1, 500.), (&cond2, 1.)];
+    let run = |controlnet: &ControlNet| -> Vec<Tensor> {
+        let residuals = steps.iter().map(|(cond, timestep)| {
+            let (mut down, mid) = tch::no_grad(|| {
+                controlnet.forward(&xs, *timestep, &encoder_hidden_states, cond, 1.)
+            });
+            down.push(mid);
+            down
+        });
+        residuals.flatten().collect()
+    };
+    controlnet.set_cond_embedding_cache(false);
+    let expected = run(&controlnet);
+    controlnet.set_cond_embedding_cache(true);
+    let cached = run(&controlnet);
+    assert_eq!(expected.len(), cached.len());
+    for (expected, cached) in expected.iter().zip(cached.iter()) {
+        assert!(expected.allclose(cached, 1e-5, 1e-5, false));
+    }
+}
+
+#[test]
+fn unet_shared_timestep_matches_per_element_timesteps() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let xs = randn(&[3, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[3, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    // A single timestep is embedded once and broadcast over the batch.
+    let shared = tch::no_grad(|| unet.forward(&xs, 421., &encoder_hidden_states));
+    let timesteps = Tensor::full([3], 421., (Kind::Float, Device::Cpu));
+    let per_element =
+        tch::no_grad(|| unet.forward_traceable(&xs, &timesteps, &encoder_hidden_states));
+    assert!(shared.allclose(&per_element, 1e-5, 1e-5, false));
+}
+
+#[test]
+fn traced_unet_matches_forward_traceable() -> anyhow::Result<()> {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let example_timesteps = Tensor::full([2], 999., (Kind::Float, Device::Cpu));
+    let module = tch::no_grad(|| {
+        unet.trace(
+            &randn(&[2, 4, 16, 24]),
+            &example_timesteps,
+            &randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]),
+        )
+    })?;
+    // Other values than the example ones, the timesteps are inputs of the traced graph.
+    let xs = randn(&[2, 4, 16, 24]);
+    let timesteps = Tensor::from_slice(&[421f32, 21.]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let traced = tch::no_grad(|| {
+        module.forward_ts(&[
+            xs.shallow_clone(),
+            timesteps.shallow_clone(),
+            encoder_hidden_states.shallow_clone(),
+        ])
+    })?;
+    let expected = tch::no_grad(|| unet.forward_traceable(&xs, &timesteps, &encoder_hidden_states));
+    assert_eq!(traced.size(), [2, 4, 16, 24]);
+    assert!(traced.allclose(&expected, 1e-5, 1e-5, false));
+    Ok(())
+}
+
+#[test]
+fn sdxl_micro_conditioning() {
+    let time_ids = sdxl_time_ids((768, 1024), (32, 0), (1024, 1024));
+    assert_eq!(
+        Vec::<f32>::try_from(time_ids.view(-1)).unwrap(),
+        [768., 1024., 32., 0., 1024., 1024.]
+    );
+    let time_ids = sdxl_default_time_ids(1024, 768);
+    assert_eq!(
+        Vec::<f32>::try_from(time_ids.view(-1)).unwrap(),
+        [1024., 768., 0., 0., 1024., 768.]
+    );
+    let pooled = randn(&[2, 1280]);
+    let added = sdxl_added_cond_embeds(&time_ids, &pooled);
+    assert_eq!(added.size(), [2, 2816]);
+    assert!(added.narrow(1, 0, 1280).equal(&pooled));
+}
+
+#[test]
+fn sdxl_unet_added_cond() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let config = tiny_sdxl_unet_config();
+    let unet = UNet2DConditionModel::new(vs.root(), 4, 4, config.clone());
+    let n_params: i64 = vs.trainable_variables().iter().map(|v| v.numel() as i64).sum();
+    assert_eq!(config.num_parameters(4, 4), n_params);
+    let paths = unet.attention_paths();
+    assert!(paths.contains(&"up_blocks.0.attentions.1.transformer_blocks.1.attn2".to_string()));
+    assert!(paths.contains(&"mid_block.attentions.0.transformer_blocks.1.attn1".to_string()));
+    assert!(!paths.iter().any(|p| p.starts_with("down_blocks.0")), "{paths:?}");
+
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let text_embeds = randn(&[2, POOLED_DIM]);
+    let forward = |time_ids: Tensor| {
+        let added_cond = AddedCond { text_embeds: text_embeds.shallow_clone(), time_ids };
+        tch::no_grad(|| {
+            unet.forward_with_added_cond(&xs, 999., &encoder_hidden_states, &added_cond)
+        })
+    };
+    let ys = forward(sdxl_default_time_ids(128, 192));
+    assert_eq!(ys.size(), [2, 4, 16, 24]);
+    // The crop changes the conditioning, hence the prediction.
+    let cropped = forward(sdxl_time_ids((128, 192), (32, 0), (128, 192)));
+    assert!(!ys.allclose(&cropped, 1e-4, 1e-4, false));
+
+    // The pipeline defaults to the generation size without any crop.
+    let embeds = SdxlPromptEmbeds {
+        context: encoder_hidden_states.shallow_clone(),
+        pooled: text_embeds.shallow_clone(),
+    };
+    let added_cond = embeds.added_cond(&SizeConditioning::default(), 128, 192);
+    assert!(added_cond.time_ids.equal(&sdxl_default_time_ids(128, 192)));
+    let xs = xs.narrow(0, 0, 1);
+    let cond =
+        tch::no_grad(|| sdxl::guided_noise_pred(&unet, None, &xs, 999., &embeds, &added_cond, 1.));
+    assert!(cond.allclose(&ys.narrow(0, 1, 1), 1e-5, 1e-5, false));
+
+    let mut scheduler = DDIMScheduler::new(2, DDIMSchedulerConfig::default());
+    let latents =
+        sdxl::denoise(&unet, None, &mut scheduler, &embeds, &Default::default(), 128, 192, 5., xs)
+            .unwrap();
+    assert_eq!(latents.size(), [1, 4, 16, 24]);
+    assert!(bool::try_from(latents.isfinite().all()).unwrap());
+}
+
+#[test]
+fn sdxl_controlnet_residuals() {
+    tch::manual_seed(42);
+    let unet_vs = nn::VarStore::new(Device::Cpu);
+    let unet_config = tiny_sdxl_unet_config();
+    let unet = UNet2DConditionModel::new(unet_vs.root(), 4, 4, unet_config.clone());
+    let config = ControlNetConfig {
+        blocks: tiny_sdxl_blocks(),
+        conditioning_embedding_out_channels: vec![4, 8, 8, 16],
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: CROSS_ATTENTION_DIM,
+        use_linear_projection: true,
+        text_time_embedding: Some(tiny_text_time_embedding()),
+        ..Default::default()
+    };
+    config.check_compatible(&unet_config).unwrap();
+    assert!(config.check_compatible(&tiny_unet_config()).is_err());
+    let n_params = config.num_parameters(4);
+    let controlnet_vs = nn::VarStore::new(Device::Cpu);
+    let controlnet = ControlNet::new(controlnet_vs.root(), 4, config);
+    let variables = controlnet_vs.trainable_variables();
+    assert_eq!(n_params, variables.iter().map(|v| v.numel() as i64).sum::<i64>());
+
+    let xs = randn(&[1, 4, 16, 24]);
+    let embeds = SdxlPromptEmbeds {
+        context: randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]),
+        pooled: randn(&[2, POOLED_DIM]),
+    };
+    let added_cond = embeds.added_cond(&SizeConditioning::default(), 128, 192);
+    let cond = Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu));
+    let pred = |control: Option<&SdxlControl>| {
+        tch::no_grad(|| {
+            sdxl::guided_noise_pred(&unet, control, &xs, 999., &embeds, &added_cond, 5.)
+        })
+    };
+    let control = |conditioning_scale| SdxlControl {
+        controlnet: &controlnet,
+        conditioning: &cond,
+        conditioning_scale,
+    };
+    let expected = pred(None);
+    assert!(pred(Some(&control(0.))).allclose(&expected, 1e-5, 1e-5, false));
+    assert!(!pred(Some(&control(1.))).allclose(&expected, 1e-4, 1e-4, false));
+}
+
+#[test]
+fn unet_freeu() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut unet = tiny_unet(&vs);
+    let xs = randn(&[2, 4, 16, 24]);
+    let encoder_hidden_states = randn(&[2, SEQ_LEN, CROSS_ATTENTION_DIM]);
+    let forward = |unet: &UNet2DConditionModel, xs: &Tensor| {
+        tch::no_grad(|| unet.forward(xs, 999., &encoder_hidden_states))
+    };
+    let baseline = forward(&unet, &xs);
+    unet.enable_freeu(0.9, 0.2, 1.5, 1.6);
+    assert!(!forward(&unet, &xs).allclose(&baseline, 1e-5, 1e-5, false));
+    // The skip connections of the lowest resolution are 1x1 for 2x2 latents, smaller
+    // than the filtered frequency window.
+    let small = forward(&unet, &randn(&[2, 4, 2, 2]));
+    assert_eq!(small.size(), [2, 4, 2, 2]);
+    unet.disable_freeu();
+    assert!(forward(&unet, &xs).equal(&baseline));
+}
+
+#[test]
+fn consistency_decoder() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let config = ConsistencyDecoderConfig {
+        block_out_channels: vec![8, 16],
+        layers_per_block: 1,
+        norm_num_groups: 4,
+        ..Default::default()
+    };
+    let decoder = ConsistencyDecoder::new(vs.root(), config);
+    let variables = vs.variables();
+    // The variable names of the diffusers ConsistencyDecoderVAE checkpoints.
+    for name in [
+        "decoder_unet.time_proj.weight",
+        "decoder_unet.time_embedding.linear_2.weight",
+        "decoder_unet.down_blocks.0.downsamplers.0.conv1.weight",
+        "decoder_unet.mid_block.resnets.1.time_emb_proj.weight",
+        "decoder_unet.up_blocks.0.resnets.1.conv_shortcut.weight",
+        "decoder_unet.up_blocks.0.upsamplers.0.norm1.weight",
+        "decoder_unet.conv_out.bias",
+    ] {
+        assert!(variables.contains_key(name), "{name}");
+    }
+    // The scale and shift of the time embedding.
+    assert_eq!(variables["decoder_unet.mid_block.resnets.1.time_emb_proj.weight"].size(), [32, 32]);
+
+    let latents = randn(&[2, 4, 4, 6]);
+    let decode = |seed| {
+        tch::manual_seed(seed);
+        tch::no_grad(|| decoder.decode(&latents))
+    };
+    let image = decode(1);
+    assert_eq!(image.size(), [2, 3, 32, 48]);
+    assert!(image.isfinite().all().int64_value(&[]) == 1);
+    // The second step samples some noise.
+    assert!(image.equal(&decode(1)));
+    assert!(!image.equal(&decode(2)));
+}
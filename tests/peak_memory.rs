@@ -0,0 +1,97 @@
+// Reports the peak host memory used when loading a merged safetensors checkpoint. The
+// peak resident set size is tracked for the whole process, so this is kept in its own
+// test binary where no other test allocates concurrently.
+#![cfg(target_os = "linux")]
+mod common;
+
+use common::{tiny_unet, tiny_unet_config, tiny_vae};
+use diffusers::checkpoint::{load_merged_safetensors, WeightVariant};
+use std::collections::HashMap;
+use tch::{nn, Device, Kind, Tensor};
+
+// The text encoder stand-in gets 32 layers of 4MiB so that the checkpoint is large
+// compared to the allocator noise.
+const N_LAYERS: i64 = 32;
+const DIM: i64 = 1024;
+const MIB: i64 = 1 << 20;
+
+// Returns a field of /proc/self/status in bytes, e.g. VmRSS or VmHWM.
+fn proc_status_bytes(field: &str) -> i64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|l| l.starts_with(field)).unwrap();
+    let kib = line[field.len()..].trim().trim_end_matches("kB").trim();
+    kib.parse::<i64>().unwrap() * 1024
+}
+
+// Runs `f` and returns how much the resident set size grew at its peak.
+fn peak_rss_increase<T, F: FnOnce() -> T>(f: F) -> (T, i64) {
+    let rss = proc_status_bytes("VmRSS:");
+    // Resets the peak resident set size to the current one.
+    std::fs::write("/proc/self/clear_refs", "5").unwrap();
+    let res = f();
+    (res, proc_status_bytes("VmHWM:") - rss)
+}
+
+#[test]
+fn merged_safetensors_peak_memory() {
+    tch::manual_seed(42);
+    let vs_unet = nn::VarStore::new(Device::Cpu);
+    let _unet = tiny_unet(&vs_unet);
+    let vs_vae = nn::VarStore::new(Device::Cpu);
+    let _vae = tiny_vae(&vs_vae);
+    // The variables are filled with ones so that their memory is resident before loading.
+    let vs_text_encoder = nn::VarStore::new(Device::Cpu);
+    for i in 0..N_LAYERS {
+        let _ = (vs_text_encoder.root() / "layers").ones_no_train(&i.to_string(), &[DIM, DIM]);
+    }
+
+    // The checkpoint uses random values, only their sums are kept to check the loaded
+    // variables.
+    let mut tensors = vec![];
+    for (prefix, vs) in [("unet", &vs_unet), ("vae", &vs_vae), ("text_encoder", &vs_text_encoder)] {
+        for (name, var) in vs.variables() {
+            tensors.push((format!("{prefix}.{name}"), Tensor::randn_like(&var)));
+        }
+    }
+    let sums: HashMap<String, f64> =
+        tensors.iter().map(|(n, t)| (n.clone(), t.sum(Kind::Double).double_value(&[]))).collect();
+    let path = std::env::temp_dir().join("diffusers-test-peak-memory.safetensors");
+    Tensor::write_safetensors(&tensors, &path).unwrap();
+    drop(tensors);
+    let file_bytes = std::fs::metadata(&path).unwrap().len() as i64;
+
+    let (variant, streamed) = peak_rss_increase(|| {
+        load_merged_safetensors(
+            &path,
+            WeightVariant::PreferEma,
+            &tiny_unet_config(),
+            2,
+            &vs_text_encoder,
+            &vs_vae,
+            &vs_unet,
+        )
+    });
+    // Reading the whole file first, as done before streaming the tensors.
+    let (whole_file, read_whole_file) =
+        peak_rss_increase(|| Tensor::read_safetensors(&path).unwrap());
+    drop(whole_file);
+    std::fs::remove_file(&path).unwrap();
+    println!(
+        "checkpoint: {} MiB, peak increase when streaming: {} MiB, when reading the whole file: {} MiB",
+        file_bytes / MIB,
+        streamed / MIB,
+        read_whole_file / MIB,
+    );
+
+    assert_eq!(variant.unwrap(), WeightVariant::Regular);
+    for (prefix, vs) in [("unet", &vs_unet), ("vae", &vs_vae), ("text_encoder", &vs_text_encoder)] {
+        for (name, var) in vs.variables() {
+            let expected = sums[&format!("{prefix}.{name}")];
+            let sum = var.sum(Kind::Double).double_value(&[]);
+            assert!((sum - expected).abs() <= 1e-3 * (1. + expected.abs()), "{name}");
+        }
+    }
+    // Only a single checkpoint tensor is held in memory on top of the var stores.
+    assert!(streamed < file_bytes / 4, "{streamed} {file_bytes}");
+    assert!(read_whole_file > file_bytes * 3 / 4, "{read_whole_file} {file_bytes}");
+}
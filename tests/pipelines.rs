@@ -0,0 +1,149 @@
+use diffusers::pipelines::controlnet::{normalize_conditioning, ControlGuidanceWindow};
+use diffusers::pipelines::inpaint::composite_with_original;
+use diffusers::pipelines::multidiffusion::TiledCanvas;
+use diffusers::pipelines::prompt_schedule::PromptSchedule;
+use tch::{Device, Kind, Tensor};
+
+#[test]
+fn control_guidance_window() {
+    for n_steps in [1, 4, 30] {
+        let active = |window: ControlGuidanceWindow| -> Vec<bool> {
+            (0..n_steps).map(|step_index| window.is_active(step_index, n_steps)).collect()
+        };
+        // An empty window at the start never applies the ControlNet.
+        let window = ControlGuidanceWindow::new(0., 0.).unwrap();
+        assert!(active(window).iter().all(|&a| !a), "{n_steps}");
+        assert!(active(ControlGuidanceWindow::default()).iter().all(|&a| a), "{n_steps}");
+    }
+    let window = ControlGuidanceWindow::new(0., 0.5).unwrap();
+    let active: Vec<_> = (0..4).map(|step_index| window.is_active(step_index, 4)).collect();
+    assert_eq!(active, [true, true, false, false]);
+    assert!(ControlGuidanceWindow::new(0.6, 0.4).is_err());
+}
+
+#[test]
+fn tiles_share_the_initial_noise() {
+    tch::manual_seed(42);
+    let canvas = TiledCanvas::new(16, 40, 16, 8).unwrap();
+    let noise = canvas.initial_noise(2, 4, Device::Cpu);
+    assert_eq!(noise.size(), [2, 4, 16, 40]);
+    let tiles = canvas.tiles();
+    assert_eq!(tiles.len(), 4);
+    for (left, right) in tiles.iter().zip(tiles.iter().skip(1)) {
+        let overlap = left.x + left.width - right.x;
+        assert!(overlap > 0);
+        let left_overlap = left.slice(&noise).narrow(3, right.x - left.x, overlap);
+        let right_overlap = right.slice(&noise).narrow(3, 0, overlap);
+        assert!(left_overlap.equal(&right_overlap));
+    }
+}
+
+#[test]
+fn tile_weights_sum_to_one() {
+    // Tiles of 16 with a stride of 6 and the last tiles aligned on the canvas end, three
+    // to four tiles overlap along each dimension around the canvas center.
+    let canvas = TiledCanvas::new(30, 37, 16, 10).unwrap();
+    let coverage = Tensor::zeros([1, 1, 30, 37], (Kind::Float, Device::Cpu));
+    for tile in canvas.tiles() {
+        let _ = tile.slice(&coverage).g_add_scalar_(1.);
+    }
+    assert!(coverage.max().double_value(&[]) >= 9.);
+    let weights = canvas.normalized_weight_map(Device::Cpu).unwrap();
+    assert_eq!(weights.size(), [1, 1, 30, 37]);
+    let error = (weights - 1.).abs().max().double_value(&[]);
+    assert!(error < 1e-5, "{error}");
+}
+
+fn entries(prompt: &str, n_steps: usize) -> Vec<(usize, String)> {
+    PromptSchedule::parse(prompt, n_steps).unwrap().entries().to_vec()
+}
+
+#[test]
+fn prompt_schedule_edits() {
+    let owned = |entries: &[(usize, &str)]| -> Vec<(usize, String)> {
+        entries.iter().map(|(step, prompt)| (*step, prompt.to_string())).collect()
+    };
+    // [from:to:when] with a fraction of the steps and with a step index.
+    let expected = owned(&[(10, "a cat photo"), (20, "a dog photo")]);
+    assert_eq!(entries("a [cat:dog:0.5] photo", 20), expected);
+    assert_eq!(entries("a [cat:dog:10] photo", 20), expected);
+    // [from::when] drops from at when.
+    assert_eq!(entries("a [cat::4] photo", 10), owned(&[(4, "a cat photo"), (10, "a  photo")]));
+    // [:to:when] adds to at when, fractional steps are rounded down.
+    assert_eq!(entries("a [:dog:0.33] photo", 10), owned(&[(3, "a  photo"), (10, "a dog photo")]));
+    assert_eq!(entries("a [:dog:0.25] photo", 30), owned(&[(7, "a  photo"), (30, "a dog photo")]));
+    // Edits switching at the first or after the last step use a single prompt.
+    assert_eq!(entries("a [cat:dog:0] photo", 10), owned(&[(10, "a dog photo")]));
+    assert_eq!(entries("a [cat:dog:12] photo", 10), owned(&[(10, "a cat photo")]));
+    // Multiple edits give one entry per switching step.
+    assert_eq!(
+        entries("[red:blue:0.5] [cat:dog:0.25]", 8),
+        owned(&[(2, "red cat"), (4, "red dog"), (8, "blue dog")])
+    );
+    // Brackets that are not edits are kept.
+    assert_eq!(entries("a [cat] photo", 10), owned(&[(10, "a [cat] photo")]));
+
+    let schedule = PromptSchedule::parse("a [cat:dog:0.5] photo", 20).unwrap();
+    assert_eq!((schedule.index_at(9), schedule.index_at(10), schedule.index_at(19)), (0, 1, 1));
+}
+
+#[test]
+fn prompt_schedule_nested_edits() {
+    assert!(PromptSchedule::parse("a [cat:[dog:fox:0.5]:0.8] photo", 20).is_err());
+    assert!(PromptSchedule::parse("a [[dog:fox:0.5]] photo", 20).is_err());
+}
+
+#[test]
+fn prompt_schedule_plain_brackets() {
+    let owned = |entries: &[(usize, &str)]| -> Vec<(usize, String)> {
+        entries.iter().map(|(step, prompt)| (*step, prompt.to_string())).collect()
+    };
+    // De-emphasis brackets, possibly unbalanced, are kept as text.
+    for prompt in ["[[blue]] eyes", "[[[blue]]] eyes, [red] hair", "[[blue] eyes", "blue] [eyes"] {
+        assert_eq!(entries(prompt, 10), owned(&[(10, prompt)]));
+    }
+    // They can be used inside an edit and next to one.
+    assert_eq!(
+        entries("[[cat]:dog:0.5] with [[blue]] eyes", 10),
+        owned(&[(5, "[cat] with [[blue]] eyes"), (10, "dog with [[blue]] eyes")])
+    );
+    assert_eq!(
+        entries("[a [cat:dog:0.5] photo", 10),
+        owned(&[(5, "[a cat photo"), (10, "[a dog photo")])
+    );
+}
+
+#[test]
+fn conditioning_is_normalized_to_unit_range() {
+    let expected = Tensor::from_slice(&[0f32, 0.2, 1.]).view([1, 1, 1, 3]);
+    let check = |image: Tensor, expected: &Tensor| {
+        let normalized = normalize_conditioning(&image);
+        assert_eq!(normalized.kind(), Kind::Float);
+        assert!(normalized.allclose(expected, 1e-6, 1e-6, false), "{normalized}");
+    };
+    // u8 images are always rescaled, even when all the values are at most 1.
+    check(Tensor::from_slice(&[0u8, 51, 255]).view([1, 1, 1, 3]), &expected);
+    check(Tensor::from_slice(&[1u8, 1, 1]).view([1, 1, 1, 3]), &(expected.ones_like() / 255.));
+    // Float images with values above 1 are in the [0, 255] range.
+    check(Tensor::from_slice(&[0f32, 51., 255.]).view([1, 1, 1, 3]), &expected);
+    // Float images already in [0, 1] are kept, out of range values get clamped.
+    check(Tensor::from_slice(&[-0.5f32, 0.2, 1.]).view([1, 1, 1, 3]), &expected);
+}
+
+#[test]
+fn composite_with_original_mask() {
+    let options = (Kind::Float, Device::Cpu);
+    let image = Tensor::rand([2, 3, 32, 48], options);
+    let original = Tensor::rand([2, 3, 32, 48], options);
+    // The mask is given at a lower resolution and gets resized to the image one.
+    let zeros = Tensor::zeros([2, 1, 4, 6], options);
+    assert!(composite_with_original(&image, &original, &zeros).equal(&original));
+    let ones = zeros.ones_like();
+    assert!(composite_with_original(&image, &original, &ones).equal(&image));
+
+    // Masking the right half only changes the right half.
+    let half = Tensor::cat(&[zeros.narrow(3, 0, 3), ones.narrow(3, 3, 3)], 3);
+    let composite = composite_with_original(&image, &original, &half);
+    assert!(composite.narrow(3, 0, 16).equal(&original.narrow(3, 0, 16)));
+    assert!(composite.narrow(3, 32, 16).equal(&image.narrow(3, 32, 16)));
+}
@@ -0,0 +1,139 @@
+// Restart sampling adds random noise, these checks compare seeded runs and are kept in
+// their own test binary so that no other test samples random values concurrently.
+use diffusers::schedulers::dpmsolver_multistep::{
+    DPMSolverMultistepScheduler, DPMSolverMultistepSchedulerConfig,
+};
+use diffusers::schedulers::euler_discrete::{EulerDiscreteScheduler, EulerDiscreteSchedulerConfig};
+use diffusers::schedulers::RestartConfig;
+use tch::{Device, Kind, Tensor};
+
+const N_STEPS: usize = 10;
+
+// Restarts in the middle of the 10 steps schedules, at sigma 0.94 for Euler and 0.83 for
+// DPM-Solver++.
+const RESTART: RestartConfig = RestartConfig { sigma_min: 1., sigma_max: 3., steps: 3, repeats: 2 };
+
+fn initial_latents(init_noise_sigma: f64) -> Tensor {
+    tch::manual_seed(0);
+    Tensor::randn([1, 4, 32, 32], (Kind::Float, Device::Cpu)) * init_noise_sigma
+}
+
+// A deterministic stand-in for the UNet, predicting some of its input as the noise.
+fn model(xs: &Tensor) -> Tensor {
+    xs * 0.5
+}
+
+// Returns the timesteps and the sample obtained after each step.
+fn euler(restart: Option<RestartConfig>, seed: i64) -> (Vec<f64>, Vec<Tensor>) {
+    let config = EulerDiscreteSchedulerConfig { restart, ..Default::default() };
+    let mut scheduler = EulerDiscreteScheduler::new(N_STEPS, config);
+    let mut latents = initial_latents(scheduler.init_noise_sigma());
+    tch::manual_seed(seed);
+    let timesteps = scheduler.timesteps().to_vec();
+    let mut samples = vec![];
+    for &timestep in timesteps.iter() {
+        let xs = scheduler.scale_model_input(latents.shallow_clone(), timestep);
+        latents = scheduler.step(&model(&xs), timestep, &latents);
+        samples.push(latents.shallow_clone())
+    }
+    (timesteps, samples)
+}
+
+fn dpm_solver(restart: Option<RestartConfig>, seed: i64) -> (Vec<usize>, Vec<Tensor>) {
+    let config = DPMSolverMultistepSchedulerConfig { restart, ..Default::default() };
+    let mut scheduler = DPMSolverMultistepScheduler::new(N_STEPS, config);
+    let mut latents = initial_latents(scheduler.init_noise_sigma());
+    tch::manual_seed(seed);
+    let timesteps = scheduler.timesteps().to_vec();
+    let mut samples = vec![];
+    for &timestep in timesteps.iter() {
+        let xs = scheduler.scale_model_input(latents.shallow_clone(), timestep);
+        latents = scheduler.step(&model(&xs), timestep, &latents);
+        samples.push(latents.shallow_clone())
+    }
+    (timesteps, samples)
+}
+
+// Checks the restart segments of a schedule and returns the index of the first restart
+// step, the noise gets added by the step just before it.
+fn check_restart_schedule<T: PartialOrd + Copy + std::fmt::Debug>(
+    timesteps: &[T],
+    restart_timesteps: &[T],
+) -> usize {
+    assert_eq!(timesteps.len(), N_STEPS);
+    let restart_index = (1..restart_timesteps.len())
+        .find(|&i| restart_timesteps[i] > restart_timesteps[i - 1])
+        .unwrap();
+    assert!(restart_index > 1 && restart_index < N_STEPS - 1, "{restart_timesteps:?}");
+    let n_segment = RESTART.steps;
+    assert_eq!(restart_timesteps.len(), N_STEPS + n_segment * RESTART.repeats);
+    // The main schedule is kept around the repeated segments.
+    let after = restart_index + n_segment * RESTART.repeats;
+    assert_eq!(restart_timesteps[..restart_index], timesteps[..restart_index]);
+    assert_eq!(restart_timesteps[after..], timesteps[restart_index..]);
+    let segment = &restart_timesteps[restart_index..restart_index + n_segment];
+    assert_eq!(&restart_timesteps[restart_index + n_segment..after], segment);
+    assert!(segment.windows(2).all(|w| w[0] > w[1]), "{segment:?}");
+    assert!(segment[n_segment - 1] > timesteps[restart_index]);
+    restart_index
+}
+
+// The runs only differ from the step adding the restart noise onwards.
+fn check_restart_samples(
+    restart_index: usize,
+    samples: &[Tensor],
+    samples_same_seed: &[Tensor],
+    samples_other_seed: &[Tensor],
+    samples_without_restart: &[Tensor],
+) {
+    for (i, xs) in samples.iter().enumerate() {
+        assert!(xs.equal(&samples_same_seed[i]), "{i}");
+        let before_restart = i + 1 < restart_index;
+        assert_eq!(xs.equal(&samples_other_seed[i]), before_restart, "{i}");
+        if i < restart_index {
+            assert_eq!(xs.equal(&samples_without_restart[i]), before_restart, "{i}");
+        }
+    }
+}
+
+fn euler_restart_boundary() {
+    let (timesteps, samples_without_restart) = euler(None, 1);
+    let (restart_timesteps, samples) = euler(Some(RESTART), 1);
+    let restart_index = check_restart_schedule(&timesteps, &restart_timesteps);
+    let (_, samples_same_seed) = euler(Some(RESTART), 1);
+    let (_, samples_other_seed) = euler(Some(RESTART), 2);
+    check_restart_samples(
+        restart_index,
+        &samples,
+        &samples_same_seed,
+        &samples_other_seed,
+        &samples_without_restart,
+    );
+    // Both runs reach the restart sigma, then noise brings the sample back to sigma_max.
+    let i = restart_index - 1;
+    let noise_std = f64::try_from((&samples[i] - &samples_without_restart[i]).std(true)).unwrap();
+    assert!((2.6..3.1).contains(&noise_std), "{noise_std}");
+}
+
+fn dpm_solver_restart_boundary() {
+    let (timesteps, samples_without_restart) = dpm_solver(None, 1);
+    let (restart_timesteps, samples) = dpm_solver(Some(RESTART), 1);
+    let restart_index = check_restart_schedule(&timesteps, &restart_timesteps);
+    let (_, samples_same_seed) = dpm_solver(Some(RESTART), 1);
+    let (_, samples_other_seed) = dpm_solver(Some(RESTART), 2);
+    check_restart_samples(
+        restart_index,
+        &samples,
+        &samples_same_seed,
+        &samples_other_seed,
+        &samples_without_restart,
+    );
+    assert!(bool::try_from(samples.last().unwrap().isfinite().all()).unwrap());
+}
+
+// A single test as the runs reseed the global random number generator.
+#[test]
+fn restart_boundary() {
+    euler_restart_boundary();
+    dpm_solver_restart_boundary();
+}
@@ -0,0 +1,163 @@
+use diffusers::pipelines::guidance;
+use diffusers::schedulers::ays::AysSchedule;
+use diffusers::schedulers::ddim::{DDIMScheduler, DDIMSchedulerConfig};
+use diffusers::schedulers::ddpm::{DDPMScheduler, DDPMSchedulerConfig};
+use diffusers::schedulers::dpmsolver_multistep::{
+    DPMSolverMultistepScheduler, DPMSolverMultistepSchedulerConfig,
+};
+use diffusers::schedulers::euler_discrete::{EulerDiscreteScheduler, EulerDiscreteSchedulerConfig};
+use diffusers::schedulers::lcm::{LCMScheduler, LCMSchedulerConfig};
+use diffusers::schedulers::tcd::{TCDScheduler, TCDSchedulerConfig};
+use diffusers::schedulers::{BetaSchedule, Scheduler, TimestepSpacing};
+use tch::{Device, Kind, Tensor};
+
+#[test]
+fn sgm_uniform_timesteps() {
+    // The SGM repo spreads the steps with
+    // np.linspace(999, 0, 30, endpoint=False), giving 999, 965.7, ..., 33.3.
+    let timesteps = TimestepSpacing::SgmUniform.timesteps(30, 1000);
+    assert_eq!(timesteps.len(), 30);
+    assert_eq!(timesteps[0], 999.);
+    assert!((timesteps[1] - 965.7).abs() < 1e-9);
+    assert!((timesteps[29] - 33.3).abs() < 1e-9);
+}
+
+#[test]
+fn timestep_spacings() {
+    let timesteps = TimestepSpacing::Linspace.timesteps(10, 1000);
+    assert_eq!((timesteps[0], timesteps[9]), (999., 0.));
+    let timesteps = TimestepSpacing::Leading.timesteps(10, 1000);
+    assert_eq!((timesteps[0], timesteps[9]), (900., 0.));
+    let timesteps = TimestepSpacing::Trailing.timesteps(10, 1000);
+    assert_eq!((timesteps[0], timesteps[9]), (999., 99.));
+}
+
+#[test]
+fn lcm_timesteps() {
+    // The diffusers LCMScheduler timesteps for 4 and 1 steps.
+    let scheduler = LCMScheduler::new(4, LCMSchedulerConfig::default());
+    assert_eq!(scheduler.timesteps(), [999, 759, 499, 259]);
+    let scheduler = LCMScheduler::new(1, LCMSchedulerConfig::default());
+    assert_eq!(scheduler.timesteps(), [999]);
+}
+
+#[test]
+fn lcm_last_step_returns_the_denoised_sample() {
+    tch::manual_seed(42);
+    let scheduler = LCMScheduler::new(4, LCMSchedulerConfig::default());
+    let sample = Tensor::randn([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let model_output = Tensor::randn([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let denoised = scheduler.pred_original_sample(&model_output, 259, &sample);
+    assert!(scheduler.step(&model_output, 259, &sample).equal(&denoised));
+    // The intermediary steps add some noise back.
+    assert!(!scheduler.step(&model_output, 999, &sample).equal(&denoised));
+}
+
+#[test]
+fn guidance_scale_one_skips_the_unconditional_branch() {
+    let xs = Tensor::ones([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let uncond = Tensor::zeros([1, 7, 32], (Kind::Float, Device::Cpu));
+    let cond = Tensor::ones([1, 7, 32], (Kind::Float, Device::Cpu));
+    let text_embeddings = Tensor::cat(&[&uncond, &cond], 0);
+    let mut calls = vec![];
+    let pred = guidance::guided_prediction(&xs, &text_embeddings, 1., true, |xs, embeddings| {
+        calls.push(embeddings.size()[0]);
+        xs * embeddings.mean(Kind::Float)
+    });
+    assert_eq!(calls, [1]);
+    assert!(pred.equal(&xs));
+}
+
+#[test]
+fn tcd_eta() {
+    let sample = Tensor::randn([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let model_output = Tensor::randn([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let step = |eta: f64, seed: i64| {
+        let scheduler = TCDScheduler::new(4, TCDSchedulerConfig { eta, ..Default::default() });
+        assert_eq!(scheduler.timesteps(), [999, 759, 499, 259]);
+        tch::manual_seed(seed);
+        scheduler.step(&model_output, 759, &sample)
+    };
+    // eta = 0 is deterministic, higher values sample some noise again.
+    assert!(step(0., 1).equal(&step(0., 2)));
+    assert!(!step(0.3, 1).equal(&step(0.3, 2)));
+    assert!(!step(0.3, 1).equal(&step(0., 1)));
+    assert!(step(0.3, 1).equal(&step(0.3, 1)));
+}
+
+#[test]
+fn ays_sigmas() {
+    // The 10 step schedules from the paper, the final sigma is replaced by 0.
+    let published = [
+        (
+            AysSchedule::StableDiffusion15,
+            [14.615, 6.475, 3.861, 2.697, 1.886, 1.396, 0.963, 0.652, 0.399, 0.152, 0.],
+        ),
+        (
+            AysSchedule::StableDiffusionXl,
+            [14.615, 6.315, 3.771, 2.181, 1.342, 0.862, 0.555, 0.380, 0.234, 0.113, 0.],
+        ),
+    ];
+    for (schedule, expected) in published {
+        assert_eq!(schedule.sigmas(10), expected, "{schedule:?}");
+        for inference_steps in [1, 4, 7, 11, 20, 50] {
+            let sigmas = schedule.sigmas(inference_steps);
+            assert_eq!(sigmas.len(), inference_steps + 1);
+            assert!((sigmas[0] - 14.615).abs() < 1e-9, "{schedule:?} {inference_steps}");
+            assert_eq!(sigmas[inference_steps], 0.);
+            assert!(sigmas.windows(2).all(|w| w[0] > w[1]), "{schedule:?} {sigmas:?}");
+        }
+    }
+}
+
+#[test]
+fn squaredcos_cap_v2_betas() {
+    // Signal to noise ratios of the diffusers betas_for_alpha_bar cosine schedule over
+    // 1000 training timesteps, the last beta is capped at 0.999.
+    let reference = [
+        (0, 24221.32715552543),
+        (1, 11437.497085434357),
+        (250, 5.488786806875221),
+        (500, 0.9696096031372297),
+        (750, 0.16710443294729208),
+        (999, 2.4287669129337654e-09),
+    ];
+    let config =
+        DDPMSchedulerConfig { beta_schedule: BetaSchedule::SquaredcosCapV2, ..Default::default() };
+    let snr = DDPMScheduler::new(1000, config).snr_array();
+    assert_eq!(snr.len(), 1000);
+    for (timestep, expected) in reference {
+        let relative_error = (snr[timestep] - expected).abs() / expected;
+        assert!(relative_error < 1e-6, "{timestep} {} {expected}", snr[timestep]);
+    }
+}
+
+// Sets a custom schedule through the `Scheduler` trait.
+fn set_custom<S: Scheduler>(scheduler: &mut S, timesteps: &[f64]) -> anyhow::Result<()> {
+    scheduler.set_timesteps_custom(timesteps)
+}
+
+#[test]
+fn custom_timesteps() {
+    let mut ddim = DDIMScheduler::new(20, DDIMSchedulerConfig::default());
+    set_custom(&mut ddim, &[999., 700., 700., 200., 0.]).unwrap();
+    assert_eq!(ddim.timesteps(), [999, 700, 700, 200, 0]);
+    let mut euler = EulerDiscreteScheduler::new(20, EulerDiscreteSchedulerConfig::default());
+    set_custom(&mut euler, &[999., 500.5, 0.]).unwrap();
+    assert_eq!(euler.timesteps(), [999., 500.5, 0.]);
+    // Same sigmas as the default schedule for the same timesteps.
+    let default = EulerDiscreteScheduler::new(3, EulerDiscreteSchedulerConfig::default());
+    set_custom(&mut euler, &[999., 499.5, 0.]).unwrap();
+    assert_eq!(euler.init_noise_sigma(), default.init_noise_sigma());
+
+    for invalid in [&[][..], &[1000.], &[-1.], &[500., 600.], &[999., 500., 501.]] {
+        assert!(set_custom(&mut ddim, invalid).is_err(), "{invalid:?}");
+        assert!(set_custom(&mut euler, invalid).is_err(), "{invalid:?}");
+    }
+    // DDIM uses integer timesteps.
+    assert!(set_custom(&mut ddim, &[999., 500.5, 0.]).is_err());
+    // Multistep solvers do not support arbitrary schedules.
+    let mut dpm_solver =
+        DPMSolverMultistepScheduler::new(20, DPMSolverMultistepSchedulerConfig::default());
+    assert!(set_custom(&mut dpm_solver, &[999., 500., 0.]).is_err());
+}
@@ -0,0 +1,55 @@
+// The random number generators are global to the process, this check is kept alone in
+// its own test binary so that no other test samples random values concurrently.
+use diffusers::models::unet_2d::{BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig};
+use diffusers::pipelines::denoise::DenoiseLoop;
+use diffusers::schedulers::ddim::{DDIMScheduler, DDIMSchedulerConfig};
+use diffusers::utils::{seeded_noise, set_seed};
+use tch::{nn, Device, Kind, Tensor};
+
+// Builds a tiny UNet and denoises some latents with it, the weights, the text
+// embeddings, and the initial noise are all sampled after seeding.
+fn generate(seed: i64) -> Tensor {
+    set_seed(seed);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let bc = |out_channels| BlockConfig {
+        out_channels,
+        use_cross_attn: true,
+        attention_head_dim: 2,
+        cross_attention_dim: None,
+        transformer_layers_per_block: 1,
+    };
+    let config = UNet2DConditionModelConfig {
+        blocks: vec![bc(8), bc(16)],
+        layers_per_block: 1,
+        norm_num_groups: 4,
+        cross_attention_dim: 16,
+        ..Default::default()
+    };
+    let unet = UNet2DConditionModel::new(vs.root(), 4, 4, config);
+    let mut scheduler = DDIMScheduler::new(3, DDIMSchedulerConfig::default());
+    let text_embeddings = Tensor::randn([1, 7, 16], (Kind::Float, Device::Cpu));
+    let latents = Tensor::randn([1, 4, 8, 8], (Kind::Float, Device::Cpu));
+    let output = tch::no_grad(|| {
+        DenoiseLoop::new().run(&mut scheduler, latents, |_step_index, timestep, xs| {
+            Ok(unet.forward(xs, timestep, &text_embeddings))
+        })
+    });
+    output.unwrap().latents
+}
+
+#[test]
+fn same_seed_same_latents() {
+    let latents = generate(42);
+    assert!(latents.equal(&generate(42)));
+    assert!(!latents.equal(&generate(43)));
+
+    let options = (Kind::Float, Device::Cpu);
+    let seeds = [7, 3, 7];
+    let noise = seeded_noise(&seeds, [3, 4, 8, 8], options).unwrap();
+    for (i, &seed) in seeds.iter().enumerate() {
+        let single = seeded_noise(&[seed], [1, 4, 8, 8], options).unwrap();
+        assert!(noise.narrow(0, i as i64, 1).equal(&single));
+    }
+    assert!(!noise.get(0).equal(&noise.get(1)));
+    assert!(seeded_noise(&seeds, [2, 4, 8, 8], options).is_err());
+}
@@ -0,0 +1,162 @@
+// Runs the forward passes of models shared behind an `Arc` from several threads, see the
+// thread safety notes in the crate documentation. Each thread uses its own inputs so that
+// mixing up the state of concurrent passes would show in the outputs, which are compared
+// with the ones of the same inputs run on a single thread.
+mod common;
+
+use common::{randn, tiny_controlnet, tiny_unet, tiny_vae, CROSS_ATTENTION_DIM};
+use diffusers::models::t2i_adapter::{T2IAdapter, T2IAdapterConfig};
+use diffusers::models::unet_2d::UNet2DConditionModel;
+use diffusers::transformers::clip::{ClipTextModelWithProjection, ClipTextTransformer, Config};
+use std::sync::Arc;
+use tch::{nn, nn::Module, Device, Kind, Tensor};
+
+const N_THREADS: usize = 4;
+const N_PASSES: usize = 3;
+
+// Runs `forward` on the inputs of each thread, first sequentially and then with all the
+// threads sharing the model, every concurrent pass has to match the sequential one.
+fn check_concurrent_forwards<M, F>(model: M, inputs: Vec<Vec<Tensor>>, forward: F)
+where
+    M: Send + Sync + 'static,
+    F: Fn(&M, &[Tensor]) -> Vec<Tensor> + Send + Sync + Copy + 'static,
+{
+    let expected: Vec<Vec<Tensor>> =
+        inputs.iter().map(|xs| tch::no_grad(|| forward(&model, xs))).collect();
+    let model = Arc::new(model);
+    let barrier = Arc::new(std::sync::Barrier::new(inputs.len()));
+    let handles: Vec<_> = inputs
+        .into_iter()
+        .map(|xs| {
+            let (model, barrier) = (model.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                // The grad mode is per thread in libtorch.
+                tch::no_grad(|| (0..N_PASSES).map(|_| forward(&model, &xs)).collect::<Vec<_>>())
+            })
+        })
+        .collect();
+    for (handle, expected) in handles.into_iter().zip(expected.iter()) {
+        for ys in handle.join().unwrap() {
+            assert_eq!(ys.len(), expected.len());
+            for (ys, expected) in ys.iter().zip(expected.iter()) {
+                assert!(ys.allclose(expected, 1e-6, 1e-6, false));
+            }
+        }
+    }
+}
+
+fn unet_inputs() -> Vec<Vec<Tensor>> {
+    (0..N_THREADS)
+        .map(|_| vec![randn(&[2, 4, 16, 24]), randn(&[2, 7, CROSS_ATTENTION_DIM])])
+        .collect()
+}
+
+// Small CLIP text models, the vocabulary is kept so that the end of text token matches.
+fn tiny_clip_config() -> Config {
+    serde_json::from_value(serde_json::json!({
+        "vocab_size": 49408,
+        "embed_dim": 16,
+        "activation": "quick_gelu",
+        "intermediate_size": 32,
+        "max_position_embeddings": 77,
+        "tokenizer_variant": "open_ai_clip",
+        "num_hidden_layers": 2,
+        "num_attention_heads": 2,
+        "projection_dim": 8,
+        "use_attention_mask": false,
+    }))
+    .unwrap()
+}
+
+fn clip_inputs() -> Vec<Vec<Tensor>> {
+    (0..N_THREADS)
+        .map(|i| {
+            let tokens = Tensor::randint(49406, [2, 77], (Kind::Int64, Device::Cpu));
+            let _ = tokens.narrow(1, 5 + i as i64, 1).fill_(49407);
+            vec![tokens]
+        })
+        .collect()
+}
+
+#[test]
+fn shared_unet() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let unet = tiny_unet(&vs);
+    let forward =
+        |unet: &UNet2DConditionModel, xs: &[Tensor]| vec![unet.forward(&xs[0], 999., &xs[1])];
+    check_concurrent_forwards(unet, unet_inputs(), forward);
+
+    // FreeU computes some scales and frequency masks for each pass.
+    let vs = nn::VarStore::new(Device::Cpu);
+    let mut unet = tiny_unet(&vs);
+    unet.enable_freeu(0.9, 0.2, 1.2, 1.4);
+    check_concurrent_forwards(unet, unet_inputs(), forward);
+}
+
+#[test]
+fn shared_controlnet() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    // The conditioning embedding cache is enabled by default, each thread uses its own
+    // conditioning so that the concurrent passes keep replacing the cached embedding.
+    let controlnet = tiny_controlnet(&vs);
+    let inputs = unet_inputs()
+        .into_iter()
+        .map(|mut xs| {
+            xs.push(Tensor::rand([1, 3, 128, 192], (Kind::Float, Device::Cpu)));
+            xs
+        })
+        .collect();
+    check_concurrent_forwards(controlnet, inputs, |controlnet, xs| {
+        let (mut down, mid) = controlnet.forward(&xs[0], 999., &xs[1], &xs[2], 1.);
+        down.push(mid);
+        down
+    });
+}
+
+#[test]
+fn shared_vae() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let vae = tiny_vae(&vs);
+    let inputs = (0..N_THREADS).map(|_| vec![randn(&[1, 3, 32, 48]), randn(&[1, 4, 8, 12])]);
+    check_concurrent_forwards(vae, inputs.collect(), |vae, xs| {
+        vec![vae.encode_image(&xs[0]), vae.decode(&xs[1])]
+    });
+}
+
+#[test]
+fn shared_t2i_adapter() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let config = T2IAdapterConfig {
+        in_channels: 3,
+        channels: vec![8, 16],
+        num_res_blocks: 1,
+        downscale_factor: 2,
+    };
+    let adapter = T2IAdapter::new(vs.root(), config);
+    let inputs = (0..N_THREADS).map(|_| vec![randn(&[1, 3, 32, 48])]);
+    check_concurrent_forwards(adapter, inputs.collect(), |adapter, xs| {
+        adapter.forward(&xs[0], 0.8)
+    });
+}
+
+#[test]
+fn shared_clip_text_models() {
+    tch::manual_seed(42);
+    let vs = nn::VarStore::new(Device::Cpu);
+    let text_model = ClipTextTransformer::new(vs.root(), &tiny_clip_config());
+    check_concurrent_forwards(text_model, clip_inputs(), |text_model, xs| {
+        vec![text_model.forward(&xs[0])]
+    });
+
+    let vs = nn::VarStore::new(Device::Cpu);
+    let text_model = ClipTextModelWithProjection::new(vs.root(), &tiny_clip_config());
+    check_concurrent_forwards(text_model, clip_inputs(), |text_model, xs| {
+        let output = text_model.forward(&xs[0]);
+        vec![output.penultimate_hidden_states, output.pooled_embeds]
+    });
+}
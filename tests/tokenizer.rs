@@ -0,0 +1,128 @@
+use diffusers::transformers::clip::{Config, PaddingStrategy, Tokenizer, TokenizerVariant};
+use std::sync::OnceLock;
+
+// Writes a vocabulary file with the layout of the CLIP one, the merges are made up
+// except for a few of them so that the tests do not depend on the actual vocabulary.
+fn vocab_file() -> &'static std::path::Path {
+    static VOCAB_FILE: OnceLock<std::path::PathBuf> = OnceLock::new();
+    VOCAB_FILE.get_or_init(|| {
+        let mut lines = vec!["#version: 0.2".to_string()];
+        for merge in ["c a", "ca t</w>", "d o", "do g</w>"] {
+            lines.push(merge.to_string())
+        }
+        let n_merges = 49152 - 256 - 2;
+        for i in lines.len() - 1..n_merges {
+            lines.push(format!("zz{i} yy{i}"))
+        }
+        let path = std::env::temp_dir().join("diffusers-test-bpe-vocab.txt");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path
+    })
+}
+
+fn tokenizer(config: &Config) -> Tokenizer {
+    Tokenizer::create(vocab_file(), config).unwrap()
+}
+
+#[test]
+fn break_keyword_starts_a_new_chunk() {
+    let tokenizer = tokenizer(&Config::v1_5());
+    let chunks = tokenizer.encode_chunks("a cat BREAK a dog").unwrap();
+    assert_eq!(chunks.len(), 2);
+    let cat = tokenizer.encode_pad("a cat", Some(77)).unwrap();
+    let dog = tokenizer.encode_pad("a dog", Some(77)).unwrap();
+    assert_eq!(chunks, [cat, dog]);
+    // Only upper case standalone words are keywords.
+    assert_eq!(tokenizer.encode_chunks("a cat break a dog").unwrap().len(), 1);
+    assert_eq!(tokenizer.encode_chunks("a BREAKdown").unwrap().len(), 1);
+}
+
+#[test]
+fn break_keyword_alone() {
+    let tokenizer = tokenizer(&Config::v1_5());
+    let empty = tokenizer.encode_pad("", Some(77)).unwrap();
+    let chunks = tokenizer.encode_chunks("BREAK").unwrap();
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0], empty);
+    assert_eq!(tokenizer.encode_chunks("BREAK BREAK").unwrap(), vec![empty.clone()]);
+    assert_eq!(tokenizer.encode_chunks("").unwrap(), [empty]);
+    let cat = tokenizer.encode_pad("a cat", Some(77)).unwrap();
+    let dog = tokenizer.encode_pad("a dog", Some(77)).unwrap();
+    assert_eq!(tokenizer.encode_chunks("a cat BREAK").unwrap(), vec![cat.clone()]);
+    // Leading and repeated keywords do not add empty chunks.
+    assert_eq!(tokenizer.encode_chunks("BREAK a dog").unwrap(), vec![dog.clone()]);
+    assert_eq!(tokenizer.encode_chunks("a cat BREAK BREAK a dog").unwrap(), [cat, dog]);
+}
+
+#[test]
+fn padding_and_truncation() {
+    let tokenizer = tokenizer(&Config::v1_5());
+    let end_of_text = tokenizer.end_of_text_token();
+    let short = "a cat";
+    // Each "a" is a single token, this gives 102 tokens with the start and end ones.
+    let long = ["a"; 100].join(" ");
+    let raw_short = tokenizer.encode_with(short, PaddingStrategy::None, false).unwrap();
+    let raw_long = tokenizer.encode_with(&long, PaddingStrategy::None, false).unwrap();
+    assert_eq!(raw_short.len(), 4);
+    assert_eq!(raw_long.len(), 102);
+    for padding in [PaddingStrategy::MaxLength, PaddingStrategy::Longest, PaddingStrategy::None] {
+        for truncation in [false, true] {
+            let encode = |s: &str| tokenizer.encode_with(s, padding, truncation).unwrap();
+            let tokens = encode(short);
+            let expected_len = if padding == PaddingStrategy::MaxLength { 77 } else { 4 };
+            assert_eq!(tokens.len(), expected_len, "{padding:?} {truncation}");
+            assert_eq!(tokens[..4], raw_short, "{padding:?} {truncation}");
+            assert!(tokens[4..].iter().all(|&t| t == end_of_text));
+
+            let tokens = encode(&long);
+            if truncation {
+                assert_eq!(tokens.len(), 77, "{padding:?}");
+                assert_eq!(tokens[..76], raw_long[..76], "{padding:?}");
+                assert_eq!(tokens[76], end_of_text, "{padding:?}");
+            } else {
+                assert_eq!(tokens, raw_long, "{padding:?}");
+            }
+
+            // In a batch, the longest strategy pads to the longest sequence.
+            let batch = tokenizer.encode_batch(&[short, long.as_str()], padding, truncation);
+            let lens: Vec<_> = batch.unwrap().iter().map(|t| t.len()).collect();
+            let long_len = if truncation { 77 } else { 102 };
+            let expected = match padding {
+                PaddingStrategy::MaxLength => [77, long_len],
+                PaddingStrategy::Longest => [long_len, long_len],
+                PaddingStrategy::None => [4, long_len],
+            };
+            assert_eq!(lens, expected, "{padding:?} {truncation}");
+        }
+    }
+}
+
+#[test]
+fn tokenizer_variant_special_tokens() {
+    use TokenizerVariant::{OpenAiClip, OpenClip};
+    let configs = [
+        (Config::v1_5(), OpenAiClip, 49407),
+        (Config::v2_1(), OpenClip, 0),
+        (Config::sdxl_text_encoder_2(), OpenClip, 0),
+        (Config::v1_5().with_tokenizer_variant(OpenClip), OpenClip, 0),
+        (Config::v2_1().with_tokenizer_variant(OpenAiClip), OpenAiClip, 49407),
+    ];
+    for (config, variant, pad) in configs {
+        assert_eq!(config.tokenizer_variant(), variant);
+        assert_eq!(config.end_of_text_token(), 49407);
+        let tokenizer = tokenizer(&config);
+        assert_eq!(tokenizer.start_of_text_token(), 49406, "{variant:?}");
+        assert_eq!(tokenizer.end_of_text_token(), 49407, "{variant:?}");
+        assert_eq!(tokenizer.pad_token().unwrap(), pad, "{variant:?}");
+
+        // The prompt is always terminated by the end of text token, then padded.
+        let tokens = tokenizer.encode_pad("a cat", Some(77)).unwrap();
+        assert_eq!(tokens.len(), 77);
+        assert_eq!((tokens[0], tokens[3]), (49406, 49407), "{variant:?}");
+        assert!(tokens[4..].iter().all(|&t| t == pad), "{variant:?}");
+        let tokens = tokenizer.encode_with("a cat", PaddingStrategy::MaxLength, false).unwrap();
+        assert_eq!((tokens[3], tokens[4], tokens[76]), (49407, pad, pad), "{variant:?}");
+        let batch = tokenizer.encode_batch(&["a", "a cat"], PaddingStrategy::Longest, false);
+        assert_eq!(batch.unwrap()[0], [49406, tokens[1], 49407, pad], "{variant:?}");
+    }
+}
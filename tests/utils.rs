@@ -0,0 +1,19 @@
+use diffusers::utils::{resize, Interpolation};
+use tch::{Device, Kind, Tensor};
+
+#[test]
+fn nearest_resize_keeps_masks_binary() {
+    tch::manual_seed(42);
+    let mask =
+        Tensor::rand([1, 1, 13, 17], (Kind::Float, Device::Cpu)).ge(0.5).to_kind(Kind::Float);
+    let is_binary = |xs: &Tensor| xs.eq(0.).logical_or(&xs.eq(1.)).all().int64_value(&[]) == 1;
+    // Upscaling and downscaling by non integer factors.
+    for (height, width) in [(32, 40), (7, 9)] {
+        let resized = resize(&mask, height, width, Interpolation::Nearest);
+        assert_eq!(resized.size(), [1, 1, height, width]);
+        assert!(is_binary(&resized), "{height}x{width}");
+        // Bilinear interpolation blends the mask values at the boundaries.
+        let resized = resize(&mask, height, width, Interpolation::Bilinear);
+        assert!(!is_binary(&resized), "{height}x{width}");
+    }
+}